@@ -202,6 +202,9 @@ static PRESETS: &'static [(&'static str, &'static str, &'static str)] = &[
     ("9",     "11q 12w 13e 14r 15t 22r 23e 24w 25q", ""),
     // 9-key PMS (BME-compatible)
     ("9-bme", "11q 12w 13e 14r 15t 18r 19e 16w 17q", ""),
+    // 4-panel and 8-panel dance charts (StepMania), SP/DP
+    ("4",     "11a 12b 13b 14a", ""),
+    ("8",     "11a 12b 13b 14a", "21a 22b 23b 24a"),
 ];
 
 /**
@@ -213,6 +216,7 @@ static PRESETS: &'static [(&'static str, &'static str, &'static str)] = &[
  *
  * - `bms`, `bme`, `bml` or no preset: Selects one of eight presets `{5,7,10,14}[/fp]`.
  * - `pms`: Selects one of two presets `9` and `9-bme`.
+ * - `sm` or `ssc`: Selects one of two presets `4` and `8`.
  */
 pub fn preset_to_key_spec(bms: &Bms, preset: Option<String>) -> Option<(String, String)> {
     use std::ascii::OwnedAsciiExt;
@@ -241,6 +245,10 @@ pub fn preset_to_key_spec(bms: &Bms, preset: Option<String>) -> Option<(String,
             let nkeys = if isbme {"9-bme"} else {"9"};
             nkeys.to_string()
         },
+        Some("sm") | Some("ssc") => {
+            let npanels = if bms.meta.mode == PlayMode::Double {"8"} else {"4"};
+            npanels.to_string()
+        },
         Some(_) => preset.unwrap()
     };
 
@@ -252,22 +260,55 @@ pub fn preset_to_key_spec(bms: &Bms, preset: Option<String>) -> Option<(String,
     None
 }
 
-/// Parses a key specification from the options.
+/// Converts the lanes declared by the BMS file (#SNRS:LANES) to the key specification if any.
+/// Channels #1x go to the left side and channels #2x go to the right side, as like presets.
+/// Malformed declarations (e.g. unknown key kinds, non-lane channels, duplicate lanes or no lane
+/// on the left side) are ignored, so that the caller can fall back to presets.
+pub fn declared_key_spec(bms: &Bms) -> Option<(String, String)> {
+    if bms.meta.lanes.is_empty() { return None; }
+
+    let mut leftkeys = String::new();
+    let mut rightkeys = String::new();
+    for &(chan, ref spec) in bms.meta.lanes.iter() {
+        let keys = if *chan < 72/*2*36*/ {&mut leftkeys} else {&mut rightkeys};
+        if !keys.is_empty() { keys.push_str(" "); }
+        keys.push_str(format!("{}{}", chan, *spec)[]);
+    }
+
+    let mut seen = [false, ..NLANES];
+    for keys in [leftkeys[], rightkeys[]].iter() {
+        match parse_key_spec(*keys) {
+            Some(specs) => {
+                for &(Lane(lane), _) in specs.iter() {
+                    if seen[lane] { return None; }
+                    seen[lane] = true;
+                }
+            }
+            None => { return None; }
+        }
+    }
+    if leftkeys.is_empty() { return None; }
+    Some((leftkeys, rightkeys))
+}
+
+/// Parses a key specification from the options. The lanes declared by the BMS file, if any,
+/// are used in the absence of explicit key specification and preset.
 pub fn key_spec(bms: &Bms, preset: Option<String>,
                 leftkeys: Option<String>, rightkeys: Option<String>) -> Result<KeySpec,String> {
     use std::ascii::AsciiExt;
     use util::std::option::StrOption;
 
+    let declared = if preset.is_none() {declared_key_spec(bms)} else {None};
     let (leftkeys, rightkeys) =
-        if leftkeys.is_none() && rightkeys.is_none() {
+        if leftkeys.is_none() && rightkeys.is_none() && declared.is_some() {
+            declared.unwrap()
+        } else if leftkeys.is_none() && rightkeys.is_none() {
             let ext = bms.bmspath.as_ref().and_then(|p| p.extension())
                                           .and_then(str::from_utf8).map(|e| e.to_ascii_lower());
-            let preset =
-                if preset.is_none() && ext.as_ref_slice() == Some("pms") {
-                    Some("pms".to_string())
-                } else {
-                    preset
-                };
+            let preset = match ext.as_ref_slice() {
+                Some("pms") | Some("sm") | Some("ssc") if preset.is_none() => ext.clone(),
+                _ => preset,
+            };
             match preset_to_key_spec(bms, preset.clone()) {
                 Some(leftright) => leftright,
                 None => {
@@ -317,3 +358,53 @@ pub fn key_spec(bms: &Bms, preset: Option<String>,
     Ok(keyspec)
 }
 
+#[cfg(test)]
+mod tests {
+    use format::obj::Lane;
+    use format::bms::testutil::{load, lane};
+    use super::{KeyKind, declared_key_spec, key_spec};
+
+    fn declared(source: &str) -> Option<(String, String)> {
+        declared_key_spec(&load(source))
+    }
+
+    #[test]
+    fn test_declared_key_spec() {
+        assert_eq!(declared("#SNRS:LANES 11 a 12 b 16 s"),
+                   Some(("11a 12b 16s".to_string(), "".to_string())));
+        assert_eq!(declared("#SNRS:LANES 11 q 21 w 13 e"),
+                   Some(("11q 13e".to_string(), "21w".to_string())));
+        assert_eq!(declared("#TITLE no lanes"), None);
+    }
+
+    #[test]
+    fn test_malformed_declared_key_spec() {
+        assert_eq!(declared("#SNRS:LANES 11 a 12 x"), None); // unknown key kind
+        assert_eq!(declared("#SNRS:LANES 11 a 31 b"), None); // not a lane channel
+        assert_eq!(declared("#SNRS:LANES 11 a 11 b"), None); // duplicate lane
+        assert_eq!(declared("#SNRS:LANES 21 a 22 b"), None); // nothing on the left side
+        assert_eq!(declared("#SNRS:LANES 11 ab"), None); // more than one key kind
+    }
+
+    #[test]
+    fn test_key_spec_with_declared_lanes() {
+        let bms = load("#SNRS:LANES 13 a 11 s 21 y\n#00111:01\n#00115:01");
+        let keyspec = key_spec(&bms, None, None, None).unwrap();
+        assert!(keyspec.order == vec![lane("13"), lane("11"), lane("21")]);
+        assert_eq!(keyspec.split, 3);
+        assert!(keyspec.kinds[*lane("11")] == Some(KeyKind::Scratch));
+        assert!(keyspec.kinds[*lane("15")].is_none());
+
+        // an explicit preset overrides the declaration
+        let keyspec = key_spec(&bms, Some("5".to_string()), None, None).unwrap();
+        assert_eq!(keyspec.order.len(), 6);
+    }
+
+    #[test]
+    fn test_key_spec_with_malformed_declared_lanes() {
+        // falls back to the 5-key preset inferred from the chart
+        let bms = load("#SNRS:LANES 11 a 12 x\n#00111:01\n#00115:01");
+        let keyspec = key_spec(&bms, None, None, None).unwrap();
+        assert!(keyspec.order == vec![Lane(6), Lane(1), Lane(2), Lane(3), Lane(4), Lane(5)]);
+    }
+}
@@ -12,16 +12,19 @@ use libc;
 
 use sdl::{get_ticks, event};
 use sdl_mixer;
-use format::obj::{NLANES, NLAYERS, Lane, BPM, Damage, BGARef};
-use format::obj::{ObjQueryOps, Visible, LNStart, LNDone, Bomb, BGM, SetBGA, SetBPM, ObjAxis};
+use format::obj::{NLANES, NLAYERS, NSOUNDGROUPS, Lane, BPM, Damage, BGARef, BGALayer};
+use format::obj::{SoundGroup, ObjQueryOps, ObjAxis};
+use format::obj::{Visible, LNStart, LNDone, Bomb, BGM, SetBGA, SetBPM};
+use format::obj::{SetBGAOpacity, SetBGAColorKey, SetSwitchBGA, SetVolume, SetText, SetGradeFactor};
 use format::timeline::TimelineInfo;
 use format::pointer::TimelinePointerUtil;
 use format::bms::{Key, ImageRef, SoundRef};
-use format::bms::{BmsTimeline, BmsPointer, BmsMeta, Bms};
+use format::bms::{BmsTimeline, BmsPointer, BmsMeta, Bms, SwitchBGA, default_total};
 use engine::keyspec::KeySpec;
 use engine::input::{Input, VirtualInput, InputState, KeyMap};
 use engine::resource::Soundlike;
-use ui::options::{Options, Modifier};
+use util::std::option::StrOption;
+use ui::options::{Options, Modifier, GaugeType};
 
 /// Applies given modifier to given group of lanes in the game data. `begin` and `end` should be
 /// a valid range from 0 to `keyspec.order.len()`, where `end` is exclusive.
@@ -55,14 +58,72 @@ pub fn apply_modf<R:Rng>(bms: &mut Bms, modf: Modifier, r: &mut R, keyspec: &Key
     }
 }
 
-/// A list of image references displayed in BGA layers (henceforth the BGA state). Not all image
-/// referenced here is directly rendered, but the references themselves are kept.
-pub type BGAState = [BGARef<ImageRef>, ..NLAYERS];
+/// A list of image references displayed in BGA layers, their opacities and color keys
+/// (henceforth the BGA state). Not all image referenced here is directly rendered, but
+/// the references themselves are kept.
+pub struct BGAState {
+    /// Image references per layer.
+    pub refs: [BGARef<ImageRef>, ..NLAYERS],
+    /// Opacities per layer, from 0 (fully transparent) to 255 (fully opaque).
+    pub opacities: [u8, ..NLAYERS],
+    /// Color keys per layer if any. The pixel with the color key is rendered transparent.
+    pub colorkeys: [Option<(u8,u8,u8)>, ..NLAYERS],
+}
 
 /// Returns the initial BGA state. Note that merely setting a particular layer doesn't start
 /// the movie playback; `poorbgafix` in `parser::parse` function handles it.
+///
+/// Like other BMS implementations, black is transparent in the second and third layers
+/// unless the color key is explicitly changed.
 pub fn initial_bga_state() -> BGAState {
-    [BGARef::Blank, BGARef::Blank, BGARef::Blank, BGARef::Image(ImageRef(Key(0)))]
+    BGAState {
+        refs: [BGARef::Blank, BGARef::Blank, BGARef::Blank, BGARef::Image(ImageRef(Key(0))),
+               BGARef::Blank],
+        opacities: [255, ..NLAYERS],
+        colorkeys: [None, Some((0,0,0)), Some((0,0,0)), None, None],
+    }
+}
+
+/// Returns color keys of BGA layers each image can be displayed in, indexed by the image
+/// reference. This follows the BGA state through the timeline, and also includes the color key
+/// of every switch BGA frame.
+pub fn image_layer_color_keys(bms: &Bms) -> Vec<Vec<(u8,u8,u8)>> {
+    fn add(layerkeys: &mut Vec<Vec<(u8,u8,u8)>>, iref: Option<&ImageRef>,
+           colorkey: Option<(u8,u8,u8)>) {
+        match (iref, colorkey) {
+            (Some(&ImageRef(Key(i))), Some(colorkey)) if (i as uint) < layerkeys.len() => {
+                if !layerkeys[i as uint].contains(&colorkey) {
+                    layerkeys[mut][i as uint].push(colorkey);
+                }
+            }
+            (_, _) => {}
+        }
+    }
+
+    let mut layerkeys = Vec::from_elem(bms.meta.imgpath.len(), Vec::new());
+    let mut state = initial_bga_state();
+    for layer in range(0, NLAYERS) {
+        add(&mut layerkeys, state.refs[layer].as_image_ref(), state.colorkeys[layer]);
+    }
+    for obj in bms.timeline.objs.iter() {
+        match obj.data {
+            SetBGA(layer, ref bgaref) => {
+                add(&mut layerkeys, bgaref.as_image_ref(), state.colorkeys[layer as uint]);
+                state.refs[layer as uint] = bgaref.clone();
+            }
+            SetBGAColorKey(layer, colorkey) => {
+                add(&mut layerkeys, state.refs[layer as uint].as_image_ref(), colorkey);
+                state.colorkeys[layer as uint] = colorkey;
+            }
+            _ => {}
+        }
+    }
+    for swbga in bms.meta.swbgas.iter().filter_map(|swbga| swbga.as_ref()) {
+        for iref in swbga.frames.iter() {
+            add(&mut layerkeys, Some(iref), Some(swbga.colorkey));
+        }
+    }
+    layerkeys
 }
 
 /// Grades. Sonorous performs the time-based grading as long as possible (it can go wrong when
@@ -73,8 +134,8 @@ pub enum Grade {
      * Issued when the player did not input the object at all, the player was pressing the key
      * while a bomb passes through the corresponding lane, or failed to unpress the key within
      * the grading area for the end of LN. Resets the combo number, decreases the gauge
-     * by severe amount (`MISS_DAMAGE` unless specified by the bomb) and displays the POOR BGA
-     * for moments.
+     * by severe amount (`GaugeRules::missdamage` unless specified by the bomb) and displays
+     * the POOR BGA for moments.
      *
      * Several games also use separate grading areas for empty lanes next to the object,
      * in order to avoid continuing the consecutive run ("combo") of acceptable grades by
@@ -87,18 +148,20 @@ pub enum Grade {
     /// Issued when the player inputed the object and the normalized time difference (that is,
     /// the time difference multiplied by `Player::gradefactor`) between the input point and
     /// the object is between `GOOD_CUTOFF` and `BAD_CUTOFF` seconds. Resets the combo number,
-    /// decreases the gauge by moderate amount (`BAD_DAMAGE`) and displays the POOR BGA for moments.
+    /// decreases the gauge by moderate amount (`GaugeRules::baddamage`) and displays the POOR BGA
+    /// for moments.
     BAD  = 1,
     /// Issued when the player inputed the object and the normalized time difference is between
-    /// `GREAT_CUTOFF` and `GOOD_CUTOFF` seconds. Both the combo number and gauge is left unchanged.
+    /// `GREAT_CUTOFF` and `GOOD_CUTOFF` seconds. The combo number is left unchanged and the gauge
+    /// is replenished by the half of the amount for GREAT.
     GOOD = 2,
     /// Issued when the player inputed the object and the normalized time difference is between
     /// `COOL_CUTOFF` and `GREAT_CUTOFF` . The combo number is increased by one and the gauge is
-    /// replenished by small amount.
+    /// replenished by the amount determined by `GaugeRules::recovery`.
     GREAT = 3,
     /// Issued when the player inputed the object and the normalized time difference is less
     /// than `COOL_CUTOFF` . The combo number is increased by one and the gauge is replenished by
-    /// large amount.
+    /// the same amount as GREAT.
     COOL = 4,
 }
 
@@ -120,11 +183,80 @@ pub const MAXGAUGE: int = 512;
 /// by the larger time difference.
 pub const SCOREPERNOTE: f64 = 300.0;
 
-/// A damage due to the MISS grading. Only applied when the grading is not due to the bomb.
+/// A damage due to the MISS grading in the normal gauge. Only applied when the grading is not
+/// due to the bomb.
 const MISS_DAMAGE: Damage = Damage::Gauge(0.059);
-/// A damage due to the BAD grading.
+/// A damage due to the BAD grading in the normal gauge.
 const BAD_DAMAGE: Damage = Damage::Gauge(0.030);
 
+/// Describes how the gauge recovers for each GREAT or COOL grade. GOOD grade always recovers
+/// the half of that amount.
+#[deriving(PartialEq,Clone)]
+pub enum GaugeRecovery {
+    /// Recovers by `Player::total` percents divided by the number of notes, multiplied by
+    /// given factor.
+    Total(f64),
+    /// Recovers by given ratio to `MAXGAUGE` no matter how many notes are there.
+    Fixed(f64),
+}
+
+/// Gauge rules determined by the gauge type.
+#[deriving(PartialEq,Clone)]
+pub struct GaugeRules {
+    /// The initial gauge as the ratio to `MAXGAUGE`.
+    pub initial: f64,
+    /// The gauge required to survive at the end of the song as the ratio to `MAXGAUGE`.
+    /// Not used when `failonempty` is set.
+    pub survival: f64,
+    /// The recovery for GREAT and COOL grades.
+    pub recovery: GaugeRecovery,
+    /// A damage due to the MISS grading. Only applied when the grading is not due to the bomb.
+    pub missdamage: Damage,
+    /// A damage due to the BAD grading.
+    pub baddamage: Damage,
+    /// True if the game play fails immediately when the gauge reaches zero, as like
+    /// `Damage::InstantDeath`.
+    pub failonempty: bool,
+}
+
+impl GaugeRules {
+    /// Returns true if the game play should stop immediately at given gauge.
+    pub fn fails_at(&self, gauge: f64) -> bool {
+        self.failonempty && gauge <= 0.0
+    }
+}
+
+/// Returns the gauge rules for given gauge type.
+///
+/// The normal gauge is the original one from Angolmois. The easy gauge takes about 80% of
+/// the normal damages and recovers 120% of the normal amount. The hard, ex-hard and hazard gauges
+/// start full, recover by a fixed amount and fail as soon as the gauge is depleted; the ex-hard
+/// gauge takes larger damages than the hard gauge, and the hazard gauge fails at any MISS or BAD.
+pub fn gauge_rules(gaugetype: GaugeType) -> GaugeRules {
+    match gaugetype {
+        GaugeType::Easy =>
+            GaugeRules { initial: 0.500, survival: 0.293, recovery: GaugeRecovery::Total(1.2),
+                         missdamage: Damage::Gauge(0.047), baddamage: Damage::Gauge(0.024),
+                         failonempty: false },
+        GaugeType::Normal =>
+            GaugeRules { initial: 0.500, survival: 0.293, recovery: GaugeRecovery::Total(1.0),
+                         missdamage: MISS_DAMAGE, baddamage: BAD_DAMAGE,
+                         failonempty: false },
+        GaugeType::Hard =>
+            GaugeRules { initial: 1.000, survival: 0.0, recovery: GaugeRecovery::Fixed(0.0016),
+                         missdamage: Damage::Gauge(0.100), baddamage: Damage::Gauge(0.060),
+                         failonempty: true },
+        GaugeType::ExHard =>
+            GaugeRules { initial: 1.000, survival: 0.0, recovery: GaugeRecovery::Fixed(0.0016),
+                         missdamage: Damage::Gauge(0.180), baddamage: Damage::Gauge(0.120),
+                         failonempty: true },
+        GaugeType::Hazard =>
+            GaugeRules { initial: 1.000, survival: 0.0, recovery: GaugeRecovery::Fixed(0.0016),
+                         missdamage: Damage::InstantDeath, baddamage: Damage::InstantDeath,
+                         failonempty: true },
+    }
+}
+
 /// Game play states independent to the display.
 pub struct Player {
     /// The game play options.
@@ -156,6 +288,16 @@ pub struct Player {
     pub lastchsnd: Vec<Option<uint>>,
     /// Currently active BGA layers.
     pub bga: BGAState,
+    /// The current volumes per sound group, as a ratio to the full volume. `BmsMeta::volume`
+    /// is applied separately.
+    pub volumes: [f64, ..NSOUNDGROUPS],
+    /// An index to the currently displayed text in `BmsMeta::texts` if any.
+    pub textindex: Option<uint>,
+    /// An index to the current switch BGA in `BmsMeta::swbgas` if any.
+    pub swbgaindex: Option<uint>,
+    /// The time when the current switch BGA has been triggered if any. Reset when the switch BGA
+    /// which is displayed as long as the lane is pressed is released.
+    pub swbgastart: Option<uint>,
 
     /// The chart expansion rate, or "play speed". One measure has the length of 400 pixels
     /// times the play speed, so higher play speed means that objects will fall much more
@@ -187,9 +329,14 @@ pub struct Player {
     /// `MeasureLine` is not rendered.
     pub reverse: Option<BmsPointer>,
 
-    /// The scale factor for grading area. The factor less than 1 causes the grading area
-    /// shrink.
+    /// The scale factor for grading area. The factor larger than 1 causes the grading area
+    /// shrink. Initially derived from the metadata, and updated by `SetGradeFactor` objects.
     pub gradefactor: f64,
+    /// The total gauge recovery in percents, when every object is graded at least GREAT.
+    /// Either taken from `BmsMeta::total` or calculated by `default_total`.
+    pub total: f64,
+    /// Gauge rules for the current gauge type.
+    pub gaugerules: GaugeRules,
     /// The last grade and time when the grade is issued.
     pub lastgrade: Option<(Grade,uint)>,
     /// The numbers of each grades.
@@ -205,11 +352,11 @@ pub struct Player {
     pub score: uint,
     /// The current health gauge. Should be no larger than `MAXGAUGE`. This can go negative
     /// (not displayed directly), which will require players much more efforts to survive.
-    pub gauge: int,
-    /// The health gauge required to survive at the end of the song. Note that the gaugex
+    pub gauge: f64,
+    /// The health gauge required to survive at the end of the song. Note that the gauge
     /// less than this value (or even zero) doesn't cause the instant game over;
-    /// only `InstantDeath` value from `Damage` does.
-    pub survival: int,
+    /// only `InstantDeath` value from `Damage` or `GaugeRules::failonempty` does.
+    pub survival: f64,
 
     /// The number of keyboard or joystick keys, mapped to each lane and and currently pressed.
     pub keymultiplicity: [uint, ..NLANES],
@@ -272,9 +419,11 @@ impl Player {
         let now = get_ticks();
         let initplayspeed = opts.playspeed;
         let originoffset = infos.originoffset;
-        let gradefactor = 1.5 - cmp::min(meta.rank, 5) as f64 * 0.25;
-        let initialgauge = MAXGAUGE * 500 / 1000;
-        let survival = MAXGAUGE * 293 / 1000;
+        let gradefactor = meta.gradefactor();
+        let total = meta.total.unwrap_or_else(|| default_total(infos.nnotes));
+        let gaugerules = gauge_rules(opts.gaugetype);
+        let initialgauge = MAXGAUGE as f64 * gaugerules.initial;
+        let survival = MAXGAUGE as f64 * gaugerules.survival;
         let initbpm = timeline.initbpm;
         let nobjs = timeline.objs.len();
         let nsounds = sndres.len();
@@ -288,14 +437,16 @@ impl Player {
 
             nograding: Vec::from_elem(nobjs, false), sndres: sndres, beep: create_beep(),
             sndlastch: Vec::from_elem(nsounds, None), lastchsnd: Vec::new(),
-            bga: initial_bga_state(),
+            bga: initial_bga_state(), volumes: [1.0, ..NSOUNDGROUPS], textindex: None,
+            swbgaindex: None, swbgastart: None,
 
             playspeed: initplayspeed, targetspeed: None, bpm: initbpm, now: now, origintime: now,
 
             origin: origin.clone(), cur: origin.clone(), checked: origin.clone(),
             thru: Vec::from_fn(NLANES, |_| None), reverse: None,
 
-            gradefactor: gradefactor, lastgrade: None, gradecounts: [0, ..NGRADES],
+            gradefactor: gradefactor, total: total, gaugerules: gaugerules,
+            lastgrade: None, gradecounts: [0, ..NGRADES],
             lastcombo: 0, bestcombo: 0, score: 0, gauge: initialgauge, survival: survival,
 
             keymultiplicity: [0, ..NLANES], joystate: [InputState::Neutral, ..NLANES],
@@ -319,10 +470,53 @@ impl Player {
         self.targetspeed.unwrap_or(self.playspeed)
     }
 
+    /// Returns the currently displayed text if any.
+    pub fn text<'a>(&'a self) -> Option<&'a str> {
+        match self.textindex {
+            Some(index) if index < self.meta.texts.len() => self.meta.texts[index].as_ref_slice(),
+            _ => None,
+        }
+    }
+
+    /// Returns the current switch BGA if any.
+    pub fn switch_bga<'a>(&'a self) -> Option<&'a SwitchBGA> {
+        match self.swbgaindex {
+            Some(index) if index < self.meta.swbgas.len() => self.meta.swbgas[index].as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Updates `BGALayer::Switch` in the BGA state according to the current switch BGA.
+    pub fn update_switch_bga(&mut self) {
+        let (bgaref, colorkey) = match (self.switch_bga(), self.swbgastart) {
+            (Some(swbga), Some(start)) => {
+                let frame = swbga.frame_at(self.now - start);
+                (frame.map_or(BGARef::Blank, BGARef::Image), Some(swbga.colorkey))
+            }
+            (_, _) => (BGARef::Blank, None),
+        };
+        self.bga.refs[BGALayer::Switch as uint] = bgaref;
+        self.bga.colorkeys[BGALayer::Switch as uint] = colorkey;
+    }
+
+    /// Returns true if the current gauge is enough to clear the song.
+    pub fn survives(&self) -> bool {
+        if self.gaugerules.failonempty {
+            self.gauge > 0.0
+        } else {
+            self.gauge >= self.survival
+        }
+    }
+
+    /// Returns true if the gauge has been depleted and the game play should stop immediately.
+    pub fn failed(&self) -> bool {
+        self.gaugerules.fails_at(self.gauge)
+    }
+
     /// Updates the score and associated statistics according to grading. `scoredelta` is
     /// an weight normalized to [0,1] that is calculated from the distance between the object
-    /// and the input time, and `damage` is an optionally associated `Damage` value for bombs.
-    /// May return true when `Damage` resulted in the instant death.
+    /// and the input time, and `damage` is an optionally associated `Damage` value.
+    /// May return false when `Damage` resulted in the instant death or the gauge failed.
     pub fn update_grade(&mut self, grade: Grade, scoredelta: f64,
                         damage: Option<Damage>) -> bool {
         self.gradecounts[grade as uint] += 1;
@@ -331,25 +525,33 @@ impl Player {
                        (1.0 + (self.lastcombo as f64) /
                               (self.infos.nnotes as f64))) as uint;
 
+        // the gauge recovers by `total` percents when every note is graded at least GREAT,
+        // unless the gauge rules use the fixed recovery
+        let recovery = match self.gaugerules.recovery {
+            GaugeRecovery::Total(factor) =>
+                MAXGAUGE as f64 * self.total * factor / 100.0 / (self.infos.nnotes as f64),
+            GaugeRecovery::Fixed(ratio) => MAXGAUGE as f64 * ratio,
+        };
         match grade {
             Grade::MISS | Grade::BAD => { self.lastcombo = 0; }
-            Grade::GOOD => {}
+            Grade::GOOD => {
+                self.gauge = (self.gauge + recovery * 0.5).min(MAXGAUGE as f64);
+            }
             Grade::GREAT | Grade::COOL => {
-                // at most 5/512(1%) recover when the combo is topped
-                let weight = if grade == Grade::GREAT {2} else {3};
-                let cmbbonus = cmp::min(self.lastcombo as int, 100) / 50;
                 self.lastcombo += 1;
-                self.gauge = cmp::min(self.gauge + weight + cmbbonus, MAXGAUGE);
+                self.gauge = (self.gauge + recovery).min(MAXGAUGE as f64);
             }
         }
         self.bestcombo = cmp::max(self.bestcombo, self.lastcombo);
 
         match damage {
             Some(Damage::Gauge(ratio)) => {
-                self.gauge -= (MAXGAUGE as f64 * ratio) as int; true
+                self.gauge -= MAXGAUGE as f64 * ratio;
+                if self.failed() { self.gauge = 0.0; }
+                !self.failed()
             }
             Some(Damage::InstantDeath) => {
-                self.gauge = cmp::min(self.gauge, 0); false
+                self.gauge = self.gauge.min(0.0); false
             }
             None => true
         }
@@ -357,30 +559,33 @@ impl Player {
 
     /// Same as `update_grade`, but the grade is calculated from the normalized difference
     /// between the object and input time in seconds. The normalized distance equals to
-    /// the actual time difference when `gradefactor` is 1.0.
+    /// the actual time difference when `gradefactor` is 1.0. The gauge may fail as a result,
+    /// which `failed` reports.
     pub fn update_grade_from_distance(&mut self, dist: f64) {
         let dist = dist.abs();
+        let baddamage = self.gaugerules.baddamage;
+        let missdamage = self.gaugerules.missdamage;
         let (grade, damage) = if      dist <  COOL_CUTOFF {(Grade::COOL,None)}
                               else if dist < GREAT_CUTOFF {(Grade::GREAT,None)}
                               else if dist <  GOOD_CUTOFF {(Grade::GOOD,None)}
-                              else if dist <   BAD_CUTOFF {(Grade::BAD,Some(BAD_DAMAGE))}
-                              else                        {(Grade::MISS,Some(MISS_DAMAGE))};
+                              else if dist <   BAD_CUTOFF {(Grade::BAD,Some(baddamage))}
+                              else                        {(Grade::MISS,Some(missdamage))};
         let scoredelta = 1.0 - dist / BAD_CUTOFF;
         let scoredelta = if scoredelta > 0.0 {scoredelta} else {0.0};
-        let keepgoing = self.update_grade(grade, scoredelta, damage);
-        assert!(keepgoing);
+        self.update_grade(grade, scoredelta, damage);
     }
 
     /// Same as `update_grade`, but with the predetermined damage value. Always results in MISS
-    /// grade. May return true when the damage resulted in the instant death.
+    /// grade. May return false when the damage resulted in the instant death.
     pub fn update_grade_from_damage(&mut self, damage: Damage) -> bool {
         self.update_grade(Grade::MISS, 0.0, Some(damage))
     }
 
     /// Same as `update_grade`, but always results in MISS grade with the standard damage value.
+    /// The gauge may fail as a result, which `failed` reports.
     pub fn update_grade_to_miss(&mut self) {
-        let keepgoing = self.update_grade(Grade::MISS, 0.0, Some(MISS_DAMAGE));
-        assert!(keepgoing);
+        let missdamage = self.gaugerules.missdamage;
+        self.update_grade(Grade::MISS, 0.0, Some(missdamage));
     }
 
     /// Allocate more SDL_mixer channels without stopping already playing channels.
@@ -396,7 +601,7 @@ impl Player {
 
     /// Plays a given sound referenced by `sref`. `bgm` indicates that the sound is a BGM and
     /// should be played with the lower volume and should in the different channel group from
-    /// key sounds.
+    /// key sounds. The current volume of the sound group and `BmsMeta::volume` also apply.
     pub fn play_sound(&mut self, sref: SoundRef, bgm: bool) {
         let sref = **sref as uint;
         if self.sndres[sref].chunk().is_none() {
@@ -414,7 +619,11 @@ impl Player {
         }
 
         let group = if bgm {1} else {0};
-        sdl_mixer::set_channel_volume(Some(ch), if bgm {96} else {128});
+        let (basevolume, soundgroup) = if bgm {(96.0, SoundGroup::Background)}
+                                       else {(128.0, SoundGroup::KeySound)};
+        let volume = basevolume * self.meta.volume * self.volumes[soundgroup as uint];
+        let volume = volume.max(0.0).min(128.0).round() as libc::c_int;
+        sdl_mixer::set_channel_volume(Some(ch), volume);
         sdl_mixer::group_channel(Some(ch), Some(group));
 
         let ch = ch as uint;
@@ -474,8 +683,16 @@ impl Player {
     }
 
     /// Processes the unpress event at given lane:
-    /// checks if we need to issue a MISS grade.
+    /// checks if we need to issue a MISS grade, and stops the switch BGA if needed.
     pub fn process_unpress(&mut self, lane: Lane) {
+        // the switch BGA without the fixed duration stops as soon as the lane is released
+        let stopswbga = self.switch_bga().map_or(false, |swbga| {
+            swbga.lane == lane && swbga.duration.is_none()
+        });
+        if stopswbga {
+            self.swbgastart = None;
+        }
+
         // if LN grading is in progress and it is not within the threshold then
         // MISS grade is issued
         let nextlndone =
@@ -497,8 +714,15 @@ impl Player {
     }
 
     /// Processes the press event at given lane:
-    /// plays the closest key sound if any, and grades the closest gradable object if possible.
+    /// plays the closest key sound if any, grades the closest gradable object if possible,
+    /// and triggers the switch BGA if any.
     pub fn process_press(&mut self, lane: Lane) {
+        // (re)starts the switch BGA associated to the lane
+        let startswbga = self.switch_bga().map_or(false, |swbga| swbga.lane == lane);
+        if startswbga {
+            self.swbgastart = Some(self.now);
+        }
+
         // plays the closest key sound
         let soundable =
             self.cur.find_closest_of_type(ObjAxis::VirtualTime, |obj| {
@@ -563,7 +787,26 @@ impl Player {
                             self.play_sound_if_nonzero(sref, true);
                         }
                         SetBGA(layer, bgaref) => {
-                            self.bga[layer as uint] = bgaref;
+                            self.bga.refs[layer as uint] = bgaref;
+                        }
+                        SetBGAOpacity(layer, opacity) => {
+                            self.bga.opacities[layer as uint] = opacity;
+                        }
+                        SetBGAColorKey(layer, colorkey) => {
+                            self.bga.colorkeys[layer as uint] = colorkey;
+                        }
+                        SetVolume(group, volume) => {
+                            self.volumes[group as uint] = volume;
+                        }
+                        SetText(index) => {
+                            self.textindex = Some(index);
+                        }
+                        SetSwitchBGA(index) => {
+                            self.swbgaindex = Some(index);
+                            self.swbgastart = None;
+                        }
+                        SetGradeFactor(factor) => {
+                            self.gradefactor = factor;
                         }
                         SetBPM(newbpm) => {
                             self.bpm = newbpm;
@@ -669,6 +912,9 @@ impl Player {
 
         }
 
+        // update the switch BGA after the inputs are processed
+        self.update_switch_bga();
+
         // process bombs
         if !opts.is_autoplay() {
             for p in prev.upto(&self.cur) {
@@ -690,6 +936,12 @@ impl Player {
             }
         }
 
+        // the hard gauges fail immediately when the gauge is depleted
+        if self.failed() {
+            self.cur = self.cur.find_end();
+            return false;
+        }
+
         // determines if we should keep playing
         if self.cur.index == self.timeline.objs.len() {
             if opts.is_autoplay() {
@@ -714,3 +966,72 @@ impl Drop for Player {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use format::obj::ObjData;
+    use format::bms::{ImageRef, SoundRef};
+    use format::bms::load::{LoaderOptions, load_bms};
+    use format::bms::testutil::{load, objs};
+    use engine::keyspec::key_spec;
+    use ui::options::{GaugeType, Modifier};
+    use util::std::rand::seeded_rng;
+    use super::{MAXGAUGE, gauge_rules, image_layer_color_keys, apply_modf};
+
+    #[test]
+    fn test_fails_at() {
+        let hard = gauge_rules(GaugeType::Hard);
+        assert!(!hard.fails_at(MAXGAUGE as f64 * hard.initial));
+        assert!(!hard.fails_at(0.1));
+        assert!(hard.fails_at(0.0));
+        assert!(hard.fails_at(-1.0));
+
+        let normal = gauge_rules(GaugeType::Normal);
+        assert!(!normal.fails_at(0.0));
+        assert!(!normal.fails_at(-1.0));
+
+        assert!(!gauge_rules(GaugeType::Easy).fails_at(0.0));
+        assert!(gauge_rules(GaugeType::ExHard).fails_at(0.0));
+        assert!(gauge_rules(GaugeType::Hazard).fails_at(0.0));
+    }
+
+    #[test]
+    fn test_image_layer_color_keys() {
+        let bms = load("#BMP01 a.bmp\n#BMP02 b.bmp\n#BMP03 c.bmp\n#BMP04 d.bmp\n\
+                        #ARGB01 255,255,0,255\n\
+                        #SWBGA01 100:0:11:1:255,0,0,255 0304\n\
+                        #00104:01\n#00107:01\n#001A1:0001\n#0020A:02\n#002A3:0001");
+        let keys = image_layer_color_keys(&bms);
+        assert_eq!(keys[0], vec![]); // POOR BGA without the color key
+        // black in layer 2, and the color key changed later in layer 1
+        assert_eq!(keys[1], vec![(0,0,0), (255,0,255)]);
+        // black in layer 3, and the color key changed while displayed
+        assert_eq!(keys[2], vec![(0,0,0), (255,0,255)]);
+        // every frame of the switch BGA
+        assert_eq!(keys[3], vec![(0,0,255)]);
+        assert_eq!(keys[4], vec![(0,0,255)]);
+    }
+
+    /// Loads a chart with #RANDOM and shuffles its lanes, using the RNG seeded by `seed` for
+    /// both as `ui::selecting::preprocess_bms` does.
+    fn load_and_shuffle(seed: u64) -> Vec<(f64, ObjData<SoundRef,ImageRef>)> {
+        let source = "#WAV01 a.wav\n#WAV02 b.wav\n#WAV03 c.wav\n#WAV04 d.wav\n#WAV05 e.wav\n\
+                      #RANDOM 100\n#IF 1\n#00211:01\n#ELSE\n#00212:02\n#ENDIF\n#ENDRANDOM\n\
+                      #00111:01\n#00112:02\n#00113:03\n#00114:04\n#00115:05";
+        let mut r = seeded_rng(seed);
+        let mut bms = load_bms(&mut BufReader::new(source.as_bytes()), &mut r,
+                               &LoaderOptions::new(), |_, _| true).unwrap();
+        let keyspec = key_spec(&bms, None, None, None).unwrap();
+        apply_modf(&mut bms, Modifier::Shuffle, &mut r, &keyspec);
+        objs(&bms)
+    }
+
+    #[test]
+    fn test_seeded_loading() {
+        for seed in range(0u64, 10) {
+            assert_eq!(load_and_shuffle(seed), load_and_shuffle(seed));
+        }
+        let first = load_and_shuffle(0);
+        assert!(range(1u64, 10).any(|seed| load_and_shuffle(seed) != first));
+    }
+}
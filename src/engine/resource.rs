@@ -4,14 +4,21 @@
 
 //! Resource management.
 
-use std::str;
+use std::{str, slice, cmp, io};
+use std::io::Seek;
+use std::num::{Float, Int};
 
 use sdl::video::{Surface, RGB, SurfaceFlag};
 use sdl_image;
 use sdl_mixer::Chunk;
 use ext::smpeg::MPEG;
+use ext::sdl::mixer;
 use util::filesearch::SearchContext;
+use gfx::color::{RGBA, to_rgb};
+use gfx::surface::{SurfaceAreaUtil, SurfacePixelsUtil};
 use gfx::gl::PreparedSurface;
+use format::bms::SoundTransform;
+use format::bms::load::ResourceResolver;
 
 /// The width of BGA, or the width of screen for the exclusive mode.
 pub const BGAW: uint = 256;
@@ -65,6 +72,21 @@ impl SearchContextAdditions for SearchContext {
     }
 }
 
+impl ResourceResolver for SearchContext {
+    fn has_sound(&mut self, path: &str, basedir: &Path) -> bool {
+        self.resolve_relative_path_for_sound(path, basedir).is_ok()
+    }
+
+    fn has_image(&mut self, path: &str, basedir: &Path) -> bool {
+        self.resolve_relative_path_for_image(path, basedir).is_ok()
+    }
+}
+
+/// Creates a new resolver for `LoaderOptions::resolver`, backed by a fresh `SearchContext`.
+pub fn new_resource_resolver() -> Box<ResourceResolver+'static> {
+    box SearchContext::new() as Box<ResourceResolver+'static>
+}
+
 /// Sound resource associated to `SoundRef`. It contains the actual SDL_mixer chunk that can be
 /// readily played.
 pub enum Soundlike {
@@ -115,11 +137,165 @@ pub enum LoadedSoundlike {
     Sound(Chunk)
 }
 
+/// Reads the sampling rate from the header of WAV or Ogg Vorbis file. Returns `None` if the file
+/// is in other formats or the header is malformed.
+fn read_sample_rate<R:Reader+Seek>(f: &mut R) -> Option<f64> {
+    let mut read_header = || -> io::IoResult<Option<f64>> {
+        let magic = try!(f.read_exact(4));
+        let rate = if magic[] == b"RIFF" {
+            let _ = try!(f.read_le_u32());
+            let wave = try!(f.read_exact(4));
+            if wave[] != b"WAVE" { return Ok(None); }
+            loop {
+                let chunkid = try!(f.read_exact(4));
+                let chunksize = try!(f.read_le_u32());
+                if chunkid[] == b"fmt " { break; }
+                // chunks are aligned to 2 bytes
+                let skip = match chunksize.checked_add(chunksize & 1) {
+                    Some(skip) => skip,
+                    None => { return Ok(None); }
+                };
+                try!(f.seek(skip as i64, io::SeekCur));
+            }
+            let _format = try!(f.read_le_u16());
+            let _channels = try!(f.read_le_u16());
+            try!(f.read_le_u32())
+        } else if magic[] == b"OggS" {
+            // the first page contains the Vorbis identification header only. skips the rest of
+            // the page header (22 bytes) and the segment table.
+            try!(f.seek(22, io::SeekCur));
+            let nsegments = try!(f.read_u8());
+            try!(f.seek(nsegments as i64, io::SeekCur));
+            let packettype = try!(f.read_exact(7));
+            if packettype[] != b"\x01vorbis" { return Ok(None); }
+            let _version = try!(f.read_le_u32());
+            let _channels = try!(f.read_u8());
+            try!(f.read_le_u32())
+        } else {
+            return Ok(None);
+        };
+        Ok(if rate > 0 {Some(rate as f64)} else {None})
+    };
+    read_header().unwrap_or(None)
+}
+
+/// Applies the sound transformation to 16-bit stereo samples at `SAMPLERATE`. `origrate` is
+/// the sampling rate of the sound file before the conversion to `SAMPLERATE` if known;
+/// the frequency in the transformation is ignored otherwise.
+fn transform_samples(samples: &[i16], origrate: Option<f64>,
+                     transform: &SoundTransform) -> Vec<i16> {
+    // skip the frames before the offset
+    let skip = cmp::min((transform.offset.max(0.0) * SAMPLERATE as f64) as uint, samples.len() / 2);
+    let samples = samples[skip * 2..];
+    let nframes = samples.len() / 2;
+
+    // the resulting sound is `speed` times faster (and higher) than the original
+    let ratio = match (transform.frequency, origrate) {
+        (Some(freq), Some(origrate)) => freq / origrate,
+        (_, _) => 1.0,
+    };
+    let speed = ratio * 2.0f64.powf(transform.pitch as f64 / 12.0);
+    let mut newnframes = (nframes as f64 / speed) as uint;
+    for &duration in transform.duration.iter() {
+        newnframes = cmp::min(newnframes, (duration * SAMPLERATE as f64) as uint);
+    }
+
+    // the panning attenuates the other channel by up to 100 dB
+    let leftgain = transform.volume * 10.0f64.powf(-5.0 * transform.pan.max(0.0));
+    let rightgain = transform.volume * 10.0f64.powf(5.0 * transform.pan.min(0.0));
+
+    let mut newsamples: Vec<i16> = Vec::with_capacity(newnframes * 2);
+    for i in range(0, newnframes) {
+        // linear interpolation between two nearest frames
+        let pos = i as f64 * speed;
+        let j = pos as uint;
+        let frac = pos - j as f64;
+        for (channel, &gain) in [leftgain, rightgain].iter().enumerate() {
+            let at = |j: uint| if j < nframes {samples[j * 2 + channel] as f64} else {0.0};
+            let v = (at(j) * (1.0 - frac) + at(j + 1) * frac) * gain;
+            newsamples.push(v.max(-32768.0).min(32767.0).round() as i16);
+        }
+    }
+    newsamples
+}
+
+/// Returns the raw bytes of the chunk, in the format the mixer has been opened with.
+fn chunk_bytes<'a>(chunk: &'a Chunk) -> &'a [u8] {
+    unsafe {
+        let llchunk = chunk.to_ll_chunk();
+        let ptr = (*llchunk).abuf as *const u8;
+        slice::from_raw_buf(&ptr, (*llchunk).alen as uint)
+    }
+}
+
+/// Applies the sound transformation to the chunk. See `transform_samples` for `origrate`.
+/// Returns `None` if the mixer has not been opened with 16-bit stereo samples at `SAMPLERATE`,
+/// since the chunk is converted to that format and `transform_samples` relies on it.
+fn transform_chunk(chunk: &Chunk, origrate: Option<f64>,
+                   transform: &SoundTransform) -> Option<Chunk> {
+    match mixer::query_spec() {
+        Some((SAMPLERATE, mixer::AUDIO_S16SYS, 2)) => {}
+        _ => { return None; }
+    }
+
+    let samples: &[i16] = unsafe {
+        let llchunk = chunk.to_ll_chunk();
+        let ptr = (*llchunk).abuf as *const i16;
+        slice::from_raw_buf(&ptr, (*llchunk).alen as uint / 2)
+    };
+    let newsamples = transform_samples(samples, origrate, transform);
+
+    unsafe {
+        let ptr = newsamples.as_ptr() as *const u8;
+        let buf = slice::from_raw_buf(&ptr, newsamples.len() * 2);
+        Some(Chunk::new(buf.to_vec(), 128))
+    }
+}
+
+/// A decoded sound file before any sound transformation. This can be kept to apply multiple
+/// transformations to the same file without decoding it again.
+pub struct SoundSource {
+    /// The decoded chunk.
+    chunk: Chunk,
+    /// The sampling rate of the sound file before the conversion to `SAMPLERATE` if known.
+    origrate: Option<f64>,
+}
+
+impl SoundSource {
+    /// Loads and decodes a sound file.
+    pub fn new(path: &Path) -> Result<SoundSource,String> {
+        let chunk = try!(Chunk::from_wav(path));
+        let origrate = io::File::open(path).ok().and_then(|mut f| read_sample_rate(&mut f));
+        Ok(SoundSource { chunk: chunk, origrate: origrate })
+    }
+
+    /// Creates a sound resource with given sound transformation, keeping the source intact.
+    /// The transformation is ignored if the mixer uses an unexpected format.
+    pub fn transform(&self, transform: &SoundTransform) -> LoadedSoundlike {
+        match transform_chunk(&self.chunk, self.origrate, transform) {
+            Some(chunk) => LoadedSoundlike::Sound(chunk),
+            None => LoadedSoundlike::Sound(Chunk::new(chunk_bytes(&self.chunk).to_vec(), 128)),
+        }
+    }
+
+    /// Creates a sound resource with given sound transformation, consuming the source.
+    /// The transformation is ignored if the mixer uses an unexpected format.
+    pub fn into_soundlike(self, transform: &SoundTransform) -> LoadedSoundlike {
+        if transform.is_identity() {
+            return LoadedSoundlike::Sound(self.chunk);
+        }
+        match transform_chunk(&self.chunk, self.origrate, transform) {
+            Some(chunk) => LoadedSoundlike::Sound(chunk),
+            None => LoadedSoundlike::Sound(self.chunk),
+        }
+    }
+}
+
 impl LoadedSoundlike {
-    /// Loads a sound resource.
-    pub fn new(path: &Path) -> Result<LoadedSoundlike,String> {
-        let res = try!(Chunk::from_wav(path));
-        Ok(LoadedSoundlike::Sound(res))
+    /// Loads a sound resource and applies given sound transformation.
+    pub fn new(path: &Path, transform: &SoundTransform) -> Result<LoadedSoundlike,String> {
+        let source = try!(SoundSource::new(path));
+        Ok(source.into_soundlike(transform))
     }
 
     /// Creates a `Soundlike` instance. There is no turning back.
@@ -131,14 +307,48 @@ impl LoadedSoundlike {
     }
 }
 
+/// Makes every pixel in the surface with given color key transparent.
+/// The surface should have an alpha channel.
+pub fn apply_color_key(surface: &PreparedSurface, colorkey: (u8,u8,u8)) {
+    let (width, height) = surface.as_surface().get_size();
+    surface.as_surface().with_pixels(|pixels| {
+        for y in range(0, height as uint) {
+            for x in range(0, width as uint) {
+                if to_rgb(pixels.get_pixel(x, y)) == colorkey {
+                    pixels.put_pixel(x, y, RGBA(0, 0, 0, 0));
+                }
+            }
+        }
+    });
+}
+
+/// Copies the whole image to `target` with the same size and applies the color key.
+/// `target` should have an alpha channel.
+pub fn copy_with_color_key(surface: &PreparedSurface, colorkey: (u8,u8,u8),
+                           target: &PreparedSurface) {
+    // SDL_SRCALPHA should be temporarily turned off in order to copy the alpha channel as is.
+    // see `LoadedImagelike::new` for relevant codes.
+    let hasalpha = unsafe {(*(*surface.as_surface().raw).format).Amask} != 0;
+    if hasalpha {
+        surface.as_surface().set_alpha([][], 255);
+    }
+    target.as_surface().blit_area(surface.as_surface(), (0u, 0u), (0u, 0u),
+                                  surface.as_surface());
+    if hasalpha {
+        surface.as_surface().set_alpha([SurfaceFlag::SrcAlpha, SurfaceFlag::RLEAccel][], 255);
+    }
+    apply_color_key(target, colorkey);
+}
+
 /// Image resource associated to `ImageRef`. It can be either a static image or a movie, and
 /// both contains an SDL surface that can be blitted to the screen.
 pub enum Imagelike {
     /// No image resource is associated, or error occurred while loading.
     None,
-    /// A static image is associated. The surface may have a transparency which is already
-    /// handled by `LoadedImagelike::new`.
-    Image(PreparedSurface),
+    /// A static image is associated. The surface may have a transparency (including the color key
+    /// for the image) which is already handled by `LoadedImagelike::new`. It also has copies of
+    /// the surface for each color key of BGA layers the image can be displayed in.
+    Image(PreparedSurface, Vec<((u8,u8,u8), PreparedSurface)>),
     /// A movie is associated. A playback starts when `start_movie` method is called, and stops
     /// when `stop_animating` is called. An associated surface is updated from the separate thread
     /// during the playback.
@@ -150,14 +360,25 @@ impl Imagelike {
     pub fn surface<'r>(&'r self) -> Option<&'r PreparedSurface> {
         match *self {
             Imagelike::None => None,
-            Imagelike::Image(ref surface) | Imagelike::Movie(ref surface,_) => Some(surface)
+            Imagelike::Image(ref surface,_) | Imagelike::Movie(ref surface,_) => Some(surface)
+        }
+    }
+
+    /// Returns a copy of the static image with given color key applied if it has been prepared.
+    pub fn color_keyed_surface<'r>(&'r self,
+                                   colorkey: (u8,u8,u8)) -> Option<&'r PreparedSurface> {
+        match *self {
+            Imagelike::Image(_, ref keyed) => {
+                keyed.iter().find(|&&(key, _)| key == colorkey).map(|&(_, ref surface)| surface)
+            }
+            Imagelike::None | Imagelike::Movie(..) => None
         }
     }
 
     /// Stops the animation/movie playback if possible.
     pub fn stop_animating(&self) {
         match *self {
-            Imagelike::None | Imagelike::Image(_) => {}
+            Imagelike::None | Imagelike::Image(..) => {}
             Imagelike::Movie(_,ref mpeg) => { mpeg.stop(); }
         }
     }
@@ -166,7 +387,7 @@ impl Imagelike {
     /// if possible.
     pub fn start_animating(&self) {
         match *self {
-            Imagelike::None | Imagelike::Image(_) => {}
+            Imagelike::None | Imagelike::Image(..) => {}
             Imagelike::Movie(_,ref mpeg) => { mpeg.rewind(); mpeg.play(); }
         }
     }
@@ -176,27 +397,43 @@ impl Imagelike {
 /// transferred across tasks and thus used for the worker model.
 pub enum LoadedImagelike {
     None,
-    Image(PreparedSurface),
+    Image(PreparedSurface, Vec<((u8,u8,u8), PreparedSurface)>),
     Movie(PreparedSurface, MPEG)
 }
 
 impl LoadedImagelike {
-    /// Loads an image resource.
-    pub fn new(path: &Path, load_movie: bool) -> Result<LoadedImagelike,String> {
+    /// Loads an image resource. `colorkey`, if any, is an RGB color which is made transparent
+    /// for static images without an alpha channel. `layerkeys` are color keys of BGA layers
+    /// the image can be displayed in, and static images get a separate copy for each of them
+    /// so that the color keys need not be applied during the game play. Movies are not affected
+    /// by `colorkey` and `layerkeys`.
+    pub fn new(path: &Path, load_movie: bool, colorkey: Option<(u8,u8,u8)>,
+               layerkeys: &[(u8,u8,u8)]) -> Result<LoadedImagelike,String> {
         use std::ascii::AsciiExt;
 
         /// Converts a surface to the native display format, while preserving a transparency or
         /// setting a color key if required.
-        fn to_display_format(surface: Surface) -> Result<PreparedSurface,String> {
+        fn to_display_format(surface: Surface,
+                             colorkey: Option<(u8,u8,u8)>) -> Result<PreparedSurface,String> {
             let surface = if unsafe {(*(*surface.raw).format).Amask} != 0 {
                 let surface = try!(surface.display_format_alpha());
                 surface.set_alpha([SurfaceFlag::SrcAlpha, SurfaceFlag::RLEAccel][], 255);
                 surface
             } else {
                 let surface = try!(surface.display_format());
-                surface.set_color_key([SurfaceFlag::SrcColorKey, SurfaceFlag::RLEAccel][],
-                                      RGB(0, 0, 0));
-                surface
+                match colorkey {
+                    Some((r, g, b)) => {
+                        // textures don't know about color keys, so the color key is turned into
+                        // the alpha channel by blitting to a transparent surface.
+                        surface.set_color_key([SurfaceFlag::SrcColorKey][], RGB(r, g, b));
+                        let (width, height) = surface.get_size();
+                        let keyed = try!(PreparedSurface::new(width as uint, height as uint,
+                                                              true));
+                        keyed.as_surface().blit_area(&surface, (0u, 0u), (0u, 0u), &surface);
+                        return Ok(keyed);
+                    }
+                    None => surface
+                }
             };
             match PreparedSurface::from_owned_surface(surface) {
                 Ok(prepared) => Ok(prepared),
@@ -218,12 +455,20 @@ impl LoadedImagelike {
             }
         } else {
             let surface = try!(sdl_image::load(path));
-            let prepared = try!(to_display_format(surface));
+            let prepared = try!(to_display_format(surface, colorkey));
 
             // PreparedSurface may destroy SDL_SRCALPHA, which is still required for alpha blitting.
             // for RGB images, it is effectively no-op as per-surface alpha is fully opaque.
             prepared.as_surface().set_alpha([SurfaceFlag::SrcAlpha, SurfaceFlag::RLEAccel][], 255);
-            Ok(LoadedImagelike::Image(prepared))
+
+            let (width, height) = prepared.as_surface().get_size();
+            let mut keyed = Vec::new();
+            for &layerkey in layerkeys.iter() {
+                let target = try!(PreparedSurface::new(width as uint, height as uint, true));
+                copy_with_color_key(&prepared, layerkey, &target);
+                keyed.push((layerkey, target));
+            }
+            Ok(LoadedImagelike::Image(prepared, keyed))
         }
     }
 
@@ -231,9 +476,88 @@ impl LoadedImagelike {
     pub fn wrap(self) -> Imagelike {
         match self {
             LoadedImagelike::None => Imagelike::None,
-            LoadedImagelike::Image(surface) => Imagelike::Image(surface),
+            LoadedImagelike::Image(surface, keyed) => Imagelike::Image(surface, keyed),
             LoadedImagelike::Movie(surface, mpeg) => Imagelike::Movie(surface, mpeg)
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use format::bms::SoundTransform;
+    use super::{read_sample_rate, transform_samples};
+
+    fn sample_rate(header: &[u8]) -> Option<f64> {
+        read_sample_rate(&mut BufReader::new(header))
+    }
+
+    #[test]
+    fn test_read_sample_rate() {
+        // an odd-sized chunk before `fmt ` is padded
+        assert_eq!(sample_rate(b"RIFF\x00\x00\x00\x00WAVELIST\x03\x00\x00\x00abc\x00\
+                                 fmt \x10\x00\x00\x00\x01\x00\x02\x00\x22\x56\x00\x00"),
+                   Some(22050.0));
+        assert_eq!(sample_rate(b"OggS\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                                 \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1e\
+                                 \x01vorbis\x00\x00\x00\x00\x02\x80\xbb\x00\x00"),
+                   Some(48000.0));
+        assert_eq!(sample_rate(b"RIFF\x00\x00\x00\x00WAVEdata"), None);
+        // the padded size of this chunk does not fit in 32 bits
+        assert_eq!(sample_rate(b"RIFF\x00\x00\x00\x00WAVELIST\xff\xff\xff\xff"), None);
+        assert_eq!(sample_rate(b"RIFF\x00\x00\x00\x00AVI "), None);
+        assert_eq!(sample_rate(b"ID3\x03\x00\x00\x00\x00\x00\x00"), None);
+    }
+
+    /// Returns the number of frames after applying `transform` to 100 frames.
+    fn nframes(transform: &SoundTransform, origrate: Option<f64>) -> uint {
+        let samples = Vec::from_elem(200, 1000i16);
+        transform_samples(samples[], origrate, transform).len() / 2
+    }
+
+    #[test]
+    fn test_resampling() {
+        let mut transform = SoundTransform::identity();
+        assert_eq!(nframes(&transform, None), 100);
+        transform.pitch = 12;
+        assert_eq!(nframes(&transform, None), 50);
+        transform.pitch = -12;
+        assert_eq!(nframes(&transform, None), 200);
+
+        let mut transform = SoundTransform::identity();
+        transform.frequency = Some(44100.0);
+        assert_eq!(nframes(&transform, Some(22050.0)), 50);
+        // the frequency is ignored when the original sampling rate is unknown
+        assert_eq!(nframes(&transform, None), 100);
+    }
+
+    #[test]
+    fn test_duration_and_offset() {
+        let mut transform = SoundTransform::identity();
+        transform.duration = Some(0.001); // 44.1 frames
+        assert_eq!(nframes(&transform, None), 44);
+        transform.duration = Some(1.0);
+        assert_eq!(nframes(&transform, None), 100);
+        transform.duration = None;
+        transform.offset = 0.001;
+        assert_eq!(nframes(&transform, None), 56);
+        transform.offset = 1.0;
+        assert_eq!(nframes(&transform, None), 0);
+    }
+
+    #[test]
+    fn test_gain() {
+        let samples = [1000i16, -1000, 30000, -30000];
+        let mut transform = SoundTransform::identity();
+        transform.volume = 0.5;
+        assert_eq!(transform_samples(samples[], None, &transform), vec![500, -500, 15000, -15000]);
+        transform.volume = 2.0;
+        assert_eq!(transform_samples(samples[], None, &transform),
+                   vec![2000, -2000, 32767, -32768]);
+        transform.volume = 1.0;
+        transform.pan = 1.0; // right only
+        assert_eq!(transform_samples(samples[], None, &transform), vec![0, -1000, 0, -30000]);
+        transform.pan = -0.2; // -20 dB to the right
+        assert_eq!(transform_samples(samples[], None, &transform), vec![1000, -100, 30000, -3000]);
+    }
+}
@@ -58,3 +58,35 @@ pub mod syswm {
     }
 }
 
+pub mod mixer {
+    use libc::c_int;
+
+    pub mod ll {
+        use libc::{c_int, uint16_t};
+
+        extern {
+            pub fn Mix_QuerySpec(frequency: *mut c_int, format: *mut uint16_t,
+                                 channels: *mut c_int) -> c_int;
+        }
+    }
+
+    /// Signed 16-bit samples in the native byte order (`AUDIO_S16SYS`).
+    #[cfg(target_endian = "little")] pub const AUDIO_S16SYS: u16 = 0x8010;
+    /// Signed 16-bit samples in the native byte order (`AUDIO_S16SYS`).
+    #[cfg(target_endian = "big")] pub const AUDIO_S16SYS: u16 = 0x9010;
+
+    /// Returns the sampling rate, the sample format and the number of channels the mixer has
+    /// actually opened with, or `None` if the mixer is not opened.
+    pub fn query_spec() -> Option<(c_int, u16, c_int)> {
+        let mut frequency = 0;
+        let mut format = 0;
+        let mut channels = 0;
+        unsafe {
+            if ll::Mix_QuerySpec(&mut frequency, &mut format, &mut channels) == 0 {
+                None
+            } else {
+                Some((frequency, format, channels))
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * Localized catalogs for diagnostic messages (`format::bms::diag`).
+ *
+ * Each catalog maps `BmsMessage::id` to the translated message. English messages are built into
+ * `BmsMessage` themselves, so messages missing from the catalog fall back to English.
+ */
+
+use std::ascii::AsciiExt;
+
+use format::bms::diag::BmsMessage;
+
+/// Languages for diagnostic messages.
+#[deriving(PartialEq,Eq,Clone,Show)]
+pub enum Language {
+    /// English. This is the default and the fallback language.
+    English,
+    /// Korean.
+    Korean,
+    /// Japanese.
+    Japanese,
+}
+
+impl Language {
+    /// Parses a language code (`en`, `ko` or `ja`) or a POSIX-like locale name (`ko_KR.UTF-8`,
+    /// `ja-JP` etc.). Returns `None` for unsupported languages.
+    pub fn from_locale(locale: &str) -> Option<Language> {
+        let lang = locale.split(|c: char| c == '_' || c == '-' || c == '.' || c == '@')
+                         .next().unwrap_or("").to_ascii_lower();
+        match lang[] {
+            "en" | "c" | "posix" => Some(Language::English),
+            "ko" => Some(Language::Korean),
+            "ja" => Some(Language::Japanese),
+            _ => None,
+        }
+    }
+
+    /// Determines the language from the environment variables (`LC_ALL`, `LC_MESSAGES` and
+    /// `LANG` in the order of precedence) with given `getenv` function. Falls back to English.
+    pub fn from_env(getenv: |&str| -> Option<String>) -> Language {
+        for var in ["LC_ALL", "LC_MESSAGES", "LANG"].iter() {
+            match getenv(*var) {
+                Some(ref locale) if !locale.is_empty() => {
+                    return Language::from_locale(locale[]).unwrap_or(Language::English);
+                }
+                _ => {}
+            }
+        }
+        Language::English
+    }
+
+    /// Returns the catalog for the language, or `None` for English.
+    fn catalog(self) -> Option<&'static [(&'static str, &'static str)]> {
+        match self {
+            Language::English => None,
+            Language::Korean => Some(CATALOG_KO),
+            Language::Japanese => Some(CATALOG_JA),
+        }
+    }
+}
+
+/// Returns the message translated to given language if possible.
+pub fn localize(msg: BmsMessage, lang: Language) -> BmsMessage {
+    let translated = lang.catalog().and_then(|catalog| {
+        catalog.iter().find(|&&(id, _)| id == msg.id).map(|&(_, message)| message)
+    });
+    match translated {
+        Some(message) => BmsMessage { message: message, ..msg },
+        None => msg,
+    }
+}
+
+/// Korean catalog.
+static CATALOG_KO: &'static [(&'static str, &'static str)] = &[
+    ("neg-init-bpm", "초기 #BPM은 음수일 수 없습니다. 이 줄은 무시됩니다."),
+    ("zero-init-bpm", "초기 #BPM은 0일 수 없습니다. 이 줄은 무시됩니다."),
+    ("neg-stop-duration", "#STOP 길이는 음수일 수 없습니다. 이 #STOP은 무시됩니다."),
+    ("neg-stp-duration", "#STP 길이는 음수일 수 없습니다. 이 줄은 무시됩니다."),
+    ("no-title", "#TITLE이 없습니다."),
+    ("empty-title", "#TITLE이 비어 있습니다."),
+    ("multiple-title", "#TITLE 명령이 여러 개 있습니다. 마지막 줄만 사용됩니다."),
+    ("couple-play", "커플 플레이(#PLAYER 2)는 제한적으로만 지원됩니다."),
+    ("battle-play", "배틀 플레이(#PLAYER 4)는 지원되지 않으며 싱글 플레이로 취급됩니다."),
+    ("invalid-lntype", "잘못된 #LNTYPE 값은 무시됩니다."),
+    ("zero-lnobj", "#LNOBJ 00은 잘못된 값이므로 무시됩니다."),
+    ("multiple-lnobj", "#LNOBJ 명령이 여러 개 있습니다. 마지막 줄만 사용됩니다."),
+    ("legacy-encoding", "파일이 예전 CJK 인코딩으로 되어 있습니다. 계속 사용하는 것은 권장되지 않습니다."),
+    ("full-width-sharp", "호환성을 위해 #은 반각 문자여야 합니다."),
+    ("one-digit-key", "한 자리 영숫자 키는 앞에 0이 붙은 것으로 간주됩니다."),
+    ("no-artist", "#ARTIST가 없습니다."),
+    ("empty-artist", "#ARTIST가 비어 있습니다."),
+    ("multiple-artist", "#ARTIST 명령이 여러 개 있습니다. 마지막 줄만 사용됩니다."),
+    ("no-genre", "#GENRE가 없습니다."),
+    ("empty-genre", "#GENRE가 비어 있습니다."),
+    ("multiple-genre", "#GENRE 명령이 여러 개 있습니다. 마지막 줄만 사용됩니다."),
+    ("genle", "#GENLE [원문 그대로]는 #GENRE로 해석됩니다."),
+    ("empty-path", "빈 경로는 무시됩니다."),
+    ("invalid-player", "잘못된 #PLAYER 값은 무시됩니다."),
+    ("invalid-canvassize", "양수가 아니거나 너무 큰 #SNRS:CANVASSIZE 값은 무시됩니다."),
+    ("neg-playlevel", "호환성을 위해 #PLAYLEVEL은 음수가 아니어야 합니다."),
+    ("difficulty-out-of-range", "호환성을 위해 #DIFFICULTY는 1에서 5 사이여야 합니다."),
+    ("exbpm", "#EXBPMxx는 임시 방편이므로 #BPMxx를 대신 사용해야 합니다."),
+    ("nonpos-bpm", "양수가 아닌 BPM은 호환성이 없으므로 사용을 권장하지 않습니다."),
+    ("neg-volwav", "음수인 #VOLWAV 값은 무시됩니다."),
+    ("nonpos-exrank", "양수가 아닌 #DEFEXRANK 또는 #EXRANKxx 값은 무시됩니다."),
+    ("nonpos-total", "양수가 아닌 #TOTAL 값은 무시됩니다."),
+    ("lntype2", "#LNTYPE 2는 더 이상 권장되지 않으므로 #LNTYPE 1(기본값)이나 #LNOBJ를 대신 \
+                 사용해야 합니다."),
+    ("unknown-wavcmd", "잘못된 #WAVCMD 명령은 무시됩니다."),
+    ("invalid-exwav", "범위를 벗어난 값이 있는 #EXWAV는 무시됩니다."),
+    ("invalid-swbga", "프레임 시간, 길이, 채널 또는 패턴이 잘못된 #SWBGA는 무시됩니다."),
+    ("undefined-wav", "#WAV로 정의되지 않은 키입니다. 이 키를 사용하는 오브젝트는 소리가 나지 \
+                       않습니다."),
+    ("undefined-bmp", "#BMP나 #BGA로 정의되지 않은 키입니다. 이 키를 사용하는 BGA는 표시되지 \
+                       않습니다."),
+    ("undefined-bpm", "#BPM으로 정의되지 않은 키입니다. 이 키를 사용하는 오브젝트는 무시됩니다."),
+    ("undefined-stop", "#STOP으로 정의되지 않은 키입니다. 이 키를 사용하는 오브젝트는 무시됩니다."),
+    ("undefined-text", "#TEXT로 정의되지 않은 키입니다. 이 키를 사용하는 오브젝트는 글자를 \
+                        표시하지 않습니다."),
+    ("undefined-exrank", "#EXRANK로 정의되지 않은 키입니다. 이 키를 사용하는 오브젝트는 \
+                          무시됩니다."),
+    ("undefined-argb", "#ARGB로 정의되지 않은 키입니다. 이 키를 사용하는 오브젝트는 무시됩니다."),
+    ("undefined-swbga", "#SWBGA로 정의되지 않은 키입니다. 이 키를 사용하는 BGA는 표시되지 \
+                         않습니다."),
+    ("unused-wav", "어떤 오브젝트도 이 #WAV를 사용하지 않습니다."),
+    ("unused-bmp", "어떤 오브젝트도 이 #BMP를 사용하지 않습니다."),
+    ("missing-wav-file", "#WAV 파일을 찾을 수 없습니다."),
+    ("missing-bmp-file", "#BMP 파일을 찾을 수 없습니다."),
+    ("song", "#SONG은 더 이상 권장되지 않으므로 #TEXT를 대신 사용해야 합니다."),
+    ("rondam", "#RONDAM [원문 그대로]은 #RANDOM으로 해석됩니다."),
+    ("rondam-no-ws", "#RANDOM 뒤에는 공백이 하나 이상 있어야 합니다."),
+    ("if-no-ws", "#IF 뒤에는 공백이 하나 이상 있어야 합니다."),
+    ("ifend", "#IFEND [원문 그대로]는 #ENDIF로 해석됩니다."),
+    ("end-without-if", "IF가 뒤따르지 않는 #END는 #ENDIF로 해석됩니다."),
+];
+
+/// Japanese catalog.
+static CATALOG_JA: &'static [(&'static str, &'static str)] = &[
+    ("neg-init-bpm", "初期#BPMは負の値にできません。この行は無視されます。"),
+    ("zero-init-bpm", "初期#BPMは0にできません。この行は無視されます。"),
+    ("neg-stop-duration", "#STOPの長さは負の値にできません。この#STOPは無視されます。"),
+    ("neg-stp-duration", "#STPの長さは負の値にできません。この行は無視されます。"),
+    ("no-title", "#TITLEがありません。"),
+    ("empty-title", "#TITLEが空です。"),
+    ("multiple-title", "#TITLEが複数あります。最後の行のみ使用されます。"),
+    ("couple-play", "カップルプレイ(#PLAYER 2)のサポートは限定的です。"),
+    ("battle-play", "バトルプレイ(#PLAYER 4)はサポートされず、シングルプレイとして扱われます。"),
+    ("invalid-lntype", "不正な#LNTYPEの値は無視されます。"),
+    ("zero-lnobj", "#LNOBJ 00は不正なため無視されます。"),
+    ("multiple-lnobj", "#LNOBJが複数あります。最後の行のみ使用されます。"),
+    ("legacy-encoding", "ファイルが旧来のCJKエンコーディングで書かれています。今後の使用は推奨されません。"),
+    ("full-width-sharp", "互換性のため、#は半角文字にしてください。"),
+    ("one-digit-key", "1桁の英数字キーは先頭に0が付いているものとみなされます。"),
+    ("no-artist", "#ARTISTがありません。"),
+    ("empty-artist", "#ARTISTが空です。"),
+    ("multiple-artist", "#ARTISTが複数あります。最後の行のみ使用されます。"),
+    ("no-genre", "#GENREがありません。"),
+    ("empty-genre", "#GENREが空です。"),
+    ("multiple-genre", "#GENREが複数あります。最後の行のみ使用されます。"),
+    ("genle", "#GENLE [原文ママ]は#GENREとして解釈されます。"),
+    ("empty-path", "空のパスは無視されます。"),
+    ("invalid-player", "不正な#PLAYERの値は無視されます。"),
+    ("invalid-canvassize", "正でないか大きすぎる#SNRS:CANVASSIZEの値は無視されます。"),
+    ("neg-playlevel", "互換性のため、#PLAYLEVELは負でない値にしてください。"),
+    ("difficulty-out-of-range", "互換性のため、#DIFFICULTYは1から5の間にしてください。"),
+    ("exbpm", "#EXBPMxxは暫定的なものです。代わりに#BPMxxを使用してください。"),
+    ("nonpos-bpm", "正でないBPMは移植性がなく、使用は推奨されません。"),
+    ("neg-volwav", "負の#VOLWAVの値は無視されます。"),
+    ("nonpos-exrank", "正でない#DEFEXRANKまたは#EXRANKxxの値は無視されます。"),
+    ("nonpos-total", "正でない#TOTALの値は無視されます。"),
+    ("lntype2", "#LNTYPE 2は非推奨です。代わりに#LNTYPE 1(既定)または#LNOBJを使用してください。"),
+    ("unknown-wavcmd", "不正な#WAVCMDは無視されます。"),
+    ("invalid-exwav", "範囲外の値を含む#EXWAVは無視されます。"),
+    ("invalid-swbga", "フレーム時間、長さ、チャンネルまたはパターンが不正な#SWBGAは無視されます。"),
+    ("undefined-wav", "#WAVで定義されていないキーです。このキーのオブジェクトは無音になります。"),
+    ("undefined-bmp", "#BMPまたは#BGAで定義されていないキーです。このキーのBGAは表示されません。"),
+    ("undefined-bpm", "#BPMで定義されていないキーです。このキーのオブジェクトは無視されます。"),
+    ("undefined-stop", "#STOPで定義されていないキーです。このキーのオブジェクトは無視されます。"),
+    ("undefined-text", "#TEXTで定義されていないキーです。このキーのオブジェクトは文字を表示しません。"),
+    ("undefined-exrank", "#EXRANKで定義されていないキーです。このキーのオブジェクトは無視されます。"),
+    ("undefined-argb", "#ARGBで定義されていないキーです。このキーのオブジェクトは無視されます。"),
+    ("undefined-swbga", "#SWBGAで定義されていないキーです。このキーのBGAは表示されません。"),
+    ("unused-wav", "この#WAVはどのオブジェクトにも使用されていません。"),
+    ("unused-bmp", "この#BMPはどのオブジェクトにも使用されていません。"),
+    ("missing-wav-file", "#WAVのファイルが見つかりません。"),
+    ("missing-bmp-file", "#BMPのファイルが見つかりません。"),
+    ("song", "#SONGは非推奨です。代わりに#TEXTを使用してください。"),
+    ("rondam", "#RONDAM [原文ママ]は#RANDOMとして解釈されます。"),
+    ("rondam-no-ws", "#RANDOMの後には1つ以上の空白が必要です。"),
+    ("if-no-ws", "#IFの後には1つ以上の空白が必要です。"),
+    ("ifend", "#IFEND [原文ママ]は#ENDIFとして解釈されます。"),
+    ("end-without-if", "IFが続かない#ENDは#ENDIFとして解釈されます。"),
+];
+
+#[cfg(test)]
+mod tests {
+    use format::bms::diag;
+    use super::{Language, localize};
+
+    #[test]
+    fn test_from_locale() {
+        assert_eq!(Language::from_locale("ko"), Some(Language::Korean));
+        assert_eq!(Language::from_locale("ja_JP.UTF-8"), Some(Language::Japanese));
+        assert_eq!(Language::from_locale("en-US"), Some(Language::English));
+        assert_eq!(Language::from_locale("C"), Some(Language::English));
+        assert_eq!(Language::from_locale("fr_FR"), None);
+
+        let env = |var: &str| if var == "LANG" {Some("ko_KR.UTF-8".to_string())} else {None};
+        assert_eq!(Language::from_env(env), Language::Korean);
+        assert_eq!(Language::from_env(|_| None), Language::English);
+    }
+
+    #[test]
+    fn test_localize() {
+        let msg = localize(diag::BmsHasNoTITLE, Language::Korean);
+        assert_eq!(msg.id, diag::BmsHasNoTITLE.id);
+        assert!(msg.message != diag::BmsHasNoTITLE.message);
+        assert!(localize(diag::BmsHasNoTITLE, Language::English) == diag::BmsHasNoTITLE);
+    }
+}
@@ -0,0 +1,280 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * Lossless representation of BMS file ("concrete syntax tree"), mainly for editing tools.
+ *
+ * The parser (`format::bms::parse`) throws away everything not relevant to the commands,
+ * including comments, blank lines and the original spacing. `BmsCst` instead keeps every line
+ * as raw bytes alongside its interpretation, so that the file can be written back byte-for-byte
+ * in its original encoding. Edited lines are re-encoded with the same encoding and re-parsed.
+ */
+
+use std::{io, f64};
+use std::io::IoResult;
+use encoding::{EncodingRef, DecoderTrap, EncoderTrap};
+
+use format::bms::diag::BmsMessage;
+use format::bms::parse::{BmsCommand, ParserOptions, parse_line};
+use format::bms::encoding::guess_decode_stream;
+
+/// The UTF-8 byte order mark.
+static UTF8_BOM: &'static [u8] = b"\xef\xbb\xbf";
+
+/// A single line of BMS file.
+pub struct CstLine {
+    /// The line number starting at 1.
+    pub lineno: uint,
+    /// The raw bytes of the line, excluding `\n` but including `\r` if any.
+    raw: Vec<u8>,
+    /// The line decoded with the file encoding. Any decoding error is substituted with U+FFFD.
+    text: String,
+    /// The parsed command if any. Blank lines and comments have no command.
+    command: Option<BmsCommand<'static>>,
+    /// Parser messages for this line.
+    messages: Vec<BmsMessage>,
+}
+
+impl CstLine {
+    /// Returns the raw bytes of the line, excluding `\n` but including `\r` if any.
+    pub fn raw<'a>(&'a self) -> &'a [u8] { self.raw[] }
+
+    /// Returns the decoded line.
+    pub fn text<'a>(&'a self) -> &'a str { self.text[] }
+
+    /// Returns the parsed command if any.
+    pub fn command<'a>(&'a self) -> Option<&'a BmsCommand<'static>> { self.command.as_ref() }
+
+    /// Returns parser messages for this line.
+    pub fn messages<'a>(&'a self) -> &'a [BmsMessage] { self.messages[] }
+
+    /// Returns true if the line ends with `\r`, i.e. the line ends with CRLF.
+    fn has_cr(&self) -> bool { self.raw.last() == Some(&b'\r') }
+}
+
+/// Lossless BMS file consisting of every line of the original file.
+pub struct BmsCst {
+    /// The encoding used to decode and encode lines.
+    encoding: EncodingRef,
+    /// The confidence of `encoding` in `[0,1]`, or infinity when it is forced.
+    confidence: f64,
+    /// Parser options for every line, with `encoding` forced.
+    opts: ParserOptions,
+    /// True if the file starts with the UTF-8 byte order mark. The mark is not a part of
+    /// the first line and only gets written back.
+    bom: bool,
+    /// Lines in the file. The file ends with a newline iff the last line is empty.
+    lines: Vec<CstLine>,
+}
+
+impl BmsCst {
+    /// Reads the BMS file from given reader. The encoding is detected as like the parser.
+    pub fn read(f: &mut Reader, opts: &ParserOptions) -> BmsCst {
+        let raw = f.read_to_end().ok().unwrap_or_else(|| Vec::new());
+        let (encoding, confidence) = match opts.force_encoding {
+            Some(enc) => (enc, f64::INFINITY),
+            None => {
+                let (_, enc, confidence) =
+                    guess_decode_stream(&mut io::BufReader::new(raw[]), &opts.chardet);
+                (enc, confidence)
+            }
+        };
+
+        let bom = encoding.name() == "utf-8" && raw[].starts_with(UTF8_BOM);
+        let raw = if bom {raw[UTF8_BOM.len()..]} else {raw[]};
+
+        let opts = ParserOptions { force_encoding: Some(encoding), ..opts.clone() };
+        let mut cst = BmsCst { encoding: encoding, confidence: confidence, opts: opts, bom: bom,
+                               lines: Vec::new() };
+        // every supported encoding keeps `\n` intact, so we can split the raw bytes
+        for (i, line) in raw.split(|&c| c == b'\n').enumerate() {
+            let line = cst.make_line(i + 1, line.to_vec());
+            cst.lines.push(line);
+        }
+        cst
+    }
+
+    /// Decodes and parses the raw line.
+    fn make_line(&self, lineno: uint, raw: Vec<u8>) -> CstLine {
+        let text = self.encoding.decode(raw[], DecoderTrap::Replace).unwrap();
+        let (command, messages) = {
+            let (command, messages) = parse_line(text[], &self.opts);
+            (command.map(|cmd| cmd.into_send()), messages)
+        };
+        CstLine { lineno: lineno, raw: raw, text: text, command: command, messages: messages }
+    }
+
+    /// Encodes the text (without `\r` and `\n`) to the line, using the file encoding.
+    fn encode_line(&self, lineno: uint, text: &str, cr: bool) -> Result<CstLine,String> {
+        if text.contains_char('\n') || text.contains_char('\r') {
+            return Err(format!("line {} should not contain a newline", lineno));
+        }
+        let mut raw = match self.encoding.encode(text, EncoderTrap::Strict) {
+            Ok(raw) => raw,
+            Err(_) => {
+                return Err(format!("line {} cannot be represented in {}",
+                                   lineno, self.encoding.name()));
+            }
+        };
+        if cr { raw.push(b'\r'); }
+        Ok(self.make_line(lineno, raw))
+    }
+
+    /// Renumbers every line after the structural change.
+    fn renumber(&mut self) {
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            line.lineno = i + 1;
+        }
+    }
+
+    /// Returns the name of encoding used by the file, and its confidence between 0 and 1.
+    /// Confidence is set to infinity when it is forced by the options.
+    pub fn encoding(&self) -> (&'static str, f64) {
+        (self.encoding.name(), self.confidence)
+    }
+
+    /// Returns every line in the file.
+    pub fn lines<'a>(&'a self) -> &'a [CstLine] {
+        self.lines[]
+    }
+
+    /// Returns every parsed command with its line number.
+    pub fn commands<'a>(&'a self) -> Vec<(uint, &'a BmsCommand<'static>)> {
+        self.lines.iter().filter_map(|line| line.command().map(|cmd| (line.lineno, cmd)))
+                         .collect()
+    }
+
+    /// Replaces the text of given line (0-based index), keeping the line ending.
+    pub fn set_text(&mut self, index: uint, text: &str) -> Result<(),String> {
+        assert!(index < self.lines.len());
+        let cr = self.lines[index].has_cr();
+        let line = try!(self.encode_line(index + 1, text, cr));
+        self.lines[mut][index] = line;
+        Ok(())
+    }
+
+    /// Replaces the command in given line (0-based index), keeping the leading whitespace and
+    /// the line ending.
+    pub fn set_command(&mut self, index: uint, cmd: &BmsCommand) -> Result<(),String> {
+        assert!(index < self.lines.len());
+        let text = {
+            let line = &self.lines[index];
+            let indent = match line.command {
+                Some(_) => line.text[].find(|c: char| c == '#' || c == '\uff03').unwrap_or(0),
+                None => 0,
+            };
+            format!("{}{}", line.text[..indent], *cmd)
+        };
+        self.set_text(index, text[])
+    }
+
+    /// Inserts a new line with given command before given line (0-based index). The line ending
+    /// follows that of the adjacent line. Inserting at the end keeps the final newline if any.
+    pub fn insert_command(&mut self, index: uint, cmd: &BmsCommand) -> Result<(),String> {
+        assert!(index <= self.lines.len());
+        let nlines = self.lines.len();
+        let index = if index == nlines && nlines > 0 && self.lines[nlines-1].raw.is_empty() {
+            nlines - 1
+        } else {
+            index
+        };
+        let cr = if index > 0 {self.lines[index-1].has_cr()}
+                 else {!self.lines.is_empty() && self.lines[0].has_cr()};
+        let line = try!(self.encode_line(index + 1, format!("{}", *cmd)[], cr));
+        self.lines.insert(index, line);
+        self.renumber();
+        Ok(())
+    }
+
+    /// Removes given line (0-based index).
+    pub fn remove_line(&mut self, index: uint) {
+        assert!(index < self.lines.len());
+        self.lines.remove(index);
+        self.renumber();
+    }
+
+    /// Writes the file back to given writer. Unchanged lines are written byte-for-byte.
+    pub fn write(&self, w: &mut Writer) -> IoResult<()> {
+        if self.bom { try!(w.write(UTF8_BOM)); }
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 { try!(w.write_u8(b'\n')); }
+            try!(w.write(line.raw[]));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, MemWriter};
+    use std::str::IntoMaybeOwned;
+    use encoding::{Encoding, EncodingRef, EncoderTrap};
+    use encoding::all::WINDOWS_31J;
+    use format::obj::BPM;
+    use format::bms::types::Key;
+    use format::bms::parse::{BmsCommand, ParserOptions};
+    use format::bms::encoding::ChardetModels;
+    use super::BmsCst;
+
+    fn read(source: &[u8], opts: &ParserOptions) -> BmsCst {
+        BmsCst::read(&mut BufReader::new(source), opts)
+    }
+
+    fn write(cst: &BmsCst) -> Vec<u8> {
+        let mut out = MemWriter::new();
+        cst.write(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_lossless() {
+        let source = b"*comment\r\n  #TITLE  spaced \r\n\r\n#foo bar\n#00111:0101\n\xff\xfe\n";
+        let cst = read(source, &ParserOptions::new());
+        assert_eq!(cst.lines().len(), 7);
+        assert!(cst.lines()[0].command().is_none());
+        assert_eq!(cst.lines()[4].lineno, 5);
+        assert!(cst.lines()[4].command().is_some());
+        assert_eq!(write(&cst)[], source[]);
+    }
+
+    #[test]
+    fn test_bom() {
+        // the byte order mark is not a part of the first line, but kept after the edit
+        let source = b"\xef\xbb\xbf#TITLE \xe2\x98\x86\n#00111:01\n";
+        let mut cst = read(source, &ParserOptions::new());
+        assert_eq!(cst.encoding().val0(), "utf-8");
+        assert_eq!(cst.lines()[0].text(), "#TITLE \u2606");
+        assert!(cst.lines()[0].messages().is_empty());
+        assert_eq!(write(&cst)[], source[]);
+
+        cst.set_text(0, "#TITLE x").unwrap();
+        assert_eq!(write(&cst)[], b"\xef\xbb\xbf#TITLE x\n#00111:01\n");
+    }
+
+    #[test]
+    fn test_edit() {
+        let title = WINDOWS_31J.encode("#TITLE テスト\r\n", EncoderTrap::Strict).unwrap();
+        let mut source = title.clone();
+        source.push_all(b"  #WAV01 a.wav\r\n#00111:01\r\n");
+        let opts = ParserOptions { autofix_commands: true,
+                                   force_encoding: Some(WINDOWS_31J as EncodingRef),
+                                   chardet: ChardetModels::builtin() };
+        let mut cst = read(source[], &opts);
+        assert_eq!(cst.encoding().val0(), "windows-31j");
+
+        let wav = BmsCommand::WAV(Key(1), "b.wav".into_maybe_owned());
+        cst.set_command(1, &wav).unwrap();
+        assert!(cst.lines()[1].command() == Some(&wav));
+        let bpm = BmsCommand::BPM(BPM(150.0));
+        cst.insert_command(2, &bpm).unwrap();
+        assert!(cst.lines()[2].command() == Some(&bpm));
+        assert_eq!(cst.lines()[3].lineno, 4);
+
+        let mut expected = title;
+        expected.push_all(b"  #WAV01 b.wav\r\n");
+        expected.push_all(format!("{}\r\n", bpm).as_bytes());
+        expected.push_all(b"#00111:01\r\n");
+        assert_eq!(write(&cst), expected);
+    }
+}
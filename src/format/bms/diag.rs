@@ -6,7 +6,8 @@
 
 #![allow(non_upper_case_globals)]
 
-use std::fmt;
+use std::{fmt, io};
+use std::collections::HashMap;
 
 /// The severity of messages. Every error message has one of the severity assigned.
 #[deriving(PartialEq,Eq,PartialOrd,Show,Clone)]
@@ -41,6 +42,126 @@ impl fmt::Show for BmsMessage {
     }
 }
 
+/**
+ * User preferences for diagnostic messages, keyed by `BmsMessage::id`. Each message can be
+ * suppressed or given a different severity, and fatal messages (after the adjustment) can abort
+ * the loading.
+ *
+ * The policy is built from a list of rules, each of which is either `<id>=<level>` (where
+ * `<id>` is one of ids in `all_messages()` and `<level>` is one of `ignore`, `note`, `warning`
+ * or `fatal`) or `abort-on-fatal`.
+ */
+#[deriving(PartialEq,Clone)]
+pub struct DiagPolicy {
+    /// The adjusted severity for each message id, or `None` if the message is suppressed.
+    severities: HashMap<String,Option<Severity>>,
+    /// True if fatal messages should abort the loading.
+    abortonfatal: bool,
+}
+
+impl DiagPolicy {
+    /// Returns a policy which keeps every message as is.
+    pub fn new() -> DiagPolicy {
+        DiagPolicy { severities: HashMap::new(), abortonfatal: false }
+    }
+
+    /// Adds a rule to the policy. The later rule overrides the former for the same id.
+    pub fn add_rule(&mut self, rule: &str) -> Result<(),String> {
+        let rule = rule.trim();
+        if rule == "abort-on-fatal" {
+            self.abortonfatal = true;
+            return Ok(());
+        }
+
+        let (id, level) = match rule.find('=') {
+            Some(idx) => (rule[..idx].trim(), rule[idx+1..].trim()),
+            None => { return Err(format!("Invalid diagnostics rule: {}", rule)); }
+        };
+        if id.is_empty() {
+            return Err(format!("Invalid diagnostics rule: {}", rule));
+        }
+        if !all_messages().iter().any(|msg| msg.id == id) {
+            return Err(format!("Unknown diagnostics message id: {}", id));
+        }
+        let severity = match level {
+            "ignore" => None,
+            "note" => Some(Severity::Note),
+            "warning" => Some(Severity::Warning),
+            "fatal" => Some(Severity::Fatal),
+            _ => { return Err(format!("Invalid diagnostics level: {}", level)); }
+        };
+        self.severities.insert(id.to_string(), severity);
+        Ok(())
+    }
+
+    /// Adds comma-separated rules, as given to the `-W`/`--diag` option.
+    pub fn add_rules(&mut self, rules: &str) -> Result<(),String> {
+        for rule in rules.split(',') {
+            try!(self.add_rule(rule));
+        }
+        Ok(())
+    }
+
+    /// Adds rules from the text with one rule per line, where `#` starts a comment.
+    pub fn add_rule_lines(&mut self, text: &str) -> Result<(),String> {
+        for (i, line) in text.lines().enumerate() {
+            let rule = line.split('#').next().unwrap_or("").trim();
+            if rule.is_empty() { continue; }
+            match self.add_rule(rule) {
+                Ok(()) => {}
+                Err(err) => { return Err(format!("{} at line {}", err, i + 1)); }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds rules from given file (see `add_rule_lines`), as given to the `-F`/`--diag-file`
+    /// option.
+    pub fn add_rules_from_file(&mut self, path: &Path) -> Result<(),String> {
+        let text = match io::File::open(path).and_then(|mut f| f.read_to_string()) {
+            Ok(text) => text,
+            Err(err) => { return Err(format!("Couldn't read {}: {}", path.display(), err)); }
+        };
+        match self.add_rule_lines(text[]) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("{} in {}", err, path.display())),
+        }
+    }
+
+    /// Returns the message adjusted by the policy, or `None` if the message is suppressed.
+    pub fn apply(&self, msg: BmsMessage) -> Option<BmsMessage> {
+        match self.severities.get(&msg.id.to_string()) {
+            Some(&Some(severity)) => Some(BmsMessage { severity: severity, ..msg }),
+            Some(&None) => None,
+            None => Some(msg),
+        }
+    }
+
+    /// Returns true if the loading should be aborted after given (adjusted) message.
+    pub fn aborts(&self, msg: &BmsMessage) -> bool {
+        self.abortonfatal && msg.severity == Severity::Fatal
+    }
+}
+
+/// Returns every message in this module, used to validate the ids in `DiagPolicy` rules.
+pub fn all_messages() -> Vec<BmsMessage> {
+    vec![BmsHasNegativeInitBPM, BmsHasZeroInitBPM, BmsHasNegativeSTOPDuration,
+         BmsHasNegativeSTPDuration, BmsHasNoTITLE, BmsHasEmptyTITLE, BmsHasMultipleTITLEs,
+         BmsUsesCouplePlay, BmsUsesBattlePlay, BmsHasInvalidLNTYPE, BmsHasZeroLNOBJ,
+         BmsHasMultipleLNOBJs, BmsUsesLegacyEncoding, BmsHasFullWidthSharp,
+         BmsHasOneDigitAlphanumericKey, BmsHasNoARTIST, BmsHasEmptyARTIST, BmsHasMultipleARTISTs,
+         BmsHasNoGENRE, BmsHasEmptyGENRE, BmsHasMultipleGENREs, BmsHasGENLE, BmsHasEmptyPath,
+         BmsHasInvalidPLAYER, BmsHasInvalidCANVASSIZE, BmsHasNegativePLAYLEVEL,
+         BmsHasDIFFICULTYOutOfRange, BmsHasEXBPM, BmsHasNonpositiveBPM, BmsHasNegativeVOLWAV,
+         BmsHasNonpositiveEXRANK, BmsHasNonpositiveTOTAL, BmsUsesLNTYPE2, BmsHasUnknownWAVCMD,
+         BmsHasInvalidEXWAV, BmsHasInvalidSWBGA, BmsHasUndefinedWAV, BmsHasUndefinedBMP,
+         BmsHasUndefinedBPM, BmsHasUndefinedSTOP, BmsHasUndefinedTEXT, BmsHasUndefinedEXRANK,
+         BmsHasUndefinedARGB, BmsHasUndefinedSWBGA, BmsHasUnusedWAV, BmsHasUnusedBMP,
+         BmsHasMissingWAVFile, BmsHasMissingBMPFile, BmsHasSONG, BmsHasRONDAM,
+         BmsHasRANDOMWithoutWhitespace, BmsHasIFWithoutWhitespace, BmsHasIFEND,
+         BmsHasENDNotFollowedByIF]
+}
+
 pub static BmsHasNegativeInitBPM: BmsMessage = BmsMessage {
     severity: Severity::Fatal,
     id: "neg-init-bpm",
@@ -113,12 +234,6 @@ pub static BmsHasMultipleLNOBJs: BmsMessage = BmsMessage {
     message: "There are multiple #LNOBJ commands. Only the last such line will be used.",
 };
 
-pub static BmsHasUnimplementedFlow: BmsMessage = BmsMessage {
-    severity: Severity::Warning,
-    id: "unimplemented-flow",
-    message: "#SWITCH and related flow commands are not yet implemented and may malfunction.",
-};
-
 pub static BmsUsesLegacyEncoding: BmsMessage = BmsMessage {
     severity: Severity::Note,
     id: "legacy-encoding",
@@ -191,6 +306,12 @@ pub static BmsHasInvalidPLAYER: BmsMessage = BmsMessage {
     message: "Invalid #PLAYER value will be ignored.",
 };
 
+pub static BmsHasInvalidCANVASSIZE: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "invalid-canvassize",
+    message: "Non-positive or too large #SNRS:CANVASSIZE value will be ignored.",
+};
+
 pub static BmsHasNegativePLAYLEVEL: BmsMessage = BmsMessage {
     severity: Severity::Note,
     id: "neg-playlevel",
@@ -214,6 +335,25 @@ pub static BmsHasNonpositiveBPM: BmsMessage = BmsMessage {
     id: "nonpos-bpm",
     message: "Non-positive BPM is not portable and its use is discouraged.",
 };
+
+pub static BmsHasNegativeVOLWAV: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "neg-volwav",
+    message: "Negative #VOLWAV value will be ignored.",
+};
+
+pub static BmsHasNonpositiveEXRANK: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "nonpos-exrank",
+    message: "Non-positive #DEFEXRANK or #EXRANKxx value will be ignored.",
+};
+
+pub static BmsHasNonpositiveTOTAL: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "nonpos-total",
+    message: "Non-positive #TOTAL value will be ignored.",
+};
+
 pub static BmsUsesLNTYPE2: BmsMessage = BmsMessage {
     severity: Severity::Note,
     id: "lntype2",
@@ -226,6 +366,90 @@ pub static BmsHasUnknownWAVCMD: BmsMessage = BmsMessage {
     message: "Invalid #WAVCMD command will be ignored.",
 };
 
+pub static BmsHasInvalidEXWAV: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "invalid-exwav",
+    message: "#EXWAV with out-of-range values will be ignored.",
+};
+
+pub static BmsHasInvalidSWBGA: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "invalid-swbga",
+    message: "#SWBGA with invalid frame time, duration, channel or pattern will be ignored.",
+};
+
+pub static BmsHasUndefinedWAV: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-wav",
+    message: "The key is not defined by #WAV. Objects with this key will be silent.",
+};
+
+pub static BmsHasUndefinedBMP: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-bmp",
+    message: "The key is not defined by #BMP or #BGA. BGA with this key will be blank.",
+};
+
+pub static BmsHasUndefinedBPM: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-bpm",
+    message: "The key is not defined by #BPM. Objects with this key will be ignored.",
+};
+
+pub static BmsHasUndefinedSTOP: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-stop",
+    message: "The key is not defined by #STOP. Objects with this key will be ignored.",
+};
+
+pub static BmsHasUndefinedTEXT: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-text",
+    message: "The key is not defined by #TEXT. Objects with this key will show no text.",
+};
+
+pub static BmsHasUndefinedEXRANK: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-exrank",
+    message: "The key is not defined by #EXRANK. Objects with this key will be ignored.",
+};
+
+pub static BmsHasUndefinedARGB: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-argb",
+    message: "The key is not defined by #ARGB. Objects with this key will be ignored.",
+};
+
+pub static BmsHasUndefinedSWBGA: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "undefined-swbga",
+    message: "The key is not defined by #SWBGA. BGA with this key will be blank.",
+};
+
+pub static BmsHasUnusedWAV: BmsMessage = BmsMessage {
+    severity: Severity::Note,
+    id: "unused-wav",
+    message: "#WAV is not used by any object.",
+};
+
+pub static BmsHasUnusedBMP: BmsMessage = BmsMessage {
+    severity: Severity::Note,
+    id: "unused-bmp",
+    message: "#BMP is not used by any object.",
+};
+
+pub static BmsHasMissingWAVFile: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "missing-wav-file",
+    message: "The file for #WAV cannot be found.",
+};
+
+pub static BmsHasMissingBMPFile: BmsMessage = BmsMessage {
+    severity: Severity::Warning,
+    id: "missing-bmp-file",
+    message: "The file for #BMP cannot be found.",
+};
+
 pub static BmsHasSONG: BmsMessage = BmsMessage {
     severity: Severity::Note,
     id: "song",
@@ -262,3 +486,77 @@ pub static BmsHasENDNotFollowedByIF: BmsMessage = BmsMessage {
     message: "#END not followed by IF will be interpreted as #ENDIF.",
 };
 
+#[cfg(test)]
+mod tests {
+    use super::{Severity, DiagPolicy, all_messages};
+    use super::{BmsHasNoTITLE, BmsHasZeroInitBPM, BmsUsesLNTYPE2};
+
+    #[test]
+    fn test_add_rule() {
+        let mut policy = DiagPolicy::new();
+        assert!(policy.add_rule("no-title").is_err());
+        assert!(policy.add_rule("=fatal").is_err());
+        assert!(policy.add_rule(" = ignore").is_err());
+        assert!(policy.add_rule("no-title=error").is_err());
+        assert!(policy.add_rule("no-title=").is_err());
+        assert!(policy.add_rule("no-titel=ignore").is_err());
+        assert!(policy == DiagPolicy::new());
+
+        assert!(policy.add_rule(" no-title = fatal ").is_ok());
+        assert!(policy.add_rule("abort-on-fatal").is_ok());
+        assert!(policy != DiagPolicy::new());
+    }
+
+    #[test]
+    fn test_all_messages() {
+        let messages = all_messages();
+        for (i, msg) in messages.iter().enumerate() {
+            assert!(!messages[..i].iter().any(|prev| prev.id == msg.id));
+        }
+    }
+
+    #[test]
+    fn test_add_rules() {
+        let mut policy = DiagPolicy::new();
+        assert!(policy.add_rules("no-title=ignore,lntype2=fatal").is_ok());
+        assert!(policy.add_rules("no-title=ignore,no-titel=fatal").is_err());
+        assert!(policy.add_rule_lines("# comment\n\nno-title=note # trailing\n").is_ok());
+        assert_eq!(policy.apply(BmsHasNoTITLE).map(|msg| msg.severity), Some(Severity::Note));
+        assert_eq!(policy.add_rule_lines("no-title=note\nno-title=error"),
+                   Err("Invalid diagnostics level: error at line 2".to_string()));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut policy = DiagPolicy::new();
+        assert_eq!(policy.apply(BmsHasNoTITLE), Some(BmsHasNoTITLE));
+
+        assert!(policy.add_rule("no-title=ignore").is_ok());
+        assert!(policy.add_rule("lntype2=warning").is_ok());
+        assert_eq!(policy.apply(BmsHasNoTITLE), None);
+        assert_eq!(policy.apply(BmsUsesLNTYPE2).map(|msg| msg.severity), Some(Severity::Warning));
+        assert_eq!(policy.apply(BmsUsesLNTYPE2).map(|msg| msg.id), Some(BmsUsesLNTYPE2.id));
+        assert_eq!(policy.apply(BmsHasZeroInitBPM), Some(BmsHasZeroInitBPM));
+
+        // the later rule overrides the former
+        assert!(policy.add_rule("no-title=note").is_ok());
+        assert_eq!(policy.apply(BmsHasNoTITLE).map(|msg| msg.severity), Some(Severity::Note));
+        assert!(policy.add_rule("no-title=ignore").is_ok());
+        assert_eq!(policy.apply(BmsHasNoTITLE), None);
+    }
+
+    #[test]
+    fn test_aborts() {
+        let mut policy = DiagPolicy::new();
+        assert!(!policy.aborts(&BmsHasZeroInitBPM));
+        assert!(policy.add_rule("abort-on-fatal").is_ok());
+        assert!(policy.aborts(&BmsHasZeroInitBPM));
+        assert!(!policy.aborts(&BmsHasNoTITLE));
+
+        // `aborts` expects the adjusted message
+        assert!(policy.add_rule("no-title=fatal").is_ok());
+        assert!(policy.add_rule("zero-init-bpm=warning").is_ok());
+        assert!(policy.apply(BmsHasNoTITLE).map_or(false, |msg| policy.aborts(&msg)));
+        assert!(!policy.apply(BmsHasZeroInitBPM).map_or(false, |msg| policy.aborts(&msg)));
+    }
+}
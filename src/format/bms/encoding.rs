@@ -4,11 +4,13 @@
 
 //! Character encoding detection for BMS format.
 
-use std::cmp;
+use std::{io, cmp, os};
+use std::io::fs::PathExtensions;
 use encoding::{Encoding, EncodingRef, DecoderTrap};
-use encoding::all::{ASCII, UTF_8, WINDOWS_949, WINDOWS_31J};
+use encoding::all::{ASCII, UTF_8, WINDOWS_949, WINDOWS_31J, GB18030, BIG5_2003};
 
-use util::chardet::{Classifier, CharClassKo, CharClassJa, convert_raw_confidence};
+use util::chardet::{Classifier, CharClass, CharClassKo, CharClassJa, CharClassZhCn, CharClassZhTw};
+use util::chardet::convert_raw_confidence;
 
 static LOG_PROBS_KO: &'static [i32] = &[
      552483, -251065, -187207, -163086,  -88603, -130451,   -2906,  -18512,  -35744,  -77761,
@@ -46,6 +48,118 @@ static LOG_PROBS_JA: &'static [i32] = &[
           0,       0,
 ];
 
+// the Chinese models are trained by `chardet-train` subprogram (rescale factor 10000) from
+// a corpus of short Simplified and Traditional Chinese strings, one per line, resembling those in
+// BMS metadata.
+
+static LOG_PROBS_ZH_CN: &'static [i32] = &[
+     301804,  -70178,  155539,       0,  -52214,   -5398,  455736,  376810,  414213,  363120,
+     525378,  472085,  296228,  360351,  421020,  331928,   59969,  -21573,  -10588,  -47655,
+      55608,  -39329,  -58861,  -12173,   49023,   60969,   -9679,   -5374,   49405,   72983,
+      94233,  113922,  173470,   -6983,   31348,  -21224,   -4172,  142108,  181986,  197510,
+    -112494, -245818, -169610,   19624,   44152,    9647, -127107, -160226, -115542,  -40208,
+    -105875,  -82385,  -79546,     689,  -86464, -160207,  291533,  419309,  299671,  341495,
+     387431,  321298,  354527,  340900,  341129,  111898,  355258,  415272,  270072,  325114,
+     332405,  214742,  231373,  369769,  374829,  279990,  175127,  405103,  419550,  393241,
+     333548,  362733,  384526,  309554,  294431,  359663,  339489,  294025,  356441,  344203,
+     367632,  354342,  338243,  265303,       0,  120176,  246005,  228400, -142954, -103217,
+     -48051,  -34704,  164741,  123652,  335356,  392284,  124395,  446645,  329376,  226176,
+     453922,  204352,  245756,  180014,   94212,  164361,  450228,  218840,   57744,  -53156,
+     232876,  116462,  -24732,  373769,   -9790,  -54760,   14363,  150902,  150902,  150902,
+     430334,  428437,  462852,  363968,  284948,   38274,  299043,  353257,  107574,  109707,
+      51170,  205269,  103403,  282526,  308393,  363555,  -33356,  287062,  302879,   62420,
+     304938,  133900,  189116,  225840,   98102,   12732,  -60237,  -70596,  132932,   70564,
+     237990,   71158,  183676,   49228,  156013,  529515,  118511,  163057,  156013,  150902,
+     -98730,  150902,  176889,   47617,   80725, -140053, -109023, -154621, -184592, -141823,
+     150902, -175467,   32243,  -73061,  150902,   96931,   32243, -174888,  -92906, -205264,
+     177299,  125960,  258057,  262768,  -12176,    8688,  -41717,  237580,  224302,  -22310,
+       6824,  -53826,  118511, -152202, -134376,  -74243,  118511,  150902,   80725,  -62447,
+     150902,  150902,  150902,  150902,  150902,  150902,  188982,  160754,   56910,  123622,
+     156013,
+];
+
+static LOG_PROBS_ZH_TW: &'static [i32] = &[
+     592261,       0,   70178,       0,  189915,       0,       0, -429499,   51045, -427577,
+       5398, -462345, -455736, -361643, -376810, -276838, -414213,  -37217, -363120, -350257,
+      57376, -350510,   35574, -105099, -296228, -105706, -360351,  -81467, -421020, -187326,
+    -331928,  -99816,   83715, -278957,   86758, -302829,   14623, -361215,   96889,   48644,
+     138308, -269515,  153155, -296804,   77883,  -57463,  138904, -299060,  107408, -337090,
+     139020, -226764,   96273, -232031,  105474, -206535,   58689,  -10337,  133990,    -930,
+      99473,  131217,   63613, -111209,   91016,  -60121,  250071, -260086,  -11762,  -65123,
+     138215, -131950,   66694,   -9319,   24951,  -38924,  -95786, -529332,  -47949,   32391,
+     401276, -123148,  245818,  -38924,  472672,       0,  386342,   32391,  327237,       0,
+     395956, -115951,  444419,  103285,  295916,   70178,  426797,  290956,  342402,       0,
+     423232,   32391,  377677,  324618,  408928,  179619,  392987,       0,  462043,  312287,
+     431540,       0,  291533,  183466,  419309,       0,  260195,       0,  341495,       0,
+     387431,  162123,  321298,  243809,  354527,  355246,  340900,  235874,  341129,  156801,
+     381752,  245414,  355258,  146933,  415272,  152053,  270072,  273276,  325114,  162123,
+     332405,  219611,  214742,  141380,  315900,  264667,  369769,  183044,  374829,  192619,
+     279990,   32391,  391052,       0,  405103,  273498,  419550,  222390,  393241,   32391,
+     366096,       0,  362733,       0,  384526,  211713,  309554,       0,  294431,       0,
+     359663,       0,  339489,       0,  294025,       0,  356441,       0,  328423,  150902,
+     150902,  150902,  150902,  150902,  150902,  192793,  154963,  411226,  414901,  388790,
+     441322,  412968,  478294,  444877,  436341,  445420,  438689,  460030,  426885,  477335,
+     449811,  478484,  385365,  384416,  284716,  309266,  311689,  374782,  314636,  289923,
+     180686,  105158,  259500,  278924,  192489,  111978,
+];
+
+/// Classifiers used to guess the encoding.
+#[deriving(PartialEq,Clone)]
+pub struct ChardetModels {
+    ko: Classifier<CharClassKo>,
+    ja: Classifier<CharClassJa>,
+    zhcn: Classifier<CharClassZhCn>,
+    zhtw: Classifier<CharClassZhTw>,
+}
+
+impl ChardetModels {
+    /// Returns the built-in classifiers.
+    pub fn builtin() -> ChardetModels {
+        ChardetModels { ko: Classifier::new(CharClassKo, LOG_PROBS_KO),
+                        ja: Classifier::new(CharClassJa, LOG_PROBS_JA),
+                        zhcn: Classifier::new(CharClassZhCn, LOG_PROBS_ZH_CN),
+                        zhtw: Classifier::new(CharClassZhTw, LOG_PROBS_ZH_TW) }
+    }
+
+    /// Returns the default directory for `load`, which is `res/chardet` in the directory of
+    /// the executable (or the current directory if unknown). The directory is not shipped, so
+    /// unless the trained models are put there `load` falls back to the built-in classifiers.
+    pub fn default_root() -> Path {
+        os::self_exe_path().unwrap_or(Path::new(".")).join_many(["res", "chardet"][])
+    }
+
+    /// Loads the classifiers written by `chardet-train` subprogram from given directory.
+    /// The files are named `ko.chardet`, `ja.chardet`, `zh-cn.chardet` and `zh-tw.chardet`,
+    /// and missing files are substituted with the built-in classifiers.
+    pub fn load(dir: &Path) -> Result<ChardetModels,String> {
+        fn load_classifier<CC:CharClass>(dir: &Path, name: &str, cc: CC,
+                                         builtin: &'static [i32]) -> Result<Classifier<CC>,String> {
+            let path = dir.join(name);
+            if !path.exists() { return Ok(Classifier::new(cc, builtin)); }
+            let ret = match io::File::open(&path) {
+                Ok(f) => Classifier::read(cc, &mut io::BufferedReader::new(f)),
+                Err(err) => Err(err.to_string()),
+            };
+            ret.map_err(|err| format!("{}: {}", path.display(), err))
+        }
+
+        Ok(ChardetModels {
+            ko: try!(load_classifier(dir, "ko.chardet", CharClassKo, LOG_PROBS_KO)),
+            ja: try!(load_classifier(dir, "ja.chardet", CharClassJa, LOG_PROBS_JA)),
+            zhcn: try!(load_classifier(dir, "zh-cn.chardet", CharClassZhCn, LOG_PROBS_ZH_CN)),
+            zhtw: try!(load_classifier(dir, "zh-tw.chardet", CharClassZhTw, LOG_PROBS_ZH_TW)),
+        })
+    }
+}
+
+/// A raw confidence added to Chinese encodings before comparing them with Korean and Japanese
+/// ones. The classifiers are trained with the same weight for every language, so they assume
+/// equal prior probabilities; we instead assume that a BMS file is ten times more likely to be
+/// in Windows-949 or 31J than in GBK or Big5, as the format mainly comes from Japan and Korea.
+/// The bias is the log prior odds in the raw confidence unit, i.e. `round(2^16 ln 10)`.
+/// Without it short Hangul strings are frequently mistaken for GBK.
+static CHINESE_BIAS: i32 = 150902;
+
 /// Reads the whole stream with given encoding. Any error would be substituted with U+FFFD.
 pub fn decode_stream(f: &mut Reader, encoding: EncodingRef) -> String {
     // TODO use incremental decoding when available
@@ -53,14 +167,15 @@ pub fn decode_stream(f: &mut Reader, encoding: EncodingRef) -> String {
     encoding.decode(s[], DecoderTrap::Replace).unwrap()
 }
 
-/// Tries to guess the encoding of the stream and reads it accordingly.
+/// Tries to guess the encoding of the stream with given classifiers and reads it accordingly.
 /// Any error would be substituted with U+FFFD.
 /// Returns a guessed encoding and confidence (probability) in addition to the decoded string.
-/// Currently recognizes `ASCII`, `UTF_8`, `WINDOWS_949` and `WINDOWS_31J` encodings.
+/// Currently recognizes `ASCII`, `UTF_8`, `WINDOWS_949`, `WINDOWS_31J`, `GB18030` (only GBK part)
+/// and `BIG5_2003` encodings.
 //
 // Rust: cannot change this to return `EncodingRef`, it seems to "infect" other uses of
 //       `UTF_8.decode(...)` etc. to fail to resolve.
-pub fn guess_decode_stream(f: &mut Reader) -> (String, EncodingRef, f64) {
+pub fn guess_decode_stream(f: &mut Reader, models: &ChardetModels) -> (String, EncodingRef, f64) {
     let s: Vec<u8> = f.read_to_end().ok().unwrap_or_else(|| Vec::new());
 
     // check for BOM (Sonorous proposal #1)
@@ -82,17 +197,96 @@ pub fn guess_decode_stream(f: &mut Reader) -> (String, EncodingRef, f64) {
         return (ASCII.decode(s[], DecoderTrap::Replace).unwrap(), ASCII as EncodingRef, 1.0);
     }
 
-    // Windows-949/31J: guess
-    let ko = WINDOWS_949.decode(s[], DecoderTrap::Replace).unwrap();
-    let ja = WINDOWS_31J.decode(s[], DecoderTrap::Replace).unwrap();
-    let koconfidence = Classifier::new(CharClassKo, LOG_PROBS_KO).raw_confidence(ko[]);
-    let jaconfidence = Classifier::new(CharClassJa, LOG_PROBS_JA).raw_confidence(ja[]);
-    let (s, encoding, confidence) =
-        if koconfidence < jaconfidence {
-            (ko, WINDOWS_949 as EncodingRef, koconfidence)
-        } else {
-            (ja, WINDOWS_31J as EncodingRef, jaconfidence)
-        };
+    // Windows-949/31J, GB 18030 and Big5: guess (earlier ones are preferred on a tie)
+    fn classify<CC:CharClass>(s: &[u8], encoding: EncodingRef, classifier: &Classifier<CC>,
+                              bias: i32) -> (String, EncodingRef, i32, i32) {
+        let decoded = encoding.decode(s, DecoderTrap::Replace).unwrap();
+        let confidence = classifier.raw_confidence(decoded[]);
+        (decoded, encoding, confidence, confidence + bias)
+    }
+    let candidates = vec![
+        classify(s[], WINDOWS_949 as EncodingRef, &models.ko, 0),
+        classify(s[], WINDOWS_31J as EncodingRef, &models.ja, 0),
+        classify(s[], GB18030 as EncodingRef, &models.zhcn, CHINESE_BIAS),
+        classify(s[], BIG5_2003 as EncodingRef, &models.zhtw, CHINESE_BIAS),
+    ];
+    let (s, encoding, confidence, _) =
+        candidates.into_iter().min_by(|&(_, _, _, biased)| biased).unwrap();
     (s, encoding, convert_raw_confidence(confidence))
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use encoding::Encoding;
+    use encoding::all::{ASCII, UTF_8, WINDOWS_949, WINDOWS_31J, GB18030, BIG5_2003};
+    use super::{ChardetModels, guess_decode_stream};
+
+    fn guess(source: &[u8]) -> (String, &'static str) {
+        let models = ChardetModels::builtin();
+        let (s, encoding, _) = guess_decode_stream(&mut BufReader::new(source), &models);
+        (s, encoding.name())
+    }
+
+    #[test]
+    fn test_unambiguous() {
+        assert_eq!(guess(b"#TITLE foo\n").val1(), ASCII.name());
+        assert_eq!(guess(b"\xef\xbb\xbf#TITLE \xe2\x98\x86\n").val1(), UTF_8.name());
+        assert_eq!(guess(b"#TITLE \xe2\x98\x86\n#ARTIST \xba\xb0\n").val1(), UTF_8.name());
+    }
+
+    #[test]
+    fn test_cjk() {
+        let (s, enc) = guess(b"#TITLE \xba\xb0\xc0\xcc \xba\xfb\xb3\xaa\xb4\xc2 \xb9\xe3\xbf\xa1\n\
+                               #ARTIST \xc7\xcf\xb4\xc3\n");
+        assert_eq!(enc, WINDOWS_949.name());
+        assert!(s[].contains("별이 빛나는 밤에"));
+
+        let (s, enc) = guess(b"#TITLE \x90\xaf\x8b\xf3\x82\xcc\x83f\x83B\x83X\x83^\x83\x93\x83X\n\
+                               #ARTIST \x95\x97\x82\xcc\x92J\n");
+        assert_eq!(enc, WINDOWS_31J.name());
+        assert!(s[].contains("星空のディスタンス"));
+
+        let (s, enc) = guess(b"#TITLE \xd0\xc7\xbf\xd5\xcf\xc2\xb5\xc4\xd4\xbc\xb6\xa8\n\
+                               #ARTIST \xc3\xce\xcf\xeb\xb5\xc4\xb3\xe1\xb0\xf2\n");
+        assert_eq!(enc, GB18030.name());
+        assert!(s[].contains("星空下的约定"));
+
+        let (s, enc) = guess(b"#TITLE \xacP\xaa\xc5\xa4U\xaa\xba\xac\xf9\xa9w\n\
+                               #ARTIST \xb9\xda\xb7Q\xaa\xba\xaf\xcd\xbbH\n");
+        assert_eq!(enc, BIG5_2003.name());
+        assert!(s[].contains("星空下的約定"));
+    }
+
+    #[test]
+    fn test_not_chinese() {
+        // short Korean and Japanese strings are also valid GBK or Big5, and should not be
+        // mistaken for Chinese ones
+        let (s, enc) = guess(b"#TITLE \xc3\xb9\xb4\xab\n");
+        assert_eq!(enc, WINDOWS_949.name());
+        assert!(s[].contains("첫눈"));
+
+        let (s, enc) = guess(b"#TITLE \xb0\xdc\xbf\xef \xb9\xd9\xb4\xd9\n\
+                               #ARTIST \xc0\xba\xc7\xcf\xbc\xf6\n");
+        assert_eq!(enc, WINDOWS_949.name());
+        assert!(s[].contains("겨울 바다"));
+
+        let (s, enc) = guess(b"#TITLE \xb9\xd9\xb6\xf7\xc0\xc7 \xb3\xeb\xb7\xa1\n");
+        assert_eq!(enc, WINDOWS_949.name());
+        assert!(s[].contains("바람의 노래"));
+
+        let (s, enc) = guess(b"#TITLE \x89\xd4\x89\xce\n");
+        assert_eq!(enc, WINDOWS_31J.name());
+        assert!(s[].contains("花火"));
+
+        let (s, enc) = guess(b"#TITLE \x96\xe9\x8d\xf7\x8c\xb6\x91z\x8b\xc8\n\
+                               #ARTIST \x8d\x95\x94L\x82\xc6\x8c\x8e\n");
+        assert_eq!(enc, WINDOWS_31J.name());
+        assert!(s[].contains("夜桜幻想曲"));
+
+        let (s, enc) = guess(b"#TITLE \x91\x93\x82\xa2\x92\xb9\n");
+        assert_eq!(enc, WINDOWS_31J.name());
+        assert!(s[].contains("蒼い鳥"));
+    }
+}
+
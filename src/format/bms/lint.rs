@@ -0,0 +1,327 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * BMS lint. Loads the BMS file and collects every diagnostic message from the loader, so that
+ * chart authors can check their files without playing them.
+ *
+ * Since the loader only sees one outcome of #RANDOM and #SWITCH commands at a time, the file is
+ * loaded once for every possible outcome (up to `MAX_OUTCOMES` outcomes) and the messages are
+ * merged.
+ */
+
+use std::io;
+use std::rand::XorShiftRng;
+
+use format::bms::diag::{BmsMessage, DiagPolicy};
+#[cfg(not(no_subprogram))] use format::bms::catalog::{Language, localize};
+#[cfg(not(no_subprogram))] use format::bms::encoding::ChardetModels;
+#[cfg(not(no_subprogram))] use format::bms::diag::Severity;
+#[cfg(not(no_subprogram))] use format::bms::load::ResourceResolver;
+use format::bms::load::{LoaderOptions, load_bms, list_random_outcomes};
+
+/// The maximum number of #RANDOM and #SWITCH outcomes to be checked.
+pub const MAX_OUTCOMES: uint = 64;
+
+/// Checks the BMS file in `source` and returns diagnostic messages without duplicates, sorted by
+/// the line number (global messages come first). Messages are adjusted by `policy`, and
+/// an error is returned if the policy aborts the loading (or the loader has otherwise failed).
+/// `opts.choices` is ignored; resource paths are checked only when both `opts.basedir`
+/// and `opts.resolver` are set.
+pub fn lint_bms(source: &[u8], opts: &LoaderOptions,
+                policy: &DiagPolicy) -> Result<Vec<(Option<uint>,BmsMessage)>,String> {
+    let outcomes =
+        list_random_outcomes(&mut io::BufReader::new(source), opts, MAX_OUTCOMES).outcomes;
+
+    let mut messages = Vec::new();
+    for choices in outcomes.into_iter() {
+        let mut loaderopts = opts.clone();
+        loaderopts.choices = choices;
+        // every choice is forced, so the RNG is never used
+        let mut r = XorShiftRng::new_unseeded();
+        let callback = |line: Option<uint>, msg: BmsMessage| {
+            match policy.apply(msg) {
+                Some(msg) => {
+                    let abort = policy.aborts(&msg);
+                    let entry = (line, msg);
+                    if !messages.contains(&entry) { messages.push(entry); }
+                    !abort
+                }
+                None => true,
+            }
+        };
+        try!(load_bms(&mut io::BufReader::new(source), &mut r, &loaderopts, callback));
+    }
+    messages.sort_by(|a, b| a.val0().cmp(&b.val0()));
+    Ok(messages)
+}
+
+/// Returns true if the path should be checked by the lint.
+#[cfg(not(no_subprogram))]
+fn is_lintable_file(path: &Path) -> bool {
+    use std::str;
+    use std::ascii::AsciiExt;
+    match path.extension().and_then(str::from_utf8).map(|ext| ext.to_ascii_lower()) {
+        Some(ext) => match ext[] {
+            "bms" | "bme" | "bml" | "pms" => true,
+            _ => false
+        },
+        None => false
+    }
+}
+
+/// Returns the lowercased name of the severity.
+#[cfg(not(no_subprogram))]
+fn severity_name(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Note => "note",
+        Severity::Warning => "warning",
+        Severity::Fatal => "fatal",
+    }
+}
+
+/**
+ * An entry point for `bms-lint` subprogram.
+ * It receives one or more paths to BMS files or directories containing them (searched
+ * recursively), and prints every diagnostic message to the standard output, either in the plain
+ * text (`path:line: severity: message [id]`) or in JSON lines (with `--json`).
+ *
+ * The exit code is 0 when there is no message, and 1, 2 or 3 when the most severe message is
+ * a note, a warning or a fatal error respectively. Unreadable files count as fatal errors.
+ * Messages can be adjusted with `-W`/`--diag` rules or `-F`/`--diag-file` rule files as in
+ * the game (see `DiagPolicy`), and `-C`/`--chardet-root` sets the encoding detection models path.
+ * Invalid arguments result in the exit code 4. Resource files are checked with resolvers made by
+ * `resolver`.
+ */
+#[cfg(not(no_subprogram))]
+pub fn bms_lint(args: &[String],
+                resolver: fn() -> Box<ResourceResolver+'static>) -> int {
+    use std::os;
+    use std::io::stderr;
+    use std::io::fs::{PathExtensions, walk_dir};
+    use std::collections::TreeMap;
+    use serialize::json::Json;
+
+    let mut json = false;
+    let mut policy = DiagPolicy::new();
+    let mut chardetroot = ChardetModels::default_root();
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    loop {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => break,
+        };
+        match arg[] {
+            "--json" => { json = true; }
+            "--text" => { json = false; }
+            "-W" | "--diag" => {
+                let rules = match args.next() {
+                    Some(rules) => rules,
+                    None => {
+                        let _ = write!(&mut stderr(), "No argument to the option {}\n", arg);
+                        return 4;
+                    }
+                };
+                match policy.add_rules(rules[]) {
+                    Ok(()) => {}
+                    Err(err) => {
+                        let _ = write!(&mut stderr(), "{}\n", err);
+                        return 4;
+                    }
+                }
+            }
+            "-F" | "--diag-file" => {
+                let path = match args.next() {
+                    Some(path) => path,
+                    None => {
+                        let _ = write!(&mut stderr(), "No argument to the option {}\n", arg);
+                        return 4;
+                    }
+                };
+                match policy.add_rules_from_file(&Path::new(path[])) {
+                    Ok(()) => {}
+                    Err(err) => {
+                        let _ = write!(&mut stderr(), "{}\n", err);
+                        return 4;
+                    }
+                }
+            }
+            "-C" | "--chardet-root" => {
+                let path = match args.next() {
+                    Some(path) => path,
+                    None => {
+                        let _ = write!(&mut stderr(), "No argument to the option {}\n", arg);
+                        return 4;
+                    }
+                };
+                chardetroot = match Path::new_opt(path[]) {
+                    Some(path) => path,
+                    None => {
+                        let _ = write!(&mut stderr(),
+                                       "Invalid encoding detection models path: {}\n", path);
+                        return 4;
+                    }
+                };
+            }
+            arg if arg.starts_with("-") => {
+                let _ = write!(&mut stderr(), "Invalid option: {}\n", arg);
+                return 4;
+            }
+            arg => { paths.push(Path::new(arg)); }
+        }
+    }
+    if paths.is_empty() {
+        let _ = write!(&mut stderr(), "\
+Usage: {prog} --subprogram bms-lint [--text|--json] [-W RULES] [-F PATH] [-C PATH] <path>...\n",
+                       prog = ::exename());
+        return 4;
+    }
+
+    let chardet = match ChardetModels::load(&chardetroot) {
+        Ok(chardet) => chardet,
+        Err(err) => {
+            let _ = write!(&mut stderr(), "Couldn't load encoding detection models: {}\n", err);
+            return 4;
+        }
+    };
+
+    let mut files = Vec::new();
+    for path in paths.into_iter() {
+        if path.is_dir() {
+            let mut found: Vec<Path> = match walk_dir(&path) {
+                Ok(entries) => entries.filter(|p| p.is_file() && is_lintable_file(p)).collect(),
+                Err(..) => vec![path], // will be reported as an unreadable file
+            };
+            found.sort();
+            files.extend(found.into_iter());
+        } else {
+            files.push(path);
+        }
+    }
+
+    let report = |path: &Path, line: Option<uint>, severity: Severity,
+                  id: &str, message: &str| {
+        if json {
+            let mut obj = TreeMap::new();
+            obj.insert("file".to_string(), Json::String(path.display().to_string()));
+            obj.insert("line".to_string(), match line {
+                Some(line) => Json::U64(line as u64),
+                None => Json::Null,
+            });
+            obj.insert("severity".to_string(), Json::String(severity_name(severity).to_string()));
+            obj.insert("id".to_string(), Json::String(id.to_string()));
+            obj.insert("message".to_string(), Json::String(message.to_string()));
+            println!("{}", Json::Object(obj));
+        } else {
+            let atline = match line {
+                Some(line) => format!(":{}", line),
+                None => String::new(),
+            };
+            println!("{}{}: {}: {} [{}]",
+                     path.display(), atline, severity_name(severity), message, id);
+        }
+    };
+
+    let lang = Language::from_env(os::getenv);
+    let mut maxseverity = None;
+    for path in files.iter() {
+        let mut loaderopts = LoaderOptions::new();
+        loaderopts.basedir = Some(path.dir_path());
+        loaderopts.resolver = Some(resolver);
+        loaderopts.parser.chardet = chardet.clone();
+        let ret = io::File::open(path).and_then(|mut f| f.read_to_end())
+                                      .map_err(|err| err.to_string())
+                                      .and_then(|source| lint_bms(source[], &loaderopts, &policy));
+        match ret {
+            Ok(messages) => {
+                for &(line, ref msg) in messages.iter() {
+                    let msg = localize(msg.clone(), lang);
+                    report(path, line, msg.severity, msg.id, msg.message);
+                    if maxseverity.map_or(true, |max| max < msg.severity) {
+                        maxseverity = Some(msg.severity);
+                    }
+                }
+            }
+            Err(err) => {
+                report(path, None, Severity::Fatal, "load-failed", err[]);
+                maxseverity = Some(Severity::Fatal);
+            }
+        }
+    }
+
+    match maxseverity {
+        None => 0,
+        Some(Severity::Note) => 1,
+        Some(Severity::Warning) => 2,
+        Some(Severity::Fatal) => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use format::bms::diag;
+    use format::bms::diag::{Severity, BmsMessage, DiagPolicy};
+    use format::bms::load::LoaderOptions;
+    use super::lint_bms;
+
+    fn lint(source: &str, rules: &[&str]) -> Result<Vec<(Option<uint>,BmsMessage)>,String> {
+        let mut policy = DiagPolicy::new();
+        for rule in rules.iter() {
+            assert!(policy.add_rule(*rule).is_ok());
+        }
+        lint_bms(source.as_bytes(), &LoaderOptions::new(), &policy)
+    }
+
+    /// Returns messages tied to a line.
+    fn lint_lines(source: &str) -> Vec<(Option<uint>,BmsMessage)> {
+        lint(source, [][]).unwrap().into_iter().filter(|&(line, _)| line.is_some()).collect()
+    }
+
+    #[test]
+    fn test_dedup() {
+        // the file is loaded twice but the global and line messages are reported once
+        let messages = lint("#00112:01\n#RANDOM 2\n#IF 1\n#00111:02\n#ENDIF", [][]).unwrap();
+        assert_eq!(messages.iter().filter(|&&(_, ref msg)| *msg == diag::BmsHasNoTITLE).count(),
+                   1);
+        assert_eq!(messages.iter().filter(|&&(_, ref msg)| *msg == diag::BmsHasUndefinedWAV)
+                           .collect::<Vec<_>>(),
+                   vec![&(Some(1), diag::BmsHasUndefinedWAV),
+                        &(Some(4), diag::BmsHasUndefinedWAV)]);
+    }
+
+    #[test]
+    fn test_other_branches() {
+        // the first outcome takes #IF 1, so #WAV02 is only checked in the second outcome
+        assert_eq!(lint_lines("#WAV01 a.wav\n#RANDOM 2\n#IF 1\n#00111:01\n#ENDIF\n\
+                               #IF 2\n#00112:02\n#ENDIF"),
+                   vec![(Some(7), diag::BmsHasUndefinedWAV)]);
+    }
+
+    #[test]
+    fn test_ordering() {
+        // the loader reports line 2 (measure #001) before line 1 (measure #002), but messages
+        // are sorted by their line numbers, with global messages first
+        let messages = lint("#00211:01\n#00111:02", [][]).unwrap();
+        let lines: Vec<Option<uint>> = messages.iter().map(|&(line, _)| line).collect();
+        assert!(lines.len() > 2 && lines[0] == None);
+        assert_eq!(lines[lines.len()-2..], [Some(1), Some(2)][]);
+    }
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(lint_lines("#BPM 0\n#00111:01"),
+                   vec![(Some(1), diag::BmsHasZeroInitBPM), (Some(2), diag::BmsHasUndefinedWAV)]);
+
+        let messages = lint("#BPM 0\n#00111:01", ["undefined-wav=ignore", "no-title=fatal"][]);
+        let messages = messages.unwrap();
+        assert!(!messages.iter().any(|&(_, ref msg)| msg.id == "undefined-wav"));
+        assert!(messages.iter().any(|&(_, ref msg)| msg.id == "no-title" &&
+                                                    msg.severity == Severity::Fatal));
+
+        // the loader fails when the policy aborts
+        assert!(lint("#BPM 0\n#00111:01", ["abort-on-fatal"][]).is_err());
+        assert!(lint("#BPM 0\n#00111:01", ["abort-on-fatal", "zero-init-bpm=warning"][]).is_ok());
+    }
+}
@@ -5,30 +5,53 @@
 //! BMS loader. Uses a BMS parser (`format::bms::parse`) to produce `format::bms::Bms` structure.
 
 use std::{iter, cmp};
+use std::num::Float;
 use std::rand::Rng;
 
-use format::obj::{NLANES, Lane, BPM, Duration, Damage, BGARef, BGALayer};
+use format::obj::{NLANES, Lane, BPM, Damage, BGARef, BGALayer};
 use format::obj::{ObjQueryOps, ObjConvOps};
 use format::obj::{Visible, Invisible, LNStart, LNDone, Bomb};
-use format::obj::{BGM, SetBGA, SetBPM, Stop, SetMeasureFactor, MeasureBar};
+use format::obj::{BGM, SetBGA, SetBGAOpacity, SetBGAColorKey, SetBPM, Stop};
+use format::obj::{SetSwitchBGA, SetVolume, SetText, SetMeasureFactor, SetGradeFactor};
+use format::obj::{MeasureBar, SoundGroup};
 use format::metadata::{Level, LevelSystem, Difficulty, Meta};
 use format::bms::{parse, diag};
 use format::bms::parse::{Parsed, BmsCommand};
 use format::bms::types::{Key, MAXKEY};
 use format::bms::diag::BmsMessage;
-use format::bms::{ImageRef, SoundRef, DEFAULT_BPM, BmsMeta, Bms};
+use format::bms::{ImageRef, SoundRef, DEFAULT_BPM, SoundTransform, SwitchBGA, BmsMeta, Bms};
+use format::bms::{exrank_to_gradefactor, MAX_CANVAS_SIZE};
 use format::bms::PlayMode;
 
+/// Path resolution for resource files, used to check if the resource files exist. The loader
+/// doesn't know how resources are searched, so the caller provides it via `LoaderOptions`.
+pub trait ResourceResolver {
+    /// Returns true if the sound file at `path` relative to `basedir` can be found.
+    fn has_sound(&mut self, path: &str, basedir: &Path) -> bool;
+    /// Returns true if the image file at `path` relative to `basedir` can be found.
+    fn has_image(&mut self, path: &str, basedir: &Path) -> bool;
+}
+
 /// Loader options for BMS format.
+#[deriving(Clone)]
 pub struct LoaderOptions {
     /// Parser options.
     pub parser: parse::ParserOptions,
+    /// Values to be used for #RANDOM and #SWITCH commands in the order of appearance, normally
+    /// one of the outcomes from `list_random_outcomes`. Remaining commands use the given RNG.
+    pub choices: Vec<int>,
+    /// A directory containing the BMS file. If given along with `resolver`, the loader also
+    /// checks if the resource files exist in this directory (or the directory set by #PATH_WAV).
+    pub basedir: Option<Path>,
+    /// A function creating a new resolver for checking resource files.
+    pub resolver: Option<fn() -> Box<ResourceResolver+'static>>,
 }
 
 impl LoaderOptions {
     /// Returns default loader options.
     pub fn new() -> LoaderOptions {
-        LoaderOptions { parser: parse::ParserOptions::new() }
+        LoaderOptions { parser: parse::ParserOptions::new(), choices: Vec::new(), basedir: None,
+                        resolver: None }
     }
 }
 
@@ -71,13 +94,24 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
     let mut banner = None;
     let mut basepath = None;
     let mut mode = PlayMode::Single;
+    let mut lanes = Vec::new();
     let mut level = None;
     let mut difficulty = None;
     let mut rank = 2;
-    let /*mut*/ canvassize = (256, 256);
+    let mut defexrank = None;
+    let mut total = None;
+    let mut volume = 1.0;
+    let mut canvassize = (256, 256);
     let mut sndpath = Vec::from_elem(MAXKEY as uint, None);
+    let mut sndtransforms = Vec::from_elem(MAXKEY as uint, SoundTransform::identity());
     let mut imgpath = Vec::from_elem(MAXKEY as uint, None);
+    // Line numbers for #WAVxx and #BMPxx (and their variants), used for diagnostics.
+    let mut sndline = Vec::from_elem(MAXKEY as uint, None);
+    let mut imgline = Vec::from_elem(MAXKEY as uint, None);
+    let mut imgcolorkeys = Vec::from_elem(MAXKEY as uint, None);
     let mut imgslices = Vec::from_elem(MAXKEY as uint, None);
+    let mut texts = Vec::from_elem(MAXKEY as uint, None);
+    let mut swbgas = Vec::from_elem(MAXKEY as uint, None);
 
     // A builder for objects.
     let mut builder = TimelineBuilder::new();
@@ -90,9 +124,13 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
     // objects.
     let mut shortens = Vec::new();
     // A table of BPMs. Maps to BMS #BPMxx command.
-    let mut bpmtab = Vec::from_elem(MAXKEY as uint, DEFAULT_BPM);
+    let mut bpmtab = Vec::from_elem(MAXKEY as uint, None);
     // A table of the length of scroll stoppers. Maps to BMS #STOP/#STP commands.
-    let mut stoptab = Vec::from_elem(MAXKEY as uint, Duration::Seconds(0.0));
+    let mut stoptab = Vec::from_elem(MAXKEY as uint, None);
+    // A table of the scale factors for grading area. Maps to BMS #EXRANKxx command.
+    let mut exranktab = Vec::from_elem(MAXKEY as uint, None);
+    // A table of the colors for BGA color keys. Maps to BMS #ARGBxx command.
+    let mut argbtab = Vec::from_elem(MAXKEY as uint, None);
 
     // Allows LNs to be specified as a consecutive row of same or non-00 alphanumeric keys (MGQ
     // type, #LNTYPE 2). The default is to specify LNs as two endpoints (RDM type, #LNTYPE 1).
@@ -102,7 +140,10 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
     // command.
     let mut lnobj = None;
 
-    for parsed in parse::PreprocessingParser::new(f, r, &opts.parser).iter() {
+    let mut parser = parse::PreprocessingParser::with_choices(f, r, &opts.parser,
+                                                              opts.choices[]);
+    let mut parsing = parser.iter();
+    for parsed in parsing.by_ref() {
         let (lineno, cmd) = match parsed {
             Parsed::Command(lineno, cmd) => (lineno, cmd),
             Parsed::Message(lineno, msg) => {
@@ -171,7 +212,7 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
                 if *bpm <= 0.0 {
                     diag!(diag::BmsHasNonpositiveBPM at lineno);
                 }
-                bpmtab[mut][i as uint] = bpm;
+                bpmtab[mut][i as uint] = Some(bpm);
             }
 
             BmsCommand::PLAYER(1) => { mode = PlayMode::Single; }
@@ -181,6 +222,15 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
             BmsCommand::PLAYER(4) => { mode = PlayMode::Battle;
                                      diag!(diag::BmsUsesBattlePlay at lineno); }
             BmsCommand::PLAYER(_) => { diag!(diag::BmsHasInvalidPLAYER at lineno); }
+            BmsCommand::LANES(declared) => { lanes = declared; }
+            BmsCommand::CANVASSIZE(width, height) => {
+                if width <= 0 || height <= 0 ||
+                   width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+                    diag!(diag::BmsHasInvalidCANVASSIZE at lineno);
+                } else {
+                    canvassize = (width, height);
+                }
+            }
 
             BmsCommand::PLAYLEVEL(v) => {
                 if v < 0 { diag!(diag::BmsHasNegativePLAYLEVEL at lineno); }
@@ -193,6 +243,27 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
             BmsCommand::RANK(v) => {
                 rank = v;
             }
+            BmsCommand::DEFEXRANK(v) => {
+                if v <= 0 {
+                    diag!(diag::BmsHasNonpositiveEXRANK at lineno);
+                } else {
+                    defexrank = Some(v);
+                }
+            }
+            BmsCommand::EXRANK(Key(i), v) => {
+                if v <= 0 {
+                    diag!(diag::BmsHasNonpositiveEXRANK at lineno);
+                } else {
+                    exranktab[mut][i as uint] = Some(exrank_to_gradefactor(v));
+                }
+            }
+            BmsCommand::TOTAL(v) => {
+                if v <= 0.0 {
+                    diag!(diag::BmsHasNonpositiveTOTAL at lineno);
+                } else {
+                    total = Some(v);
+                }
+            }
 
             BmsCommand::LNTYPE(1) => { consecutiveln = false; }
             BmsCommand::LNTYPE(2) => { consecutiveln = true;
@@ -210,19 +281,85 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
 
             BmsCommand::WAV(Key(i), s) => {
                 sndpath[mut][i as uint] = Some(s.into_string());
+                sndline[mut][i as uint] = lineno;
+            }
+            BmsCommand::EXWAV(Key(i), pan, vol, freq, s) => {
+                let pan = pan.unwrap_or(0);
+                let vol = vol.unwrap_or(0);
+                if pan < -10000 || pan > 10000 || vol < -10000 || vol > 0 ||
+                   freq.map_or(false, |freq| freq < 100 || freq > 100000) {
+                    diag!(diag::BmsHasInvalidEXWAV at lineno);
+                } else {
+                    // pan and volume are in 1/100 dB (as like DirectSound), frequency is in Hz
+                    sndpath[mut][i as uint] = Some(s.into_string());
+                    sndline[mut][i as uint] = lineno;
+                    let transform = &mut sndtransforms[mut][i as uint];
+                    transform.pan = pan as f64 / 10000.0;
+                    transform.volume = 10.0f64.powf(vol as f64 / 2000.0);
+                    transform.frequency = freq.map(|freq| freq as f64);
+                }
+            }
+            BmsCommand::WAVCMD(cmd, Key(i), v) => {
+                let transform = &mut sndtransforms[mut][i as uint];
+                match (cmd, v) {
+                    (0, 0...127) => { transform.pitch = v - 60; }
+                    (1, 0...100) => { transform.volume = v as f64 / 100.0; }
+                    (2, 0) => { transform.duration = None; }
+                    (2, _) if v > 0 => { transform.duration = Some(v as f64 / 1000.0); }
+                    (_, _) => { diag!(diag::BmsHasUnknownWAVCMD at lineno); }
+                }
+            }
+            BmsCommand::VOLWAV(v) => {
+                if v < 0 {
+                    diag!(diag::BmsHasNegativeVOLWAV at lineno);
+                } else {
+                    volume = v as f64 / 100.0;
+                }
             }
             BmsCommand::BMP(Key(i), s) => {
                 imgpath[mut][i as uint] = Some(s.into_string());
+                imgline[mut][i as uint] = lineno;
+                imgcolorkeys[mut][i as uint] = None;
+            }
+            BmsCommand::EXBMP(Key(i), (_a,r,g,b), s) => {
+                imgpath[mut][i as uint] = Some(s.into_string());
+                imgline[mut][i as uint] = lineno;
+                imgcolorkeys[mut][i as uint] = Some((r,g,b));
+            }
+            BmsCommand::ARGB(Key(i), (_a,r,g,b)) => {
+                argbtab[mut][i as uint] = Some((r,g,b));
+            }
+            BmsCommand::SWBGA(Key(i), fr, time, line, doloop, (_a,r,g,b), pattern) => {
+                // the pattern is a list of alphanumeric keys to #BMPxx
+                let pattern: Vec<char> = pattern.as_slice().chars().collect();
+                let frames: Option<Vec<ImageRef>> =
+                    pattern[].chunks(2).map(|key| Key::from_chars(key).map(ImageRef)).collect();
+                let validline = 36/*1*36*/ <= *line && *line < 108/*3*36*/;
+                match frames {
+                    Some(ref frames) if fr > 0 && time >= 0 && validline && !frames.is_empty() => {
+                        swbgas[mut][i as uint] = Some(SwitchBGA {
+                            lane: line.to_lane(), frametime: fr as uint,
+                            duration: if time > 0 {Some(time as uint)} else {None},
+                            repeat: doloop, colorkey: (r,g,b), frames: frames.clone(),
+                        });
+                    }
+                    _ => {
+                        diag!(diag::BmsHasInvalidSWBGA at lineno);
+                    }
+                }
             }
             BmsCommand::BGA(Key(i), Key(j), slice) => {
                 imgslices[mut][i as uint] = Some((ImageRef(Key(j)), slice));
             }
+            BmsCommand::TEXT(Key(i), s) => {
+                texts[mut][i as uint] = Some(s.into_string());
+            }
 
             BmsCommand::STOP(Key(i), dur) => {
                 if dur.sign() < 0 {
                     diag!(diag::BmsHasNegativeSTOPDuration at lineno);
                 } else {
-                    stoptab[mut][i as uint] = dur;
+                    stoptab[mut][i as uint] = Some(dur);
                 }
             }
             BmsCommand::STP(pos, dur) => {
@@ -294,6 +431,16 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
     // the LN or not.
     let mut lastln: [Option<Mark>, ..NLANES] = [None, ..NLANES];
 
+    // Flags for #WAVxx and #BMPxx keys referenced by objects. #BMP00 is implicitly used as
+    // the default POOR BGA.
+    let mut sndused = Vec::from_elem(MAXKEY as uint, false);
+    let mut imgused = Vec::from_elem(MAXKEY as uint, false);
+    imgused[mut][0] = true;
+
+    // Flags for keys referenced by channels #08, #09, #99, #A0, #A1-A4 and #A5 in this order,
+    // `MAXKEY` entries per channel kind. Only used to report undefined keys once.
+    let mut keyused = Vec::from_elem(6 * MAXKEY as uint, false);
+
     // Replaces the reference to #BGA/#@BGA keys into a pair of the reference to #BMP keys
     // and corresponding `ImageSlice`, if possible.
     let imgref_to_bgaref = |iref: ImageRef| -> BGARef<ImageRef> {
@@ -304,11 +451,68 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
     };
 
     {
-        // Handles a non-00 alphanumeric key `v` positioned at the particular channel `chan` and
+        // Handles an alphanumeric key `v` positioned at the particular channel `chan` and
         // particular position `t`. The position `t2` next to `t` is used for some cases that
-        // an alphanumeric key designates an area rather than a point.
-        let handle_key = |chan: Key, t: f64, t2: f64, v: Key, _lineno: Option<uint>| -> bool {
-            let /*mut*/ ret = true;
+        // an alphanumeric key designates an area rather than a point. The key is non-00 except
+        // for the channels where 00 is a valid value (see `zero_allowed` below).
+        let handle_key = |chan: Key, t: f64, t2: f64, v: Key, lineno: Option<uint>| -> bool {
+            let mut ret = true;
+
+            // check if the key is defined, once per key
+            match *chan {
+                // channels #01 and #1x-6x: sound keys. the #LNOBJ key is often left undefined.
+                1 | 36/*1*36*/...251/*7*36-1*/ => {
+                    if !sndused[*v as uint] {
+                        sndused[mut][*v as uint] = true;
+                        if sndpath[*v as uint].is_none() && lnobj != Some(v) {
+                            ret = callback(lineno, diag::BmsHasUndefinedWAV);
+                        }
+                    }
+                }
+                // channels #04/06/07/0A: image keys, possibly through #BGAxx
+                4 | 6 | 7 | 10 => {
+                    let i = match imgslices[*v as uint] {
+                        Some((ImageRef(Key(i)), _)) => i as uint,
+                        None => *v as uint,
+                    };
+                    if !imgused[i] {
+                        imgused[mut][i] = true;
+                        if imgpath[i].is_none() {
+                            ret = callback(lineno, diag::BmsHasUndefinedBMP);
+                        }
+                    }
+                }
+                // channels #08/09/99/A0/A1-A4/A5: keys defined by #BPMxx, #STOPxx, #TEXTxx,
+                // #EXRANKxx, #ARGBxx and #SWBGAxx respectively
+                8 | 9 | 333/*9*36+9*/ | 360/*0xA*36*/...365/*0xA*36+5*/ => {
+                    let i = *v as uint;
+                    let (kind, defined, message) = match *chan {
+                        8 => (0, bpmtab[i].is_some(), diag::BmsHasUndefinedBPM),
+                        9 => (1, stoptab[i].is_some(), diag::BmsHasUndefinedSTOP),
+                        333 => (2, texts[i].is_some(), diag::BmsHasUndefinedTEXT),
+                        360 => (3, exranktab[i].is_some(), diag::BmsHasUndefinedEXRANK),
+                        365 => (5, swbgas[i].is_some(), diag::BmsHasUndefinedSWBGA),
+                        _ => (4, argbtab[i].is_some(), diag::BmsHasUndefinedARGB),
+                    };
+                    if !keyused[kind * MAXKEY as uint + i] {
+                        keyused[mut][kind * MAXKEY as uint + i] = true;
+                        if !defined {
+                            ret = callback(lineno, message);
+                        }
+                    }
+
+                    // every frame of #SWBGAxx is used by channel #A5
+                    if *chan == 365 {
+                        for swbga in swbgas[i].iter() {
+                            for &ImageRef(Key(i)) in swbga.frames.iter() {
+                                imgused[mut][i as uint] = true;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+
             match *chan {
                 // channel #01: BGM
                 1 => { builder.add(t, BGM(SoundRef(v))); }
@@ -333,14 +537,36 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
                 7 => { builder.add(t, SetBGA(BGALayer::Layer2, imgref_to_bgaref(ImageRef(v)))); }
 
                 // channel #08: BPM defined by #BPMxx
-                8 => { builder.add(t, SetBPM(bpmtab[*v as uint])); }
+                8 => {
+                    for &bpm in bpmtab[*v as uint].iter() {
+                        builder.add(t, SetBPM(bpm));
+                    }
+                }
 
                 // channel #09: scroll stopper defined by #STOPxx
-                9 => { builder.add(t, Stop(stoptab[*v as uint])); }
+                9 => {
+                    for &dur in stoptab[*v as uint].iter() {
+                        builder.add(t, Stop(dur));
+                    }
+                }
 
                 // channel #0A: BGA layer 3
                 10 => { builder.add(t, SetBGA(BGALayer::Layer3, imgref_to_bgaref(ImageRef(v)))); }
 
+                // channels #0B/0C/0D/0E: BGA opacity as an hexadecimal key, for layer 1, 2, 3
+                // and POOR BGA respectively (00 makes the layer fully transparent)
+                11...14 => {
+                    let layer = match *chan {
+                        11 => BGALayer::Layer1,
+                        12 => BGALayer::Layer2,
+                        13 => BGALayer::Layer3,
+                        _ => BGALayer::PoorBGA,
+                    };
+                    for &v in v.to_hex().iter() {
+                        builder.add(t, SetBGAOpacity(layer, v as u8));
+                    }
+                }
+
                 // channels #1x/2x: visible object, possibly LNs when #LNOBJ is in active
                 36/*1*36*/...107/*3*36-1*/ => {
                     let lane = chan.to_lane();
@@ -427,14 +653,62 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
                     }
                 }
 
-                // unsupported: channels #0B/0C/0D/0E (BGA opacity), #97/98 (sound volume),
-                // #99 (text), #A0 (dynamic #RANK), #A1/A2/A3/A4 (BGA color key update),
-                // #A5 (BGA on keypress), #A6 (player-specific option)
+                // channel #A0: dynamic judge rank defined by #EXRANKxx
+                360/*0xA*36*/ => {
+                    for &factor in exranktab[*v as uint].iter() {
+                        builder.add(t, SetGradeFactor(factor));
+                    }
+                }
+
+                // channels #97/98: BGM and key sound volume as an hexadecimal key (00 mutes
+                // the sounds)
+                331/*9*36+7*/ | 332/*9*36+8*/ => {
+                    let group = if *chan == 331 {SoundGroup::Background}
+                                else {SoundGroup::KeySound};
+                    for &v in v.to_hex().iter() {
+                        builder.add(t, SetVolume(group, v as f64 / 255.0));
+                    }
+                }
+
+                // channel #99: text defined by #TEXTxx
+                333/*9*36+9*/ => {
+                    builder.add(t, SetText(*v as uint));
+                }
+
+                // channels #A1/A2/A3/A4: BGA color key defined by #ARGBxx, for layer 1, 2, 3 and
+                // POOR BGA respectively (the alpha component is ignored)
+                361/*0xA*36+1*/...364/*0xA*36+4*/ => {
+                    let layer = match *chan {
+                        361 => BGALayer::Layer1,
+                        362 => BGALayer::Layer2,
+                        363 => BGALayer::Layer3,
+                        _ => BGALayer::PoorBGA,
+                    };
+                    for &colorkey in argbtab[*v as uint].iter() {
+                        builder.add(t, SetBGAColorKey(layer, Some(colorkey)));
+                    }
+                }
+
+                // channel #A5: switch BGA defined by #SWBGAxx
+                365/*0xA*36+5*/ => {
+                    builder.add(t, SetSwitchBGA(*v as uint));
+                }
+
+                // unsupported: channel #A6 (player-specific option)
                 _ => {}
             }
             ret
         };
 
+        // Returns true if 00 in given channel is a value rather than an absence of objects.
+        // Channels #0B-#0E use it for zero opacity, and channels #97/98 for muting.
+        let zero_allowed = |chan: Key| -> bool {
+            match *chan {
+                11...14 | 331/*9*36+7*/ | 332/*9*36+8*/ => true,
+                _ => false,
+            }
+        };
+
         // loop over the sorted bmslines
         bmsline.sort_by(|a, b| (a.measure, a.chan).cmp(&(b.measure, b.chan)));
         for line in bmsline.iter() {
@@ -445,7 +719,7 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
             for i in iter::range_step(0, max, 2) {
                 let v = Key::from_chars(data[i..i+2]);
                 for &v in v.iter() {
-                    if v != Key(0) { // ignores 00
+                    if v != Key(0) || zero_allowed(line.chan) { // ignores 00 in most channels
                         let t = measure + i as f64 / count;
                         let t2 = measure + (i + 2) as f64 / count;
                         if !handle_key(line.chan, t, t2, v, line.lineno) {
@@ -457,6 +731,35 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
         }
     }
 
+    // report unused definitions. this is skipped when the file depends on the randomness, since
+    // definitions may be used by objects in other outcomes.
+    if !parsing.is_random() {
+        for i in range(0, MAXKEY as uint) {
+            if sndpath[i].is_some() && !sndused[i] { diag!(diag::BmsHasUnusedWAV at sndline[i]); }
+            if imgpath[i].is_some() && !imgused[i] { diag!(diag::BmsHasUnusedBMP at imgline[i]); }
+        }
+    }
+
+    // report resource files that cannot be found
+    let basedir = basepath.as_ref().or(opts.basedir.as_ref());
+    for (basedir, &newresolver) in basedir.iter().zip(opts.resolver.iter()) {
+        // the directory of a bare file name is an empty path, which can't be listed
+        let basedir = if **basedir == Path::new("") {Path::new(".")} else {(*basedir).clone()};
+        let mut resolver = newresolver();
+        for i in range(0, MAXKEY as uint) {
+            for path in sndpath[i].iter() {
+                if !resolver.has_sound(path[], &basedir) {
+                    diag!(diag::BmsHasMissingWAVFile at sndline[i]);
+                }
+            }
+            for path in imgpath[i].iter() {
+                if !resolver.has_image(path[], &basedir) {
+                    diag!(diag::BmsHasMissingBMPFile at imgline[i]);
+                }
+            }
+        }
+    }
+
     // insert an artificial `SetBGA` object at 0.0 if required
     if poorbgafix {
         builder.add(0.0, SetBGA(BGALayer::PoorBGA, imgref_to_bgaref(ImageRef(Key(0)))));
@@ -491,13 +794,355 @@ pub fn load_bms<'r,R:Rng>(f: &mut Reader, r: &mut R, opts: &LoaderOptions,
 
     let timeline = builder.build();
     let meta = BmsMeta {
-        common: Meta { random: false, // XXX
+        common: Meta { random: parsing.is_random(),
                        title: title, subtitles: subtitles, genre: genre,
                        artist: artist, subartists: subartists, comments: comments,
                        level: level, difficulty: difficulty },
         encoding: encoding, stagefile: stagefile, banner: banner, basepath: basepath,
-        mode: mode, rank: rank, sndpath: sndpath, imgpath: imgpath,
+        mode: mode, lanes: lanes, rank: rank, defexrank: defexrank, total: total, volume: volume,
+        sndpath: sndpath, sndtransforms: sndtransforms, imgpath: imgpath,
+        imgcolorkeys: imgcolorkeys, texts: texts, swbgas: swbgas,
+        canvassize: (canvassize.val0() as uint, canvassize.val1() as uint),
     };
     Ok(Bms { bmspath: None, meta: meta, timeline: timeline })
 }
 
+/// Possible outcomes of #RANDOM and #SWITCH commands in the BMS file.
+pub struct RandomOutcomes {
+    /// Each outcome is a list of values chosen for #RANDOM and #SWITCH commands in the order of
+    /// appearance, and can be given to `LoaderOptions::choices` to load that outcome.
+    pub outcomes: Vec<Vec<int>>,
+    /// False if there are more outcomes than the limit.
+    pub complete: bool,
+}
+
+/// Lists possible outcomes of #RANDOM and #SWITCH commands in the BMS file, up to `limit`
+/// outcomes. The file has no randomness if there is only one (possibly empty) outcome.
+///
+/// Since a nested command may or may not be chosen depending on the prior values, outcomes are
+/// enumerated by running the preprocessor repeatedly over the once parsed commands.
+pub fn list_random_outcomes(f: &mut Reader, opts: &LoaderOptions,
+                            limit: uint) -> RandomOutcomes {
+    use std::rand::XorShiftRng;
+    use format::bms::preproc::{BmsFlow, Preprocessor};
+
+    let mut parser = parse::Parser::new(f, &opts.parser);
+    let cmds: Vec<BmsCommand<'static>> = parser.iter().filter_map(|parsed| {
+        match parsed {
+            Parsed::Command(_, cmd) => Some(cmd.into_send()),
+            _ => None,
+        }
+    }).collect();
+
+    // every #RANDOM and #SWITCH command chooses at most one value, so forcing this many values
+    // never falls back to the RNG (which is only there to satisfy the preprocessor).
+    let nflows = cmds.iter().filter(|cmd| match **cmd {
+        BmsCommand::Flow(BmsFlow::RANDOM(..)) | BmsCommand::Flow(BmsFlow::SWITCH(..)) => true,
+        _ => false,
+    }).count();
+    let mut r = XorShiftRng::new_unseeded();
+
+    let mut outcomes = Vec::new();
+    let mut prefix = Vec::new();
+    while outcomes.len() < limit {
+        let mut forced = prefix.clone();
+        forced.grow(nflows - prefix.len(), 1);
+
+        let mut pp = Preprocessor::new(&mut r);
+        pp.force_choices(forced[]);
+        let mut messages = Vec::new();
+        let mut out = Vec::new();
+        for cmd in cmds.iter() {
+            match *cmd {
+                BmsCommand::Flow(ref flow) => pp.feed_flow(None, flow, &mut messages, &mut out),
+                _ => pp.feed_other((), &mut messages, &mut out),
+            }
+            out.clear();
+        }
+        pp.finish(&mut messages, &mut out);
+
+        let choices = pp.choices();
+        outcomes.push(choices.iter().map(|&(val, _)| val).collect());
+
+        // advance the last value which can be advanced, and reset every following value
+        match choices.iter().rposition(|&(val, max)| val < max) {
+            Some(i) => {
+                prefix = choices[..i].iter().map(|&(val, _)| val).collect();
+                prefix.push(choices[i].val0() + 1);
+            }
+            None => { return RandomOutcomes { outcomes: outcomes, complete: true }; }
+        }
+    }
+    RandomOutcomes { outcomes: outcomes, complete: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::rand::task_rng;
+    use format::obj::{ObjData, ObjQueryOps, BGARef, BGALayer, SoundGroup, ImageSlice};
+    use format::obj::{SetBGAOpacity, SetBGAColorKey, SetSwitchBGA, SetVolume, SetText};
+    use format::obj::{SetBGA, SetGradeFactor};
+    use format::bms::{Key, ImageRef, SoundRef};
+    use format::bms::diag;
+    use format::bms::diag::BmsMessage;
+    use format::bms::testutil::{load, objs, lane};
+    use super::{LoaderOptions, load_bms, list_random_outcomes};
+
+    /// Loads the BMS file and returns the virtual positions and data of objects satisfying
+    /// given predicate.
+    fn objs_where(source: &str, mut pred: |&ObjData<SoundRef,ImageRef>| -> bool)
+                                    -> Vec<(f64, ObjData<SoundRef,ImageRef>)> {
+        objs(&load(source)).into_iter().filter(|&(_, ref data)| pred(data)).collect()
+    }
+
+    /// Loads the BMS file and returns every diagnostic message tied to a line.
+    fn messages(source: &str) -> Vec<(Option<uint>,BmsMessage)> {
+        let mut messages = Vec::new();
+        let ret = load_bms(&mut BufReader::new(source.as_bytes()), &mut task_rng(),
+                           &LoaderOptions::new(), |line, msg| {
+            if line.is_some() { messages.push((line, msg)); }
+            true
+        });
+        assert!(ret.is_ok());
+        messages
+    }
+
+    #[test]
+    fn test_undefined_wav() {
+        assert_eq!(messages("#WAV01 a.wav\n#00111:0102\n#00112:0202"),
+                   vec![(Some(2), diag::BmsHasUndefinedWAV)]);
+    }
+
+    #[test]
+    fn test_undefined_lnobj() {
+        assert_eq!(messages("#WAV01 a.wav\n#LNOBJ 02\n#00111:0102"), vec![]);
+    }
+
+    #[test]
+    fn test_undefined_bpm_and_stop() {
+        // objects with undefined #BPMxx or #STOPxx keys are ignored
+        let source = "#BPM01 150\n#STOP01 48\n#00108:0102\n#00109:0102";
+        assert_eq!(messages(source), vec![(Some(3), diag::BmsHasUndefinedBPM),
+                                          (Some(4), diag::BmsHasUndefinedSTOP)]);
+        assert_eq!(objs_where(source, |data| data.is_setbpm()).len(), 1);
+        assert_eq!(objs_where(source, |data| data.is_stop()).len(), 1);
+    }
+
+    #[test]
+    fn test_undefined_keys() {
+        let source = "#00199:01\n#001A0:01\n#001A1:01\n#001A5:01";
+        assert_eq!(messages(source), vec![(Some(1), diag::BmsHasUndefinedTEXT),
+                                          (Some(2), diag::BmsHasUndefinedEXRANK),
+                                          (Some(3), diag::BmsHasUndefinedARGB),
+                                          (Some(4), diag::BmsHasUndefinedSWBGA)]);
+    }
+
+    #[test]
+    fn test_undefined_keys_reported_once() {
+        // channels #A1-A4 share #ARGBxx, so the key is reported only at its first use
+        let source = "#00199:0101\n#00299:01\n#001A1:01\n#001A4:0101";
+        assert_eq!(messages(source), vec![(Some(1), diag::BmsHasUndefinedTEXT),
+                                          (Some(3), diag::BmsHasUndefinedARGB)]);
+    }
+
+    #[test]
+    fn test_unused_bmp() {
+        assert_eq!(messages("#BMP01 a.bmp\n#BMP02 b.bmp\n#00104:01"),
+                   vec![(Some(2), diag::BmsHasUnusedBMP)]);
+    }
+
+    #[test]
+    fn test_unused_bmp00() {
+        // #BMP00 is implicitly used as the default POOR BGA
+        assert_eq!(messages("#BMP00 miss.bmp"), vec![]);
+    }
+
+    #[test]
+    fn test_unused_with_random() {
+        // #WAV01 and #BMP01 are used only in some outcomes
+        assert_eq!(messages("#WAV01 a.wav\n#BMP01 a.bmp\n#RANDOM 2\n#IF 2\n#00211:01\n#00204:01\n\
+                             #ENDIF"),
+                   vec![]);
+    }
+
+    fn outcomes(source: &str, limit: uint) -> (Vec<Vec<int>>, bool) {
+        let ret = list_random_outcomes(&mut BufReader::new(source.as_bytes()),
+                                       &LoaderOptions::new(), limit);
+        (ret.outcomes, ret.complete)
+    }
+
+    #[test]
+    fn test_no_outcomes() {
+        assert_eq!(outcomes("#TITLE foo\n#00111:01", 10), (vec![vec![]], true));
+    }
+
+    #[test]
+    fn test_sequential_outcomes() {
+        // the last value advances first, and following values are reset after the advance
+        let source = "#RANDOM 2\n#ENDRANDOM\n#SWITCH 3\n#ENDSW";
+        assert_eq!(outcomes(source, 10),
+                   (vec![vec![1, 1], vec![1, 2], vec![1, 3],
+                         vec![2, 1], vec![2, 2], vec![2, 3]], true));
+        assert_eq!(outcomes(source, 6).val1(), true);
+        assert_eq!(outcomes(source, 4),
+                   (vec![vec![1, 1], vec![1, 2], vec![1, 3], vec![2, 1]], false));
+    }
+
+    #[test]
+    fn test_nested_outcomes() {
+        // the inner #RANDOM is only chosen when the outer #RANDOM takes the branch
+        let source = "#RANDOM 2\n#IF 1\n#RANDOM 3\n#ENDRANDOM\n#ENDIF\n#ENDRANDOM\n\
+                      #RANDOM 2\n#ENDRANDOM";
+        assert_eq!(outcomes(source, 10),
+                   (vec![vec![1, 1, 1], vec![1, 1, 2], vec![1, 2, 1], vec![1, 2, 2],
+                         vec![1, 3, 1], vec![1, 3, 2], vec![2, 1], vec![2, 2]], true));
+        assert_eq!(outcomes(source, 7),
+                   (vec![vec![1, 1, 1], vec![1, 1, 2], vec![1, 2, 1], vec![1, 2, 2],
+                         vec![1, 3, 1], vec![1, 3, 2], vec![2, 1]], false));
+    }
+
+    #[test]
+    fn test_bga_opacity() {
+        assert_eq!(objs_where("#0010B:80\n#0010C:GG\n#0010D:01", |data| data.is_setbgaopacity()),
+                   vec![(1.0, SetBGAOpacity(BGALayer::Layer1, 0x80)),
+                        (1.0, SetBGAOpacity(BGALayer::Layer3, 0x01))]);
+    }
+
+    #[test]
+    fn test_zero_bga_opacity() {
+        // 00 makes the layer fully transparent instead of being ignored
+        assert_eq!(objs_where("#0020E:FF00", |data| data.is_setbgaopacity()),
+                   vec![(2.0, SetBGAOpacity(BGALayer::PoorBGA, 0xFF)),
+                        (2.5, SetBGAOpacity(BGALayer::PoorBGA, 0))]);
+    }
+
+    #[test]
+    fn test_volume() {
+        assert_eq!(objs_where("#00197:FF\n#00198:0033", |data| data.is_setvolume()),
+                   vec![(1.0, SetVolume(SoundGroup::Background, 1.0)),
+                        (1.0, SetVolume(SoundGroup::KeySound, 0.0)),
+                        (1.5, SetVolume(SoundGroup::KeySound, 0.2))]);
+    }
+
+    #[test]
+    fn test_mute() {
+        // 00 mutes the sound group instead of being ignored
+        assert_eq!(objs_where("#00297:00", |data| data.is_setvolume()),
+                   vec![(2.0, SetVolume(SoundGroup::Background, 0.0))]);
+    }
+
+    #[test]
+    fn test_volwav() {
+        assert_eq!(load("#VOLWAV 50").meta.volume, 0.5);
+        assert_eq!(load("#VOLWAV 150").meta.volume, 1.5);
+        assert_eq!(load("").meta.volume, 1.0);
+        assert_eq!(messages("#VOLWAV -1"), vec![(Some(1), diag::BmsHasNegativeVOLWAV)]);
+    }
+
+    #[test]
+    fn test_defexrank() {
+        assert_eq!(load("#RANK 3").meta.gradefactor(), 0.75);
+        assert_eq!(load("#RANK 3\n#DEFEXRANK 50").meta.gradefactor(), 2.0);
+        assert_eq!(load("#DEFEXRANK 200\n#RANK 3").meta.gradefactor(), 0.5);
+    }
+
+    #[test]
+    fn test_exrank() {
+        assert_eq!(objs_where("#EXRANK01 50\n#EXRANK02 200\n#001A0:01\n#002A0:0002",
+                              |data| data.is_setgradefactor()),
+                   vec![(1.0, SetGradeFactor(2.0)), (2.5, SetGradeFactor(0.5))]);
+    }
+
+    #[test]
+    fn test_nonpositive_exrank() {
+        // #EXRANK01 is ignored, and channel #A0 with 01 (or any other undefined key) does nothing
+        let source = "#EXRANK01 0\n#001A0:0102";
+        assert_eq!(objs_where(source, |data| data.is_setgradefactor()), vec![]);
+        assert!(messages(source).contains(&(Some(1), diag::BmsHasNonpositiveEXRANK)));
+        assert!(messages("#DEFEXRANK -10").contains(&(Some(1), diag::BmsHasNonpositiveEXRANK)));
+        assert_eq!(load("#DEFEXRANK 0").meta.gradefactor(), 1.0);
+    }
+
+    #[test]
+    fn test_text() {
+        let source = "#TEXT01 Hello, world!\n#SONG0A lyrics\n#00199:010A\n#00299:01";
+        let bms = load(source);
+        assert_eq!(bms.meta.texts[1], Some("Hello, world!".to_string()));
+        assert_eq!(bms.meta.texts[10], Some("lyrics".to_string()));
+        assert_eq!(bms.meta.texts[2], None);
+        assert_eq!(objs_where(source, |data| data.is_settext()),
+                   vec![(1.0, SetText(1)), (1.5, SetText(10)), (2.0, SetText(1))]);
+        assert!(messages(source).contains(&(Some(2), diag::BmsHasSONG)));
+    }
+
+    #[test]
+    fn test_exbmp() {
+        let bms = load("#BMP01 a.bmp\n#EXBMP02 255,1,2,3 b.bmp\n#EXBMP03 256,0,0,0 c.bmp\n\
+                        #EXBMP01 0,4,5,6 d.bmp\n#BMP02 e.bmp");
+        assert_eq!(bms.meta.imgpath[1], Some("d.bmp".to_string()));
+        assert_eq!(bms.meta.imgcolorkeys[1], Some((4,5,6))); // the alpha component is ignored
+        assert_eq!(bms.meta.imgcolorkeys[2], None); // reset by later #BMP02
+        assert_eq!(bms.meta.imgpath[3], None); // out of range
+    }
+
+    #[test]
+    fn test_argb() {
+        let source = "#ARGB01 255,10,20,30\n#ARGB02 0,40,50,60\n#ARGB03 1,2,3\n\
+                      #001A1:01\n#001A4:0203";
+        assert_eq!(objs_where(source, |data| data.is_setbgacolorkey()),
+                   vec![(1.0, SetBGAColorKey(BGALayer::Layer1, Some((10,20,30)))),
+                        (1.0, SetBGAColorKey(BGALayer::PoorBGA, Some((40,50,60))))]);
+    }
+
+    #[test]
+    fn test_swbga() {
+        let source = "#SWBGA01 50:200:12:1:255,1,2,3 0102ZZ\n\
+                      #SWBGA02 50:0:31:0:255,0,0,0 01\n\
+                      #SWBGA03 0:0:11:0:255,0,0,0 01\n\
+                      #001A5:0001";
+        let bms = load(source);
+        let swbga = bms.meta.swbgas[1].as_ref().unwrap();
+        assert!(swbga.lane == lane("12"));
+        assert_eq!((swbga.frametime, swbga.duration, swbga.repeat), (50, Some(200), true));
+        assert_eq!(swbga.colorkey, (1,2,3));
+        assert_eq!(swbga.frames, vec![ImageRef(Key(1)), ImageRef(Key(2)), ImageRef(Key(1295))]);
+        assert!(bms.meta.swbgas[2].is_none()); // not a lane channel
+        assert!(bms.meta.swbgas[3].is_none()); // zero frame time
+        assert_eq!(messages(source).iter().filter(|&&(_, msg)| msg == diag::BmsHasInvalidSWBGA)
+                                   .count(), 2);
+        assert_eq!(objs_where(source, |data| data.is_setswitchbga()),
+                   vec![(1.5, SetSwitchBGA(1))]);
+    }
+
+    #[test]
+    fn test_canvassize() {
+        assert_eq!(load("#SNRS:CANVASSIZE 512 384").meta.canvassize, (512, 384));
+        assert_eq!(load("#TITLE foo").meta.canvassize, (256, 256));
+        assert_eq!(load("#SNRS:CANVASSIZE 0 384").meta.canvassize, (256, 256));
+        assert_eq!(messages("#SNRS:CANVASSIZE 0 384"),
+                   vec![(Some(1), diag::BmsHasInvalidCANVASSIZE)]);
+        assert_eq!(load("#SNRS:CANVASSIZE 4096 4096").meta.canvassize, (4096, 4096));
+        assert_eq!(load("#SNRS:CANVASSIZE 100000 100000").meta.canvassize, (256, 256));
+        assert_eq!(messages("#SNRS:CANVASSIZE 512 4097"),
+                   vec![(Some(1), diag::BmsHasInvalidCANVASSIZE)]);
+    }
+
+    #[test]
+    fn test_bga_slice_clipping() {
+        let source = "#SNRS:CANVASSIZE 512 128\n#BMP01 a.png\n\
+                      #BGA02 01 0 0 600 600 10 20\n#@BGA03 01 -10 -20 100 100 0 0\n\
+                      #00104:0203";
+        let slice = |sx: int, sy: int, dx: int, dy: int, w: int, h: int| -> BGARef<ImageRef> {
+            BGARef::SlicedImage(ImageRef(Key(1)),
+                                box ImageSlice { sx: sx, sy: sy, dx: dx, dy: dy, w: w, h: h })
+        };
+        let layer1 = objs_where(source, |data| match *data {
+            SetBGA(BGALayer::Layer1, _) => true,
+            _ => false,
+        });
+        assert_eq!(layer1,
+                   vec![(1.0, SetBGA(BGALayer::Layer1, slice(0, 0, 10, 20, 512, 128))),
+                        (1.5, SetBGA(BGALayer::Layer1, slice(0, 0, 0, 0, 100, 100)))]);
+    }
+}
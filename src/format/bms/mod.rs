@@ -31,9 +31,10 @@
  * command memo](http://hitkey.nekokan.dyndns.info/cmds.htm).
  */
 
-use std::fmt;
+use std::{fmt, cmp};
+use std::num::Float;
 
-use format::obj::BPM;
+use format::obj::{BPM, Lane};
 use format::metadata::Meta;
 use format::timeline::Timeline;
 use format::pointer::Pointer;
@@ -42,10 +43,15 @@ pub use format::bms::types::{Key, MAXKEY};
 
 pub mod types;
 pub mod diag;
+pub mod catalog;
 pub mod encoding;
 pub mod preproc;
 pub mod parse;
 pub mod load;
+pub mod lint;
+pub mod cst;
+pub mod write;
+#[cfg(test)] pub mod testutil;
 
 /// Sound reference.
 #[deriving(PartialEq,Eq,Clone)]
@@ -80,6 +86,9 @@ impl fmt::Show for ImageRef {
 /// Default BPM. This value comes from the original BMS specification.
 pub const DEFAULT_BPM: BPM = BPM(130.0);
 
+/// The largest width or height of the BGA canvas accepted from #SNRS:CANVASSIZE command.
+pub const MAX_CANVAS_SIZE: int = 4096;
+
 /// Play mode specified in the BMS file. This maps to BMS #PLAYER command. Over the course of
 /// the evolution of the BMS format, this value became highly ambiguous and the client is advised
 /// not to solely rely on this value.
@@ -103,6 +112,74 @@ pub enum PlayMode {
     Battle = 4,
 }
 
+/// Transformations applied to the sound resource at the loading time.
+/// Maps to BMS #WAVCMD and #EXWAV commands.
+#[deriving(PartialEq,Clone,Show)]
+pub struct SoundTransform {
+    /// The sampling rate in Hz at which the sound should be played, overriding the sampling rate
+    /// of the sound file itself. Maps to the `f` field of BMS #EXWAV command. Ignored when
+    /// the sampling rate of the sound file is unknown.
+    pub frequency: Option<f64>,
+    /// The pitch change in semitones, applied after `frequency`. Maps to BMS #WAVCMD 00 command,
+    /// where 60 is the original pitch.
+    pub pitch: int,
+    /// The volume multiplier. Maps to BMS #WAVCMD 01 command or the `v` field of BMS #EXWAV
+    /// command, whichever comes last.
+    pub volume: f64,
+    /// The stereo balance from -1.0 (left only) to 1.0 (right only). The other channel is
+    /// attenuated by up to 100 dB. Maps to the `p` field of BMS #EXWAV command.
+    pub pan: f64,
+    /// The maximum duration in seconds if any. Maps to BMS #WAVCMD 02 command.
+    pub duration: Option<f64>,
+    /// The position in seconds where the playback starts, applied before `frequency`.
+    /// Used for slicing the sound in bmson sound channels.
+    pub offset: f64,
+}
+
+impl SoundTransform {
+    /// Returns a transformation which does not change the sound.
+    pub fn identity() -> SoundTransform {
+        SoundTransform { frequency: None, pitch: 0, volume: 1.0, pan: 0.0, duration: None,
+                         offset: 0.0 }
+    }
+
+    /// Returns true if the transformation does not change the sound.
+    pub fn is_identity(&self) -> bool {
+        *self == SoundTransform::identity()
+    }
+}
+
+/// A switch BGA, an animation displayed while the player presses the associated lane.
+/// Maps to BMS #SWBGA command.
+#[deriving(Clone)]
+pub struct SwitchBGA {
+    /// The lane which triggers the animation.
+    pub lane: Lane,
+    /// The duration of each frame in milliseconds.
+    pub frametime: uint,
+    /// The duration of the animation in milliseconds after the press, or `None` if the animation
+    /// is displayed as long as the lane is pressed.
+    pub duration: Option<uint>,
+    /// True if the animation repeats. Otherwise the last frame is kept until the animation ends.
+    pub repeat: bool,
+    /// The RGB color key applied to every frame.
+    pub colorkey: (u8,u8,u8),
+    /// Image references for each frame.
+    pub frames: Vec<ImageRef>,
+}
+
+impl SwitchBGA {
+    /// Returns the frame displayed at `elapsed` milliseconds after the press if any.
+    pub fn frame_at(&self, elapsed: uint) -> Option<ImageRef> {
+        if self.frames.is_empty() { return None; }
+        if self.duration.map_or(false, |duration| elapsed >= duration) { return None; }
+        let index = elapsed / self.frametime;
+        let index = if self.repeat {index % self.frames.len()}
+                    else {cmp::min(index, self.frames.len() - 1)};
+        Some(self.frames[index].clone())
+    }
+}
+
 /// Loaded BMS metadata and resources.
 pub struct BmsMeta {
     /// Common metadata.
@@ -121,13 +198,71 @@ pub struct BmsMeta {
 
     /// Game mode. Maps to BMS #PLAYER command.
     pub mode: PlayMode,
+    /// Lanes declared by the chart, as a list of channels and key kind specifications (e.g.
+    /// `a` for `WhiteKey`). Maps to BMS #SNRS:LANES command (experimental). Empty if not declared.
+    pub lanes: Vec<(Key, String)>,
     /// Gauge difficulty. Higher is easier. Maps to BMS #RANK command.
     pub rank: int,
+    /// The size of grading area in percents of the normal size, overriding `rank` if any.
+    /// Maps to BMS #DEFEXRANK command.
+    pub defexrank: Option<int>,
+    /// The total gauge recovery in percents, when every note and LN is graded at least GREAT.
+    /// Maps to BMS #TOTAL command. The game play defaults to the value derived from
+    /// the number of notes when it is missing.
+    pub total: Option<f64>,
+
+    /// The global volume multiplier for every sound. Maps to BMS #VOLWAV command.
+    pub volume: f64,
 
     /// Paths to sound file relative to `basepath` or BMS file.
     pub sndpath: Vec<Option<String>>,
+    /// Transformations applied to sound files.
+    pub sndtransforms: Vec<SoundTransform>,
     /// Paths to image/movie file relative to `basepath` or BMS file.
     pub imgpath: Vec<Option<String>>,
+    /// RGB color keys for images, applied in addition to the color key of the BGA layer.
+    /// Maps to the color given in BMS #EXBMP command; the alpha component is ignored.
+    pub imgcolorkeys: Vec<Option<(u8,u8,u8)>>,
+    /// The width and height of the BGA canvas in pixels. Image slices are clipped to this size,
+    /// and the canvas is scaled to the BGA area during the game play. Maps to BMS
+    /// #SNRS:CANVASSIZE command (experimental), which defaults to 256 by 256 pixels and is
+    /// limited to `MAX_CANVAS_SIZE` pixels in each dimension.
+    pub canvassize: (uint, uint),
+    /// Texts displayed during the game play. Maps to BMS #TEXT and #SONG commands.
+    pub texts: Vec<Option<String>>,
+    /// Switch BGAs. Maps to BMS #SWBGA command.
+    pub swbgas: Vec<Option<SwitchBGA>>,
+}
+
+/// Converts #RANK value to the scale factor for grading area (`SetGradeFactor`).
+/// #RANK 2 (NORMAL) corresponds to the factor of 1.0, and higher #RANK means larger grading area.
+pub fn rank_to_gradefactor(rank: int) -> f64 {
+    1.5 - cmp::min(rank, 5) as f64 * 0.25
+}
+
+/// Converts #DEFEXRANK or #EXRANKxx value, which is the size of grading area in percents of
+/// the normal size, to the scale factor for grading area (`SetGradeFactor`).
+pub fn exrank_to_gradefactor(exrank: int) -> f64 {
+    assert!(exrank > 0);
+    100.0 / exrank as f64
+}
+
+/// Returns the default total gauge recovery in percents for given number of notes, used when
+/// the chart doesn't have #TOTAL. Sparse charts recover faster per note, but the total recovery
+/// never goes below 260% so that they are not too hard to survive.
+pub fn default_total(nnotes: uint) -> f64 {
+    let nnotes = nnotes as f64;
+    (7.605 * nnotes / (0.01 * nnotes + 6.5)).max(260.0)
+}
+
+impl BmsMeta {
+    /// Returns the initial scale factor for grading area.
+    pub fn gradefactor(&self) -> f64 {
+        match self.defexrank {
+            Some(exrank) => exrank_to_gradefactor(exrank),
+            None => rank_to_gradefactor(self.rank),
+        }
+    }
 }
 
 /// Timeline for the BMS file.
@@ -169,3 +304,59 @@ impl Bms {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use format::obj::Lane;
+    use super::{Key, ImageRef, SwitchBGA, default_total};
+
+    fn swbga(duration: Option<uint>, repeat: bool) -> SwitchBGA {
+        SwitchBGA { lane: Lane(1), frametime: 100, duration: duration, repeat: repeat,
+                    colorkey: (0,0,0), frames: vec![ImageRef(Key(1)), ImageRef(Key(2))] }
+    }
+
+    #[test]
+    fn test_frame_at() {
+        let once = swbga(None, false);
+        assert_eq!(once.frame_at(0), Some(ImageRef(Key(1))));
+        assert_eq!(once.frame_at(99), Some(ImageRef(Key(1))));
+        assert_eq!(once.frame_at(100), Some(ImageRef(Key(2))));
+        assert_eq!(once.frame_at(10000), Some(ImageRef(Key(2)))); // the last frame is kept
+    }
+
+    #[test]
+    fn test_frame_at_repeat() {
+        let looping = swbga(None, true);
+        assert_eq!(looping.frame_at(150), Some(ImageRef(Key(2))));
+        assert_eq!(looping.frame_at(200), Some(ImageRef(Key(1))));
+        assert_eq!(looping.frame_at(10150), Some(ImageRef(Key(2))));
+    }
+
+    #[test]
+    fn test_frame_at_duration() {
+        let fixed = swbga(Some(250), true);
+        assert_eq!(fixed.frame_at(249), Some(ImageRef(Key(1))));
+        assert_eq!(fixed.frame_at(250), None);
+        assert_eq!(swbga(Some(250), false).frame_at(1000), None);
+    }
+
+    #[test]
+    fn test_frame_at_no_frames() {
+        let mut empty = swbga(None, true);
+        empty.frames.clear();
+        assert_eq!(empty.frame_at(0), None);
+    }
+
+    #[test]
+    fn test_default_total() {
+        // sparse charts hit the floor
+        assert_eq!(default_total(0), 260.0);
+        assert_eq!(default_total(100), 260.0);
+        assert_eq!(default_total(337), 260.0);
+        assert!(default_total(338) > 260.0);
+
+        // 7.605 * 1000 / (0.01 * 1000 + 6.5) = 460.9...
+        assert!((default_total(1000) - 7605.0 / 16.5).abs() < 1e-9);
+        assert!(default_total(2000) > default_total(1000));
+    }
+}
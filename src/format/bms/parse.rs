@@ -5,6 +5,7 @@
 //! BMS parser.
 
 use std::{str, iter, f64, fmt};
+use std::num::Float;
 // ugh, we cannot immediately migrate into CowString as it doesn't implement Clone! (#19359)
 use std::str::{MaybeOwned, IntoMaybeOwned};
 use std::rand::Rng;
@@ -17,7 +18,7 @@ use format::bms::diag;
 use format::bms::diag::BmsMessage;
 pub use format::bms::preproc::BmsFlow;
 use format::bms::preproc::Preprocessor;
-use format::bms::encoding::{decode_stream, guess_decode_stream};
+use format::bms::encoding::{ChardetModels, decode_stream, guess_decode_stream};
 
 /// A tuple of four `u8` values. Mainly used for BMS #ARGB command and its family.
 pub type ARGB = (u8,u8,u8,u8);
@@ -78,7 +79,7 @@ pub enum BmsCommand<'r> {
     RANK(int),                              // #RANK
     DEFEXRANK(int),                         // #DEFEXRANK
     EXRANK(Key, int),                       // #EXRANK
-    TOTAL(int),                             // #TOTAL
+    TOTAL(f64),                             // #TOTAL
     LNTYPE(int),                            // #LNTYPE
     LNOBJ(Key),                             // #LNOBJ
     WAV(Key, MaybeOwned<'r>),               // #WAV
@@ -212,7 +213,7 @@ impl<'r> fmt::Show for BmsCommand<'r> {
             BmsCommand::LNTYPE(lntype) => write!(f, "#LNTYPE {}", lntype),
             BmsCommand::LNOBJ(key) => write!(f, "#LNOBJ {}", key),
             BmsCommand::WAV(key, ref s) => write!(f, "#WAV{} {}", key, *s),
-            BmsCommand::WAVCMD(cmd, key, v) => write!(f, "#WAVCMD {:02} {}{}", cmd, key, v),
+            BmsCommand::WAVCMD(cmd, key, v) => write!(f, "#WAVCMD {:02} {} {}", cmd, key, v),
             BmsCommand::EXWAV(_key, None, None, None, ref _s) => panic!("unsupported"),
             BmsCommand::EXWAV(key, pan, vol, freq, ref s) => {
                 let mut flags = String::new();
@@ -250,10 +251,10 @@ impl<'r> fmt::Show for BmsCommand<'r> {
             BmsCommand::MOVIE(ref s) => write!(f, "#MOVIE {}", *s),
             BmsCommand::CANVASSIZE(w, h) => write!(f, "#SNRS:CANVASSIZE {} {}", w, h),
             BmsCommand::STOP(key, Duration::Measures(dur)) =>
-                write!(f, "#STOP{} {}", key, (dur * 192.0) as int),
+                write!(f, "#STOP{} {}", key, (dur * 192.0).round() as int),
             BmsCommand::STOP(..) => panic!("unsupported"),
             BmsCommand::STP(pos, Duration::Seconds(dur)) =>
-                write!(f, "#STP {:07.3} {}", pos, (dur * 1000.0) as int),
+                write!(f, "#STP{:07.3} {}", pos, (dur * 1000.0).round() as int),
             BmsCommand::STP(..) => panic!("unsupported"),
             BmsCommand::TEXT(key, ref s) => write!(f, "#TEXT{} {}", key, *s),
             BmsCommand::OPTION(ref opt) => write!(f, "#OPTION {}", *opt),
@@ -318,17 +319,31 @@ impl<'r> Parsed<'r> {
 }
 
 /// Parser options for BMS format.
+#[deriving(Clone)]
 pub struct ParserOptions {
     /// Enables a parsing of several obviously mistyped commands. (Default: true)
     pub autofix_commands: bool,
     /// Disables an automatic encoding detection and forces the use of given encoding.
     pub force_encoding: Option<EncodingRef>,
+    /// Classifiers used for an automatic encoding detection. (Default: built-in classifiers)
+    pub chardet: ChardetModels,
 }
 
 impl ParserOptions {
     /// Returns default parser options.
     pub fn new() -> ParserOptions {
-        ParserOptions { autofix_commands: true, force_encoding: None }
+        ParserOptions { autofix_commands: true, force_encoding: None,
+                        chardet: ChardetModels::builtin() }
+    }
+}
+
+/// Reads the whole stream with the encoding forced by parser options, or the encoding guessed
+/// by their classifiers. Returns the encoding and confidence in addition to the decoded string.
+/// Other text-based chart formats also use this, as they share the same encoding issues.
+pub fn decode_with_options(f: &mut Reader, opts: &ParserOptions) -> (String, EncodingRef, f64) {
+    match opts.force_encoding {
+        Some(enc) => (decode_stream(f, enc), enc, f64::INFINITY),
+        None => guess_decode_stream(f, &opts.chardet),
     }
 }
 
@@ -374,10 +389,7 @@ pub struct ParsingIterator<'r> {
 impl<'r> Parser<'r> {
     /// Creates a new parser that returns all BMS commands including flow commands.
     pub fn new(f: &'r mut Reader, opts: &'r ParserOptions) -> Parser<'r> {
-        let (file, encoding, confidence) = match opts.force_encoding {
-            Some(enc) => (decode_stream(f, enc), enc, f64::INFINITY),
-            None => guess_decode_stream(f),
-        };
+        let (file, encoding, confidence) = decode_with_options(f, opts);
         Parser { opts: opts, file: file, encoding: (encoding.name(), confidence) }
     }
 
@@ -389,6 +401,24 @@ impl<'r> Parser<'r> {
     }
 }
 
+/// Parses a single line of BMS file, which should not contain `\n`. Returns a parsed command
+/// if any and parser messages for that line. The flow commands are returned as is.
+pub fn parse_line<'r>(line: &'r str,
+                      opts: &'r ParserOptions) -> (Option<BmsCommand<'r>>, Vec<BmsMessage>) {
+    let iter = ParsingIterator { iter: line.split('\u000a'), lineno: 0, opts: opts,
+                                 queued: Vec::new() };
+    let mut command = None;
+    let mut messages = Vec::new();
+    for parsed in iter {
+        match parsed {
+            Parsed::Command(_, cmd) => { command = Some(cmd); }
+            Parsed::Message(_, msg) => { messages.push(msg); }
+            Parsed::Encoding(..) => {}
+        }
+    }
+    (command, messages)
+}
+
 impl<'r> Iterator<Parsed<'r>> for ParsingIterator<'r> {
     fn next(&mut self) -> Option<Parsed<'r>> {
         use std::ascii::AsciiExt;
@@ -588,9 +618,15 @@ impl<'r> Iterator<Parsed<'r>> for ParsingIterator<'r> {
             if_prefix!("DIFFICULTY" value -> BmsCommand::DIFFICULTY)
             if_prefix!("RANK"       value -> BmsCommand::RANK)
             if_prefix!("DEFEXRANK"  value -> BmsCommand::DEFEXRANK)
-            if_prefix!("TOTAL"      value -> BmsCommand::TOTAL)
             if_prefix!("LNTYPE"     value -> BmsCommand::LNTYPE)
 
+            if_prefix!("TOTAL" |line| { // #TOTAL <float>
+                let mut total = 0.0;
+                if lex!(line; ws, f64 -> total) {
+                    emit!(BmsCommand::TOTAL(total));
+                }
+            })
+
             if_prefix!("LNOBJ" |line| { // #LNOBJ <key>
                 let mut key = PartialKey::dummy();
                 if lex!(line; ws, PartialKey -> key) {
@@ -843,6 +879,8 @@ pub struct PreprocessingParser<'r, R:'r> {
     parser: Parser<'r>,
     /// The random number generator.
     r: &'r mut R,
+    /// Values forced to #RANDOM and #SWITCH commands. See `Preprocessor::force_choices`.
+    choices: Vec<int>,
 }
 
 /**
@@ -870,16 +908,39 @@ impl<'r,R:Rng> PreprocessingParser<'r,R> {
     /// Iterates over the parsed BMS commands, with flow commands have been preprocessed.
     pub fn new(f: &'r mut Reader, r: &'r mut R,
                opts: &'r ParserOptions) -> PreprocessingParser<'r,R> {
-        PreprocessingParser { parser: Parser::new(f, opts), r: r }
+        PreprocessingParser::with_choices(f, r, opts, &[])
+    }
+
+    /// Same as `new`, but the values of #RANDOM and #SWITCH commands are taken from `choices`
+    /// in the order of appearance until they are exhausted.
+    pub fn with_choices(f: &'r mut Reader, r: &'r mut R, opts: &'r ParserOptions,
+                        choices: &[int]) -> PreprocessingParser<'r,R> {
+        PreprocessingParser { parser: Parser::new(f, opts), r: r, choices: choices.to_vec() }
     }
 
     /// Returns a parsing iterator over this BMS file.
     pub fn iter<'a>(&'a mut self) -> PreprocessingParsingIterator<'a,R> {
-        PreprocessingParsingIterator { pp: Preprocessor::new(self.r), iter: self.parser.iter(),
+        let mut pp = Preprocessor::new(self.r);
+        pp.force_choices(self.choices[]);
+        PreprocessingParsingIterator { pp: pp, iter: self.parser.iter(),
                                        done: false, queued: Vec::new() }
     }
 }
 
+impl<'r,R:Rng> PreprocessingParsingIterator<'r,R> {
+    /// Returns true if any command read so far depends on randomly chosen values.
+    /// See `Preprocessor::is_random`.
+    pub fn is_random(&self) -> bool {
+        self.pp.is_random()
+    }
+
+    /// Returns the values chosen for #RANDOM and #SWITCH commands so far.
+    /// See `Preprocessor::choices`.
+    pub fn choices<'a>(&'a self) -> &'a [(int,int)] {
+        self.pp.choices()
+    }
+}
+
 impl<'r,R:Rng> Iterator<Parsed<'static>> for PreprocessingParsingIterator<'r,R> {
     fn next(&mut self) -> Option<Parsed<'static>> {
         loop {
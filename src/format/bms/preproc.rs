@@ -4,10 +4,9 @@
 
 //! BMS preprocessor.
 
-use std::fmt;
+use std::{fmt, cmp};
 use std::rand::Rng;
 
-use format::bms::diag;
 use format::bms::diag::BmsMessage;
 
 /// Represents one line of BMS command that may affect the control flow.
@@ -49,6 +48,15 @@ impl fmt::Show for BmsFlow {
     }
 }
 
+/// The kind of the block.
+#[deriving(PartialEq,Eq)]
+enum BlockKind {
+    /// Started with #RANDOM or #SETRANDOM, and contains #IF blocks.
+    Random,
+    /// Started with #SWITCH or #SETSWITCH, and contains #CASE and #DEF clauses.
+    Switch,
+}
+
 /// The state of the block, for determining which lines should be processed.
 #[deriving(PartialEq,Eq)]
 enum BlockState {
@@ -57,7 +65,8 @@ enum BlockState {
     /// Active.
     Process,
     /// Inactive, but (for the purpose of #IF/#ELSEIF/#ELSE/#ENDIF structure) can move to
-    /// `Process` state when matching clause appears.
+    /// `Process` state when matching clause appears. For #SWITCH blocks this means that
+    /// no #CASE clause has matched yet.
     Ignore,
     /// Inactive and won't be processed until the end of block.
     NoFurther
@@ -78,22 +87,31 @@ impl BlockState {
  * Block information. The parser keeps a list of nested blocks and determines if
  * a particular line should be processed or not.
  *
- * Sonorous actually recognizes only one kind of blocks, starting with #RANDOM or
- * #SETRANDOM and ending with #ENDRANDOM or #END(IF) outside an #IF block. An #IF block is
+ * Sonorous recognizes two kinds of blocks. The first kind starts with #RANDOM or
+ * #SETRANDOM and ends with #ENDRANDOM or #END(IF) outside an #IF block. An #IF block is
  * a state within #RANDOM, so it follows that #RANDOM/#SETRANDOM blocks can nest but #IF
- * can't nest unless its direct parent is #RANDOM/#SETRANDOM.
+ * can't nest unless its direct parent is #RANDOM/#SETRANDOM. The second kind starts with
+ * #SWITCH or #SETSWITCH and ends with #ENDSW, and its state is driven by #CASE, #SKIP and
+ * #DEF. An #IF block directly inside the #SWITCH block gets an implicit #RANDOM block
+ * which shares the value of the nearest #RANDOM block.
  */
 #[deriving(PartialEq,Eq)]
 struct Block {
+    /// The kind of the block.
+    kind: BlockKind,
     /// A generated value if any. It can be `None` if this block is the topmost one (which
     /// is actually not a block but rather a sentinel) or the last `#RANDOM` or `#SETRANDOM`
     /// command was invalid, and #IF in that case will always evaluates to false.
+    /// The same goes for #SWITCH blocks and #CASE.
     val: Option<int>,
     /// The state of the block.
     state: BlockState,
     /// True if the parent block is already ignored so that this block should be ignored
     /// no matter what `state` is.
-    skip: bool
+    skip: bool,
+    /// True if `val` has been chosen from two or more possible values, so that the lines
+    /// processed in this block may change on the next run.
+    random: bool,
 }
 
 /// A generic BMS preprocessor. `T` is normally a BMS command, but there is no restriction.
@@ -102,13 +120,51 @@ pub struct Preprocessor<'r, T, R:'r> {
     blocks: Vec<Block>,
     /// Random number generator.
     r: &'r mut R,
+    /// Values to be used in place of generated values, in the order of #RANDOM and #SWITCH.
+    forced: Vec<int>,
+    /// Values chosen for #RANDOM and #SWITCH so far, paired with their maximum values.
+    choices: Vec<(int,int)>,
+    /// True if any command has appeared in the random block.
+    random: bool,
 }
 
 impl<'r,T:Send+Clone,R:Rng> Preprocessor<'r,T,R> {
     /// Creates a new preprocessor with given RNG.
     pub fn new(r: &'r mut R) -> Preprocessor<'r,T,R> {
-        let blocks = vec![Block { val: None, state: BlockState::Outside, skip: false }];
-        Preprocessor { blocks: blocks, r: r }
+        let blocks = vec![Block { kind: BlockKind::Random, val: None,
+                                  state: BlockState::Outside, skip: false, random: false }];
+        Preprocessor { blocks: blocks, r: r, forced: Vec::new(), choices: Vec::new(),
+                       random: false }
+    }
+
+    /// Forces the values of subsequent #RANDOM and #SWITCH commands in the order of appearance.
+    /// Each value is clamped to the valid range, and the RNG is used once they are exhausted.
+    pub fn force_choices(&mut self, choices: &[int]) {
+        self.forced.push_all(choices);
+    }
+
+    /// Returns the values chosen for #RANDOM and #SWITCH commands (except for those skipped)
+    /// so far, paired with their maximum values. Giving these values to `force_choices`
+    /// reproduces the same result.
+    pub fn choices<'a>(&'a self) -> &'a [(int,int)] {
+        self.choices[]
+    }
+
+    /// Returns true if any command so far has appeared in #RANDOM or #SWITCH blocks whose
+    /// value was randomly chosen, i.e. the result may differ on the next run. Commands in
+    /// the inactive branches count as well.
+    pub fn is_random(&self) -> bool {
+        self.random
+    }
+
+    /// Chooses a value between 1 and `max` (inclusive) for #RANDOM or #SWITCH command.
+    fn choose(&mut self, max: int) -> int {
+        let val = match self.forced.remove(0) {
+            Some(val) => cmp::max(1, cmp::min(val, max)),
+            None => self.r.gen_range(1, max + 1),
+        };
+        self.choices.push((val, max));
+        val
     }
 
     /// Returns true if any command which appears at this position should be ignored.
@@ -117,10 +173,32 @@ impl<'r,T:Send+Clone,R:Rng> Preprocessor<'r,T,R> {
         last.skip || last.state.inactive()
     }
 
+    /// Returns the index to the innermost #SWITCH block if any.
+    fn innermost_switch(&self) -> Option<uint> {
+        self.blocks.iter().rposition(|b| b.kind == BlockKind::Switch)
+    }
+
+    /// Makes sure that the innermost block is a #RANDOM block, so that #IF and related
+    /// commands can be applied to it. `inactive` should be the result of `self.inactive()`.
+    fn ensure_random_block(&mut self, inactive: bool) {
+        if self.blocks.last().unwrap().kind == BlockKind::Switch {
+            let (val, random) =
+                match self.blocks.iter().rev().find(|b| b.kind == BlockKind::Random) {
+                    Some(b) => (b.val, b.random),
+                    None => (None, false),
+                };
+            self.blocks.push(Block { kind: BlockKind::Random, val: val,
+                                     state: BlockState::Outside, skip: inactive, random: random });
+        }
+    }
+
     /// Adds the non-flow command (or any appropriate data) into the preprocessor.
     /// `messages` will have zero or more messages inserted.
     /// `result` will have zero or more preprocessed commands (or any appropriate data) inserted.
     pub fn feed_other(&mut self, cmd: T, _messages: &mut Vec<BmsMessage>, result: &mut Vec<T>) {
+        if !self.random {
+            self.random = self.blocks.iter().any(|b| b.random && b.state != BlockState::Outside);
+        }
         if !self.inactive() {
             result.push(cmd);
         }
@@ -130,12 +208,18 @@ impl<'r,T:Send+Clone,R:Rng> Preprocessor<'r,T,R> {
     /// `messages` will have zero or more messages inserted.
     /// `result` will have zero or more preprocessed commands (or any appropriate data) inserted.
     pub fn feed_flow(&mut self, _lineno: Option<uint>, flow: &BmsFlow,
-                     messages: &mut Vec<BmsMessage>, _result: &mut Vec<T>) {
+                     _messages: &mut Vec<BmsMessage>, _result: &mut Vec<T>) {
         let inactive = self.inactive();
         match *flow {
-            BmsFlow::RANDOM(val) | BmsFlow::SETRANDOM(val) => {
+            BmsFlow::RANDOM(val) | BmsFlow::SETRANDOM(val) |
+            BmsFlow::SWITCH(val) | BmsFlow::SETSWITCH(val) => {
                 let val = if val <= 0 {None} else {Some(val)};
-                let setrandom = match *flow { BmsFlow::SETRANDOM(..) => true, _ => false };
+                let (kind, setrandom) = match *flow {
+                    BmsFlow::RANDOM(..) => (BlockKind::Random, false),
+                    BmsFlow::SETRANDOM(..) => (BlockKind::Random, true),
+                    BmsFlow::SWITCH(..) => (BlockKind::Switch, false),
+                    _ => (BlockKind::Switch, true),
+                };
 
                 // do not generate a random value if the entire block is skipped (but it
                 // still marks the start of block)
@@ -143,21 +227,31 @@ impl<'r,T:Send+Clone,R:Rng> Preprocessor<'r,T,R> {
                     if setrandom {
                         Some(val)
                     } else if !inactive {
-                        Some(self.r.gen_range(1, val + 1))
+                        Some(self.choose(val))
                     } else {
                         None
                     }
                 });
-                self.blocks.push(Block { val: generated, state: BlockState::Outside,
-                                         skip: inactive });
+
+                // lines before the first #CASE or #DEF are never processed
+                let state = match kind {
+                    BlockKind::Random => BlockState::Outside,
+                    BlockKind::Switch => BlockState::Ignore,
+                };
+                let random = !setrandom && generated.is_some() && val != Some(1);
+                self.blocks.push(Block { kind: kind, val: generated, state: state,
+                                         skip: inactive, random: random });
             }
             BmsFlow::ENDRANDOM => {
-                if self.blocks.len() > 1 { self.blocks.pop(); }
+                if self.blocks.len() > 1 && self.blocks.last().unwrap().kind == BlockKind::Random {
+                    self.blocks.pop();
+                }
             }
             BmsFlow::IF(val) | BmsFlow::ELSEIF(val) => {
                 let val = if val <= 0 {None} else {Some(val)};
                 let haspriorelse = match *flow { BmsFlow::ELSEIF(..) => true, _ => false };
 
+                self.ensure_random_block(inactive);
                 let last = self.blocks.last_mut().unwrap();
                 last.state =
                     if (!haspriorelse && !last.state.inactive()) ||
@@ -169,21 +263,52 @@ impl<'r,T:Send+Clone,R:Rng> Preprocessor<'r,T,R> {
                     };
             }
             BmsFlow::ELSE => {
+                self.ensure_random_block(inactive);
                 let last = self.blocks.last_mut().unwrap();
                 last.state = if last.state == BlockState::Ignore {BlockState::Process}
                              else {BlockState::NoFurther};
             }
             BmsFlow::ENDIF => {
-                for &idx in self.blocks.iter()
-                                       .rposition(|&i| i.state != BlockState::Outside).iter() {
-                    if idx > 0 { self.blocks.truncate(idx + 1); }
+                // #ENDIF never closes the enclosing #SWITCH block
+                let base = self.innermost_switch().map_or(0, |idx| idx + 1);
+                for &idx in self.blocks[base..].iter()
+                                               .rposition(|&i| i.state != BlockState::Outside)
+                                               .iter() {
+                    if base + idx > 0 { self.blocks.truncate(base + idx + 1); }
                 }
 
-                self.blocks.last_mut().unwrap().state = BlockState::Outside;
+                let last = self.blocks.last_mut().unwrap();
+                if last.kind == BlockKind::Random {
+                    last.state = BlockState::Outside;
+                }
             }
-            BmsFlow::SWITCH(..) | BmsFlow::SETSWITCH(..) | BmsFlow::ENDSW |
-            BmsFlow::CASE(..) | BmsFlow::SKIP | BmsFlow::DEF => {
-                messages.push(diag::BmsHasUnimplementedFlow);
+            BmsFlow::CASE(..) | BmsFlow::SKIP | BmsFlow::DEF | BmsFlow::ENDSW => {
+                // ignore stray commands outside the #SWITCH block
+                let idx = match self.innermost_switch() {
+                    Some(idx) => idx,
+                    None => return,
+                };
+
+                // any unclosed block inside the current clause is implicitly closed
+                if *flow == BmsFlow::ENDSW {
+                    self.blocks.truncate(idx);
+                    return;
+                }
+                self.blocks.truncate(idx + 1);
+
+                let last = self.blocks.last_mut().unwrap();
+                last.state = match (flow, last.state) {
+                    // once #SKIP is seen, the remaining clauses are never processed
+                    (_, BlockState::NoFurther) => BlockState::NoFurther,
+                    (&BmsFlow::SKIP, BlockState::Process) => BlockState::NoFurther,
+                    (&BmsFlow::SKIP, state) => state,
+                    // otherwise the prior clause falls through the current clause
+                    (_, BlockState::Process) => BlockState::Process,
+                    (&BmsFlow::CASE(val), _) =>
+                        if val > 0 && Some(val) == last.val {BlockState::Process}
+                        else {BlockState::Ignore},
+                    (_, _) => BlockState::Process, // #DEF without any prior matching #CASE
+                };
             }
         }
     }
@@ -199,6 +324,9 @@ impl<'r,T:Send+Clone,R:Rng> Preprocessor<'r,T,R> {
 mod tests {
     use std::rand::task_rng;
     use super::Preprocessor;
+    use super::BmsFlow;
+    use super::BmsFlow::{RANDOM, SETRANDOM, ENDRANDOM, IF, ELSE, ENDIF,
+                         SWITCH, SETSWITCH, ENDSW, CASE, SKIP, DEF};
 
     macro_rules! with_pp(
         (|$pp:ident| $blk:expr) => ({
@@ -224,6 +352,167 @@ mod tests {
         })
     }
 
-    // TODO add more tests
+    /// Feeds the commands (`Ok`) and flow commands (`Err`) to the fresh preprocessor,
+    /// and returns the preprocessed commands.
+    fn preprocess(cmds: &[Result<uint,BmsFlow>]) -> Vec<uint> {
+        let mut out = Vec::new();
+        with_pp!(|pp| {
+            let mut messages = Vec::new();
+            for cmd in cmds.iter() {
+                match *cmd {
+                    Ok(cmd) => pp.feed_other(cmd, &mut messages, &mut out),
+                    Err(ref flow) => pp.feed_flow(None, flow, &mut messages, &mut out),
+                }
+            }
+            pp.finish(&mut messages, &mut out);
+            assert!(messages[] == []);
+        });
+        out
+    }
+
+    #[test]
+    fn test_switch() {
+        let cmds = [Err(SETSWITCH(2)), Ok(0u),
+                    Err(CASE(1)), Ok(1), Err(SKIP),
+                    Err(CASE(2)), Ok(2), Err(SKIP),
+                    Err(CASE(3)), Ok(3), Err(SKIP),
+                    Err(ENDSW), Ok(4)];
+        assert!(preprocess(&cmds)[] == [2, 4]);
+
+        let cmds = [Err(SWITCH(1)), Err(CASE(1)), Ok(1u), Err(ENDSW)];
+        assert!(preprocess(&cmds)[] == [1]);
+    }
+
+    #[test]
+    fn test_switch_fall_through() {
+        let cmds = [Err(SETSWITCH(2)),
+                    Err(CASE(1)), Ok(1u),
+                    Err(CASE(2)), Ok(2),
+                    Err(CASE(3)), Ok(3), Err(SKIP),
+                    Err(CASE(4)), Ok(4),
+                    Err(ENDSW)];
+        assert!(preprocess(&cmds)[] == [2, 3]);
+    }
+
+    #[test]
+    fn test_switch_def() {
+        let cmds = |val| [Err(SETSWITCH(val)),
+                          Err(CASE(1)), Ok(1u), Err(SKIP),
+                          Err(CASE(2)), Ok(2), Err(SKIP),
+                          Err(DEF), Ok(3), Err(SKIP),
+                          Err(ENDSW)];
+        assert!(preprocess(&cmds(2))[] == [2]);
+        assert!(preprocess(&cmds(5))[] == [3]);
+        assert!(preprocess(&cmds(0))[] == [3]);
+    }
+
+    #[test]
+    fn test_switch_nested() {
+        let cmds = [Err(SETSWITCH(1)),
+                    Err(CASE(1)),
+                        Err(SETSWITCH(2)),
+                        Err(CASE(1)), Ok(1u), Err(SKIP),
+                        Err(CASE(2)), Ok(2), Err(SKIP),
+                        Err(ENDSW),
+                        Ok(3), Err(SKIP),
+                    Err(CASE(2)), Ok(4),
+                    Err(ENDSW)];
+        assert!(preprocess(&cmds)[] == [2, 3]);
+
+        // the unclosed #RANDOM block is implicitly closed by #SKIP
+        let cmds = [Err(SETSWITCH(1)),
+                    Err(CASE(1)),
+                        Err(SETRANDOM(1)),
+                        Err(IF(1)), Ok(1u),
+                    Err(SKIP),
+                    Err(CASE(2)), Ok(2),
+                    Err(ENDSW), Ok(3)];
+        assert!(preprocess(&cmds)[] == [1, 3]);
+    }
+
+    #[test]
+    fn test_switch_in_random() {
+        let cmds = |val| [Err(SETRANDOM(val)),
+                          Err(IF(1)),
+                              Err(SETSWITCH(2)),
+                              Err(CASE(2)), Ok(1u), Err(SKIP),
+                              Err(ENDSW),
+                              Ok(2),
+                          Err(ELSE), Ok(3),
+                          Err(ENDIF), Ok(4)];
+        assert!(preprocess(&cmds(1))[] == [1, 2, 4]);
+        assert!(preprocess(&cmds(2))[] == [3, 4]);
+    }
+
+    #[test]
+    fn test_if_in_switch() {
+        let cmds = |val| [Err(SETRANDOM(val)),
+                          Err(SETSWITCH(1)),
+                          Err(CASE(1)),
+                              Err(IF(1)), Ok(1u), Err(ELSE), Ok(2), Err(ENDIF),
+                              Ok(3), Err(SKIP),
+                          Err(CASE(2)),
+                              Err(IF(1)), Ok(4), Err(ENDIF),
+                          Err(ENDSW), Ok(5)];
+        assert!(preprocess(&cmds(1))[] == [1, 3, 5]);
+        assert!(preprocess(&cmds(2))[] == [2, 3, 5]);
+    }
+
+    /// Same as `preprocess` but forces given choices, and also returns the chosen values and
+    /// whether the result was random.
+    fn preprocess_with(cmds: &[Result<uint,BmsFlow>],
+                       choices: &[int]) -> (Vec<uint>, Vec<(int,int)>, bool) {
+        let mut out = Vec::new();
+        let mut chosen = Vec::new();
+        let mut random = false;
+        with_pp!(|pp| {
+            let mut messages = Vec::new();
+            pp.force_choices(choices);
+            for cmd in cmds.iter() {
+                match *cmd {
+                    Ok(cmd) => pp.feed_other(cmd, &mut messages, &mut out),
+                    Err(ref flow) => pp.feed_flow(None, flow, &mut messages, &mut out),
+                }
+            }
+            pp.finish(&mut messages, &mut out);
+            chosen = pp.choices().to_vec();
+            random = pp.is_random();
+        });
+        (out, chosen, random)
+    }
+
+    #[test]
+    fn test_forced_choices() {
+        let cmds = [Err(RANDOM(3)),
+                    Err(IF(1)), Ok(1u), Err(ENDIF),
+                    Err(IF(2)),
+                        Err(SWITCH(2)),
+                        Err(CASE(1)), Ok(2), Err(SKIP),
+                        Err(CASE(2)), Ok(3), Err(SKIP),
+                        Err(ENDSW),
+                    Err(ENDIF),
+                    Err(IF(3)), Ok(4), Err(ENDIF),
+                    Err(ENDRANDOM), Ok(5)];
+        assert!(preprocess_with(&cmds, &[1]) == (vec![1, 5], vec![(1, 3)], true));
+        assert!(preprocess_with(&cmds, &[2, 2]) == (vec![3, 5], vec![(2, 3), (2, 2)], true));
+        assert!(preprocess_with(&cmds, &[7]).val0() == vec![4, 5]); // clamped to 3
+    }
+
+    #[test]
+    fn test_random_flag() {
+        // outside the random block
+        let cmds = [Ok(1u), Err(RANDOM(2)), Err(ENDRANDOM), Ok(2)];
+        assert!(!preprocess_with(&cmds, &[]).val2());
+
+        // the value is fixed
+        let cmds = [Err(SETRANDOM(2)), Err(IF(1)), Ok(1u), Err(ENDIF), Err(ENDRANDOM)];
+        assert!(!preprocess_with(&cmds, &[]).val2());
+        let cmds = [Err(RANDOM(1)), Err(IF(1)), Ok(1u), Err(ENDIF), Err(ENDRANDOM)];
+        assert!(!preprocess_with(&cmds, &[]).val2());
+
+        // the command is random even when it is not processed
+        let cmds = [Err(RANDOM(2)), Err(IF(2)), Ok(1u), Err(ENDIF), Err(ENDRANDOM)];
+        assert!(preprocess_with(&cmds, &[1]) == (vec![], vec![(1, 2)], true));
+    }
 }
 
@@ -0,0 +1,27 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+//! Helpers shared by the tests of the BMS loader and writer and other chart loaders.
+
+use std::io::BufReader;
+use std::rand::task_rng;
+use format::obj::{Lane, ObjData};
+use format::bms::{Key, ImageRef, SoundRef, Bms};
+use format::bms::load::{load_bms, LoaderOptions};
+
+/// Loads the BMS file from given source with the default options, ignoring any diagnostics.
+pub fn load(source: &str) -> Bms {
+    let mut f = BufReader::new(source.as_bytes());
+    load_bms(&mut f, &mut task_rng(), &LoaderOptions::new(), |_, _| true).unwrap()
+}
+
+/// Returns the virtual positions and data of every object in the timeline.
+pub fn objs(bms: &Bms) -> Vec<(f64, ObjData<SoundRef,ImageRef>)> {
+    bms.timeline.objs.iter().map(|obj| (obj.loc.vpos, obj.data.clone())).collect()
+}
+
+/// Returns the lane for given BMS channel, e.g. `11` for the first key.
+pub fn lane(chan: &str) -> Lane {
+    Key::from_str(chan).unwrap().to_lane()
+}
@@ -0,0 +1,747 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * BMS writer. Converts `format::bms::Bms` structure back to a list of BMS commands
+ * (`format::bms::parse::BmsCommand`), which can be read back by the BMS loader.
+ *
+ * The conversion is not entirely lossless. Object positions are quantized to at most
+ * `MAX_RESOLUTION` slots per measure, long notes are always written as #LNTYPE 1 endpoints,
+ * and objects which BMS cannot express (e.g. `Blank` BGAs, as an alphanumeric key `00` is never
+ * an object in most channels) are dropped. Notes without sounds refer to a reserved sound key
+ * without #WAV, which the loader reports as undefined when the output is read back but plays
+ * no sound as intended. The output is always encoded in UTF-8.
+ */
+
+use std::{io, cmp};
+use std::io::{IoResult, IoError};
+use std::num::Float;
+use std::str::{MaybeOwned, IntoMaybeOwned};
+
+use format::obj::{BPM, Duration, Damage, BGARef, BGALayer, SoundGroup, ImageSlice};
+use format::obj::ObjQueryOps;
+use format::obj::{Visible, Invisible, LNStart, LNDone, Bomb};
+use format::obj::{BGM, SetBGA, SetBGAOpacity, SetBGAColorKey, SetBPM, Stop};
+use format::obj::{SetSwitchBGA, SetVolume, SetText, SetMeasureFactor, SetGradeFactor};
+use format::metadata::Difficulty;
+use format::bms::parse::BmsCommand;
+use format::bms::types::{Key, MAXKEY};
+use format::bms::{ImageRef, SoundRef, Bms};
+
+/// The maximum number of slots in a single data line. Positions which cannot be exactly
+/// represented with fewer slots are rounded to this resolution.
+pub const MAX_RESOLUTION: uint = 960;
+
+/// The largest measure number representable in BMS.
+const MAX_MEASURE: uint = 999;
+
+/// Returns an I/O error for the data which cannot be represented in BMS.
+fn unrepresentable(desc: &'static str, detail: String) -> IoError {
+    IoError { kind: io::InvalidInput, desc: desc, detail: Some(detail) }
+}
+
+/// Converts the string to the form accepted by `BmsCommand`.
+fn owned(s: &String) -> MaybeOwned<'static> {
+    s.clone().into_maybe_owned()
+}
+
+/// Converts an integer from 0 to 255 to an alphanumeric key which reads as the same hexadecimal
+/// number. This is the reverse of `Key::to_hex`.
+fn hex_key(v: int) -> Key {
+    let v = cmp::max(cmp::min(v, 255), 0);
+    Key(v / 16 * 36 + v % 16)
+}
+
+/// An allocator for alphanumeric keys referring to the value tables (e.g. #BPMxx). Equal values
+/// share the same key.
+struct KeyTable<T> {
+    /// The name of the corresponding command, used for error messages.
+    name: &'static str,
+    /// Allocated keys and values in the order of allocation.
+    entries: Vec<(Key, T)>,
+    /// Remaining keys in the reverse order.
+    free: Vec<Key>,
+}
+
+impl<T:PartialEq> KeyTable<T> {
+    /// Creates a table which allocates keys from `01` to `ZZ`, except for `reserved` ones.
+    fn new(name: &'static str, reserved: |Key| -> bool) -> KeyTable<T> {
+        let mut free = Vec::new();
+        for i in range(1, MAXKEY).rev() {
+            if !reserved(Key(i)) { free.push(Key(i)); }
+        }
+        KeyTable { name: name, entries: Vec::new(), free: free }
+    }
+
+    /// Returns a key for given value, allocating a new key if required.
+    fn key_for(&mut self, value: T) -> IoResult<Key> {
+        for &(ref key, ref v) in self.entries.iter() {
+            if *v == value { return Ok(key.clone()); }
+        }
+        match self.free.pop() {
+            Some(key) => {
+                self.entries.push((key.clone(), value));
+                Ok(key)
+            }
+            None => Err(unrepresentable("too many distinct values",
+                                        format!("no more keys available for #{}", self.name))),
+        }
+    }
+}
+
+/// A non-00 alphanumeric key placed in the particular channel and measure.
+struct Placed {
+    measure: uint,
+    chan: Key,
+    /// The position inside the measure, from 0 (inclusive) to 1 (exclusive).
+    frac: f64,
+    key: Key,
+}
+
+/// Returns the smallest number of slots which can exactly represent every given position inside
+/// the measure, or `MAX_RESOLUTION` if there is no such number.
+fn resolution(fracs: &[f64]) -> uint {
+    for n in range(1, MAX_RESOLUTION) {
+        let n_ = n as f64;
+        if fracs.iter().all(|&frac| (frac * n_ - (frac * n_).round()).abs() < 1e-6) {
+            return n;
+        }
+    }
+    MAX_RESOLUTION
+}
+
+/// Renders non-00 alphanumeric keys in the same channel and measure to the data of one or more
+/// data lines. Keys at the same slot are spread to subsequent lines, so the loader reads them
+/// in the original order.
+fn render_data(objs: &[(f64, Key)]) -> Vec<String> {
+    let fracs: Vec<f64> = objs.iter().map(|&(frac, _)| frac).collect();
+    let n = resolution(fracs[]);
+
+    let mut rows: Vec<Vec<Key>> = Vec::new();
+    for &(frac, ref key) in objs.iter() {
+        let slot = cmp::min((frac * n as f64).round() as uint, n - 1);
+        match rows.iter().position(|row| row[slot] == Key(0)) {
+            Some(i) => {
+                let row = &mut rows[mut][i];
+                row[mut][slot] = key.clone();
+            }
+            None => {
+                let mut row = Vec::from_elem(n, Key(0));
+                row[mut][slot] = key.clone();
+                rows.push(row);
+            }
+        }
+    }
+
+    rows.iter().map(|row| {
+        let mut data = String::new();
+        for key in row.iter() {
+            data.push_str(format!("{}", *key)[]);
+        }
+        data
+    }).collect()
+}
+
+/// Returns true if 00 in given channel is a value rather than an absence of objects. These are
+/// the BGA opacity channels (#0B-#0E) and the volume channels (#97/98).
+fn zero_allowed(chan: Key) -> bool {
+    match *chan {
+        11...14 | 331 | 332 => true,
+        _ => false,
+    }
+}
+
+/// Renders alphanumeric keys in the channel where 00 is a value to the data of one data line.
+/// Since every slot sets the value, empty slots repeat the value in effect, starting from `*last`
+/// which is updated to the value at the end of the line. Of the keys at the same slot, only
+/// the last one is kept.
+fn render_values(objs: &[(f64, Key)], last: &mut Key) -> String {
+    let fracs: Vec<f64> = objs.iter().map(|&(frac, _)| frac).collect();
+    let n = resolution(fracs[]);
+
+    let mut slots = Vec::from_elem(n, None);
+    for &(frac, ref key) in objs.iter() {
+        let slot = cmp::min((frac * n as f64).round() as uint, n - 1);
+        slots[mut][slot] = Some(key.clone());
+    }
+
+    let mut data = String::new();
+    for slot in slots.into_iter() {
+        for key in slot.into_iter() { *last = key; }
+        data.push_str(format!("{}", *last)[]);
+    }
+    data
+}
+
+/// Converts the BMS data to a list of BMS commands. Headers come first, followed by resource
+/// definitions, value tables and data lines sorted by the measure.
+pub fn to_commands(bms: &Bms) -> IoResult<Vec<BmsCommand<'static>>> {
+    let meta = &bms.meta;
+    let timeline = &bms.timeline;
+    let mut cmds = Vec::new();
+
+    for s in meta.common.title.iter() { cmds.push(BmsCommand::TITLE(owned(s))); }
+    for s in meta.common.subtitles.iter() { cmds.push(BmsCommand::SUBTITLE(owned(s))); }
+    for s in meta.common.genre.iter() { cmds.push(BmsCommand::GENRE(owned(s))); }
+    for s in meta.common.artist.iter() { cmds.push(BmsCommand::ARTIST(owned(s))); }
+    for s in meta.common.subartists.iter() { cmds.push(BmsCommand::SUBARTIST(owned(s))); }
+    for s in meta.common.comments.iter() { cmds.push(BmsCommand::COMMENT(owned(s))); }
+    for s in meta.stagefile.iter() { cmds.push(BmsCommand::STAGEFILE(owned(s))); }
+    for s in meta.banner.iter() { cmds.push(BmsCommand::BANNER(owned(s))); }
+
+    cmds.push(BmsCommand::PLAYER(meta.mode as int));
+    if !meta.lanes.is_empty() {
+        cmds.push(BmsCommand::LANES(meta.lanes.clone()));
+    }
+    if meta.canvassize != (256, 256) {
+        let (width, height) = meta.canvassize;
+        cmds.push(BmsCommand::CANVASSIZE(width as int, height as int));
+    }
+    for level in meta.common.level.iter() { cmds.push(BmsCommand::PLAYLEVEL(level.value)); }
+    for &Difficulty(v) in meta.common.difficulty.iter() { cmds.push(BmsCommand::DIFFICULTY(v)); }
+    cmds.push(BmsCommand::RANK(meta.rank));
+    for &exrank in meta.defexrank.iter() { cmds.push(BmsCommand::DEFEXRANK(exrank)); }
+    for &total in meta.total.iter() { cmds.push(BmsCommand::TOTAL(total)); }
+    if meta.volume != 1.0 {
+        cmds.push(BmsCommand::VOLWAV((meta.volume * 100.0).round() as int));
+    }
+    cmds.push(BmsCommand::BPM(timeline.initbpm));
+    cmds.push(BmsCommand::LNTYPE(1));
+
+    // ----8<----
+
+    for (i, path) in meta.sndpath.iter().enumerate() {
+        let path = match *path { Some(ref path) => path, None => continue };
+        let key = Key(i as int);
+        if !key.is_valid() {
+            return Err(unrepresentable("too many sounds", format!("no key for sound #{}", i)));
+        }
+
+        let transform = &meta.sndtransforms[i];
+        if transform.frequency.is_some() || transform.pan != 0.0 {
+            // pan and volume are in 1/100 dB (as like DirectSound), frequency is in Hz
+            let pan = (transform.pan * 10000.0).round() as int;
+            let vol = if transform.volume > 0.0 {
+                (transform.volume.log10() * 2000.0).round() as int
+            } else {
+                -10000
+            };
+            let freq = transform.frequency.map(|freq| freq.round() as int);
+            cmds.push(BmsCommand::EXWAV(key, Some(cmp::max(cmp::min(pan, 10000), -10000)),
+                                        Some(cmp::max(cmp::min(vol, 0), -10000)), freq,
+                                        owned(path)));
+        } else {
+            cmds.push(BmsCommand::WAV(key, owned(path)));
+            if transform.volume != 1.0 {
+                let vol = (transform.volume * 100.0).round() as int;
+                cmds.push(BmsCommand::WAVCMD(1, key, cmp::max(cmp::min(vol, 100), 0)));
+            }
+        }
+        if transform.pitch != 0 {
+            cmds.push(BmsCommand::WAVCMD(0, key, transform.pitch + 60));
+        }
+        for &duration in transform.duration.iter() {
+            cmds.push(BmsCommand::WAVCMD(2, key, (duration * 1000.0).round() as int));
+        }
+    }
+
+    // notes without sounds use a reserved sound key without #WAV. BMS has no other way to
+    // express them, so the loader reports the key as undefined when the output is read back.
+    let silent = if timeline.objs.iter().any(|obj| {
+        obj.is_soundable() && obj.sounds().iter().all(|&SoundRef(key)| *key == 0)
+    }) {
+        let silent = range(1, MAXKEY).rev().map(Key).find(|key| {
+            let i = **key as uint;
+            i >= meta.sndpath.len() || meta.sndpath[i].is_none()
+        });
+        if silent.is_none() {
+            return Err(unrepresentable("too many sounds",
+                                       format!("no key left for notes without sounds")));
+        }
+        silent
+    } else {
+        None
+    };
+
+    // image keys used as is, which the keys for #BGA should avoid.
+    let mut imgused = Vec::from_elem(MAXKEY as uint, false);
+    for (i, path) in meta.imgpath.iter().enumerate() {
+        let path = match *path { Some(ref path) => path, None => continue };
+        let key = Key(i as int);
+        if !key.is_valid() {
+            return Err(unrepresentable("too many images", format!("no key for image #{}", i)));
+        }
+        imgused[mut][i] = true;
+        match meta.imgcolorkeys[i] {
+            Some((r,g,b)) => { cmds.push(BmsCommand::EXBMP(key, (255,r,g,b), owned(path))); }
+            None => { cmds.push(BmsCommand::BMP(key, owned(path))); }
+        }
+    }
+
+    for (i, text) in meta.texts.iter().enumerate() {
+        for s in text.iter() { cmds.push(BmsCommand::TEXT(Key(i as int), owned(s))); }
+    }
+
+    for (i, swbga) in meta.swbgas.iter().enumerate() {
+        for swbga in swbga.iter() {
+            let mut pattern = String::new();
+            for &ImageRef(ref key) in swbga.frames.iter() {
+                if key.is_valid() { imgused[mut][**key as uint] = true; }
+                pattern.push_str(format!("{}", *key)[]);
+            }
+            let (r,g,b) = swbga.colorkey;
+            cmds.push(BmsCommand::SWBGA(Key(i as int), swbga.frametime as int,
+                                        swbga.duration.unwrap_or(0) as int,
+                                        Key(36 + *swbga.lane as int), swbga.repeat,
+                                        (255,r,g,b), pattern.into_maybe_owned()));
+        }
+    }
+
+    for obj in timeline.objs.iter() {
+        match obj.data {
+            SetBGA(_, BGARef::Image(ImageRef(key))) if key.is_valid() => {
+                imgused[mut][*key as uint] = true;
+            }
+            _ => {}
+        }
+    }
+
+    // ----8<----
+
+    // discontinuities for vpos-pos relation, as a list of the virtual position, the actual
+    // position and the measure scaling factor.
+    let mut factors = vec![(0.0, 0.0, 1.0)];
+    for obj in timeline.objs.iter() {
+        match obj.data {
+            SetMeasureFactor(factor) => { factors.push((obj.loc.vpos, obj.loc.pos, factor)); }
+            _ => {}
+        }
+    }
+    let factor_at = |vpos: f64| -> (f64, f64, f64) {
+        *factors.iter().rev().find(|&&(vpos0, _, _)| vpos0 <= vpos).unwrap()
+    };
+
+    // BMS can only change the measure length at the measure boundary. if the measure scaling
+    // factor changes in the middle of the measure, the measure gets the average length instead,
+    // and objects are placed according to their actual positions.
+    // the end of the timeline is normally past every object, but loaders other than BMS may
+    // place objects at or beyond it. objects past the last representable measure are rejected
+    // later.
+    let endvpos = timeline.end().vpos;
+    let nmeasures = timeline.objs.iter().fold(endvpos.ceil() as uint, |n, obj| {
+        cmp::max(n, obj.loc.vpos.floor() as uint + 1)
+    });
+    let nmeasures = cmp::min(nmeasures, MAX_MEASURE + 1);
+    let mut measures = Vec::new(); // (start pos, length, uniform?)
+    for measure in range(0, nmeasures) {
+        let start = measure as f64;
+        let (vpos0, pos0, factor) = factor_at(start);
+        let startpos = (start - vpos0) * factor + pos0;
+        let uniform = !factors.iter().any(|&(vpos, _, _)| start < vpos && vpos < start + 1.0);
+        let len = if uniform {
+            factor
+        } else {
+            let (vpos1, pos1, factor1) = factor_at(start + 1.0);
+            (start + 1.0 - vpos1) * factor1 + pos1 - startpos
+        };
+        measures.push((startpos, len, uniform));
+    }
+
+    let mut placed = Vec::new();
+    let mut stps = Vec::new();
+    let mut bpmtab = KeyTable::new("BPM", |_| false);
+    let mut stoptab = KeyTable::new("STOP", |_| false);
+    let mut exranktab = KeyTable::new("EXRANK", |_| false);
+    let mut argbtab = KeyTable::new("ARGB", |_| false);
+    let mut bgatab = KeyTable::new("BGA", |key| imgused[*key as uint]);
+
+    let sound_key = |sref: &Option<SoundRef>| -> Key {
+        match *sref {
+            Some(SoundRef(key)) if *key != 0 => key,
+            _ => silent.unwrap(),
+        }
+    };
+
+    for obj in timeline.objs.iter() {
+        if obj.is_end() { continue; }
+
+        let measure = obj.loc.vpos.floor() as uint;
+        if measure > MAX_MEASURE {
+            return Err(unrepresentable("too many measures",
+                                       format!("{} at measure {}", obj.data, measure)));
+        }
+        let (startpos, len, uniform) = measures[measure];
+        let frac = if uniform || len <= 0.0 {
+            obj.loc.vpos - measure as f64
+        } else {
+            (obj.loc.pos - startpos) / len
+        };
+        let frac = if frac < 0.0 {0.0} else if frac > 1.0 {1.0} else {frac};
+
+        let (chan, key) = match obj.data {
+            Visible(lane, ref sref) => (36 + *lane as int, sound_key(sref)),
+            Invisible(lane, ref sref) => (108 + *lane as int, sound_key(sref)),
+            LNStart(lane, ref sref) | LNDone(lane, ref sref) =>
+                (180 + *lane as int, sound_key(sref)),
+            Bomb(lane, _, damage) => {
+                let key = match damage {
+                    Damage::Gauge(ratio) =>
+                        Key(cmp::max(cmp::min((ratio * 200.0).round() as int, 200), 1)),
+                    Damage::InstantDeath => Key(MAXKEY - 1),
+                };
+                (468 + *lane as int, key)
+            }
+            BGM(SoundRef(key)) => (1, key),
+            SetBGA(layer, ref bgaref) => {
+                let chan = match layer {
+                    BGALayer::Layer1 => 4,
+                    BGALayer::Layer2 => 7,
+                    BGALayer::Layer3 => 10,
+                    BGALayer::PoorBGA => 6,
+                    BGALayer::Switch => continue,
+                };
+                let key = match *bgaref {
+                    BGARef::Blank => continue,
+                    BGARef::Image(ImageRef(key)) => key,
+                    BGARef::SlicedImage(ImageRef(key), ref slice) => {
+                        let slice: ImageSlice = (**slice).clone();
+                        try!(bgatab.key_for((key, slice)))
+                    }
+                };
+                (chan, key)
+            }
+            SetBGAOpacity(layer, opacity) => {
+                let chan = match layer {
+                    BGALayer::Layer1 => 11,
+                    BGALayer::Layer2 => 12,
+                    BGALayer::Layer3 => 13,
+                    BGALayer::PoorBGA => 14,
+                    BGALayer::Switch => continue,
+                };
+                (chan, hex_key(opacity as int))
+            }
+            SetBGAColorKey(layer, Some(colorkey)) => {
+                let chan = match layer {
+                    BGALayer::Layer1 => 361,
+                    BGALayer::Layer2 => 362,
+                    BGALayer::Layer3 => 363,
+                    BGALayer::PoorBGA => 364,
+                    BGALayer::Switch => continue,
+                };
+                (chan, try!(argbtab.key_for(colorkey)))
+            }
+            SetSwitchBGA(i) => (365, Key(i as int)),
+            SetVolume(group, volume) => {
+                let chan = match group {
+                    SoundGroup::Background => 331,
+                    SoundGroup::KeySound => 332,
+                };
+                (chan, hex_key((volume * 255.0).round() as int))
+            }
+            SetText(i) => (333, Key(i as int)),
+            SetBPM(BPM(bpm)) => {
+                if bpm == bpm.floor() && 1.0 <= bpm && bpm <= 255.0 {
+                    (3, hex_key(bpm as int))
+                } else {
+                    (8, try!(bpmtab.key_for(bpm)))
+                }
+            }
+            Stop(Duration::Measures(dur)) => {
+                let dur = (dur * 192.0).round() as int;
+                if dur <= 0 { continue; }
+                (9, try!(stoptab.key_for(dur)))
+            }
+            Stop(Duration::Seconds(dur)) => {
+                // #STP has a resolution of 1/1000 measure
+                let pos = measure as f64 + (frac * 1000.0).round() / 1000.0;
+                stps.push(BmsCommand::STP(pos, Duration::Seconds(dur)));
+                continue;
+            }
+            SetGradeFactor(factor) => {
+                let exrank = (100.0 / factor).round() as int;
+                if exrank <= 0 { continue; }
+                (360, try!(exranktab.key_for(exrank)))
+            }
+            _ => continue,
+        };
+
+        if *key == 0 && !zero_allowed(Key(chan)) { continue; } // 00 is never an object
+        if !key.is_valid() {
+            return Err(unrepresentable("alphanumeric key out of range",
+                                       format!("{} at measure {}", obj.data, measure)));
+        }
+        placed.push(Placed { measure: measure, chan: Key(chan), frac: frac, key: key });
+    }
+
+    // ----8<----
+
+    for &(ref key, bpm) in bpmtab.entries.iter() {
+        cmds.push(BmsCommand::EXBPM(key.clone(), BPM(bpm)));
+    }
+    for &(ref key, dur) in stoptab.entries.iter() {
+        cmds.push(BmsCommand::STOP(key.clone(), Duration::Measures(dur as f64 / 192.0)));
+    }
+    for &(ref key, exrank) in exranktab.entries.iter() {
+        cmds.push(BmsCommand::EXRANK(key.clone(), exrank));
+    }
+    for &(ref key, (r,g,b)) in argbtab.entries.iter() {
+        cmds.push(BmsCommand::ARGB(key.clone(), (255,r,g,b)));
+    }
+    for &(ref key, (ref src, ref slice)) in bgatab.entries.iter() {
+        cmds.push(BmsCommand::BGA(key.clone(), src.clone(), slice.clone()));
+    }
+    cmds.extend(stps.into_iter());
+
+    // the loader sorts data lines with a stable algorithm, so lines for the same channel should
+    // retain the order of objects.
+    placed.sort_by(|a, b| (a.measure, a.chan).cmp(&(b.measure, b.chan)));
+    let mut lastvalues = Vec::from_elem(MAXKEY as uint, hex_key(255)); // full opacity and volume
+    let mut i = 0;
+    for measure in range(0, nmeasures) {
+        let (_, len, _) = measures[measure];
+        if len > 0.0 && len != 1.0 {
+            cmds.push(BmsCommand::Shorten(measure, len));
+        }
+        while i < placed.len() && placed[i].measure == measure {
+            let chan = placed[i].chan.clone();
+            let mut j = i;
+            while j < placed.len() && placed[j].measure == measure && placed[j].chan == chan {
+                j += 1;
+            }
+            let objs: Vec<(f64, Key)> =
+                placed[i..j].iter().map(|p| (p.frac, p.key.clone())).collect();
+            let rows = if zero_allowed(chan.clone()) {
+                vec![render_values(objs[], &mut lastvalues[mut][*chan as uint])]
+            } else {
+                render_data(objs[])
+            };
+            for data in rows.into_iter() {
+                cmds.push(BmsCommand::Data(measure, chan.clone(), data.into_maybe_owned()));
+            }
+            i = j;
+        }
+    }
+
+    Ok(cmds)
+}
+
+/// Writes the BMS data to given writer as a BMS file. See `to_commands` for the details.
+pub fn write_bms(bms: &Bms, w: &mut Writer) -> IoResult<()> {
+    for cmd in try!(to_commands(bms)).iter() {
+        try!(writeln!(w, "{}", *cmd));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, MemWriter};
+    use std::rand::task_rng;
+    use format::obj::{Lane, ObjData, BGALayer, BPM};
+    use format::obj::{Visible, Invisible, LNStart, LNDone, SetBGAOpacity, SetBPM};
+    use format::timeline::modf;
+    use format::bms::{ImageRef, SoundRef, Bms};
+    use format::bms::diag;
+    use format::bms::diag::BmsMessage;
+    use format::bms::load::{load_bms, LoaderOptions};
+    use format::bms::testutil::{load, objs};
+    use super::write_bms;
+
+    /// Loads the written BMS data and returns it with every diagnostic message tied to a line.
+    fn reload(source: &str) -> (Bms, Vec<BmsMessage>) {
+        let mut messages = Vec::new();
+        let bms = load_bms(&mut BufReader::new(source.as_bytes()), &mut task_rng(),
+                           &LoaderOptions::new(), |line, msg| {
+            if line.is_some() { messages.push(msg); }
+            true
+        }).unwrap();
+        (bms, messages)
+    }
+
+    fn write(bms: &Bms) -> String {
+        let mut out = MemWriter::new();
+        write_bms(bms, &mut out).unwrap();
+        String::from_utf8(out.into_inner()).unwrap()
+    }
+
+    /// Removes references to sounds without #WAV from the note. These play no sound, and
+    /// the writer uses one of them for notes without sounds.
+    fn strip_undefined(bms: &Bms, data: ObjData<SoundRef,ImageRef>) -> ObjData<SoundRef,ImageRef> {
+        let defined = |sref: Option<SoundRef>| -> Option<SoundRef> {
+            sref.and_then(|SoundRef(key)| {
+                let i = *key as uint;
+                if i < bms.meta.sndpath.len() && bms.meta.sndpath[i].is_some() {
+                    Some(SoundRef(key))
+                } else {
+                    None
+                }
+            })
+        };
+        match data {
+            Visible(lane, sref) => Visible(lane, defined(sref)),
+            Invisible(lane, sref) => Invisible(lane, defined(sref)),
+            LNStart(lane, sref) => LNStart(lane, defined(sref)),
+            LNDone(lane, sref) => LNDone(lane, defined(sref)),
+            data => data,
+        }
+    }
+
+    /// Returns the objects like `objs`, but without references to undefined sounds.
+    fn audible_objs(bms: &Bms) -> Vec<(f64, ObjData<SoundRef,ImageRef>)> {
+        objs(bms).into_iter().map(|(vpos, data)| (vpos, strip_undefined(bms, data))).collect()
+    }
+
+    /// Checks if the BMS data written and loaded again has the same timeline as the original,
+    /// and returns the number of undefined sound keys reported during the reload.
+    fn assert_round_trip(bms: &Bms) -> uint {
+        let (reloaded, messages) = reload(write(bms)[]);
+        assert!(!messages.contains(&diag::BmsHasUnusedWAV));
+        assert_eq!(reloaded.timeline.initbpm, bms.timeline.initbpm);
+        assert_eq!(audible_objs(&reloaded), audible_objs(bms));
+        messages.iter().filter(|&msg| *msg == diag::BmsHasUndefinedWAV).count()
+    }
+
+    #[test]
+    fn test_notes_and_bgms() {
+        let bms = load("#TITLE Round Trip\n\
+                        #ARTIST Sonorous\n\
+                        #GENRE Test\n\
+                        #TOTAL 250.5\n\
+                        #WAV01 a.wav\n\
+                        #WAV02 b.wav\n\
+                        #WAV03 c.wav\n\
+                        #00101:0102\n\
+                        #00101:03\n\
+                        #00111:01000200\n\
+                        #00112:000000000003000000000000\n\
+                        #00215:010203\n\
+                        #00236:0303\n\
+                        #00316:0101000002020000\n");
+        assert_eq!(assert_round_trip(&bms), 0);
+
+        let (reloaded, _) = reload(write(&bms)[]);
+        assert_eq!(reloaded.meta.common.title, bms.meta.common.title);
+        assert_eq!(reloaded.meta.common.artist, bms.meta.common.artist);
+        assert_eq!(reloaded.meta.common.genre, bms.meta.common.genre);
+        assert_eq!(reloaded.meta.total, Some(250.5));
+        assert_eq!(reloaded.meta.sndpath, bms.meta.sndpath);
+    }
+
+    #[test]
+    fn test_timing() {
+        let bms = load("#BPM 120\n\
+                        #BPM01 150.5\n\
+                        #STOP01 96\n\
+                        #STP003.500 1500\n\
+                        #WAV01 a.wav\n\
+                        #00103:78\n\
+                        #00108:0001\n\
+                        #00109:000001\n\
+                        #00111:01010101\n\
+                        #00202:0.75\n\
+                        #00211:010101\n\
+                        #00302:1.5\n\
+                        #00311:010000000001\n");
+        assert_eq!(assert_round_trip(&bms), 0);
+    }
+
+    #[test]
+    fn test_long_notes_and_effects() {
+        let bms = load("#LNOBJ ZZ\n\
+                        #WAV01 a.wav\n\
+                        #WAVZZ z.wav\n\
+                        #BMP01 bg.png\n\
+                        #BMP02 key.png\n\
+                        #BGA03 02 0 0 64 64 10 20\n\
+                        #TEXT01 hello\n\
+                        #EXRANK01 50\n\
+                        #ARGB01 255,0,0,0\n\
+                        #00111:0100ZZ00\n\
+                        #00152:00010001\n\
+                        #001D3:0AZZ\n\
+                        #00104:01000300\n\
+                        #00106:02\n\
+                        #0010B:80\n\
+                        #00197:7F\n\
+                        #00199:01\n\
+                        #001A0:0001\n\
+                        #001A1:01\n");
+        assert_eq!(assert_round_trip(&bms), 0);
+    }
+
+    #[test]
+    fn test_zero_opacity_and_volume() {
+        let bms = load("#0010B:00\n#00198:0080\n");
+        let written = write(&bms);
+        assert!(written[].contains("#0010B:00\n"));
+        assert!(written[].contains("#00198:0080\n"));
+        assert_eq!(assert_round_trip(&bms), 0);
+    }
+
+    #[test]
+    fn test_opacity_gaps() {
+        // an empty slot repeats the value in effect, as 00 would make the layer transparent
+        let mut bms = load("#WAV01 a.wav\n#00111:01\n");
+        let mut obj = bms.timeline.objs[0].clone();
+        obj.loc.vpos = 1.5;
+        obj.loc.pos = 1.5;
+        obj.data = SetBGAOpacity(BGALayer::Layer1, 0x80);
+        bms.timeline.objs.insert(1, obj);
+        assert!(write(&bms)[].contains("#0010B:FF80\n"));
+    }
+
+    #[test]
+    fn test_undefined_lnobj() {
+        // #LNOBJ is not written, so the end of LN refers to an undefined sound when reloaded.
+        let bms = load("#LNOBJ ZZ\n\
+                        #WAV01 a.wav\n\
+                        #00111:0100ZZ00\n");
+        assert_eq!(assert_round_trip(&bms), 1);
+    }
+
+    #[test]
+    fn test_notes_without_sounds() {
+        let mut bms = load("#WAV01 a.wav\n\
+                            #00111:0102\n\
+                            #00132:02\n\
+                            #00152:02000200\n");
+        let stripped: Vec<ObjData<SoundRef,ImageRef>> =
+            bms.timeline.objs.iter().map(|obj| strip_undefined(&bms, obj.data.clone())).collect();
+        for (obj, data) in bms.timeline.objs.iter_mut().zip(stripped.into_iter()) {
+            obj.data = data;
+        }
+        assert!(bms.timeline.objs.iter().any(|obj| obj.data == Visible(Lane(1), None)));
+
+        // the reserved key for notes without sounds is reported once, but plays no sound.
+        assert_eq!(assert_round_trip(&bms), 1);
+    }
+
+    #[test]
+    fn test_objects_past_the_end() {
+        // other loaders may place objects at the end of the timeline.
+        let mut bms = load("#WAV01 a.wav\n#00111:01\n");
+        let end = bms.timeline.end().vpos;
+        let mut obj = bms.timeline.objs[0].clone();
+        obj.loc.vpos = end;
+        obj.loc.pos = end;
+        obj.data = SetBPM(BPM(150.0));
+        bms.timeline.objs.insert(bms.timeline.objs.len() - 1, obj);
+        let written = write(&bms);
+        assert!(written[].contains(format!("#{:03}03:96", end as uint)[]));
+    }
+
+    #[test]
+    fn test_mirror() {
+        let lanes = [Lane(1), Lane(2), Lane(3), Lane(4), Lane(5)];
+        let mut bms = load("#WAV01 a.wav\n\
+                            #WAV02 b.wav\n\
+                            #WAV03 c.wav\n\
+                            #WAV04 d.wav\n\
+                            #00111:01020304\n\
+                            #00113:0000000001\n\
+                            #00152:0101\n");
+        modf::mirror(&mut bms.timeline, lanes[]);
+        assert_eq!(assert_round_trip(&bms), 0);
+    }
+}
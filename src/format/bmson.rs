@@ -0,0 +1,604 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * bmson format implementation.
+ *
+ * bmson is a JSON-based successor of the BMS format. Unlike BMS, every object is positioned by
+ * an integral number of pulses (`y`), where `info.resolution` pulses make one beat and four beats
+ * make one measure. Every sound file is a "sound channel" which notes refer to, and a note can
+ * either restart the sound or continue the playback from the position the previous note in
+ * the same channel left off; this allows a single sound file to be sliced over many notes.
+ * A new note in the channel always stops the playback of the previous note.
+ *
+ * The loader produces the same `format::bms::Bms` structure as the BMS loader, so that the rest
+ * of Sonorous can treat bmson charts as BMS charts. A sound channel without continued notes
+ * becomes a single sound resource, and each slice of other channels becomes a separate sound
+ * resource with the appropriate `SoundTransform` as long as the alphanumeric key range allows.
+ */
+
+use std::num::Float;
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::Json;
+
+use format::obj::{Lane, BPM, Duration, BGARef, BGALayer};
+use format::obj::{Visible, LNStart, LNDone, BGM, SetBGA, SetBPM, Stop, MeasureBar};
+use format::metadata::{Level, LevelSystem, Meta};
+use format::bms::{Key, MAXKEY, ImageRef, SoundRef, DEFAULT_BPM, SoundTransform, PlayMode};
+use format::bms::{BmsMeta, Bms, default_total};
+
+/// The default number of pulses per beat.
+pub const DEFAULT_RESOLUTION: uint = 240;
+
+/// Known values of `info.mode_hint`. Each entry has a play mode, BMS channels for lanes `x`
+/// starting from 1 (`00` for unused lanes) and the lane declaration in the format of
+/// BMS #SNRS:LANES command. Unknown mode hints are treated as `beat-7k`.
+static MODE_HINTS: &'static [(&'static str, PlayMode, &'static str, &'static str)] = &[
+    ("beat-5k", PlayMode::Single,
+     "11 12 13 14 15 00 00 16",
+     "16s 11a 12b 13a 14b 15a"),
+    ("beat-7k", PlayMode::Single,
+     "11 12 13 14 15 18 19 16",
+     "16s 11a 12b 13a 14b 15a 18b 19a"),
+    ("beat-10k", PlayMode::Double,
+     "11 12 13 14 15 00 00 16 21 22 23 24 25 00 00 26",
+     "16s 11a 12b 13a 14b 15a 21a 22b 23a 24b 25a 26s"),
+    ("beat-14k", PlayMode::Double,
+     "11 12 13 14 15 18 19 16 21 22 23 24 25 28 29 26",
+     "16s 11a 12b 13a 14b 15a 18b 19a 21a 22b 23a 24b 25a 28b 29a 26s"),
+    ("popn-5k", PlayMode::Single,
+     "13 14 15 22 23",
+     "13e 14r 15t 22r 23e"),
+    ("popn-9k", PlayMode::Single,
+     "11 12 13 14 15 22 23 24 25",
+     "11q 12w 13e 14r 15t 22r 23e 24w 25q"),
+];
+
+/// A JSON object.
+type Object = TreeMap<String,Json>;
+
+/// Extracts a number from `json` if possible.
+fn num(json: &Json) -> Option<f64> {
+    match *json {
+        Json::I64(v) => Some(v as f64),
+        Json::U64(v) => Some(v as f64),
+        Json::F64(v) => Some(v),
+        _ => None,
+    }
+}
+
+/// Converts `json` to an object. `what` is used for the error message.
+fn into_object(json: Json, what: &str) -> Result<Object,String> {
+    match json {
+        Json::Object(map) => Ok(map),
+        _ => Err(format!("{} should be an object", what)),
+    }
+}
+
+/// Removes a numeric field from the object if any.
+fn take_num(map: &mut Object, key: &str) -> Result<Option<f64>,String> {
+    match map.remove(&key.to_string()) {
+        None | Some(Json::Null) => Ok(None),
+        Some(v) => match num(&v) {
+            Some(v) => Ok(Some(v)),
+            None => Err(format!("`{}` should be a number", key)),
+        },
+    }
+}
+
+/// Removes a numeric field representing a non-negative number of pulses from the object if any.
+fn take_pulses(map: &mut Object, key: &str) -> Result<Option<uint>,String> {
+    match try!(take_num(map, key)) {
+        Some(v) if v < 0.0 => Err(format!("`{}` should not be negative", key)),
+        v => Ok(v.map(|v| v as uint)),
+    }
+}
+
+/// Removes a string field from the object if any.
+fn take_str(map: &mut Object, key: &str) -> Result<Option<String>,String> {
+    match map.remove(&key.to_string()) {
+        None | Some(Json::Null) => Ok(None),
+        Some(Json::String(s)) => Ok(Some(s)),
+        Some(_) => Err(format!("`{}` should be a string", key)),
+    }
+}
+
+/// Removes a boolean field from the object if any.
+fn take_bool(map: &mut Object, key: &str) -> Result<Option<bool>,String> {
+    match map.remove(&key.to_string()) {
+        None | Some(Json::Null) => Ok(None),
+        Some(Json::Boolean(b)) => Ok(Some(b)),
+        Some(_) => Err(format!("`{}` should be a boolean", key)),
+    }
+}
+
+/// Removes an array field from the object. A missing field is treated as an empty array.
+fn take_array(map: &mut Object, key: &str) -> Result<Vec<Json>,String> {
+    match map.remove(&key.to_string()) {
+        None | Some(Json::Null) => Ok(Vec::new()),
+        Some(Json::Array(l)) => Ok(l),
+        Some(_) => Err(format!("`{}` should be an array", key)),
+    }
+}
+
+/// Removes an object field from the object. A missing field is treated as an empty object.
+fn take_object(map: &mut Object, key: &str) -> Result<Object,String> {
+    match map.remove(&key.to_string()) {
+        None | Some(Json::Null) => Ok(TreeMap::new()),
+        Some(v) => into_object(v, format!("`{}`", key)[]),
+    }
+}
+
+/// Converts a list of `{"y": ..., <key>: ...}` objects to a list of pairs sorted by the position.
+fn take_events(map: &mut Object, listkey: &str, key: &str) -> Result<Vec<(uint, f64)>,String> {
+    let mut events = Vec::new();
+    for ev in try!(take_array(map, listkey)).into_iter() {
+        let mut ev = try!(into_object(ev, format!("an element of `{}`", listkey)[]));
+        let y = try!(take_pulses(&mut ev, "y")).unwrap_or(0);
+        match try!(take_num(&mut ev, key)) {
+            Some(v) => { events.push((y, v)); }
+            None => { return Err(format!("an element of `{}` lacks `{}`", listkey, key)); }
+        }
+    }
+    events.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+    Ok(events)
+}
+
+/// Converts the position in pulses to the time in seconds. This is only used for slicing sound
+/// channels; the game play uses the timeline instead.
+struct Tempo {
+    /// The number of pulses per beat.
+    resolution: f64,
+    /// The initial BPM.
+    initbpm: f64,
+    /// BPM changes sorted by the position.
+    bpms: Vec<(uint, f64)>,
+    /// Scroll stoppers sorted by the position, with durations in pulses.
+    stops: Vec<(uint, f64)>,
+}
+
+impl Tempo {
+    /// Returns the BPM in effect at given position.
+    fn bpm_at(&self, y: uint) -> f64 {
+        let mut bpm = self.initbpm;
+        for &(by, v) in self.bpms.iter() {
+            if by > y { break; }
+            bpm = v;
+        }
+        bpm
+    }
+
+    /// Returns the time in seconds at given position. Scroll stoppers at the same position
+    /// are not counted, as the objects at the position are processed before stopping.
+    fn seconds_at(&self, y: uint) -> f64 {
+        let pulse_to_sec = |pulses: f64, bpm: f64| pulses * 60.0 / (bpm * self.resolution);
+        let mut t = 0.0;
+        let mut lasty = 0;
+        let mut bpm = self.initbpm;
+        for &(by, v) in self.bpms.iter() {
+            if by >= y { break; }
+            t += pulse_to_sec((by - lasty) as f64, bpm);
+            lasty = by;
+            bpm = v;
+        }
+        t += pulse_to_sec((y - lasty) as f64, bpm);
+        for &(sy, duration) in self.stops.iter() {
+            if sy >= y { break; }
+            t += pulse_to_sec(duration, self.bpm_at(sy));
+        }
+        t
+    }
+}
+
+/// Reads the bmson file from given reader.
+pub fn load_bmson(f: &mut Reader) -> Result<Bms,String> {
+    use format::timeline::builder::TimelineBuilder;
+
+    let root = match json::from_reader(f) {
+        Ok(json) => json,
+        Err(err) => { return Err(format!("{}", err)); }
+    };
+    let mut root = try!(into_object(root, "the bmson file"));
+    let mut info = try!(take_object(&mut root, "info"));
+
+    let title = try!(take_str(&mut info, "title"));
+    let mut subtitles = Vec::new();
+    for subtitle in try!(take_str(&mut info, "subtitle")).iter() {
+        subtitles.extend(subtitle[].lines().filter(|s| !s.is_empty()).map(|s| s.to_string()));
+    }
+    for chartname in try!(take_str(&mut info, "chart_name")).into_iter() {
+        if !chartname.is_empty() { subtitles.push(chartname); }
+    }
+    let artist = try!(take_str(&mut info, "artist"));
+    let mut subartists = Vec::new();
+    for subartist in try!(take_array(&mut info, "subartists")).into_iter() {
+        match subartist {
+            Json::String(s) => { subartists.push(s); }
+            _ => { return Err(format!("`subartists` should be an array of strings")); }
+        }
+    }
+    let genre = try!(take_str(&mut info, "genre"));
+    let level = try!(take_num(&mut info, "level"))
+                    .map(|v| Level { value: v as int, system: LevelSystem::Bms });
+    let stagefile = try!(take_str(&mut info, "eyecatch_image"));
+    let banner = try!(take_str(&mut info, "banner_image"));
+    // `judge_rank` rounding to zero or less and non-positive `total` are ignored,
+    // as the BMS loader does for #EXRANK and #TOTAL
+    let judgerank = try!(take_num(&mut info, "judge_rank")).unwrap_or(100.0).round();
+    let reltotal = try!(take_num(&mut info, "total")).unwrap_or(100.0);
+
+    let BPM(defaultbpm) = DEFAULT_BPM;
+    let initbpm = match try!(take_num(&mut info, "init_bpm")) {
+        Some(bpm) if bpm > 0.0 => bpm,
+        _ => defaultbpm,
+    };
+    let resolution = match try!(take_pulses(&mut info, "resolution")) {
+        Some(0) | None => DEFAULT_RESOLUTION,
+        Some(resolution) => resolution,
+    };
+    let pulse_to_vpos = |y: uint| y as f64 / (4 * resolution) as f64;
+
+    let modehint = try!(take_str(&mut info, "mode_hint")).unwrap_or("beat-7k".to_string());
+    let (_, mode, xchannels, lanedecl) =
+        MODE_HINTS.iter().find(|&&(name, _, _, _)| name == modehint[])
+                         .unwrap_or(&MODE_HINTS[1]).clone();
+    let xlanes: Vec<Option<Lane>> = xchannels.words().map(|chan| {
+        Key::from_str(chan).and_then(|chan| if *chan == 0 {None} else {Some(chan.to_lane())})
+    }).collect();
+    let lanes: Vec<(Key, String)> = lanedecl.words().map(|decl| {
+        (Key::from_str(decl).unwrap(), decl[2..].to_string())
+    }).collect();
+
+    let mut builder = TimelineBuilder::new();
+    builder.set_initbpm(BPM(initbpm));
+    let mut endy = 0;
+
+    // BPM changes and scroll stoppers
+    let bpms: Vec<(uint, f64)> = try!(take_events(&mut root, "bpm_events", "bpm"))
+                                     .into_iter().filter(|&(_, bpm)| bpm > 0.0).collect();
+    for &(y, bpm) in bpms.iter() {
+        builder.add(pulse_to_vpos(y), SetBPM(BPM(bpm)));
+        if endy < y { endy = y; }
+    }
+    let stops: Vec<(uint, f64)> = try!(take_events(&mut root, "stop_events", "duration"))
+                                      .into_iter().filter(|&(_, dur)| dur > 0.0).collect();
+    for &(y, duration) in stops.iter() {
+        let measures = duration / (4 * resolution) as f64;
+        builder.add(pulse_to_vpos(y), Stop(Duration::Measures(measures)));
+        if endy < y { endy = y; }
+    }
+    let tempo = Tempo { resolution: resolution as f64, initbpm: initbpm,
+                        bpms: bpms, stops: stops };
+
+    // sound channels. notes only restarting the playback share one sound resource per channel,
+    // as a new note stops the previous playback anyway. channels with continued notes are
+    // sliced so that every distinct position gets its own slice, as long as sound references
+    // fit in the alphanumeric key range (`00` is reserved); otherwise continued notes are left
+    // without sounds and the playback from the last restart simply continues.
+    let mut channels = Vec::new();
+    for chan in try!(take_array(&mut root, "sound_channels")).into_iter() {
+        let mut chan = try!(into_object(chan, "an element of `sound_channels`"));
+        let name = match try!(take_str(&mut chan, "name")) {
+            Some(name) => name,
+            None => { return Err(format!("an element of `sound_channels` lacks `name`")); }
+        };
+
+        let mut notes = Vec::new();
+        for note in try!(take_array(&mut chan, "notes")).into_iter() {
+            let mut note = try!(into_object(note, "an element of `notes`"));
+            let x = try!(take_pulses(&mut note, "x")).unwrap_or(0);
+            let y = try!(take_pulses(&mut note, "y")).unwrap_or(0);
+            let l = try!(take_pulses(&mut note, "l")).unwrap_or(0);
+            let c = try!(take_bool(&mut note, "c")).unwrap_or(false);
+            notes.push((y, x, l, c));
+        }
+        notes.sort_by(|&(a, _, _, _), &(b, _, _, _)| a.cmp(&b));
+        channels.push((name, notes));
+    }
+    if channels.len() >= MAXKEY as uint {
+        return Err(format!("too many sound channels"));
+    }
+
+    let mut sndpath = vec![None];
+    let mut sndtransforms = vec![SoundTransform::identity()];
+    let mut nsounds = channels.len() + 1;
+    let mut nnotes = 0u;
+    for (name, notes) in channels.into_iter() {
+        // distinct positions in the channel, and whether the playback restarts there
+        let mut positions = Vec::new();
+        let mut lasty = None;
+        for &(y, _, _, c) in notes.iter() {
+            if lasty != Some(y) {
+                positions.push((y, !c || lasty.is_none()));
+                lasty = Some(y);
+            }
+        }
+
+        let mut srefs = Vec::new();
+        let continued = positions.iter().any(|&(_, restart)| !restart);
+        if continued && nsounds + positions.len() - 1 < MAXKEY as uint {
+            nsounds += positions.len() - 1;
+            // the position where the current playback has been (re)started
+            let mut starty = 0;
+            for (i, &(y, restart)) in positions.iter().enumerate() {
+                if restart { starty = y; }
+                let start = tempo.seconds_at(y);
+                let transform = SoundTransform {
+                    offset: start - tempo.seconds_at(starty),
+                    duration: positions.get(i + 1).map(|&(nexty, _)| {
+                        tempo.seconds_at(nexty) - start
+                    }),
+                    ..SoundTransform::identity()
+                };
+                sndpath.push(Some(name.clone()));
+                sndtransforms.push(transform);
+                srefs.push(Some(SoundRef(Key((sndpath.len() - 1) as int))));
+            }
+        } else {
+            sndpath.push(Some(name.clone()));
+            sndtransforms.push(SoundTransform::identity());
+            let sref = SoundRef(Key((sndpath.len() - 1) as int));
+            for &(_, restart) in positions.iter() {
+                srefs.push(if restart {Some(sref.clone())} else {None});
+            }
+        }
+
+        let mut i = 0;
+        let mut lasty = None;
+        for &(y, x, l, _) in notes.iter() {
+            if lasty.is_some() && lasty != Some(y) { i += 1; }
+            lasty = Some(y);
+
+            let sref = srefs[i].clone();
+            let lane = if x > 0 && x <= xlanes.len() {xlanes[x-1]} else {None};
+            match lane {
+                Some(lane) if l > 0 => {
+                    builder.add(pulse_to_vpos(y), LNStart(lane, sref));
+                    builder.add(pulse_to_vpos(y + l), LNDone(lane, None));
+                    nnotes += 1;
+                }
+                Some(lane) => {
+                    builder.add(pulse_to_vpos(y), Visible(lane, sref));
+                    nnotes += 1;
+                }
+                // notes outside of known lanes are played as BGMs
+                None => match sref {
+                    Some(sref) => { builder.add(pulse_to_vpos(y), BGM(sref)); }
+                    None => {}
+                },
+            }
+            if endy < y + l { endy = y + l; }
+        }
+    }
+
+    // BGA layers. image references are assigned in the order of `bga_header`, starting from 1.
+    let mut bga = try!(take_object(&mut root, "bga"));
+    let mut imgpath = vec![None];
+    let mut imgids = Vec::new();
+    for header in try!(take_array(&mut bga, "bga_header")).into_iter() {
+        let mut header = try!(into_object(header, "an element of `bga_header`"));
+        let id = try!(take_num(&mut header, "id"));
+        let name = try!(take_str(&mut header, "name"));
+        match (id, name) {
+            (Some(id), Some(name)) => {
+                imgids.push(id);
+                imgpath.push(Some(name));
+            }
+            (_, _) => { return Err(format!("an element of `bga_header` lacks `id` or `name`")); }
+        }
+    }
+    for &(listkey, layer) in [("bga_events", BGALayer::Layer1),
+                              ("layer_events", BGALayer::Layer2),
+                              ("poor_events", BGALayer::PoorBGA)].iter() {
+        for (y, id) in try!(take_events(&mut bga, listkey, "id")).into_iter() {
+            let bgaref = match imgids.iter().position(|&id_| id_ == id) {
+                Some(i) => BGARef::Image(ImageRef(Key((i + 1) as int))),
+                None => BGARef::Blank,
+            };
+            builder.add(pulse_to_vpos(y), SetBGA(layer, bgaref));
+            if endy < y { endy = y; }
+        }
+    }
+
+    // measure bars are placed at every given line, or at every four beats if none
+    let lines = try!(take_array(&mut root, "lines"));
+    if lines.is_empty() {
+        let nmeasures = endy / (4 * resolution) + 1;
+        for measure in range(0, nmeasures + 1) {
+            builder.add(measure as f64, MeasureBar);
+        }
+    } else {
+        for line in lines.into_iter() {
+            let mut line = try!(into_object(line, "an element of `lines`"));
+            let y = try!(take_pulses(&mut line, "y")).unwrap_or(0);
+            builder.add(pulse_to_vpos(y), MeasureBar);
+            if endy < y { endy = y; }
+        }
+    }
+
+    // set the end of the chart (no measure bar at this position)
+    builder.set_end(pulse_to_vpos(endy) + 1.0);
+
+    let timeline = builder.build();
+    let nimages = imgpath.len();
+    let meta = BmsMeta {
+        common: Meta { random: false,
+                       title: title, subtitles: subtitles, genre: genre,
+                       artist: artist, subartists: subartists, comments: Vec::new(),
+                       level: level, difficulty: None },
+        encoding: ("utf-8", 1.0), stagefile: stagefile, banner: banner, basepath: None,
+        mode: mode, lanes: lanes, rank: 2,
+        defexrank: if judgerank >= 1.0 {Some(judgerank as int)} else {None},
+        total: if reltotal <= 0.0 || reltotal == 100.0 {None}
+               else {Some(default_total(nnotes) * reltotal / 100.0)},
+        volume: 1.0,
+        sndpath: sndpath, sndtransforms: sndtransforms, imgpath: imgpath,
+        imgcolorkeys: Vec::from_elem(nimages, None), texts: Vec::new(), swbgas: Vec::new(),
+        canvassize: (256, 256),
+    };
+    Ok(Bms { bmspath: None, meta: meta, timeline: timeline })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use format::obj::{Visible, LNStart, LNDone, BGM, MeasureBar};
+    use format::bms::{Key, MAXKEY, SoundRef, SoundTransform, PlayMode, Bms};
+    use format::bms::testutil::{objs, lane};
+    use super::{Tempo, load_bmson};
+
+    fn load(source: &str) -> Result<Bms,String> {
+        load_bmson(&mut BufReader::new(source.as_bytes()))
+    }
+
+    fn sref(i: int) -> Option<SoundRef> {
+        Some(SoundRef(Key(i)))
+    }
+
+    #[test]
+    fn test_tempo() {
+        let tempo = Tempo { resolution: 240.0, initbpm: 120.0,
+                            bpms: vec![(480, 240.0)], stops: vec![(720, 240.0)] };
+        assert_eq!(tempo.seconds_at(0), 0.0);
+        assert_eq!(tempo.seconds_at(240), 0.5);
+        assert_eq!(tempo.seconds_at(480), 1.0);
+        assert_eq!(tempo.seconds_at(720), 1.25); // the stop at the same position doesn't count
+        assert_eq!(tempo.seconds_at(960), 1.75);
+    }
+
+    #[test]
+    fn test_sliced_channels() {
+        let bms = load(r#"{"info": {"init_bpm": 120, "resolution": 240},
+                           "sound_channels": [
+                               {"name": "a.wav", "notes": [{"x": 1, "y": 0, "c": false},
+                                                           {"x": 2, "y": 240, "c": true},
+                                                           {"x": 1, "y": 480, "c": true},
+                                                           {"x": 2, "y": 960, "c": false}]},
+                               {"name": "b.wav", "notes": [{"x": 3, "y": 0, "c": false},
+                                                           {"x": 3, "y": 480, "c": false}]}
+                           ]}"#).unwrap();
+        let a = Some("a.wav".to_string());
+        let b = Some("b.wav".to_string());
+        assert_eq!(bms.meta.sndpath, vec![None, a.clone(), a.clone(), a.clone(), a, b]);
+
+        let slice = |offset: f64, duration: Option<f64>| {
+            SoundTransform { offset: offset, duration: duration, ..SoundTransform::identity() }
+        };
+        assert_eq!(bms.meta.sndtransforms, vec![SoundTransform::identity(),
+                                                slice(0.0, Some(0.5)), slice(0.5, Some(0.5)),
+                                                slice(1.0, Some(1.0)), slice(0.0, None),
+                                                SoundTransform::identity()]);
+
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, Visible(lane("11"), sref(1)))));
+        assert!(objs.contains(&(0.25, Visible(lane("12"), sref(2)))));
+        assert!(objs.contains(&(0.5, Visible(lane("11"), sref(3)))));
+        assert!(objs.contains(&(1.0, Visible(lane("12"), sref(4)))));
+        assert!(objs.contains(&(0.0, Visible(lane("13"), sref(5)))));
+        assert!(objs.contains(&(0.5, Visible(lane("13"), sref(5)))));
+    }
+
+    #[test]
+    fn test_too_many_slices() {
+        let notes: Vec<String> = range(0u, MAXKEY as uint).map(|i| {
+            format!(r#"{{"x": 1, "y": {}, "c": true}}"#, i * 60)
+        }).collect();
+        let bms = load(format!(r#"{{"sound_channels": [{{"name": "a.wav", "notes": [{}]}}]}}"#,
+                               notes.connect(", "))[]).unwrap();
+        assert_eq!(bms.meta.sndpath, vec![None, Some("a.wav".to_string())]);
+        assert_eq!(bms.meta.sndtransforms, vec![SoundTransform::identity(),
+                                                SoundTransform::identity()]);
+
+        // only the first note restarts the playback
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, Visible(lane("11"), sref(1)))));
+        assert!(objs.contains(&(0.0625, Visible(lane("11"), None))));
+    }
+
+    #[test]
+    fn test_mode_hint() {
+        let bms = load(r#"{"info": {"mode_hint": "popn-5k"},
+                           "sound_channels": [
+                               {"name": "a.wav", "notes": [{"x": 1, "y": 0},
+                                                           {"x": 5, "y": 240, "l": 480},
+                                                           {"x": 0, "y": 960},
+                                                           {"x": 6, "y": 1920}]}
+                           ]}"#).unwrap();
+        assert!(bms.meta.mode == PlayMode::Single);
+        assert_eq!(bms.meta.lanes.len(), 5);
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, Visible(lane("13"), sref(1)))));
+        assert!(objs.contains(&(0.25, LNStart(lane("23"), sref(1)))));
+        assert!(objs.contains(&(0.75, LNDone(lane("23"), None))));
+        assert!(objs.contains(&(1.0, BGM(SoundRef(Key(1))))));
+        assert!(objs.contains(&(2.0, BGM(SoundRef(Key(1))))));
+
+        // unknown mode hints are treated as `beat-7k`, where `x` of 6 and 7 are used
+        let bms = load(r#"{"info": {"mode_hint": "keyboard-24k"},
+                           "sound_channels": [
+                               {"name": "a.wav", "notes": [{"x": 6, "y": 0},
+                                                           {"x": 9, "y": 240}]}
+                           ]}"#).unwrap();
+        assert_eq!(bms.meta.lanes.len(), 8);
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, Visible(lane("18"), sref(1)))));
+        assert!(objs.contains(&(0.25, BGM(SoundRef(Key(1))))));
+    }
+
+    fn measure_bars(bms: &Bms) -> Vec<f64> {
+        objs(bms).into_iter().filter(|&(_, ref data)| *data == MeasureBar)
+                             .map(|(vpos, _)| vpos).collect()
+    }
+
+    #[test]
+    fn test_measure_bars() {
+        let bms = load(r#"{"sound_channels": [{"name": "a.wav", "notes": [{"y": 1000}]}]}"#);
+        assert_eq!(measure_bars(&bms.unwrap()), vec![0.0, 1.0, 2.0]);
+
+        let bms = load(r#"{"lines": [{"y": 0}, {"y": 720}],
+                           "sound_channels": [{"name": "a.wav", "notes": [{"y": 1000}]}]}"#);
+        assert_eq!(measure_bars(&bms.unwrap()), vec![0.0, 0.75]);
+    }
+
+    #[test]
+    fn test_judge_rank() {
+        let defexrank = |source: &str| load(source).unwrap().meta.defexrank;
+        assert_eq!(defexrank(r#"{}"#), Some(100));
+        assert_eq!(defexrank(r#"{"info": {"judge_rank": 75.6}}"#), Some(76));
+        assert_eq!(defexrank(r#"{"info": {"judge_rank": 0.5}}"#), Some(1));
+        assert_eq!(defexrank(r#"{"info": {"judge_rank": 0.4}}"#), None);
+        assert_eq!(defexrank(r#"{"info": {"judge_rank": 0}}"#), None);
+        assert_eq!(defexrank(r#"{"info": {"judge_rank": -50}}"#), None);
+    }
+
+    #[test]
+    fn test_total() {
+        let total = |source: &str| load(source).unwrap().meta.total;
+        assert_eq!(total(r#"{}"#), None);
+        assert_eq!(total(r#"{"info": {"total": 100}}"#), None);
+        assert_eq!(total(r#"{"info": {"total": 50}}"#), Some(130.0)); // no notes
+        assert_eq!(total(r#"{"info": {"total": 0}}"#), None);
+        assert_eq!(total(r#"{"info": {"total": -20}}"#), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |source: &str| load(source).err().unwrap();
+        assert_eq!(err("[]"), "the bmson file should be an object".to_string());
+        assert_eq!(err(r#"{"info": {"title": 1}}"#), "`title` should be a string".to_string());
+        assert_eq!(err(r#"{"info": {"init_bpm": "fast"}}"#),
+                   "`init_bpm` should be a number".to_string());
+        assert_eq!(err(r#"{"sound_channels": {}}"#),
+                   "`sound_channels` should be an array".to_string());
+        assert_eq!(err(r#"{"sound_channels": [{"notes": []}]}"#),
+                   "an element of `sound_channels` lacks `name`".to_string());
+        assert_eq!(err(r#"{"sound_channels": [{"name": "a.wav", "notes": [{"y": -1}]}]}"#),
+                   "`y` should not be negative".to_string());
+        assert_eq!(err(r#"{"sound_channels": [{"name": "a.wav", "notes": [{"c": "yes"}]}]}"#),
+                   "`c` should be a boolean".to_string());
+        assert_eq!(err(r#"{"bpm_events": [{"y": 0}]}"#),
+                   "an element of `bpm_events` lacks `bpm`".to_string());
+    }
+}
@@ -7,7 +7,9 @@
 use std::fmt;
 
 pub use self::ObjData::{Deleted, Visible, Invisible, LNStart, LNDone, Bomb};
-pub use self::ObjData::{BGM, SetBGA, SetBPM, Stop, StopEnd, SetMeasureFactor, MeasureBar, End};
+pub use self::ObjData::{BGM, SetBGA, SetBGAOpacity, SetBGAColorKey, SetBPM, Stop, StopEnd};
+pub use self::ObjData::{SetSwitchBGA, SetVolume, SetText, SetMeasureFactor, SetGradeFactor};
+pub use self::ObjData::{MeasureBar, End};
 
 /// A game play element mapped to the single input element (for example, button) and the screen
 /// area (henceforth "lane").
@@ -35,11 +37,27 @@ pub enum BGALayer {
     Layer3 = 2,
     /// The layer only displayed shortly after the MISS grade. It is technically not over
     /// `Layer3`, but several extensions to BMS assumes it. BMS channel #06.
-    PoorBGA = 3
+    PoorBGA = 3,
+    /// The layer displayed over `Layer3` while the player presses certain lanes. It is not
+    /// directly set by the chart but by the player from the current switch BGA (`SetSwitchBGA`).
+    Switch = 4,
 }
 
 /// The number of BGA layers.
-pub const NLAYERS: uint = 4;
+pub const NLAYERS: uint = 5;
+
+/// Groups of sounds which volume can be changed separately.
+#[deriving(PartialEq,Eq,Show,Clone)]
+pub enum SoundGroup {
+    /// Sounds played by the key input (or automatically in place of the key input).
+    /// BMS channel #98.
+    KeySound = 0,
+    /// Background sounds. BMS channel #97.
+    Background = 1,
+}
+
+/// The number of sound groups.
+pub const NSOUNDGROUPS: uint = 2;
 
 /// Beats per minute. Used as a conversion factor between the time position and actual time
 /// in BMS.
@@ -155,6 +173,24 @@ pub enum ObjData<SoundRef,ImageRef> {
      * can be shared among multiple layers.
      */
     SetBGA(BGALayer, BGARef<ImageRef>),
+    /// Sets the opacity of the virtual BGA layer, from 0 (fully transparent) to 255 (fully
+    /// opaque). The opacity is kept even when the image in that layer changes.
+    SetBGAOpacity(BGALayer, u8),
+    /// Sets the color key of the virtual BGA layer, i.e. an RGB color treated as transparent in
+    /// the images displayed in that layer. `None` disables the color key. The color key is kept
+    /// even when the image in that layer changes.
+    SetBGAColorKey(BGALayer, Option<(u8,u8,u8)>),
+    /// Sets the current switch BGA, as an index to the table of switch BGAs
+    /// (e.g. `BmsMeta::swbgas`). The switch BGA is an animation displayed in `BGALayer::Switch`
+    /// when the player presses the associated lane.
+    SetSwitchBGA(uint),
+    /// Sets the volume of given sound group, as a ratio to the full volume. The volume applies to
+    /// the sounds played afterwards.
+    SetVolume(SoundGroup, f64),
+    /// Sets the text displayed during the game play, as an index to the table of texts
+    /// (e.g. `BmsMeta::texts`). The text is kept until the next `SetText` object, and
+    /// an index to the missing text clears the text.
+    SetText(uint),
     /// Sets the BPM. Negative BPM causes the chart scrolls backwards. Zero BPM causes the chart
     /// immediately terminates. In both cases, the chart is considered unfinished if there are
     /// remaining gradable objects.
@@ -170,6 +206,10 @@ pub enum ObjData<SoundRef,ImageRef> {
     /// This can be ignored for the game play, but we still keep this relation since the virtual
     /// position is often directly used to refer certain point in the chart.
     SetMeasureFactor(f64),
+    /// Sets the scale factor for grading area, where the factor larger than 1 causes the grading
+    /// area shrink. The factor is multiplied to the time difference between the input point and
+    /// the object. See also `Player::gradefactor`.
+    SetGradeFactor(f64),
     /// Start of the measure, where the measure bar is drawn. This is derived from
     /// `SetMeasureFactor` but made into the separate object as an optimization.
     MeasureBar,
@@ -224,6 +264,18 @@ impl<S:fmt::Show,I:fmt::Show> fmt::Show for ObjData<S,I> {
                 write!(f, "SetBGA({},{}:{}+{}+{}x{}:{}+{}+{}x{})",
                           layer, iref, slice.sx, slice.sy, slice.w, slice.h,
                           slice.dx, slice.dy, slice.w, slice.h),
+            SetBGAOpacity(layer, opacity) =>
+                write!(f, "SetBGAOpacity({},{})", layer, opacity),
+            SetBGAColorKey(layer, Some((r,g,b))) =>
+                write!(f, "SetBGAColorKey({},#{:02x}{:02x}{:02x})", layer, r, g, b),
+            SetBGAColorKey(layer, None) =>
+                write!(f, "SetBGAColorKey({},none)", layer),
+            SetSwitchBGA(index) =>
+                write!(f, "SetSwitchBGA({})", index),
+            SetVolume(group, volume) =>
+                write!(f, "SetVolume({},{})", group, volume),
+            SetText(index) =>
+                write!(f, "SetText({})", index),
             SetBPM(BPM(bpm)) =>
                 write!(f, "SetBPM({})", bpm),
             Stop(Duration::Seconds(secs)) =>
@@ -234,6 +286,8 @@ impl<S:fmt::Show,I:fmt::Show> fmt::Show for ObjData<S,I> {
                 write!(f, "StopEnd"),
             SetMeasureFactor(factor) =>
                 write!(f, "SetMeasureFactor({})", factor),
+            SetGradeFactor(factor) =>
+                write!(f, "SetGradeFactor({})", factor),
             MeasureBar =>
                 write!(f, "MeasureBar"),
             End =>
@@ -290,6 +344,16 @@ pub trait ObjQueryOps<SoundRef:Clone,ImageRef:Clone> {
     fn is_bgm(&self) -> bool;
     /// Returns true if the data is a BGA.
     fn is_setbga(&self) -> bool;
+    /// Returns true if the data is a change in the BGA opacity.
+    fn is_setbgaopacity(&self) -> bool;
+    /// Returns true if the data is a change in the BGA color key.
+    fn is_setbgacolorkey(&self) -> bool;
+    /// Returns true if the data is a change in the switch BGA.
+    fn is_setswitchbga(&self) -> bool;
+    /// Returns true if the data is a volume change.
+    fn is_setvolume(&self) -> bool;
+    /// Returns true if the data is a text change.
+    fn is_settext(&self) -> bool;
     /// Returns true if the data is a BPM change.
     fn is_setbpm(&self) -> bool;
     /// Returns true if the data is a scroll stopper.
@@ -298,6 +362,8 @@ pub trait ObjQueryOps<SoundRef:Clone,ImageRef:Clone> {
     fn is_stopend(&self) -> bool;
     /// Returns true if the data is a change in the measure scaling factor.
     fn is_setmeasurefactor(&self) -> bool;
+    /// Returns true if the data is a change in the scale factor for grading area.
+    fn is_setgradefactor(&self) -> bool;
     /// Returns true if the data is a measure bar.
     fn is_measurebar(&self) -> bool;
     /// Returns true if the data is an end mark.
@@ -401,6 +467,26 @@ impl<S:Clone,I:Clone,T:ToObjData<S,I>> ObjQueryOps<S,I> for T {
         match self.to_obj_data() { SetBGA(..) => true, _ => false }
     }
 
+    fn is_setbgaopacity(&self) -> bool {
+        match self.to_obj_data() { SetBGAOpacity(..) => true, _ => false }
+    }
+
+    fn is_setbgacolorkey(&self) -> bool {
+        match self.to_obj_data() { SetBGAColorKey(..) => true, _ => false }
+    }
+
+    fn is_setswitchbga(&self) -> bool {
+        match self.to_obj_data() { SetSwitchBGA(..) => true, _ => false }
+    }
+
+    fn is_setvolume(&self) -> bool {
+        match self.to_obj_data() { SetVolume(..) => true, _ => false }
+    }
+
+    fn is_settext(&self) -> bool {
+        match self.to_obj_data() { SetText(..) => true, _ => false }
+    }
+
     fn is_setbpm(&self) -> bool {
         match self.to_obj_data() { SetBPM(..) => true, _ => false }
     }
@@ -417,6 +503,10 @@ impl<S:Clone,I:Clone,T:ToObjData<S,I>> ObjQueryOps<S,I> for T {
         match self.to_obj_data() { SetMeasureFactor(..) => true, _ => false }
     }
 
+    fn is_setgradefactor(&self) -> bool {
+        match self.to_obj_data() { SetGradeFactor(..) => true, _ => false }
+    }
+
     fn is_measurebar(&self) -> bool {
         match self.to_obj_data() { MeasureBar => true, _ => false }
     }
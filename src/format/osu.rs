@@ -0,0 +1,547 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * osu!mania beatmap (`.osu`) implementation.
+ *
+ * The `.osu` format is a sectioned plain text format. Sections like `[General]`, `[Metadata]` and
+ * `[Difficulty]` contain `key: value` pairs, and sections like `[Events]`, `[TimingPoints]` and
+ * `[HitObjects]` contain comma-separated records. Unlike BMS, every object is positioned by
+ * the time in milliseconds from the start of the audio file, and timing points only define
+ * the tempo and the number of beats per measure from given time.
+ *
+ * The loader converts the time to the measure-based position by treating each uninherited timing
+ * point as the start of a new measure; the last measure before the next timing point is shortened
+ * with `SetMeasureFactor` as needed. Inherited timing points (scroll speed changes) are ignored.
+ * The loader produces the same `format::bms::Bms` structure as the BMS loader, and the lanes are
+ * declared via `BmsMeta::lanes` so that the key specification follows the number of columns.
+ *
+ * Hit sounds are mapped to the default sample names (`<set>-hit<sound><index>.wav`) in the beatmap
+ * directory, with sample sets and indices inherited from the timing points as needed. The normal
+ * hit sound becomes the key sound, while additions (whistle, finish and clap) are played as BGMs
+ * at the same position since an object can only have one key sound. A custom sample file replaces
+ * every hit sound of the object. Skin-provided samples are not available.
+ */
+
+use std::{f64, cmp};
+use std::num::Float;
+
+use format::obj::{Lane, BPM};
+use format::obj::{Visible, LNStart, LNDone, BGM, SetBGA, SetBPM, SetMeasureFactor, MeasureBar};
+use format::obj::{BGARef, BGALayer};
+use format::metadata::Meta;
+use format::bms::{Key, MAXKEY, ImageRef, SoundRef, SoundTransform, PlayMode};
+use format::bms::{BmsMeta, Bms};
+use format::bms::parse::{ParserOptions, decode_with_options};
+
+/// Lanes for each column count, as a list of BMS channels for columns and the lane declaration
+/// in the format of BMS #SNRS:LANES command.
+static COLUMNS: &'static [(PlayMode, &'static str, &'static str)] = &[
+    (PlayMode::Single, "11", "11a"),
+    (PlayMode::Single, "11 12", "11a 12a"),
+    (PlayMode::Single, "11 12 13", "11a 12y 13a"),
+    (PlayMode::Single, "11 12 13 14", "11a 12b 13b 14a"),
+    (PlayMode::Single, "11 12 13 14 15", "11a 12b 13y 14b 15a"),
+    (PlayMode::Single, "11 12 13 14 15 18", "11a 12b 13a 14a 15b 18a"),
+    (PlayMode::Single, "11 12 13 14 15 18 19", "11a 12b 13a 14y 15a 18b 19a"),
+    (PlayMode::Single, "16 11 12 13 14 15 18 19", "16s 11a 12b 13a 14y 15a 18b 19a"),
+    (PlayMode::Single, "11 12 13 14 15 22 23 24 25", "11q 12w 13e 14r 15t 22r 23e 24w 25q"),
+    (PlayMode::Double, "11 12 13 14 15 21 22 23 24 25",
+                       "11a 12b 13y 14b 15a 21a 22b 23y 24b 25a"),
+];
+
+/// The width of the playfield in osu! pixels, used for calculating the column from `x`.
+const PLAYFIELD_WIDTH: f64 = 512.0;
+
+/// A tempo segment started by an uninherited timing point.
+struct Segment {
+    /// The starting time in milliseconds.
+    time: f64,
+    /// The starting position in measures.
+    vpos: f64,
+    /// The duration of one beat in milliseconds.
+    beatlen: f64,
+    /// The number of beats per measure.
+    meter: f64,
+    /// The number of full measures in the segment. The remaining portion of the segment, if any,
+    /// is a single shortened measure which is `frac` times the normal measure.
+    full: f64,
+    /// The length of the last shortened measure relative to the normal measure.
+    frac: f64,
+}
+
+/// Hit sounds of the hit object before resolving sample sets and indices.
+struct HitSound {
+    /// The `hitSound` bit field. 2 for whistle, 4 for finish and 8 for clap.
+    flags: uint,
+    /// The sample set for the normal sound. 0 means the sample set of the timing point.
+    normalset: uint,
+    /// The sample set for additions. 0 means the same set as the normal sound.
+    additionset: uint,
+    /// The sample index. 0 means the sample index of the timing point.
+    index: uint,
+    /// The custom sample file name replacing every hit sound, if any.
+    filename: Option<String>,
+}
+
+/// Returns the sample file name for given sample set, sound and sample index.
+fn sample_name(set: uint, sound: &str, index: uint) -> String {
+    let set = match set { 2 => "soft", 3 => "drum", _ => "normal" };
+    if index > 1 {
+        format!("{}-hit{}{}.wav", set, sound, index)
+    } else {
+        format!("{}-hit{}.wav", set, sound)
+    }
+}
+
+/// Converts the time in milliseconds to the position in measures.
+fn vpos_at(segments: &[Segment], time: f64) -> f64 {
+    let seg = segments.iter().rev().find(|seg| seg.time <= time).unwrap_or(&segments[0]);
+    let n = (time - seg.time) / (seg.beatlen * seg.meter);
+    if n > seg.full {
+        seg.vpos + seg.full + (n - seg.full) / seg.frac
+    } else {
+        seg.vpos + n
+    }
+}
+
+/// Parses a comma-separated field as a number.
+fn field(fields: &[&str], i: uint) -> Option<f64> {
+    if i < fields.len() {from_str::<f64>(fields[i].trim())} else {None}
+}
+
+/// Extracts a double-quoted file name used in `[Events]` section.
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    let s = if s.len() >= 2 && s.starts_with("\"") && s.ends_with("\"") {s[1..s.len()-1]} else {s};
+    s.to_string()
+}
+
+/// Reads the osu!mania beatmap from given reader. The encoding is forced or guessed as like
+/// BMS files, since older beatmaps are not always in UTF-8.
+pub fn load_osu(f: &mut Reader, opts: &ParserOptions) -> Result<Bms,String> {
+    use format::timeline::builder::TimelineBuilder;
+
+    let (text, encoding, confidence) = decode_with_options(f, opts);
+    let encoding = (encoding.name(), confidence);
+    let text = if text[].starts_with("\uFEFF") {text[3..].to_string()} else {text};
+
+    let mut title = None;
+    let mut artist = None;
+    let mut subtitles = Vec::new();
+    let mut subartists = Vec::new();
+    let mut comments = Vec::new();
+    let mut audio = None;
+    let mut background = None;
+    let mut ncolumns = None;
+    let mut gamemode = 0.0;
+    let mut defaultset = 1;
+
+    // sound files referenced by the beatmap. the audio file, if any, goes to `01`.
+    let mut sndpath = vec![None, None];
+    let mut bgmsamples = Vec::new(); // (time, sound index)
+    let mut timingpoints = Vec::new(); // (time, beat length, meter)
+    let mut samplepoints = Vec::new(); // (time, sample set, sample index)
+    let mut hitobjects = Vec::new(); // (time, end time if any, x, hit sounds)
+
+    // sound files which do not fit in alphanumeric keys are ignored.
+    fn sound_index(sndpath: &mut Vec<Option<String>>, name: String) -> Option<uint> {
+        let path = Some(name);
+        match sndpath.iter().skip(2).position(|p| *p == path) {
+            Some(i) => Some(i + 2),
+            None if sndpath.len() < MAXKEY as uint => {
+                sndpath.push(path);
+                Some(sndpath.len() - 1)
+            }
+            None => None,
+        }
+    }
+
+    let mut section = "";
+    for line in text[].lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") { continue; }
+        if line.starts_with("[") && line.ends_with("]") {
+            section = line[1..line.len()-1];
+            continue;
+        }
+
+        let (key, value) = match line.find(':') {
+            Some(i) => (line[..i].trim(), line[i+1..].trim()),
+            None => ("", line),
+        };
+        let fields: Vec<&str> = line.split(',').collect();
+        match section {
+            "General" => match key {
+                "AudioFilename" if !value.is_empty() => { audio = Some(value.to_string()); }
+                "Mode" => { gamemode = from_str::<f64>(value).unwrap_or(0.0); }
+                "SampleSet" => match value {
+                    "Soft" => { defaultset = 2; }
+                    "Drum" => { defaultset = 3; }
+                    _ => { defaultset = 1; }
+                },
+                _ => {}
+            },
+            "Metadata" => match key {
+                "Title" => { title = title.or(Some(value.to_string())); }
+                "TitleUnicode" if !value.is_empty() => { title = Some(value.to_string()); }
+                "Artist" => { artist = artist.or(Some(value.to_string())); }
+                "ArtistUnicode" if !value.is_empty() => { artist = Some(value.to_string()); }
+                "Version" if !value.is_empty() => { subtitles.push(value.to_string()); }
+                "Creator" if !value.is_empty() => { subartists.push(value.to_string()); }
+                "Source" if !value.is_empty() => { comments.push(value.to_string()); }
+                _ => {}
+            },
+            "Difficulty" => match key {
+                "CircleSize" => { ncolumns = from_str::<f64>(value).map(|v| v as uint); }
+                _ => {}
+            },
+            "Events" => {
+                // background image: `0,0,"filename",...` or `Background,0,"filename",...`
+                // storyboard sound sample: `Sample,time,layer,"filename",volume`
+                let isbg = fields[0] == "0" || fields[0] == "Background";
+                if fields.len() >= 3 && isbg && background.is_none() {
+                    background = Some(unquote(fields[2]));
+                } else if fields.len() >= 4 && fields[0] == "Sample" {
+                    for &time in field(fields[], 1).iter() {
+                        for &sound in sound_index(&mut sndpath, unquote(fields[3])).iter() {
+                            bgmsamples.push((time, sound));
+                        }
+                    }
+                }
+            }
+            "TimingPoints" => {
+                // `time,beatLength,meter,sampleSet,sampleIndex,volume,uninherited,effects`
+                let uninherited = field(fields[], 6).map_or(true, |v| v != 0.0);
+                for &time in field(fields[], 0).iter() {
+                    let set = field(fields[], 3).unwrap_or(0.0) as uint;
+                    let index = field(fields[], 4).unwrap_or(0.0) as uint;
+                    samplepoints.push((time, set, index));
+                }
+                match (field(fields[], 0), field(fields[], 1)) {
+                    (Some(time), Some(beatlen)) if uninherited && beatlen > 0.0 => {
+                        let meter = field(fields[], 2).unwrap_or(4.0);
+                        let meter = if meter > 0.0 {meter} else {4.0};
+                        timingpoints.push((time, beatlen, meter));
+                    }
+                    (_, _) => {}
+                }
+            }
+            "HitObjects" => {
+                // `x,y,time,type,hitSound,objectParams,hitSample`, where the hold note
+                // (type 128) has `endTime:hitSample` in place of `objectParams,hitSample`
+                // and `hitSample` is `normalSet:additionSet:index:volume:filename`.
+                let (x, time, kind) =
+                    match (field(fields[], 0), field(fields[], 2), field(fields[], 3)) {
+                        (Some(x), Some(time), Some(kind)) => (x, time, kind as uint),
+                        (_, _, _) => { continue; }
+                    };
+                let extra = if fields.len() > 5 {fields[fields.len()-1]} else {""};
+                let extra: Vec<&str> = extra.split(':').collect();
+                let (endtime, sample) = if kind & 128 != 0 && extra.len() > 0 {
+                    (from_str::<f64>(extra[0].trim()), extra[1..])
+                } else {
+                    (None, extra[])
+                };
+                let samplename = if sample.len() > 4 {sample[4].trim()} else {""};
+                let hitsound = HitSound {
+                    flags: field(fields[], 4).unwrap_or(0.0) as uint,
+                    normalset: field(sample, 0).unwrap_or(0.0) as uint,
+                    additionset: field(sample, 1).unwrap_or(0.0) as uint,
+                    index: field(sample, 2).unwrap_or(0.0) as uint,
+                    filename: if samplename.is_empty() {None}
+                              else {Some(samplename.to_string())},
+                };
+                hitobjects.push((time, endtime, x, hitsound));
+            }
+            _ => {}
+        }
+    }
+
+    if gamemode != 3.0 {
+        return Err(format!("not an osu!mania beatmap"));
+    }
+    let ncolumns = match ncolumns {
+        Some(n) if 0 < n && n <= COLUMNS.len() => n,
+        _ => { return Err(format!("unsupported number of columns")); }
+    };
+    if timingpoints.is_empty() {
+        return Err(format!("no timing points"));
+    }
+    let (mode, channels, lanedecl) = COLUMNS[ncolumns-1];
+    let columns: Vec<Lane> = channels.words().map(|chan| Key::from_str(chan).unwrap().to_lane())
+                                             .collect();
+    let lanes: Vec<(Key, String)> = lanedecl.words().map(|decl| {
+        (Key::from_str(decl).unwrap(), decl[2..].to_string())
+    }).collect();
+
+    // timing points at the same time are replaced by the last one
+    timingpoints.sort_by(|&(a, _, _), &(b, _, _)| a.partial_cmp(&b).unwrap_or(Equal));
+    let mut dedup: Vec<(f64, f64, f64)> = Vec::new();
+    for &tp in timingpoints.iter() {
+        let (time, _, _) = tp;
+        match dedup.last() {
+            Some(&(lasttime, _, _)) if lasttime == time => { dedup.pop(); }
+            _ => {}
+        }
+        dedup.push(tp);
+    }
+    let timingpoints = dedup;
+
+    // the chart starts at or before the audio, and the first measure starts at the first
+    // timing point; the lead-in portion is given by a partial measure at the beginning.
+    let starttime = hitobjects.iter().map(|&(time, _, _, _)| time)
+                              .chain(bgmsamples.iter().map(|&(time, _)| time))
+                              .fold(0.0, |a: f64, b| a.min(b));
+    let (firsttime, firstbeatlen, firstmeter) = timingpoints[0];
+    let lead = ((firsttime - starttime) / (firstbeatlen * firstmeter)).max(0.0);
+    let mut segments = Vec::new();
+    let mut vpos = lead.ceil();
+    for (i, &(time, beatlen, meter)) in timingpoints.iter().enumerate() {
+        let (full, frac, nextvpos) = if i + 1 < timingpoints.len() {
+            let (nexttime, _, _) = timingpoints[i+1];
+            let n = (nexttime - time) / (beatlen * meter);
+            let full = (n + 1e-6).floor();
+            if n - full > 1e-6 {(full, n - full, vpos + full + 1.0)}
+            else {(f64::INFINITY, 1.0, vpos + full)}
+        } else {
+            (f64::INFINITY, 1.0, vpos)
+        };
+        segments.push(Segment { time: time, vpos: vpos, beatlen: beatlen, meter: meter,
+                                full: full, frac: frac });
+        vpos = nextvpos;
+    }
+
+    let mut builder = TimelineBuilder::new();
+    builder.set_initbpm(BPM(60000.0 / firstbeatlen));
+    let mut endvpos = 0.0f64;
+    for (i, seg) in segments.iter().enumerate() {
+        let vpos = if i == 0 {0.0} else {seg.vpos};
+        builder.add(vpos, SetBPM(BPM(60000.0 / seg.beatlen)));
+        builder.add(vpos, SetMeasureFactor(seg.meter / 4.0));
+        if seg.full.is_finite() {
+            builder.add(seg.vpos + seg.full, SetMeasureFactor(seg.meter / 4.0 * seg.frac));
+        }
+        endvpos = endvpos.max(seg.vpos);
+    }
+
+    if audio.is_some() {
+        let vpos = vpos_at(segments[], 0.0);
+        builder.add(vpos, BGM(SoundRef(Key(1))));
+    }
+    for &(time, sound) in bgmsamples.iter() {
+        let vpos = vpos_at(segments[], time);
+        builder.add(vpos, BGM(SoundRef(Key(sound as int))));
+        endvpos = endvpos.max(vpos);
+    }
+
+    // the sample set and index of the last timing point at or before the object apply
+    samplepoints.sort_by(|&(a, _, _), &(b, _, _)| a.partial_cmp(&b).unwrap_or(Equal));
+    for (time, endtime, x, hitsound) in hitobjects.into_iter() {
+        let column = (x * ncolumns as f64 / PLAYFIELD_WIDTH).floor().max(0.0) as uint;
+        let lane = columns[cmp::min(column, ncolumns - 1)];
+        let vpos = vpos_at(segments[], time);
+
+        let (tpset, tpindex) =
+            match samplepoints.iter().rev().find(|&&(t, _, _)| t <= time).or(samplepoints.head()) {
+                Some(&(_, set, index)) => (set, index),
+                None => (0, 0),
+            };
+        let normalset = match (hitsound.normalset, tpset) {
+            (0, 0) => defaultset,
+            (0, set) | (set, _) => set,
+        };
+        let additionset = if hitsound.additionset == 0 {normalset} else {hitsound.additionset};
+        let index = if hitsound.index == 0 {tpindex} else {hitsound.index};
+        let keysound = match hitsound.filename {
+            Some(filename) => sound_index(&mut sndpath, filename),
+            None => {
+                for &(flag, sound) in [(2, "whistle"), (4, "finish"), (8, "clap")].iter() {
+                    if hitsound.flags & flag == 0 { continue; }
+                    let name = sample_name(additionset, sound, index);
+                    for &addition in sound_index(&mut sndpath, name).iter() {
+                        builder.add(vpos, BGM(SoundRef(Key(addition as int))));
+                    }
+                }
+                sound_index(&mut sndpath, sample_name(normalset, "normal", index))
+            }
+        };
+        let sref = keysound.map(|keysound| SoundRef(Key(keysound as int)));
+        match endtime {
+            Some(endtime) if endtime > time => {
+                let endvpos_ = vpos_at(segments[], endtime);
+                builder.add(vpos, LNStart(lane, sref));
+                builder.add(endvpos_, LNDone(lane, None));
+                endvpos = endvpos.max(endvpos_);
+            }
+            _ => {
+                builder.add(vpos, Visible(lane, sref));
+                endvpos = endvpos.max(vpos);
+            }
+        }
+    }
+
+    let mut imgpath = vec![None];
+    if background.is_some() {
+        imgpath.push(background.clone());
+        builder.add(0.0, SetBGA(BGALayer::Layer1, BGARef::Image(ImageRef(Key(1)))));
+    }
+
+    // measure bars are placed at every measure up to the end of the chart
+    let nmeasures = endvpos.floor() as uint + 1;
+    for measure in range(0, nmeasures + 1) {
+        builder.add(measure as f64, MeasureBar);
+    }
+    builder.set_end(nmeasures as f64 + 1.0);
+
+    sndpath[mut][1] = audio;
+    let nsounds = sndpath.len();
+    let nimages = imgpath.len();
+    let timeline = builder.build();
+    let meta = BmsMeta {
+        common: Meta { random: false,
+                       title: title, subtitles: subtitles, genre: None,
+                       artist: artist, subartists: subartists, comments: comments,
+                       level: None, difficulty: None },
+        encoding: encoding, stagefile: background, banner: None, basepath: None,
+        mode: mode, lanes: lanes, rank: 2, defexrank: None, total: None, volume: 1.0,
+        sndpath: sndpath, sndtransforms: Vec::from_elem(nsounds, SoundTransform::identity()),
+        imgpath: imgpath, imgcolorkeys: Vec::from_elem(nimages, None),
+        texts: Vec::new(), swbgas: Vec::new(), canvassize: (256, 256),
+    };
+    Ok(Bms { bmspath: None, meta: meta, timeline: timeline })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use encoding::Encoding;
+    use encoding::all::WINDOWS_31J;
+    use format::obj::{Lane, Visible, LNStart, LNDone, BGM, SetMeasureFactor};
+    use format::bms::{Key, MAXKEY, SoundRef, Bms};
+    use format::bms::parse::ParserOptions;
+    use format::bms::testutil::{objs, lane};
+    use super::load_osu;
+
+    fn load(timingpoints: &str, hitobjects: &str) -> Bms {
+        let source = format!("osu file format v14\n\n\
+                              [General]\nAudioFilename: audio.mp3\nSampleSet: Soft\nMode: 3\n\n\
+                              [Difficulty]\nCircleSize:4\n\n\
+                              [TimingPoints]\n{}\n\n\
+                              [HitObjects]\n{}\n", timingpoints, hitobjects);
+        load_osu(&mut BufReader::new(source.as_bytes()), &ParserOptions::new()).unwrap()
+    }
+
+    /// Returns the virtual positions and lanes of every visible object in the timeline.
+    fn notes(bms: &Bms) -> Vec<(f64, Lane)> {
+        objs(bms).into_iter().filter_map(|(vpos, data)| match data {
+            Visible(lane, _) => Some((vpos, lane)),
+            _ => None,
+        }).collect()
+    }
+
+    fn sound(bms: &Bms, name: &str) -> SoundRef {
+        let i = bms.meta.sndpath.iter().position(|p| *p == Some(name.to_string())).unwrap();
+        SoundRef(Key(i as int))
+    }
+
+    #[test]
+    fn test_timing_points() {
+        // the first segment lasts for 1.5 measures, so its last measure is shortened by half
+        let bms = load("0,500,4,2,0,100,1,0\n\
+                        3000,250,4,2,0,100,1,0",
+                       "64,192,1000,1,0,0:0:0:0:\n\
+                        64,192,2500,1,0,0:0:0:0:\n\
+                        64,192,3000,1,0,0:0:0:0:\n\
+                        64,192,3500,1,0,0:0:0:0:");
+        assert!(notes(&bms) == vec![(0.5, lane("11")), (1.5, lane("11")),
+                                    (2.0, lane("11")), (2.5, lane("11"))]);
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, SetMeasureFactor(1.0))));
+        assert!(objs.contains(&(1.0, SetMeasureFactor(0.5))));
+        assert!(objs.contains(&(2.0, SetMeasureFactor(1.0))));
+    }
+
+    #[test]
+    fn test_lead_in() {
+        // the first timing point is half a measure after the audio starts
+        let bms = load("1000,500,4,2,0,100,1,0",
+                       "64,192,0,1,0,0:0:0:0:\n\
+                        64,192,1000,1,0,0:0:0:0:");
+        assert!(notes(&bms) == vec![(0.5, lane("11")), (1.0, lane("11"))]);
+        assert!(objs(&bms).contains(&(0.5, BGM(SoundRef(Key(1))))));
+        assert_eq!(bms.meta.sndpath[1], Some("audio.mp3".to_string()));
+    }
+
+    #[test]
+    fn test_columns() {
+        let bms = load("0,500,4,2,0,100,1,0",
+                       "0,192,0,1,0,0:0:0:0:\n\
+                        128,192,500,1,0,0:0:0:0:\n\
+                        320,192,1000,1,0,0:0:0:0:\n\
+                        448,192,1500,1,0,0:0:0:0:\n\
+                        512,192,2000,1,0,0:0:0:0:");
+        assert!(notes(&bms) == vec![(0.0, lane("11")), (0.25, lane("12")), (0.5, lane("13")),
+                                    (0.75, lane("14")), (1.0, lane("14"))]);
+    }
+
+    #[test]
+    fn test_hold_notes() {
+        let bms = load("0,500,4,2,0,100,1,0",
+                       "192,192,1000,128,0,1500:0:0:0:0:");
+        let objs = objs(&bms);
+        let keysound = Some(sound(&bms, "soft-hitnormal.wav"));
+        assert!(objs.contains(&(0.5, LNStart(lane("12"), keysound))));
+        assert!(objs.contains(&(0.75, LNDone(lane("12"), None))));
+    }
+
+    #[test]
+    fn test_hit_sounds() {
+        // the inherited timing point changes the sample set and index but not the timing
+        let bms = load("0,500,4,0,0,100,1,0\n\
+                        2000,-100,4,3,2,100,0,0",
+                       "64,192,1000,1,2,0:0:0:0:\n\
+                        64,192,1500,1,8,0:1:0:0:\n\
+                        64,192,2500,1,0,0:0:0:0:\n\
+                        64,192,3000,1,4,0:0:0:0:kick.wav");
+        let objs = objs(&bms);
+        let note = |name: &str| Visible(lane("11"), Some(sound(&bms, name)));
+        assert!(objs.contains(&(0.5, note("soft-hitnormal.wav"))));
+        assert!(objs.contains(&(0.5, BGM(sound(&bms, "soft-hitwhistle.wav")))));
+        assert!(objs.contains(&(0.75, note("soft-hitnormal.wav"))));
+        assert!(objs.contains(&(0.75, BGM(sound(&bms, "normal-hitclap.wav")))));
+        assert!(objs.contains(&(1.25, note("drum-hitnormal2.wav"))));
+        assert!(objs.contains(&(1.5, note("kick.wav"))));
+        assert!(!bms.meta.sndpath.iter().any(|p| {
+            p.as_ref().map_or(false, |p| p[].contains("hitfinish"))
+        }));
+    }
+
+    #[test]
+    fn test_legacy_encoding() {
+        let source = b"osu file format v14\n\n\
+                       [General]\nMode: 3\n\n\
+                       [Metadata]\nTitle:Yozakura\n\
+                       TitleUnicode:\x96\xe9\x8d\xf7\x8c\xb6\x91z\x8b\xc8\n\n\
+                       [Difficulty]\nCircleSize:4\n\n\
+                       [TimingPoints]\n0,500,4,2,0,100,1,0\n";
+        let bms = load_osu(&mut BufReader::new(source), &ParserOptions::new()).unwrap();
+        assert_eq!(bms.meta.common.title, Some("夜桜幻想曲".to_string()));
+        assert_eq!(bms.meta.encoding.val0(), WINDOWS_31J.name());
+    }
+
+    #[test]
+    fn test_too_many_sounds() {
+        // notes with sounds beyond the last alphanumeric key become silent
+        let hitobjects: Vec<String> = range(0u, MAXKEY as uint).map(|i| {
+            format!("64,192,{},1,0,0:0:0:0:{}.wav", i * 10, i)
+        }).collect();
+        let bms = load("0,500,4,2,0,100,1,0", hitobjects.connect("\n")[]);
+        assert_eq!(bms.meta.sndpath.len(), MAXKEY as uint);
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, Visible(lane("11"), Some(sound(&bms, "0.wav"))))));
+        let lastvpos = (MAXKEY - 1) as f64 * 10.0 / 2000.0;
+        assert!(objs.contains(&(lastvpos, Visible(lane("11"), None))));
+    }
+}
@@ -0,0 +1,510 @@
+// This is a part of Sonorous.
+// Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+// See README.md and LICENSE.txt for details.
+
+/*!
+ * StepMania chart (`.sm` and `.ssc`) implementation.
+ *
+ * Both formats consist of `#TAG:VALUE;` pairs, where the value may span multiple lines.
+ * Song-wide tags like `#TITLE`, `#MUSIC`, `#OFFSET`, `#BPMS` and `#STOPS` come first, and
+ * a single file contains multiple charts (usually one per difficulty):
+ *
+ * - In `.sm` files, each chart is a single `#NOTES` tag with six colon-separated fields:
+ *   the steps type, the description, the difficulty, the meter, the groove radar values and
+ *   the note data.
+ * - In `.ssc` files, each chart starts with `#NOTEDATA` and has separate `#STEPSTYPE`,
+ *   `#DESCRIPTION`, `#DIFFICULTY`, `#METER` and `#NOTES` tags. A chart may also override
+ *   `#OFFSET`, `#BPMS` and `#STOPS`.
+ *
+ * The note data is a list of measures separated by commas, where each measure is evenly divided
+ * into rows and each row has one character per panel. Only `dance-single` (4 panels) and
+ * `dance-double` (8 panels) charts are supported; charts are indexed in the order of appearance
+ * with unsupported charts skipped.
+ *
+ * The loader produces the same `format::bms::Bms` structure as the BMS loader, with the music
+ * file as a single BGM. Positions are given in beats, so four beats make one measure. The music
+ * starts at `#OFFSET` seconds relative to the beat 0, which is converted to the position following
+ * BPM changes and stops; the chart is delayed by whole measures when the music starts earlier.
+ */
+
+use std::num::Float;
+
+use format::obj::{Lane, BPM, Duration, Damage, BGARef, BGALayer};
+use format::obj::{Visible, LNStart, LNDone, Bomb, BGM, SetBGA, SetBPM, Stop, MeasureBar};
+use format::metadata::{Level, LevelSystem, Difficulty, Meta};
+use format::bms::{Key, ImageRef, SoundRef, SoundTransform, PlayMode, DEFAULT_BPM};
+use format::bms::{BmsMeta, Bms};
+use format::bms::parse::{ParserOptions, decode_with_options};
+
+/// The damage from mines, in the same unit as `Damage::Gauge`.
+const MINE_DAMAGE: Damage = Damage::Gauge(0.06);
+
+/// A single chart in the file.
+struct Chart {
+    /// The steps type (e.g. `dance-single`).
+    stepstype: String,
+    /// The difficulty name (e.g. `Hard`).
+    difficulty: String,
+    /// The chart description, usually the name of the author for edit charts.
+    description: String,
+    /// The meter (numeric difficulty) if any.
+    meter: Option<int>,
+    /// The note data.
+    notes: String,
+    /// Chart-specific `#OFFSET` value in `.ssc` files.
+    offset: Option<String>,
+    /// Chart-specific `#BPMS` value in `.ssc` files.
+    bpms: Option<String>,
+    /// Chart-specific `#STOPS` value in `.ssc` files.
+    stops: Option<String>,
+}
+
+/// Returns the play mode and lanes for each panel for given steps type if supported.
+fn panels_for_steps_type(stepstype: &str) -> Option<(PlayMode, Vec<Lane>)> {
+    let channels = match stepstype {
+        "dance-single" => "11 12 13 14",
+        "dance-double" => "11 12 13 14 21 22 23 24",
+        _ => { return None; }
+    };
+    let mode = if stepstype == "dance-double" {PlayMode::Double} else {PlayMode::Single};
+    let lanes = channels.words().map(|chan| Key::from_str(chan).unwrap().to_lane()).collect();
+    Some((mode, lanes))
+}
+
+/// Splits the file into a list of `#TAG:VALUE;` pairs, in the order of appearance.
+/// Tag names are converted to upper case.
+fn parse_tags(text: &str) -> Vec<(String, String)> {
+    use std::ascii::AsciiExt;
+
+    // strip comments first, as they may contain `#` or `;`
+    let stripped: Vec<&str> = text.lines().map(|line| {
+        match line.find_str("//") { Some(i) => line[..i], None => line }
+    }).collect();
+    let stripped = stripped.connect("\n");
+
+    let mut tags = Vec::new();
+    let mut rest = stripped[];
+    loop {
+        rest = match rest.find('#') { Some(i) => rest[i+1..], None => break };
+        let (tag, value, next) = match (rest.find(':'), rest.find(';')) {
+            (Some(colon), Some(semi)) if colon < semi => (rest[..colon], rest[colon+1..semi],
+                                                          rest[semi+1..]),
+            (Some(colon), None) => (rest[..colon], rest[colon+1..], ""),
+            (_, _) => { continue; }
+        };
+        tags.push((tag.trim().to_ascii_upper(), value.trim().to_string()));
+        rest = next;
+    }
+    tags
+}
+
+/// Groups tags into song-wide tags and charts.
+fn parse_charts(tags: Vec<(String, String)>) -> (Vec<(String, String)>, Vec<Chart>) {
+    let newchart = || Chart { stepstype: String::new(), difficulty: String::new(),
+                              description: String::new(), meter: None, notes: String::new(),
+                              offset: None, bpms: None, stops: None };
+
+    let mut global = Vec::new();
+    let mut charts = Vec::new();
+    let mut current: Option<Chart> = None; // `.ssc` chart being read
+    for (tag, value) in tags.into_iter() {
+        if tag[] == "NOTEDATA" {
+            for chart in current.take().into_iter() { charts.push(chart); }
+            current = Some(newchart());
+            continue;
+        }
+
+        match current {
+            Some(ref mut chart) => match tag[] {
+                "STEPSTYPE" => { chart.stepstype = value; }
+                "DIFFICULTY" => { chart.difficulty = value; }
+                "DESCRIPTION" => { chart.description = value; }
+                "METER" => { chart.meter = from_str::<int>(value[]); }
+                "NOTES" => { chart.notes = value; }
+                "OFFSET" => { chart.offset = Some(value); }
+                "BPMS" => { chart.bpms = Some(value); }
+                "STOPS" => { chart.stops = Some(value); }
+                _ => {}
+            },
+            None => {
+                if tag[] == "NOTES" {
+                    // `.sm` chart: type:description:difficulty:meter:radar:notes
+                    let fields: Vec<&str> = value[].split(':').map(|s| s.trim()).collect();
+                    if fields.len() >= 6 {
+                        let mut chart = newchart();
+                        chart.stepstype = fields[0].to_string();
+                        chart.description = fields[1].to_string();
+                        chart.difficulty = fields[2].to_string();
+                        chart.meter = from_str::<int>(fields[3]);
+                        chart.notes = fields[5].to_string();
+                        charts.push(chart);
+                    }
+                } else {
+                    global.push((tag, value));
+                }
+            }
+        }
+    }
+    for chart in current.take().into_iter() { charts.push(chart); }
+
+    let charts = charts.into_iter().filter(|chart| {
+        panels_for_steps_type(chart.stepstype[]).is_some()
+    }).collect();
+    (global, charts)
+}
+
+/// Parses a list of `beat=value` pairs used by `#BPMS` and `#STOPS`.
+fn parse_beat_values(s: &str) -> Vec<(f64, f64)> {
+    let mut ret = Vec::new();
+    for pair in s.split(',') {
+        let pair: Vec<&str> = pair.split('=').map(|s| s.trim()).collect();
+        if pair.len() != 2 { continue; }
+        match (from_str::<f64>(pair[0]), from_str::<f64>(pair[1])) {
+            (Some(beat), Some(value)) if beat >= 0.0 => { ret.push((beat, value)); }
+            (_, _) => {}
+        }
+    }
+    ret
+}
+
+/// Converts the time in seconds relative to the beat 0 to the beat, following BPM changes and
+/// stops. Times before the beat 0 always use the initial BPM.
+fn beat_at(initbpm: f64, bpms: &[(f64, f64)], stops: &[(f64, f64)], secs: f64) -> f64 {
+    if secs <= 0.0 { return secs * initbpm / 60.0; }
+
+    // (beat, new BPM or 0, stop duration or 0) sorted by the beat
+    let mut events: Vec<(f64, f64, f64)> =
+        bpms.iter().map(|&(beat, bpm)| (beat, bpm, 0.0))
+            .chain(stops.iter().map(|&(beat, secs)| (beat, 0.0, secs.max(0.0)))).collect();
+    events.sort_by(|&(a, _, _), &(b, _, _)| a.partial_cmp(&b).unwrap_or(Equal));
+
+    let mut beat = 0.0;
+    let mut bpm = initbpm;
+    let mut remaining = secs;
+    for &(evbeat, evbpm, evstop) in events.iter() {
+        let elapsed = (evbeat - beat) * 60.0 / bpm;
+        if elapsed >= remaining { break; }
+        remaining -= elapsed;
+        beat = evbeat;
+        if evbpm > 0.0 { bpm = evbpm; }
+        if evstop >= remaining { return beat; }
+        remaining -= evstop;
+    }
+    beat + remaining * bpm / 60.0
+}
+
+/// Converts the difficulty name to the difficulty group.
+fn difficulty_from_name(name: &str) -> Option<Difficulty> {
+    use std::ascii::AsciiExt;
+    match name.to_ascii_lower()[] {
+        "beginner" => Some(Difficulty(1)),
+        "easy" | "basic" | "light" => Some(Difficulty(2)),
+        "medium" | "another" | "trick" | "standard" | "difficult" => Some(Difficulty(3)),
+        "hard" | "ssr" | "maniac" | "heavy" => Some(Difficulty(4)),
+        "challenge" | "smaniac" | "expert" | "oni" => Some(Difficulty(5)),
+        _ => None,
+    }
+}
+
+/// Reads the file from given reader, with the encoding forced or guessed as like BMS files.
+/// Returns the name and confidence of the encoding in addition to the text.
+fn read_text(f: &mut Reader, opts: &ParserOptions) -> (String, (&'static str, f64)) {
+    let (text, encoding, confidence) = decode_with_options(f, opts);
+    let text = if text[].starts_with("\uFEFF") {text[3..].to_string()} else {text};
+    (text, (encoding.name(), confidence))
+}
+
+/// Returns the number of supported charts in the StepMania file from given reader.
+pub fn count_charts(f: &mut Reader, opts: &ParserOptions) -> Result<uint,String> {
+    let (text, _) = read_text(f, opts);
+    let (_, charts) = parse_charts(parse_tags(text[]));
+    Ok(charts.len())
+}
+
+/// Reads the `index`-th supported chart in the StepMania file from given reader.
+pub fn load_stepmania(f: &mut Reader, index: uint, opts: &ParserOptions) -> Result<Bms,String> {
+    use format::timeline::builder::TimelineBuilder;
+
+    let (text, encoding) = read_text(f, opts);
+    let (global, charts) = parse_charts(parse_tags(text[]));
+    let chart = match charts.into_iter().nth(index) {
+        Some(chart) => chart,
+        None => { return Err(format!("no such chart in the file")); }
+    };
+    let (mode, panels) = panels_for_steps_type(chart.stepstype[]).unwrap();
+
+    let mut title = None;
+    let mut subtitles = Vec::new();
+    let mut artist = None;
+    let mut subartists = Vec::new();
+    let mut genre = None;
+    let mut banner = None;
+    let mut background = None;
+    let mut music = None;
+    let mut offset = 0.0;
+    let mut bpms = Vec::new();
+    let mut stops = Vec::new();
+    for (tag, value) in global.into_iter() {
+        if value.is_empty() { continue; }
+        match tag[] {
+            "TITLE" => { title = Some(value); }
+            "SUBTITLE" => { subtitles.push(value); }
+            "ARTIST" => { artist = Some(value); }
+            "CREDIT" => { subartists.push(value); }
+            "GENRE" => { genre = Some(value); }
+            "BANNER" => { banner = Some(value); }
+            "BACKGROUND" => { background = Some(value); }
+            "MUSIC" => { music = Some(value); }
+            "OFFSET" => { offset = from_str::<f64>(value[]).unwrap_or(0.0); }
+            "BPMS" => { bpms = parse_beat_values(value[]); }
+            "STOPS" | "FREEZES" => { stops = parse_beat_values(value[]); }
+            _ => {}
+        }
+    }
+    for value in chart.offset.iter() { offset = from_str::<f64>(value[]).unwrap_or(offset); }
+    for value in chart.bpms.iter() { bpms = parse_beat_values(value[]); }
+    for value in chart.stops.iter() { stops = parse_beat_values(value[]); }
+    if !chart.difficulty.is_empty() { subtitles.push(chart.difficulty.clone()); }
+    if !chart.description.is_empty() { subartists.push(chart.description.clone()); }
+
+    let bpms: Vec<(f64, f64)> = bpms.into_iter().filter(|&(_, bpm)| bpm > 0.0).collect();
+    let BPM(defaultbpm) = DEFAULT_BPM;
+    let initbpm = match bpms.head() {
+        Some(&(beat, bpm)) if beat == 0.0 => bpm,
+        _ => defaultbpm,
+    };
+
+    // the music starts at `offset` seconds relative to the beat 0
+    let musicvpos = beat_at(initbpm, bpms[], stops[], offset) / 4.0;
+    let shift = (-musicvpos).max(0.0).ceil();
+    let beat_to_vpos = |beat: f64| beat / 4.0 + shift;
+
+    let mut builder = TimelineBuilder::new();
+    builder.set_initbpm(BPM(initbpm));
+    let mut endvpos = shift;
+    for &(beat, bpm) in bpms.iter() {
+        builder.add(beat_to_vpos(beat), SetBPM(BPM(bpm)));
+        endvpos = endvpos.max(beat_to_vpos(beat));
+    }
+    for &(beat, secs) in stops.iter() {
+        if secs > 0.0 {
+            builder.add(beat_to_vpos(beat), Stop(Duration::Seconds(secs)));
+            endvpos = endvpos.max(beat_to_vpos(beat));
+        }
+    }
+    if music.is_some() {
+        builder.add(shift + musicvpos, BGM(SoundRef(Key(1))));
+    }
+    if background.is_some() {
+        builder.add(0.0, SetBGA(BGALayer::Layer1, BGARef::Image(ImageRef(Key(1)))));
+    }
+
+    // notes: `1` tap, `2` hold head, `4` roll head (treated as hold), `3` hold/roll tail, `M` mine
+    let mut holding = Vec::from_elem(panels.len(), false);
+    for (measure, rows) in chart.notes[].split(',').enumerate() {
+        let rows: Vec<&str> = rows.words().collect();
+        for (i, row) in rows.iter().enumerate() {
+            let vpos = shift + measure as f64 + i as f64 / rows.len() as f64;
+            for (panel, c) in row.chars().take(panels.len()).enumerate() {
+                let lane = panels[panel];
+                match c {
+                    '1' => { builder.add(vpos, Visible(lane, None)); }
+                    '2' | '4' => {
+                        builder.add(vpos, LNStart(lane, None));
+                        holding[mut][panel] = true;
+                    }
+                    '3' if holding[panel] => {
+                        builder.add(vpos, LNDone(lane, None));
+                        holding[mut][panel] = false;
+                    }
+                    'M' => { builder.add(vpos, Bomb(lane, None, MINE_DAMAGE)); }
+                    _ => { continue; }
+                }
+                endvpos = endvpos.max(vpos);
+            }
+        }
+    }
+
+    // measure bars are placed at every measure up to the end of the chart
+    let nmeasures = endvpos.floor() as uint + 1;
+    for (panel, &held) in holding.iter().enumerate() {
+        if held { builder.add(nmeasures as f64, LNDone(panels[panel], None)); }
+    }
+    for measure in range(0, nmeasures + 1) {
+        builder.add(measure as f64, MeasureBar);
+    }
+    builder.set_end(nmeasures as f64 + 1.0);
+
+    let timeline = builder.build();
+    let meta = BmsMeta {
+        common: Meta { random: false,
+                       title: title, subtitles: subtitles, genre: genre,
+                       artist: artist, subartists: subartists, comments: Vec::new(),
+                       level: chart.meter.map(|v| Level { value: v, system: LevelSystem::Bms }),
+                       difficulty: difficulty_from_name(chart.difficulty[]) },
+        encoding: encoding, stagefile: background.clone(), banner: banner, basepath: None,
+        mode: mode, lanes: Vec::new(), rank: 2, defexrank: None, total: None, volume: 1.0,
+        sndpath: vec![None, music], sndtransforms: Vec::from_elem(2, SoundTransform::identity()),
+        imgpath: vec![None, background], imgcolorkeys: vec![None, None],
+        texts: Vec::new(), swbgas: Vec::new(), canvassize: (256, 256),
+    };
+    Ok(Bms { bmspath: None, meta: meta, timeline: timeline })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use encoding::Encoding;
+    use encoding::all::WINDOWS_31J;
+    use format::obj::{BPM, LNStart, LNDone, Bomb, BGM};
+    use format::bms::{Key, SoundRef, PlayMode, Bms};
+    use format::bms::parse::ParserOptions;
+    use format::bms::testutil::{objs, lane};
+    use super::{MINE_DAMAGE, parse_tags, parse_charts, count_charts, load_stepmania};
+
+    fn load(source: &str, index: uint) -> Bms {
+        let opts = ParserOptions::new();
+        load_stepmania(&mut BufReader::new(source.as_bytes()), index, &opts).unwrap()
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(parse_tags("#TITLE:Foo; // #ARTIST:Bar;\n\
+                         #bpms:0=120\n\
+                         ,4=240; // the comment; with a semicolon\n\
+                         #SUBTITLE:a // b\n\
+                         c;\n\
+                         #GENRE:unterminated"),
+                   vec![("TITLE".to_string(), "Foo".to_string()),
+                        ("BPMS".to_string(), "0=120\n,4=240".to_string()),
+                        ("SUBTITLE".to_string(), "a \nc".to_string()),
+                        ("GENRE".to_string(), "unterminated".to_string())]);
+    }
+
+    #[test]
+    fn test_sm_charts() {
+        let (global, charts) = parse_charts(parse_tags("#TITLE:Foo;\n\
+                                                  #BPMS:0=120;\n\
+                                                  #NOTES:dance-single:Someone:Hard:9:\
+                                                         0.1,0.2,0.3,0.4,0.5:1000;\n\
+                                                  #NOTES:dance-double::Easy:3::10000000;"));
+        assert_eq!(global, vec![("TITLE".to_string(), "Foo".to_string()),
+                                ("BPMS".to_string(), "0=120".to_string())]);
+        assert_eq!(charts.len(), 2);
+        assert_eq!(charts[0].stepstype[], "dance-single");
+        assert_eq!(charts[0].description[], "Someone");
+        assert_eq!(charts[0].difficulty[], "Hard");
+        assert_eq!(charts[0].meter, Some(9));
+        assert_eq!(charts[0].notes[], "1000");
+        assert_eq!(charts[0].bpms, None);
+        assert_eq!(charts[1].stepstype[], "dance-double");
+        assert_eq!(charts[1].notes[], "10000000");
+    }
+
+    #[test]
+    fn test_ssc_charts() {
+        let source = "#TITLE:Foo;\n\
+                      #OFFSET:0;\n\
+                      #BPMS:0=120;\n\
+                      #NOTEDATA:;\n\
+                      #STEPSTYPE:dance-single;\n\
+                      #DIFFICULTY:Challenge;\n\
+                      #METER:12;\n\
+                      #BPMS:0=240;\n\
+                      #OFFSET:-1;\n\
+                      #NOTES:1000;\n\
+                      #NOTEDATA:;\n\
+                      #STEPSTYPE:dance-double;\n\
+                      #NOTES:10000000;\n";
+        let (global, charts) = parse_charts(parse_tags(source));
+        assert_eq!(global.len(), 3);
+        assert_eq!(charts.len(), 2);
+        assert_eq!(charts[0].difficulty[], "Challenge");
+        assert_eq!(charts[0].meter, Some(12));
+        assert_eq!(charts[0].bpms, Some("0=240".to_string()));
+        assert_eq!(charts[0].offset, Some("-1".to_string()));
+        assert_eq!(charts[1].bpms, None);
+
+        // the chart-level #BPMS and #OFFSET override song-wide ones:
+        // the music starts one second (one measure at 240 BPM) before the beat 0
+        let source = format!("#MUSIC:song.ogg;\n{}", source);
+        let bms = load(source[], 0);
+        assert_eq!(bms.timeline.initbpm, BPM(240.0));
+        assert!(objs(&bms).contains(&(0.0, BGM(SoundRef(Key(1))))));
+        let bms = load(source[], 1);
+        assert_eq!(bms.timeline.initbpm, BPM(120.0));
+        assert!(objs(&bms).contains(&(0.0, BGM(SoundRef(Key(1))))));
+    }
+
+    #[test]
+    fn test_holds_and_mines() {
+        let bms = load("#BPMS:0=120;\n\
+                        #NOTES:dance-single::Hard:9::\n\
+                        2000\n\
+                        0400\n\
+                        3300\n\
+                        M000\n\
+                        ,\n\
+                        0020\n\
+                        0000;", 0);
+        let objs = objs(&bms);
+        assert!(objs.contains(&(0.0, LNStart(lane("11"), None))));
+        assert!(objs.contains(&(0.25, LNStart(lane("12"), None))));
+        assert!(objs.contains(&(0.5, LNDone(lane("11"), None))));
+        assert!(objs.contains(&(0.5, LNDone(lane("12"), None))));
+        assert!(objs.contains(&(0.75, Bomb(lane("11"), None, MINE_DAMAGE))));
+
+        // the unterminated hold is closed at the end of the chart
+        assert!(objs.contains(&(1.0, LNStart(lane("13"), None))));
+        assert!(objs.contains(&(2.0, LNDone(lane("13"), None))));
+    }
+
+    #[test]
+    fn test_count_charts() {
+        let source = "#NOTES:dance-single::Easy:3::1000;\n\
+                      #NOTES:pump-single::Easy:3::10000;\n\
+                      #NOTES:dance-couple::Easy:3::10000000;\n\
+                      #NOTES:dance-double::Easy:3::10000000;\n\
+                      #NOTES:dance-single:too few fields;\n";
+        assert_eq!(count_charts(&mut BufReader::new(source.as_bytes()), &ParserOptions::new()),
+                   Ok(2));
+
+        // indices skip unsupported charts
+        let bms = load(source, 1);
+        assert!(bms.meta.mode == PlayMode::Double);
+    }
+
+    #[test]
+    fn test_legacy_encoding() {
+        let source = b"#TITLE:\x96\xe9\x8d\xf7\x8c\xb6\x91z\x8b\xc8;\n\
+                       #NOTES:dance-single::Easy:3::1000;";
+        let bms = load_stepmania(&mut BufReader::new(source), 0, &ParserOptions::new()).unwrap();
+        assert_eq!(bms.meta.common.title, Some("夜桜幻想曲".to_string()));
+        assert_eq!(bms.meta.encoding.val0(), WINDOWS_31J.name());
+    }
+
+    #[test]
+    fn test_offset() {
+        // two seconds for four beats at 120 BPM, then one second for four beats at 240 BPM
+        let bms = load("#MUSIC:song.ogg;\n\
+                        #OFFSET:3;\n\
+                        #BPMS:0=120,4=240;\n\
+                        #NOTES:dance-single::Easy:3::1000;", 0);
+        assert!(objs(&bms).contains(&(2.0, BGM(SoundRef(Key(1))))));
+
+        // two beats at 120 BPM, a half-second stop, then three beats at 120 BPM
+        let bms = load("#MUSIC:song.ogg;\n\
+                        #OFFSET:3;\n\
+                        #BPMS:0=120;\n\
+                        #STOPS:2=0.5;\n\
+                        #NOTES:dance-single::Easy:3::1000;", 0);
+        assert!(objs(&bms).contains(&(1.25, BGM(SoundRef(Key(1))))));
+
+        // the music starting earlier delays the chart by whole measures
+        let bms = load("#MUSIC:song.ogg;\n\
+                        #OFFSET:-1;\n\
+                        #BPMS:0=120;\n\
+                        #NOTES:dance-single::Easy:3::1000;", 0);
+        assert!(objs(&bms).contains(&(0.5, BGM(SoundRef(Key(1))))));
+    }
+}
@@ -109,7 +109,9 @@ pub mod builder {
     use std::f64;
     use format::obj::{Lane, NLANES, Obj, ObjLoc, BPM, BGALayer};
     use format::obj::{ObjData, Deleted, Visible, Invisible, LNStart, LNDone, Bomb};
-    use format::obj::{BGM, SetBGA, SetBPM, Stop, StopEnd, SetMeasureFactor, End, MeasureBar};
+    use format::obj::{BGM, SetBGA, SetBGAOpacity, SetBGAColorKey, SetBPM, Stop, StopEnd};
+    use format::obj::{SetSwitchBGA, SetVolume, SetText, SetMeasureFactor, SetGradeFactor};
+    use format::obj::{End, MeasureBar};
     use format::obj::{ObjQueryOps, ObjConvOps};
     use super::Timeline;
 
@@ -126,9 +128,10 @@ pub mod builder {
     fn classify<S,I>(obj: &ObjData<S,I>) -> int {
         match *obj {
             Deleted | StopEnd | End => -1, // should be removed
-            SetMeasureFactor(..) | MeasureBar => 0,
+            SetMeasureFactor(..) | SetGradeFactor(..) | SetVolume(..) | MeasureBar => 0,
             Visible(..) | Invisible(..) | LNStart(..) | LNDone(..) | Bomb(..) |
-                BGM(..) | SetBGA(..) => 1,
+                BGM(..) | SetBGA(..) | SetBGAOpacity(..) | SetBGAColorKey(..) |
+                SetSwitchBGA(..) | SetText(..) => 1,
             SetBPM(..) => 2,
             Stop(..) => 3,
         }
@@ -265,8 +268,15 @@ pub mod builder {
                            SetBGA(BGALayer::Layer2,_) => Some(1),
                            SetBGA(BGALayer::Layer3,_) => Some(2),
                            SetBGA(BGALayer::PoorBGA,_) => Some(3),
-                           SetBPM(..) => Some(4),
-                           Stop(..) => Some(5),
+                           SetBGA(BGALayer::Switch,_) => Some(4),
+                           SetBPM(..) => Some(5),
+                           Stop(..) => Some(6),
+                           SetGradeFactor(..) => Some(7),
+                           SetBGAOpacity(layer,_) => Some(8 + layer as uint),
+                           SetBGAColorKey(layer,_) => Some(13 + layer as uint),
+                           SetVolume(group,_) => Some(18 + group as uint),
+                           SetText(..) => Some(20),
+                           SetSwitchBGA(..) => Some(21),
                            _ => None,
                        },
                  |types| types);
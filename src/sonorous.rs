@@ -88,6 +88,9 @@ pub mod format {
     pub mod timeline;
     pub mod pointer;
     pub mod bms;
+    pub mod bmson;
+    pub mod osu;
+    pub mod stepmania;
 }
 
 pub mod engine {
@@ -123,7 +126,8 @@ pub fn exename() -> String {
 }
 
 /// Dumps the recognized BMS commands. This is used by `-Z dump-bmscommand[-full]` debug options.
-pub fn dump_bmscommand(bmspath: &Path, full: bool,
+/// `seed` is used for preprocessing when `full` is not set.
+pub fn dump_bmscommand(bmspath: &Path, full: bool, seed: u64,
                        parseropts: &format::bms::parse::ParserOptions,
                        callback: |line: Option<uint>, msg: format::bms::diag::BmsMessage| -> bool) {
     use format::bms::diag::BmsMessage;
@@ -151,7 +155,7 @@ pub fn dump_bmscommand(bmspath: &Path, full: bool,
             print_command(parsed, |line, msg| callback(line, msg));
         }
     } else {
-        let mut r = std::rand::task_rng();
+        let mut r = util::std::rand::seeded_rng(seed);
         for parsed in PreprocessingParser::new(&mut f, &mut r, parseropts).iter() {
             print_command(parsed, |line, msg| callback(line, msg));
         }
@@ -160,7 +164,7 @@ pub fn dump_bmscommand(bmspath: &Path, full: bool,
 
 /// Parses the BMS file, initializes the display, shows the loading screen and runs the game play
 /// loop.
-pub fn play(bmspath: &Path, opts: ui::options::Options) {
+pub fn play(bmspath: &Path, mut opts: ui::options::Options) {
     use std::rc::Rc;
     use std::cell::RefCell;
     use std::collections::HashMap;
@@ -185,22 +189,24 @@ pub fn play(bmspath: &Path, opts: ui::options::Options) {
         scene = SelectingScene::new(screen, bmspath, wrap_opts(opts)) as Box<Scene>
     } else {
         if opts.debug_dumpbmscommand || opts.debug_dumpbmscommandfull {
-            dump_bmscommand(bmspath, opts.debug_dumpbmscommandfull,
-                            &opts.loader_options().parser, print_diag);
+            dump_bmscommand(bmspath, opts.debug_dumpbmscommandfull, opts.seed_or_random(),
+                            &opts.loader_options().parser,
+                            |line, msg| print_diag(&opts, line, msg));
             ui::common::exit(0);
         }
 
         // parses the file and sanitizes it
-        let mut r = std::rand::task_rng();
         let preproc = match std::io::File::open(bmspath) {
-            Ok(mut f) => preprocess_bms(bmspath, &mut f, &opts,
-                                        &mut r, &opts.loader_options(), print_diag),
+            Ok(mut f) => preprocess_bms(bmspath, None, &mut f, &opts, opts.seed_or_random(),
+                                        &opts.loader_options(),
+                                        |line, msg| print_diag(&opts, line, msg)),
             Err(err) => Err(err.to_string()),
         };
-        let PreprocessedBms { bms, infos, keyspec } = match preproc {
+        let PreprocessedBms { bms, infos, keyspec, seed } = match preproc {
             Ok(preproc) => preproc,
             Err(err) => die!("Couldn't load BMS file: {}", err)
         };
+        opts.seed = Some(seed); // so that the game play can report the seed actually used
 
         if opts.debug_dumptimeline {
             let _ = bms.timeline.dump(&mut std::io::stdout());
@@ -247,7 +253,9 @@ pub fn usage() {
 https://github.com/snrs/sonorous/
 
 Usage: {prog} <options> <path>
-  Accepts any BMS, BME, BML or PMS file or the directory path.
+  Accepts any BMS, BME, BML, PMS, bmson, osu!mania (.osu) or StepMania
+  (.sm/.ssc) file or the directory path. For StepMania files the first
+  supported chart is played unless selected from the directory path.
   The directory path enables the song/pattern selection mode,
   where game play options are used as the initial settings.
 
@@ -268,6 +276,8 @@ Options:
   -S, --shuffle-ex        Uses a shuffle modifier, even for scratches
   -r, --random            Uses a random modifier
   -R, --random-ex         Uses a random modifier, even for scratches
+  -G TYPE, --gauge TYPE   Sets the gauge type: easy, normal (default),
+                          hard, ex-hard or hazard
   -k NAME, --preset NAME  Forces a use of given key preset (default: bms)
   -K LEFT RIGHT, --key-spec LEFT RIGHT
                           Sets a custom key specification (see the manual)
@@ -276,10 +286,22 @@ Options:
   -M, --no-movie          Do not load and show the BGA movie
   -j N, --joystick N      Enables the joystick with index N (normally 0)
   -E ENCODING             Forces the use of specified encoding
+  -C PATH, --chardet-root PATH
+                          Sets the encoding detection models path
+                          (default: <root>/res/chardet)
+  -W RULES, --diag RULES  Adjusts diagnostic messages with comma-separated
+                          rules: ID=ignore|note|warning|fatal, or
+                          abort-on-fatal to stop loading at fatal messages
+  -F PATH, --diag-file PATH
+                          Reads diagnostics rules from the file, one per line
+  -L LANG, --lang LANG    Sets the language for diagnostic messages: en, ko
+                          or ja (default: from LC_ALL, LC_MESSAGES or LANG)
   -D PATH, --database-root PATH
                           Sets the database path which should be writable
   -Y PATH, --skin-root PATH
                           Sets the skin lookup path (default: <root>/res/skin)
+  -e N, --seed N          Sets the random seed for #RANDOM and modifiers, so
+                          that the same pattern can be played again
   -Z OPTION               Enables the specified debugging option
 
 Environment Variables:
@@ -309,11 +331,14 @@ pub fn subprogram(args: &[String]) -> ! {
             let _ = write!(&mut std::io::stderr(), "\
 The list of available subprograms:
   chardet-train         Trains a character encoding detection algorithm.
+  bms-lint              Reports every diagnostic message from BMS files.
 
 ");
             0
         }
         Some("chardet-train") => util::chardet::chardet_train(args.tail()),
+        Some("bms-lint") =>
+            format::bms::lint::bms_lint(args.tail(), engine::resource::new_resource_resolver),
         Some(prog) => {
             let _ = write!(&mut std::io::stderr(), "Subprogram {} is unknown.", prog);
             1
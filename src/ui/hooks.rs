@@ -14,6 +14,7 @@ use gfx::skin::hook::Hook;
 define_hooks! {
     for options::Options |opts, id, parent, body| {
         scalar "opts.playspeed" => opts.playspeed.into_scalar();
+        scalar "opts.seed" => return opts.seed.map(|seed| seed.into_scalar());
 
         block "opts.autoplay" => opts.is_autoplay() && body(parent, "");
         block "opts.modifier" => match opts.modf {
@@ -24,10 +25,18 @@ define_hooks! {
             Some(options::Modifier::RandomEx)  => { body(parent, "random-ex"); }
             None => {}
         };
+        block "opts.gaugetype" => match opts.gaugetype {
+            options::GaugeType::Easy   => { body(parent, "easy"); }
+            options::GaugeType::Normal => { body(parent, "normal"); }
+            options::GaugeType::Hard   => { body(parent, "hard"); }
+            options::GaugeType::ExHard => { body(parent, "ex-hard"); }
+            options::GaugeType::Hazard => { body(parent, "hazard"); }
+        };
         block "opts.hasbga" => opts.has_bga() && body(parent, "");
         block "opts.hasmovie" => opts.has_movie() && body(parent, "");
         block "opts.showinfo" => opts.showinfo && body(parent, "");
         block "opts.fullscreen" => opts.fullscreen && body(parent, "");
+        block "opts.seed" => opts.seed.is_some() && body(parent, "");
     }
 }
 
@@ -59,6 +68,7 @@ define_hooks! {
         scalar "meta.difficulty" =>
             return meta.difficulty.map(|metadata::Difficulty(diff)| diff.into_scalar());
 
+        block "meta.random" => meta.random && body(parent, "");
         block "meta.title" => meta.title.is_some() && body(parent, "");
         block "meta.subtitle" =>
             meta.subtitles.iter().all(|s|
@@ -146,11 +156,11 @@ define_hooks! {
         scalar "player.score" => player.score.into_scalar();
         scalar "player.lastcombo" => player.lastcombo.into_scalar();
         scalar "player.bestcombo" => player.bestcombo.into_scalar();
-        scalar "player.gauge" =>
-            (player.gauge as f64 / player::MAXGAUGE as f64).into_scalar();
-        scalar "player.survival" =>
-            (player.survival as f64 / player::MAXGAUGE as f64).into_scalar();
-        block "player.survival" => player.gauge >= player.survival && body(parent, "");
+        scalar "player.gauge" => (player.gauge / player::MAXGAUGE as f64).into_scalar();
+        scalar "player.survival" => (player.survival / player::MAXGAUGE as f64).into_scalar();
+        scalar "player.total" => player.total.into_scalar();
+        scalar "player.text" => return player.text().map(|s| s.into_scalar());
+        block "player.survival" => player.survives() && body(parent, "");
         block "player.grades" => {
             static GRADENAMES: [&'static str, ..5] = ["cool", "great", "good", "bad", "miss"];
             GRADENAMES.iter().zip(player.gradecounts.iter().rev()).all(|(&name, &count)|
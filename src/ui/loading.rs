@@ -19,9 +19,9 @@ use gfx::screen::Screen;
 use gfx::skin::render::Renderer;
 use engine::input::KeyMap;
 use engine::keyspec::KeySpec;
-use engine::resource::{Soundlike, LoadedSoundlike, Imagelike, LoadedImagelike};
+use engine::resource::{Soundlike, SoundSource, Imagelike, LoadedImagelike};
 use engine::resource::{SearchContextAdditions};
-use engine::player::Player;
+use engine::player::{Player, image_layer_color_keys};
 use ui::common::{update_line};
 use ui::options::Options;
 use ui::scene::{Scene, SceneOptions, SceneCommand};
@@ -50,6 +50,8 @@ pub struct LoadingContext {
 
     /// The most recently loaded file name from the resource loader.
     pub lastpath: Option<String>,
+    /// The most recently decoded sound file, kept while the next job loads the same file.
+    pub lastsound: Option<(String, SoundSource)>,
     /// Context for searching files.
     pub search: SearchContext,
     /// A list of jobs to be executed.
@@ -65,6 +67,8 @@ pub struct LoadingContext {
     pub sndres: Vec<Soundlike>,
     /// A list of loaded image resources. Initially populated with `Imagelike::None`.
     pub imgres: Vec<Imagelike>,
+    /// Color keys of BGA layers each image can be displayed in.
+    pub layerkeys: Vec<Vec<(u8,u8,u8)>>,
 }
 
 impl LoadingContext {
@@ -74,6 +78,7 @@ impl LoadingContext {
         let basedir = bms.meta.basepath.clone().unwrap_or(Path::new("."));
         let sndres = Vec::from_fn(bms.meta.sndpath.len(), |_| Soundlike::None);
         let imgres = Vec::from_fn(bms.meta.imgpath.len(), |_| Imagelike::None);
+        let layerkeys = image_layer_color_keys(&bms);
 
         let mut jobs = DList::new();
         if opts.has_bga() { // should go first
@@ -91,8 +96,10 @@ impl LoadingContext {
 
         LoadingContext {
             opts: opts, bms: bms, infos: infos, keyspec: keyspec, keymap: keymap,
-            lastpath: None, search: SearchContext::new(), jobs: jobs, ntotaljobs: njobs,
+            lastpath: None, lastsound: None, search: SearchContext::new(),
+            jobs: jobs, ntotaljobs: njobs,
             basedir: basedir, stagefile: None, sndres: sndres, imgres: imgres,
+            layerkeys: layerkeys,
         }
     }
 
@@ -104,10 +111,10 @@ impl LoadingContext {
         };
         let fullpath = self.search.resolve_relative_path_for_image(path[], &self.basedir);
 
-        let res = fullpath.and_then(|path| LoadedImagelike::new(&path, false));
+        let res = fullpath.and_then(|path| LoadedImagelike::new(&path, false, None, [][]));
         let tex_or_err = res.and_then(|res| {
             match res {
-                LoadedImagelike::Image(surface) => {
+                LoadedImagelike::Image(surface, _) => {
                     // in principle we don't need this, but some STAGEFILEs mistakenly uses alpha
                     // channel or SDL_image fails to read them so we need to force STAGEFILEs to
                     // ignore alpha channels. (cf. http://bugzilla.libsdl.org/show_bug.cgi?id=1943)
@@ -124,15 +131,37 @@ impl LoadingContext {
         }
     }
 
-    /// Loads the sound and creates a `Chunk` for it.
+    /// Loads the sound and creates a `Chunk` for it. The decoded sound file is reused when
+    /// consecutive jobs refer to the same file, as in sliced bmson sound channels.
     pub fn load_sound(&mut self, i: uint) {
         let path = self.bms.meta.sndpath[i].as_ref().unwrap().clone();
         self.lastpath = Some(path.clone());
-        let fullpath = self.search.resolve_relative_path_for_sound(path[], &self.basedir);
 
-        match fullpath.and_then(|path| LoadedSoundlike::new(&path)) {
-            Ok(res) => {
-                self.sndres[mut][i] = res.wrap();
+        let cached = match self.lastsound.take() {
+            Some((lastpath, source)) => if lastpath == path {Some(source)} else {None},
+            None => None,
+        };
+        let source = match cached {
+            Some(source) => Ok(source),
+            None => {
+                let fullpath = self.search.resolve_relative_path_for_sound(path[], &self.basedir);
+                fullpath.and_then(|path| SoundSource::new(&path))
+            }
+        };
+        let reused = match self.jobs.front() {
+            Some(&LoadingJob::LoadSound(j)) => self.bms.meta.sndpath[j] == self.bms.meta.sndpath[i],
+            _ => false,
+        };
+
+        let transform = &self.bms.meta.sndtransforms[i];
+        match source {
+            Ok(source) => {
+                if reused {
+                    self.sndres[mut][i] = source.transform(transform).wrap();
+                    self.lastsound = Some((path, source));
+                } else {
+                    self.sndres[mut][i] = source.into_soundlike(transform).wrap();
+                }
             }
             Err(_) => {
                 warn!("failed to load sound #WAV{} ({})", Key(i as int), path);
@@ -147,7 +176,10 @@ impl LoadingContext {
         let fullpath = self.search.resolve_relative_path_for_image(path[], &self.basedir);
 
         let has_movie = self.opts.has_movie();
-        match fullpath.and_then(|path| LoadedImagelike::new(&path, has_movie)) {
+        let colorkey = self.bms.meta.imgcolorkeys[i];
+        let layerkeys = self.layerkeys[i].clone();
+        match fullpath.and_then(|path| LoadedImagelike::new(&path, has_movie, colorkey,
+                                                            layerkeys[])) {
             Ok(res) => {
                 self.imgres[mut][i] = res.wrap();
             }
@@ -5,10 +5,14 @@
 //! Global game options.
 
 use std::{io, os};
+use std::rand::{task_rng, Rng};
 use std::collections::HashMap;
 use encoding::label::encoding_from_whatwg_label;
 
 use format::bms::load::LoaderOptions;
+use format::bms::encoding::ChardetModels;
+use format::bms::diag::DiagPolicy;
+use format::bms::catalog::Language;
 use gfx::skin::ast::Skin;
 use gfx::skin::parse::load_skin;
 use engine::cache::MetadataCache;
@@ -44,6 +48,24 @@ pub enum Modifier {
     RandomEx
 }
 
+/// Gauge types, which determine how the health gauge changes and when the game play fails.
+/// See `player::gauge_rules` for the detailed rules.
+#[deriving(PartialEq,Eq,Clone)]
+pub enum GaugeType {
+    /// Same as `Normal`, but the gauge recovers faster and the damage is smaller.
+    Easy,
+    /// The gauge starts at 50% and the song is cleared if the gauge is no less than the survival
+    /// line at the end.
+    Normal,
+    /// The gauge starts at 100% and recovers very slowly, and the game play fails immediately
+    /// when the gauge reaches zero.
+    Hard,
+    /// Same as `Hard`, but the damage is much larger.
+    ExHard,
+    /// Same as `Hard`, but any BAD or MISS grade results in the instant failure.
+    Hazard
+}
+
 /// Specifies how the BGA is displayed.
 #[deriving(PartialEq,Eq,Clone)]
 pub enum Bga {
@@ -63,6 +85,11 @@ pub struct Options {
     pub mode: Mode,
     /// Modifiers that affect the game data.
     pub modf: Option<Modifier>,
+    /// A seed to the random number generator used for #RANDOM/#SWITCH commands and modifiers.
+    /// A fresh seed is chosen for each loading if not given.
+    pub seed: Option<u64>,
+    /// Gauge type.
+    pub gaugetype: GaugeType,
     /// Specifies how the BGA is displayed.
     pub bga: Bga,
     /// True if the metadata (either overlaid in the loading screen or printed separately
@@ -82,6 +109,12 @@ pub struct Options {
     pub playspeed: f64,
     /// A character encoding *name* forced to the loader.
     pub encoding: Option<String>,
+    /// Classifiers for the automatic encoding detection.
+    pub chardet: ChardetModels,
+    /// Adjustments to the diagnostic messages from the loader.
+    pub diagpolicy: DiagPolicy,
+    /// The language for diagnostic messages.
+    pub lang: Language,
     /// A root path to the data files. This is used to normalize the cached path.
     pub dataroot: Path,
     /// A root path to the skin.
@@ -114,11 +147,17 @@ impl Options {
     /// Returns true if the graphical screen is enabled.
     pub fn has_screen(&self) -> bool { !self.is_exclusive() || self.has_bga() }
 
+    /// Returns the seed to be used for the next loading, choosing a fresh one if not given.
+    pub fn seed_or_random(&self) -> u64 {
+        self.seed.unwrap_or_else(|| task_rng().gen())
+    }
+
     /// Returns loader options.
     pub fn loader_options(&self) -> LoaderOptions {
         let mut loaderopts = LoaderOptions::new();
         loaderopts.parser.force_encoding =
             self.encoding.as_ref().and_then(|s| encoding_from_whatwg_label(s[]));
+        loaderopts.parser.chardet = self.chardet.clone();
         loaderopts
     }
 
@@ -165,11 +204,13 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
         ("--windowed", 'w'), ("--no-fullscreen", 'w'),
         ("--fullscreen", ' '), ("--info", ' '), ("--no-info", 'q'),
         ("--mirror", 'm'), ("--shuffle", 's'), ("--shuffle-ex", 'S'),
-        ("--random", 'r'), ("--random-ex", 'R'), ("--preset", 'k'),
+        ("--random", 'r'), ("--random-ex", 'R'), ("--gauge", 'G'), ("--preset", 'k'),
         ("--key-spec", 'K'), ("--bga", ' '), ("--no-bga", 'B'),
         ("--movie", ' '), ("--no-movie", 'M'), ("--joystick", 'j'),
-        ("--encoding", 'E'), ("--database-root", 'D'), ("--skin-root", 'Y'),
-        ("--debug", 'Z')
+        ("--encoding", 'E'), ("--chardet-root", 'C'), ("--database-root", 'D'),
+        ("--skin-root", 'Y'),
+        ("--seed", 'e'), ("--diag", 'W'), ("--diag-file", 'F'),
+        ("--lang", 'L'), ("--debug", 'Z')
     ].into_iter().collect();
 
     let nargs = args.len();
@@ -178,6 +219,8 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
     let mut bmspath = None;
     let mut mode = Mode::Play;
     let mut modf = None;
+    let mut seed = None;
+    let mut gaugetype = GaugeType::Normal;
     let mut bga = Bga::WithMovie;
     let mut showinfo = true;
     let mut fullscreen = true;
@@ -187,7 +230,10 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
     let mut rightkeys = None;
     let mut playspeed = 1.0;
     let mut encoding = None;
+    let mut diagpolicy = DiagPolicy::new();
+    let mut lang = None;
     let mut skinroot = selforcwd.join_many(["res", "skin"][]);
+    let mut chardetroot = ChardetModels::default_root();
     let mut dataroot = selforcwd.clone();
     let mut metadatacache = None;
     let mut debug_dumpbmscommandfull = false;
@@ -258,6 +304,14 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
                     'S' => { modf = Some(Modifier::ShuffleEx); }
                     'r' => { modf = Some(Modifier::Random); }
                     'R' => { modf = Some(Modifier::RandomEx); }
+                    'G' => match fetch_arg!('G') {
+                        "easy" => { gaugetype = GaugeType::Easy; }
+                        "normal" => { gaugetype = GaugeType::Normal; }
+                        "hard" => { gaugetype = GaugeType::Hard; }
+                        "ex-hard" => { gaugetype = GaugeType::ExHard; }
+                        "hazard" => { gaugetype = GaugeType::Hazard; }
+                        arg => error!("Invalid gauge type: {}", arg)
+                    },
                     'k' => { preset = Some(fetch_arg!('k').to_string()); }
                     'K' => { leftkeys = Some(fetch_arg!('K').to_string());
                              rightkeys = Some(fetch_arg!('K').to_string()); }
@@ -271,6 +325,12 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
                             _ => error!("Invalid argument to option -a")
                         }
                     }
+                    'e' => {
+                        match from_str::<u64>(fetch_arg!('e')) {
+                            Some(n) => { seed = Some(n); }
+                            _ => error!("Invalid argument to option -e")
+                        }
+                    }
                     'B' => { bga = Bga::None; }
                     'M' => { bga = Bga::WithoutMovie; }
                     'j' => {
@@ -287,6 +347,25 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
                             None => error!("Invalid encoding name: {}", arg)
                         }
                     }
+                    'W' => {
+                        match diagpolicy.add_rules(fetch_arg!('W')) {
+                            Ok(()) => {}
+                            Err(err) => error!("{}", err)
+                        }
+                    }
+                    'F' => {
+                        match diagpolicy.add_rules_from_file(&Path::new(fetch_arg!('F'))) {
+                            Ok(()) => {}
+                            Err(err) => error!("{}", err)
+                        }
+                    }
+                    'L' => {
+                        let arg = fetch_arg!('L');
+                        match Language::from_locale(arg) {
+                            Some(l) => { lang = Some(l); }
+                            None => error!("Unsupported language: {}", arg)
+                        }
+                    }
                     'D' => {
                         let arg = fetch_arg!('D');
                         match Path::new_opt(arg[]) {
@@ -304,6 +383,13 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
                             None => error!("Invalid skin path: {}", arg)
                         }
                     }
+                    'C' => {
+                        let arg = fetch_arg!('C');
+                        match Path::new_opt(arg[]) {
+                            Some(path) => { chardetroot = path; }
+                            None => error!("Invalid encoding detection models path: {}", arg)
+                        }
+                    }
                     'Z' => match fetch_arg!('Z') {
                         "dump-bmscommand-full" => { debug_dumpbmscommandfull = true; }
                         "dump-bmscommand" => { debug_dumpbmscommand = true; }
@@ -320,6 +406,11 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
         i += 1;
     }
 
+    let chardet = match ChardetModels::load(&chardetroot) {
+        Ok(chardet) => chardet,
+        Err(err) => error!("Couldn't load encoding detection models: {}", err)
+    };
+
     // shows a file dialog if the path to the BMS file is missing and the system supports it
     if bmspath.is_none() {
         bmspath = get_path();
@@ -330,6 +421,8 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
         Some(bmspath) => ParsingResult::PathAndOptions(bmspath, Options {
             mode: mode,
             modf: modf,
+            seed: seed,
+            gaugetype: gaugetype,
             bga: bga,
             showinfo: showinfo,
             fullscreen: fullscreen,
@@ -338,6 +431,9 @@ pub fn parse_opts(args: &[String], get_path: || -> Option<Path>) -> ParsingResul
             leftkeys: leftkeys, rightkeys: rightkeys,
             playspeed: playspeed,
             encoding: encoding,
+            chardet: chardet,
+            diagpolicy: diagpolicy,
+            lang: lang.unwrap_or_else(|| Language::from_env(os::getenv)),
             dataroot: dataroot,
             skinroot: skinroot,
             metadatacache: metadatacache,
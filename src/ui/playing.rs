@@ -286,7 +286,7 @@ impl PlayingScene {
         let bgax = leftmost + (centerwidth - BGAW) / 2;
         let bgay = (SCREENH - BGAH) / 2;
         let sprite = create_sprite(leftmost, rightmost, styles[]);
-        let bgacanvas = BGACanvas::new(imgres[]);
+        let bgacanvas = BGACanvas::new(imgres[], player.meta.canvassize);
 
         Ok(box PlayingScene {
             player: player, sprite: sprite, screen: screen, imgres: imgres,
@@ -357,10 +357,11 @@ impl Scene for PlayingScene {
         // render BGAs (should render before the lanes since lanes can overlap with BGAs)
         if self.player.opts.has_bga() {
             static POOR_LAYERS: [BGALayer, ..1] = [BGALayer::PoorBGA];
-            static NORM_LAYERS: [BGALayer, ..3] = [BGALayer::Layer1, BGALayer::Layer2,
-                                                   BGALayer::Layer3];
+            static NORM_LAYERS: [BGALayer, ..4] = [BGALayer::Layer1, BGALayer::Layer2,
+                                                   BGALayer::Layer3, BGALayer::Switch];
             let layers = if self.poorlimit.is_some() {POOR_LAYERS[]} else {NORM_LAYERS[]};
             self.bgacanvas.render_to_texture(screen.deref_mut(), layers);
+            // the canvas is scaled to the BGA area
             screen.draw_textured(self.bgacanvas.as_texture(), |d| {
                 d.rect(self.bgax as f32, self.bgay as f32,
                        (self.bgax + BGAW) as f32, (self.bgay + BGAH) as f32);
@@ -496,6 +497,17 @@ impl Scene for PlayingScene {
                              Gradient { zero: RGB(0xc0,0xc0,0xc0), one: RGB(0x40,0x40,0x40) });
                 }
             }
+
+            // render the text event (#TEXT/#SONG) below the BGA
+            match self.player.text() {
+                Some(text) => {
+                    let cx = (self.bgax + BGAW / 2) as f32;
+                    let cy = (self.bgay + BGAH + 4) as f32;
+                    d.string(cx, cy, 1.0, Alignment::Center, text,
+                             Gradient { zero: RGB(0xff,0xff,0xff), one: RGB(0xc0,0xc0,0xc0) });
+                }
+                None => {}
+            }
         });
 
         screen.draw_textured(&self.sprite, |d| {
@@ -534,10 +546,11 @@ impl Scene for PlayingScene {
                 d.rect(4.0, H-12.0, 360.0, H-4.0, black);
 
                 // cycles four times per measure, [0,40)
-                let width = if self.player.gauge < 0 {0}
-                            else {self.player.gauge * 400 / MAXGAUGE - (beat * 40.0) as int};
+                let width = if self.player.gauge < 0.0 {0}
+                            else {(self.player.gauge * 400.0 / MAXGAUGE as f64) as int -
+                                  (beat * 40.0) as int};
                 let width = cmp::min(cmp::max(width, 5), 360);
-                let color = if self.player.gauge >= self.player.survival {RGB(0xc0,0,0)}
+                let color = if self.player.survives() {RGB(0xc0,0,0)}
                             else {RGB(0xc0 - (beat * 160.0) as u8, 0, 0)};
                 d.rect(4.0, H-12.0, 4.0 + width as f32, H-4.0, color);
             }
@@ -8,7 +8,6 @@ use std::{str, cmp, io, os, comm, task};
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::comm::{Sender, Receiver};
-use std::rand::{task_rng, Rng};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RWLock, TaskPool};
 
@@ -18,10 +17,14 @@ use format::timeline::TimelineInfo;
 use format::metadata::Meta;
 use format::bms;
 use format::bms::Bms;
+use format::bms::parse::ParserOptions;
 use format::bms::diag::Severity;
+use format::bms::catalog::localize;
+use format::{bmson, osu, stepmania};
 use util::filesearch::SearchContext;
 use util::envelope::Envelope;
-use util::md5::MD5Hash;
+use util::std::rand::seeded_rng;
+use util::md5::{MD5, MD5Hash};
 use gfx::gl::{PreparedSurface, Texture2D};
 use gfx::screen::Screen;
 use gfx::skin::scalar::{Scalar, IntoScalar};
@@ -29,7 +32,7 @@ use gfx::skin::hook::Hook;
 use gfx::skin::render::Renderer;
 use engine::input::read_keymap;
 use engine::keyspec::{KeySpec, key_spec};
-use engine::resource::{SearchContextAdditions, LoadedImagelike};
+use engine::resource::{SearchContextAdditions, LoadedImagelike, new_resource_resolver};
 use engine::cache::MetadataCache;
 use engine::player::apply_modf;
 use ui::scene::{Scene, SceneOptions, SceneCommand};
@@ -44,44 +47,81 @@ pub struct PreprocessedBms {
     pub infos: TimelineInfo,
     /// The key specification.
     pub keyspec: KeySpec,
+    /// The seed used for #RANDOM/#SWITCH commands and modifiers. Loading the same file with
+    /// this seed and the same options results in the same data.
+    pub seed: u64,
+}
+
+/// A reference to the selectable chart. A file may contain multiple charts (e.g. each difficulty
+/// in StepMania files), in which case `index` designates one of them.
+#[deriving(PartialEq,Eq,Hash,Clone)]
+pub struct ChartRef {
+    /// A path to the file.
+    pub path: Path,
+    /// The index of the chart in the file, if the file can contain multiple charts.
+    pub index: Option<uint>,
 }
 
 /// Loads and preprocesses the BMS file from given options. Frontend routines should use this.
-pub fn preprocess_bms<'r,R:Rng>(
-        bmspath: &Path, f: &mut Reader, opts: &Options, r: &mut R,
+/// Files with the `.bmson`, `.osu`, `.sm` or `.ssc` extension are loaded with the corresponding
+/// loader instead, where `index` selects one of multiple charts in the file (defaults to 0).
+/// Every random choice is made from `seed`, normally given by `Options::seed_or_random`.
+pub fn preprocess_bms<'r>(
+        bmspath: &Path, index: Option<uint>, f: &mut Reader, opts: &Options, seed: u64,
         loaderopts: &bms::load::LoaderOptions, callback: bms::load::Callback<'r>)
                                 -> Result<PreprocessedBms,String> {
-    let bms = try!(bms::load::load_bms(f, r, loaderopts, callback));
+    use util::std::option::StrOption;
+
+    let mut r = seeded_rng(seed);
+
+    let bms = match file_extension(bmspath).as_ref_slice() {
+        Some("bmson") => try!(bmson::load_bmson(f)),
+        Some("osu") => try!(osu::load_osu(f, &loaderopts.parser)),
+        Some("sm") | Some("ssc") => {
+            try!(stepmania::load_stepmania(f, index.unwrap_or(0), &loaderopts.parser))
+        }
+        _ => {
+            // lets the loader check resource paths as well
+            let mut loaderopts = loaderopts.clone();
+            if loaderopts.basedir.is_none() {
+                loaderopts.basedir = Some(bmspath.dir_path());
+            }
+            if loaderopts.resolver.is_none() {
+                loaderopts.resolver = Some(new_resource_resolver);
+            }
+            try!(bms::load::load_bms(f, &mut r, &loaderopts, callback))
+        }
+    };
     let mut bms = bms.with_bmspath(bmspath);
     let keyspec = try!(key_spec(&bms, opts.preset.clone(),
                                 opts.leftkeys.clone(), opts.rightkeys.clone()));
     keyspec.filter_timeline(&mut bms.timeline);
     let infos = bms.timeline.analyze();
     for &modf in opts.modf.iter() {
-        apply_modf(&mut bms, modf, r, &keyspec);
+        apply_modf(&mut bms, modf, &mut r, &keyspec);
     }
-    Ok(PreprocessedBms { bms: bms, infos: infos, keyspec: keyspec })
+    Ok(PreprocessedBms { bms: bms, infos: infos, keyspec: keyspec, seed: seed })
 }
 
 /// Internal message from the worker task to the main task.
 enum Message {
     /// The worker has scanned more files, some of them with their MD5 hashes.
-    PushFiles(Vec<(Path, Option<MD5Hash>)>),
+    PushFiles(Vec<(ChartRef, Option<MD5Hash>)>),
     /// The worker has finished scanning files.
     NoMoreFiles,
     /// The worker has failed to read and/or load the BMS file. Error message follows.
-    LoadFailed(Path, String),
+    LoadFailed(ChartRef, String),
     /// The worker has read the BMS file and calculated its MD5 hash.
-    HashRead(Path, MD5Hash),
+    HashRead(ChartRef, MD5Hash),
     /// The worker has read the cached metadata.
-    CacheLoaded(Path, Meta),
+    CacheLoaded(ChartRef, Meta),
     /// The worker has loaded the BMS file or failed to do so. Since this message can be delayed,
-    /// the main task should ignore the message with non-current paths.
-    Loaded(Path, PreprocessedBms, Vec<(Option<uint>,bms::diag::BmsMessage)>),
+    /// the main task should ignore the message with non-current charts.
+    Loaded(ChartRef, PreprocessedBms, Vec<(Option<uint>,bms::diag::BmsMessage)>),
     /// The worker has loaded the banner image (the second `String`) for the BMS file (the first
-    /// `Path`). This may be sent after `BmsLoaded` message. Due to the same reason as above
-    /// the main task should ignore the message with non-current paths.
-    BannerLoaded(Path, String, Envelope<PreparedSurface>),
+    /// `ChartRef`). This may be sent after `BmsLoaded` message. Due to the same reason as above
+    /// the main task should ignore the message with non-current charts.
+    BannerLoaded(ChartRef, String, Envelope<PreparedSurface>),
 }
 
 /// Preloaded game data.
@@ -124,8 +164,8 @@ pub enum PreloadState {
 
 /// The scanned entry.
 pub struct Entry {
-    /// A path to loaded file.
-    pub path: Path,
+    /// A reference to loaded chart.
+    pub chart: ChartRef,
     /// MD5 hash. Only present when it has been read.
     pub hash: Option<MD5Hash>,
     /// Loaded metadata if any.
@@ -150,8 +190,8 @@ pub struct SelectingScene {
     pub root: Path,
     /// A list of scanned entries.
     pub files: Vec<Entry>,
-    /// A mapping from the path to scanned entries.
-    fileindices: HashMap<Path, uint>,
+    /// A mapping from the chart to scanned entries.
+    fileindices: HashMap<ChartRef, uint>,
     /// Set to true when the scanner finished scanning.
     pub filesdone: bool,
     /// The index of the topmost entry visible on the screen.
@@ -171,18 +211,57 @@ pub struct SelectingScene {
     keepgoing: Arc<RWLock<bool>>,
 }
 
-/// Returns true if the path should be parsed as a BMS file.
-fn is_bms_file(path: &Path) -> bool {
+/// Returns the lowercased extension of the path if any.
+fn file_extension(path: &Path) -> Option<String> {
     use std::ascii::AsciiExt;
-    match path.extension().and_then(str::from_utf8) {
-        Some(ext) => match ext.to_ascii_lower()[] {
-            "bms" | "bme" | "bml" | "pms" => true,
+    path.extension().and_then(str::from_utf8).map(|ext| ext.to_ascii_lower())
+}
+
+/// Returns true if the path should be parsed as a BMS file (or other supported chart file).
+fn is_bms_file(path: &Path) -> bool {
+    match file_extension(path) {
+        Some(ext) => match ext[] {
+            "bms" | "bme" | "bml" | "pms" | "bmson" | "osu" | "sm" | "ssc" => true,
             _ => false
         },
         _ => false
     }
 }
 
+/// Returns true if the path should be parsed as a file containing multiple charts.
+fn has_multiple_charts(path: &Path) -> bool {
+    match file_extension(path) {
+        Some(ext) => ext[] == "sm" || ext[] == "ssc",
+        _ => false
+    }
+}
+
+/// Derives a hash for the chart in a file containing multiple charts, so that the metadata cache
+/// can distinguish charts in the same file.
+fn chart_hash(hash: &MD5Hash, index: uint) -> MD5Hash {
+    let mut md5 = MD5::new();
+    md5.update(hash.as_slice());
+    md5.update(index.to_string().as_bytes());
+    md5.finish()
+}
+
+/// Expands the scanned file into selectable charts. Files containing multiple charts are read
+/// to count the charts, and unreadable files result in no charts.
+fn expand_charts(path: Path, hash: Option<MD5Hash>,
+                 parseropts: &ParserOptions) -> Vec<(ChartRef, Option<MD5Hash>)> {
+    if !has_multiple_charts(&path) {
+        return vec![(ChartRef { path: path, index: None }, hash)];
+    }
+    let count = io::File::open(&path).map_err(|e| e.to_string())
+                                     .and_then(|mut f| {
+                                         stepmania::count_charts(&mut f, parseropts)
+                                     })
+                                     .unwrap_or(0);
+    Vec::from_fn(count, |i| {
+        (ChartRef { path: path.clone(), index: Some(i) }, hash.map(|h| chart_hash(&h, i)))
+    })
+}
+
 /// Spawns an worker task. We are required to use `libnative` due to the SDL event loop.
 /// Also we need to use our own wrapper to avoid "sending on a closed channel" error from
 /// the default `future_result` wrapper.
@@ -193,14 +272,21 @@ fn spawn_worker_task(name: &'static str, body: proc():Send, on_error: proc():Sen
     });
 }
 
-/// Prints a diagnostic message to the screen.
-/// This can be directly used as a parser message callback.
-pub fn print_diag(line: Option<uint>, msg: bms::diag::BmsMessage) -> bool {
+/// Prints a diagnostic message, adjusted by the diagnostics policy and localized, to the screen.
+/// Returns false when the policy aborts the loading, so that it can be used in a parser message
+/// callback.
+pub fn print_diag(opts: &Options, line: Option<uint>, msg: bms::diag::BmsMessage) -> bool {
     use util::std::option::StrOption;
 
-    let atline = line.map(|line| format!(" at line {}", line));
-    warn!("[{}{}] {}", msg.severity, atline.as_ref_slice_or(""), msg);
-    true
+    match opts.diagpolicy.apply(msg) {
+        Some(msg) => {
+            let msg = localize(msg, opts.lang);
+            let atline = line.map(|line| format!(" at line {}", line));
+            warn!("[{}{}] {}", msg.severity, atline.as_ref_slice_or(""), msg);
+            !opts.diagpolicy.aborts(&msg)
+        }
+        None => true,
+    }
 }
 
 /// The maximum number of entries displayed in one screen.
@@ -240,17 +326,22 @@ impl SelectingScene {
         let sender_ = self.sender.clone();
         let keepgoing = self.keepgoing.clone();
         let cache = self.cache.clone();
+        let parseropts = self.opts.loader_options().parser;
         spawn_worker_task("scanner", proc() {
             debug!("scanner: start");
 
-            fn recur(cache: &Mutex<MetadataCache>, root: Path,
-                     sender: &Sender<Message>, keepgoing: &Arc<RWLock<bool>>) -> bool {
+            fn recur(cache: &Mutex<MetadataCache>, root: Path, sender: &Sender<Message>,
+                     keepgoing: &Arc<RWLock<bool>>, parseropts: &ParserOptions) -> bool {
                 if !*keepgoing.read() { return false; }
 
                 let ret = cache.lock().get_entries(&root);
                 let (dirs, files) = match ret {
                     Ok((dirs, files)) => {
-                        (dirs, files.into_iter().filter(|&(ref p, _)| is_bms_file(p)).collect())
+                        let files = files.into_iter().filter(|&(ref p, _)| is_bms_file(p))
+                                         .flat_map(|(p, h)| {
+                                             expand_charts(p, h, parseropts).into_iter()
+                                         });
+                        (dirs, files.collect())
                     }
                     Err(err) => {
                         warn!("scanner failed to read {}: {}", root.display(), err);
@@ -259,12 +350,12 @@ impl SelectingScene {
                 };
                 sender.send(Message::PushFiles(files));
                 for dir in dirs.into_iter() {
-                    if !recur(cache, dir, sender, keepgoing) { return false; }
+                    if !recur(cache, dir, sender, keepgoing, parseropts) { return false; }
                 }
                 true
             }
 
-            if recur(cache.deref(), root.clone(), &sender, &keepgoing) {
+            if recur(cache.deref(), root.clone(), &sender, &keepgoing, &parseropts) {
                 sender.send(Message::NoMoreFiles);
             }
 
@@ -275,73 +366,86 @@ impl SelectingScene {
     }
 
     /// Makes a new preloading task. This can run on a separate task or a task pool.
-    fn make_preloading_task(&self, path: &Path) -> proc(): Send {
-        let bmspath = path.clone();
+    fn make_preloading_task(&self, chart: &ChartRef) -> proc(): Send {
+        let chart = chart.clone();
         let opts = self.opts.deref().clone();
         let sender = self.sender.clone();
         let cache = self.cache.clone();
         proc() {
-            debug!("preloader for {}: start", bmspath.display());
+            debug!("preloader for {}: start", chart.path.display());
 
             let opts = Rc::new(opts);
 
-            let load_banner = |bmspath: &Path, bannerpath: String, basepath: Option<Path>| {
+            let load_banner = |chart: &ChartRef, bannerpath: String, basepath: Option<Path>| {
                 let mut search = SearchContext::new();
                 let basedir = basepath.clone().unwrap_or(Path::new("."));
                 let fullpath =
                     search.resolve_relative_path_for_image(bannerpath[], &basedir);
-                let res = fullpath.and_then(|path| LoadedImagelike::new(&path, false));
+                let res = fullpath.and_then(|path| LoadedImagelike::new(&path, false, None, [][]));
                 match res {
-                    Ok(LoadedImagelike::Image(surface)) => {
-                        sender.send(Message::BannerLoaded(bmspath.clone(), bannerpath,
+                    Ok(LoadedImagelike::Image(surface, _)) => {
+                        sender.send(Message::BannerLoaded(chart.clone(), bannerpath,
                                                           Envelope::new(surface)));
                     }
                     _ => {}
                 }
             };
 
-            let load_with_reader = |bmspath: &Path,
+            let load_with_reader = |chart: &ChartRef,
                                     (hash, mut f): (MD5Hash, io::File)| -> Result<(), String> {
-                let mut r = task_rng();
                 let mut diags = Vec::new();
                 let loaderopts = opts.loader_options();
 
                 let preproc = {
                     let callback = |line: Option<uint>, msg: bms::diag::BmsMessage| {
-                        diags.push((line, msg));
-                        true
+                        match opts.diagpolicy.apply(msg) {
+                            Some(msg) => {
+                                let abort = opts.diagpolicy.aborts(&msg);
+                                diags.push((line, localize(msg, opts.lang)));
+                                !abort
+                            }
+                            None => true,
+                        }
                     };
-                    try!(preprocess_bms(bmspath, &mut f, opts.deref(),
-                                        &mut r, &loaderopts, callback))
+                    try!(preprocess_bms(&chart.path, chart.index, &mut f, opts.deref(),
+                                        opts.seed_or_random(), &loaderopts, callback))
                 };
 
                 let banner = preproc.bms.meta.banner.clone();
                 let basepath = preproc.bms.meta.basepath.clone();
                 let meta = preproc.bms.meta.common.clone();
-                sender.send(Message::CacheLoaded(bmspath.clone(), meta.clone()));
-                sender.send(Message::Loaded(bmspath.clone(), preproc, diags));
+                sender.send(Message::CacheLoaded(chart.clone(), meta.clone()));
+                sender.send(Message::Loaded(chart.clone(), preproc, diags));
 
-                let _ = cache.lock().put_metadata(&hash, meta);
+                // the random metadata may differ on the next load, so it should not be cached
+                if !meta.random {
+                    let _ = cache.lock().put_metadata(&hash, meta);
+                }
                 if banner.is_some() {
-                    load_banner(bmspath, banner.unwrap(), basepath);
+                    load_banner(chart, banner.unwrap(), basepath);
                 }
                 Ok(())
             };
 
-            let get_hash_and_reader = |bmspath: &Path| -> Result<(MD5Hash, io::File), String> {
+            let get_hash_and_reader = |chart: &ChartRef| -> Result<(MD5Hash, io::File), String> {
                 // we have read the file so we don't want the parser to read it again.
-                let (hash, f) = try!(cache.lock().get_hash(bmspath).map_err(|e| e.to_string()));
-                sender.send(Message::HashRead(bmspath.clone(), hash));
+                let (hash, f) = try!(cache.lock().get_hash(&chart.path)
+                                                 .map_err(|e| e.to_string()));
+                let hash = match chart.index {
+                    Some(index) => chart_hash(&hash, index),
+                    None => hash,
+                };
+                sender.send(Message::HashRead(chart.clone(), hash));
                 let f = match f {
                     Some(f) => f,
-                    None => try!(io::File::open(bmspath).map_err(|e| e.to_string())),
+                    None => try!(io::File::open(&chart.path).map_err(|e| e.to_string())),
                 };
                 Ok((hash, f))
             };
 
-            match get_hash_and_reader(&bmspath).and_then(|ret| load_with_reader(&bmspath, ret)) {
+            match get_hash_and_reader(&chart).and_then(|ret| load_with_reader(&chart, ret)) {
                 Ok(()) => {}
-                Err(err) => { sender.send(Message::LoadFailed(bmspath, err)); }
+                Err(err) => { sender.send(Message::LoadFailed(chart, err)); }
             }
 
             debug!("preloader: done");
@@ -350,22 +454,22 @@ impl SelectingScene {
 
     /// Makes a new cached preloading task.
     /// This task tries to read the cache first, and falls back to the preloading task if needed.
-    fn make_cached_preloading_task(&self, hash: &MD5Hash, path: &Path) -> proc(): Send {
+    fn make_cached_preloading_task(&self, hash: &MD5Hash, chart: &ChartRef) -> proc(): Send {
         let hash = *hash;
-        let bmspath = path.clone();
+        let chart_ = chart.clone();
         let cache = self.cache.clone();
         let sender = self.sender.clone();
-        let job = self.make_preloading_task(path);
+        let job = self.make_preloading_task(chart);
         proc() {
-            debug!("cached preloader for {} ({}): start", bmspath.display(), hash);
+            debug!("cached preloader for {} ({}): start", chart_.path.display(), hash);
             let meta = cache.lock().get_metadata(&hash);
             match meta {
-                Ok(Some(meta)) => {
-                    sender.send(Message::CacheLoaded(bmspath, meta.clone()));
-                    let _ = cache.lock().put_metadata(&hash, meta);
+                Ok(Some(ref meta)) if !meta.random => {
+                    sender.send(Message::CacheLoaded(chart_, meta.clone()));
+                    let _ = cache.lock().put_metadata(&hash, meta.clone());
                     debug!("cached preloader: done");
                 }
-                Ok(None) | Err(..) => {
+                Ok(Some(..)) | Ok(None) | Err(..) => {
                     job();
                 }
             }
@@ -373,12 +477,12 @@ impl SelectingScene {
     }
 
     /// Spawns a new preloading task.
-    pub fn spawn_preloading_task(&self, path: &Path) {
-        let bmspath = path.clone();
+    pub fn spawn_preloading_task(&self, chart: &ChartRef) {
+        let chart_ = chart.clone();
         let sender = self.sender.clone();
-        spawn_worker_task("preloader", self.make_preloading_task(path), proc() {
+        spawn_worker_task("preloader", self.make_preloading_task(chart), proc() {
             // the task failed to send the error message, so the wrapper sends it instead
-            sender.send(Message::LoadFailed(bmspath, format!("unexpected error")));
+            sender.send(Message::LoadFailed(chart_, format!("unexpected error")));
         });
     }
 
@@ -408,18 +512,18 @@ impl SelectingScene {
         self.spawn_scanning_task();
     }
 
-    /// Returns a `Path` to the currently selected entry if any.
-    pub fn current<'r>(&'r self) -> Option<&'r Path> {
+    /// Returns a `ChartRef` to the currently selected entry if any.
+    pub fn current<'r>(&'r self) -> Option<&'r ChartRef> {
         if self.offset < self.files.len() {
-            Some(&self.files[self.offset].path)
+            Some(&self.files[self.offset].chart)
         } else {
             None
         }
     }
 
-    /// Checks if a given `Path` indeed points to the current entry.
-    pub fn is_current(&self, path: &Path) -> bool {
-        match self.current() { Some(current) => current == path, None => false }
+    /// Checks if a given `ChartRef` indeed points to the current entry.
+    pub fn is_current(&self, chart: &ChartRef) -> bool {
+        match self.current() { Some(current) => current == chart, None => false }
     }
 
     /// Updates the selected entry. `offset` may be out of the range.
@@ -444,25 +548,26 @@ impl SelectingScene {
         use std::mem::replace;
 
         let preloaded = replace(&mut self.preloaded, PreloadState::WaitUntil(0));
-        let PreprocessedBms { bms, infos, keyspec } =
+        let PreprocessedBms { bms, infos, keyspec, seed } =
             match preloaded {
                 PreloadState::Done(data) => data.preproc, // use the preloaded data if possible
                 _ => {
-                    let path = match self.current() {
-                        Some(path) => path,
+                    let chart = match self.current() {
+                        Some(chart) => chart,
                         None => { return None; }
                     };
-                    let mut f = match io::File::open(path) {
+                    let mut f = match io::File::open(&chart.path) {
                         Ok(f) => f,
                         Err(err) => {
-                            warn!("Failed to open {}: {}", path.display(), err);
+                            warn!("Failed to open {}: {}", chart.path.display(), err);
                             return None;
                         }
                     };
-                    let mut r = task_rng();
                     let opts = self.opts.deref();
-                    let ret = preprocess_bms(path, &mut f as &mut Reader, opts, &mut r,
-                                             &opts.loader_options(), print_diag);
+                    let ret = preprocess_bms(&chart.path, chart.index, &mut f as &mut Reader,
+                                             opts, opts.seed_or_random(),
+                                             &opts.loader_options(),
+                                             |line, msg| print_diag(opts, line, msg));
                     match ret {
                         Ok(preproc) => preproc,
                         Err(err) => { warn!("{}", err); return None; }
@@ -473,8 +578,11 @@ impl SelectingScene {
             Ok(map) => map,
             Err(err) => die!("{}", err)
         };
+        // the game play should report the seed actually used
+        let mut opts = self.opts.deref().clone();
+        opts.seed = Some(seed);
         Some(LoadingScene::new(self.screen.clone(), bms, infos,
-                               keyspec, keymap, self.opts.clone()) as Box<Scene+'static>)
+                               keyspec, keymap, Rc::new(opts)) as Box<Scene+'static>)
     }
 }
 
@@ -556,47 +664,47 @@ impl Scene for SelectingScene {
 
         loop {
             match self.receiver.try_recv() {
-                Ok(Message::PushFiles(paths)) => {
+                Ok(Message::PushFiles(charts)) => {
                     if self.files.is_empty() { // immediately preloads the first entry
                         self.preloaded = PreloadState::WaitUntil(0);
                     }
-                    for (path, hash) in paths.into_iter() {
+                    for (chart, hash) in charts.into_iter() {
                         let job = match hash {
-                            Some(hash) => self.make_cached_preloading_task(&hash, &path),
-                            None => self.make_preloading_task(&path),
+                            Some(hash) => self.make_cached_preloading_task(&hash, &chart),
+                            None => self.make_preloading_task(&chart),
                         };
 
                         let index = self.files.len();
-                        self.fileindices.insert(path.clone(), index);
-                        self.files.push(Entry { path: path, hash: hash, meta: None });
+                        self.fileindices.insert(chart.clone(), index);
+                        self.files.push(Entry { chart: chart, hash: hash, meta: None });
                         self.pool.execute(proc() job());
                     }
                 }
                 Ok(Message::NoMoreFiles) => {
                     self.filesdone = true;
                 }
-                Ok(Message::LoadFailed(bmspath, err)) => {
-                    if !self.is_current(&bmspath) { continue; }
+                Ok(Message::LoadFailed(chart, err)) => {
+                    if !self.is_current(&chart) { continue; }
                     self.preloaded = PreloadState::Failed(err);
                 }
-                Ok(Message::HashRead(bmspath, hash)) => {
-                    match self.fileindices.get(&bmspath) {
+                Ok(Message::HashRead(chart, hash)) => {
+                    match self.fileindices.get(&chart) {
                         Some(&offset) => { self.files[mut][offset].hash = Some(hash); }
                         None => {}
                     }
                 }
-                Ok(Message::CacheLoaded(bmspath, meta)) => {
-                    match self.fileindices.get(&bmspath) {
+                Ok(Message::CacheLoaded(chart, meta)) => {
+                    match self.fileindices.get(&chart) {
                         Some(&offset) => { self.files[mut][offset].meta = Some(meta); }
                         None => {}
                     }
                 }
-                Ok(Message::Loaded(bmspath, preproc, messages)) => {
-                    if !self.is_current(&bmspath) { continue; }
+                Ok(Message::Loaded(chart, preproc, messages)) => {
+                    if !self.is_current(&chart) { continue; }
                     self.preloaded = PreloadState::Done(PreloadedData::new(preproc, messages));
                 }
-                Ok(Message::BannerLoaded(bmspath, imgpath, prepared)) => {
-                    if !self.is_current(&bmspath) { continue; }
+                Ok(Message::BannerLoaded(chart, imgpath, prepared)) => {
+                    if !self.is_current(&chart) { continue; }
                     match self.preloaded {
                         PreloadState::Done(ref mut data) if data.banner.is_none() => {
                             let prepared = prepared.unwrap();
@@ -619,8 +727,8 @@ impl Scene for SelectingScene {
                 PreloadState::WaitUntil(timeout) if timeout < get_ticks() => {
                     // preload the current entry after some delay
                     self.preloaded = match self.current() {
-                        Some(path) => {
-                            self.spawn_preloading_task(path);
+                        Some(chart) => {
+                            self.spawn_preloading_task(chart);
                             PreloadState::InProgress
                         },
                         None => PreloadState::None, // XXX wait what happened?!
@@ -729,8 +837,8 @@ impl<'a> Hook for (&'a SelectingScene, bool, &'a Entry) {
         let (scene, _inverted, entry) = *self;
         match id {
             "entry.path" => {
-                let path = entry.path.path_relative_from(&scene.root)
-                                     .unwrap_or_else(|| entry.path.clone());
+                let path = entry.chart.path.path_relative_from(&scene.root)
+                                           .unwrap_or_else(|| entry.chart.path.clone());
                 Some(path.display().to_string().into_scalar())
             },
             "entry.hash" => entry.hash.map(|h| h.to_string().into_scalar()),
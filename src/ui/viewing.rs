@@ -16,7 +16,7 @@ use gfx::surface::SurfaceAreaUtil;
 use gfx::gl::{Texture2D, PreparedSurface, FrameBuffer};
 use gfx::draw::TexturedDrawingTraits;
 use gfx::screen::Screen;
-use engine::resource::{BGAW, BGAH, Imagelike};
+use engine::resource::{BGAW, BGAH, Imagelike, apply_color_key, copy_with_color_key};
 use engine::player::{BGAState, initial_bga_state, Player};
 use ui::common::{Ticker, update_line};
 use ui::scene::{Scene, SceneOptions, SceneCommand};
@@ -32,7 +32,7 @@ impl Uploadable for Imagelike {
     fn upload_to_texture(&self, texture: &Texture2D) {
         match *self {
             Imagelike::None => {}
-            Imagelike::Image(ref surface) | Imagelike::Movie(ref surface,_) => {
+            Imagelike::Image(ref surface,_) | Imagelike::Movie(ref surface,_) => {
                 texture.upload_surface(surface, false, false);
             }
         }
@@ -40,14 +40,17 @@ impl Uploadable for Imagelike {
 
     fn should_always_upload(&self) -> bool {
         match *self {
-            Imagelike::None | Imagelike::Image(_) => false,
+            Imagelike::None | Imagelike::Image(..) => false,
             Imagelike::Movie(_,ref mpeg) => mpeg.status() == SMPEG_PLAYING
         }
     }
 }
 
-/// The canvas to which the BGA is drawn.
+/// The canvas to which the BGA is drawn. The canvas can be larger or smaller than the BGA area
+/// (`BGAW` by `BGAH` pixels), in which case the caller should scale it.
 pub struct BGACanvas {
+    /// The width and height of the canvas.
+    size: (uint, uint),
     /// The current BGA states.
     state: BGAState,
     /// Per-layer textures.
@@ -58,25 +61,78 @@ pub struct BGACanvas {
     framebuf: FrameBuffer,
     /// The scratch surface for partial blitting.
     scratch: PreparedSurface,
+    /// Per-layer scratch surfaces for applying color keys to movies, allocated on demand.
+    keyscratches: Vec<Option<PreparedSurface>>,
+}
+
+/// Returns the scratch surface in `slot` with the same size as `surface`. A new scratch surface
+/// is allocated only when there is none or its size differs.
+fn scratch_for<'r>(slot: &'r mut Option<PreparedSurface>,
+                   surface: &PreparedSurface) -> &'r PreparedSurface {
+    let size = surface.as_surface().get_size();
+    if slot.as_ref().map_or(true, |scratch| scratch.as_surface().get_size() != size) {
+        let (width, height) = size;
+        *slot = match PreparedSurface::new(width as uint, height as uint, true) {
+            Ok(scratch) => Some(scratch),
+            Err(err) => die!("PreparedSurface::new failed: {}", err)
+        };
+    }
+    slot.as_ref().unwrap()
 }
 
 /// Uploads the image pointed by the BGA reference to the texture.
-/// It performs a necessary clipping for `BGARef::SlicedImage`.
+/// It performs a necessary clipping for `BGARef::SlicedImage`, and applies the color key if any.
+/// Static images use the copy color-keyed in advance if possible, otherwise (mostly movies)
+/// the color key is applied to `keyscratch` which is reused for later uploads.
 /// `force` should be set to true when the image has to be updated immediately.
-fn upload_bga_ref_to_texture(bgaref: &BGARef<ImageRef>, imgres: &[Imagelike],
-                             texture: &Texture2D, scratch: &PreparedSurface, force: bool) {
+fn upload_bga_ref_to_texture(bgaref: &BGARef<ImageRef>, colorkey: Option<(u8,u8,u8)>,
+                             imgres: &[Imagelike], texture: &Texture2D,
+                             scratch: &PreparedSurface, keyscratch: &mut Option<PreparedSurface>,
+                             force: bool) {
     match *bgaref {
         BGARef::Image(iref) if force || imgres[**iref as uint].should_always_upload() => {
-            imgres[**iref as uint].upload_to_texture(texture);
+            let res = &imgres[**iref as uint];
+            match (colorkey, res.surface()) {
+                (Some(colorkey), Some(surface)) => {
+                    match res.color_keyed_surface(colorkey) {
+                        Some(keyed) => {
+                            texture.upload_surface(keyed, false, false);
+                        }
+                        None => {
+                            let target = scratch_for(keyscratch, surface);
+                            copy_with_color_key(surface, colorkey, target);
+                            texture.upload_surface(target, false, false);
+                        }
+                    }
+                }
+                _ => {
+                    res.upload_to_texture(texture);
+                }
+            }
         }
         BGARef::SlicedImage(iref, ref slice)
                 if force || imgres[**iref as uint].should_always_upload() => {
+            let res = &imgres[**iref as uint];
             scratch.as_surface().fill(RGBA(0, 0, 0, 0));
-            for surface in imgres[**iref as uint].surface().into_iter() {
-                // this requires SDL_SRCALPHA flags in `surface` (and not `scratch`).
-                // see `LoadedImagelike::new` for relevant codes.
-                scratch.as_surface().blit_area(surface.as_surface(), (slice.sx, slice.sy),
-                                               (slice.dx, slice.dy), (slice.w, slice.h));
+            match colorkey.and_then(|colorkey| res.color_keyed_surface(colorkey)) {
+                Some(keyed) => {
+                    // the color-keyed copy has no SDL_SRCALPHA flag, so the alpha channel
+                    // is copied as is.
+                    scratch.as_surface().blit_area(keyed.as_surface(), (slice.sx, slice.sy),
+                                                   (slice.dx, slice.dy), (slice.w, slice.h));
+                }
+                None => {
+                    for surface in res.surface().into_iter() {
+                        // this requires SDL_SRCALPHA flags in `surface` (and not `scratch`).
+                        // see `LoadedImagelike::new` for relevant codes.
+                        scratch.as_surface().blit_area(surface.as_surface(),
+                                                       (slice.sx, slice.sy),
+                                                       (slice.dx, slice.dy), (slice.w, slice.h));
+                    }
+                    for &colorkey in colorkey.iter() {
+                        apply_color_key(scratch, colorkey);
+                    }
+                }
             }
             texture.upload_surface(scratch, false, false);
         }
@@ -85,25 +141,29 @@ fn upload_bga_ref_to_texture(bgaref: &BGARef<ImageRef>, imgres: &[Imagelike],
 }
 
 impl BGACanvas {
-    /// Creates an initial canvas state and resources.
-    pub fn new(imgres: &[Imagelike]) -> BGACanvas {
+    /// Creates an initial canvas state and resources. `size` is the width and height of
+    /// the canvas (e.g. `BmsMeta::canvassize`).
+    pub fn new(imgres: &[Imagelike], size: (uint, uint)) -> BGACanvas {
         let state = initial_bga_state();
+        let (width, height) = size;
 
-        let scratch = match PreparedSurface::new(BGAW, BGAH, true) {
+        let scratch = match PreparedSurface::new(width, height, true) {
             Ok(surface) => surface,
             Err(err) => die!("PreparedSurface::new failed: {}", err)
         };
 
-        let textures = state.iter().map(|iref| {
-            let texture = match Texture2D::new(BGAW, BGAH) {
+        let mut keyscratches = Vec::from_fn(NLAYERS, |_| None);
+        let textures = range(0, NLAYERS).map(|layer| {
+            let texture = match Texture2D::new(width, height) {
                 Ok(texture) => texture,
                 Err(err) => die!("Texture2D::new failed: {}", err)
             };
-            upload_bga_ref_to_texture(iref, imgres, &texture, &scratch, true);
+            upload_bga_ref_to_texture(&state.refs[layer], state.colorkeys[layer], imgres,
+                                      &texture, &scratch, &mut keyscratches[mut][layer], true);
             texture
         }).collect();
 
-        let canvas = match Texture2D::new(BGAW, BGAH) {
+        let canvas = match Texture2D::new(width, height) {
             Ok(texture) => texture,
             Err(err) => die!("Texture2D::new failed: {}", err)
         };
@@ -111,45 +171,55 @@ impl BGACanvas {
 
         let framebuf = FrameBuffer::from_texture(&canvas);
 
-        BGACanvas { state: state, textures: textures, canvas: canvas, framebuf: framebuf,
-                    scratch: scratch }
+        BGACanvas { size: size, state: state, textures: textures, canvas: canvas,
+                    framebuf: framebuf, scratch: scratch, keyscratches: keyscratches }
     }
 
     /// Updates the BGA state. This method prepares given image resources for the next rendering,
     /// notably by starting and stopping the movie playback and uploading textures as needed.
     pub fn update(&mut self, current: &BGAState, imgres: &[Imagelike]) {
         for layer in range(0, NLAYERS) {
-            if self.state[layer] != current[layer] {
+            if self.state.refs[layer] != current.refs[layer] ||
+                    self.state.colorkeys[layer] != current.colorkeys[layer] {
                 // TODO this design can't handle the case that a BGA layer is updated to the same
                 // image reference, which should rewind the movie playback.
-                if self.state[layer].as_image_ref() != current[layer].as_image_ref() {
-                    for &iref in self.state[layer].as_image_ref().into_iter() {
+                if self.state.refs[layer].as_image_ref() != current.refs[layer].as_image_ref() {
+                    for &iref in self.state.refs[layer].as_image_ref().into_iter() {
                         imgres[**iref as uint].stop_animating();
                     }
-                    for &iref in current[layer].as_image_ref().into_iter() {
+                    for &iref in current.refs[layer].as_image_ref().into_iter() {
                         imgres[**iref as uint].start_animating();
                     }
                 }
-                upload_bga_ref_to_texture(&current[layer], imgres,
-                                          &self.textures[layer], &self.scratch, true);
+                upload_bga_ref_to_texture(&current.refs[layer], current.colorkeys[layer], imgres,
+                                          &self.textures[layer], &self.scratch,
+                                          &mut self.keyscratches[mut][layer], true);
             } else {
-                upload_bga_ref_to_texture(&self.state[layer], imgres,
-                                          &self.textures[layer], &self.scratch, false);
+                upload_bga_ref_to_texture(&self.state.refs[layer], self.state.colorkeys[layer],
+                                          imgres, &self.textures[layer], &self.scratch,
+                                          &mut self.keyscratches[mut][layer], false);
             }
-            self.state[layer] = current[layer].clone();
+            self.state.refs[layer] = current.refs[layer].clone();
+            self.state.opacities[layer] = current.opacities[layer];
+            self.state.colorkeys[layer] = current.colorkeys[layer];
         }
     }
 
     /// Renders the image resources to the internal canvas texture.
+    /// Each layer is blended with its current opacity.
     pub fn render_to_texture(&self, screen: &mut Screen, layers: &[BGALayer]) {
+        let (width, height) = self.size;
         screen.render_to_framebuffer(&self.framebuf, |buf| {
             buf.clear();
             for &layer in layers.iter() {
-                match self.state[layer as uint] {
+                let opacity = self.state.opacities[layer as uint];
+                match self.state.refs[layer as uint] {
                     BGARef::Blank => {}
+                    _ if opacity == 0 => {}
                     _ => {
                         buf.draw_textured(&self.textures[layer as uint], |d| {
-                            d.rect(0.0, 0.0, BGAW as f32, BGAH as f32);
+                            d.rect_rgba(0.0, 0.0, width as f32, height as f32,
+                                        (255, 255, 255, opacity));
                         });
                     }
                 }
@@ -190,13 +260,17 @@ impl Scene for TextualViewingScene {
 
         let elapsed = (self.player.now - self.player.origintime) / 100;
         let duration = (self.player.duration * 10.0) as uint;
+        let text = match self.player.text() {
+            Some(text) => format!(" | {}", text),
+            None => String::new(),
+        };
         update_line(format!("{:02}:{:02}.{} / {:02}:{:02}.{} (@{pos:9.4}) | \
-                             BPM {bpm:6.2} | {lastcombo} / {nnotes} notes",
+                             BPM {bpm:6.2} | {lastcombo} / {nnotes} notes{text}",
                             elapsed/600, elapsed/10%60, elapsed%10,
                             duration/600, duration/10%60, duration%10,
                             pos = self.player.cur.loc.vpos, bpm = *self.player.bpm,
                             lastcombo = self.player.lastcombo,
-                            nnotes = self.player.infos.nnotes)[]);
+                            nnotes = self.player.infos.nnotes, text = text)[]);
     }
 
     fn deactivate(&mut self) {
@@ -224,7 +298,7 @@ impl ViewingScene {
     /// and pre-loaded image resources.
     pub fn new(screen: Rc<RefCell<Screen>>, imgres: Vec<Imagelike>,
                player: Player) -> Box<ViewingScene> {
-        let bgacanvas = BGACanvas::new(imgres[]);
+        let bgacanvas = BGACanvas::new(imgres[], player.meta.canvassize);
         box ViewingScene { parent: TextualViewingScene::new(player),
                            screen: screen, imgres: imgres, bgacanvas: bgacanvas }
     }
@@ -246,6 +320,7 @@ impl Scene for ViewingScene {
 
         screen.clear();
 
+        // the canvas is scaled to the screen
         let layers = &[BGALayer::Layer1, BGALayer::Layer2, BGALayer::Layer3];
         self.bgacanvas.render_to_texture(screen.deref_mut(), layers);
         screen.draw_textured(self.bgacanvas.as_texture(), |d| {
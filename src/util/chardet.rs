@@ -41,12 +41,17 @@
 
 extern crate "encoding-index-korean" as encoding_index_korean;
 extern crate "encoding-index-japanese" as encoding_index_japanese;
+extern crate "encoding-index-simpchinese" as encoding_index_simpchinese;
+extern crate "encoding-index-tradchinese" as encoding_index_tradchinese;
 
 use std::num::Float;
+use std::io::IoResult;
 
 use util::maybe_owned::{MaybeOwnedVec, IntoMaybeOwnedVec};
 use self::encoding_index_korean::euc_kr;
 use self::encoding_index_japanese::jis0208;
+use self::encoding_index_simpchinese::gb18030;
+use self::encoding_index_tradchinese::big5;
 
 /// An iterator returned by `CharClass::classes`.
 pub struct CharClasses {
@@ -104,7 +109,7 @@ pub trait CharClass {
 }
 
 /// Character classes for Korean.
-#[deriving(Clone)]
+#[deriving(Clone,PartialEq)]
 pub struct CharClassKo;
 
 impl CharClass for CharClassKo {
@@ -149,7 +154,7 @@ impl CharClass for CharClassKo {
 }
 
 /// Character classes for Japanese.
-#[deriving(Clone)]
+#[deriving(Clone,PartialEq)]
 pub struct CharClassJa;
 
 impl CharClass for CharClassJa {
@@ -187,6 +192,90 @@ impl CharClass for CharClassJa {
     }
 }
 
+/// Character classes for Simplified Chinese.
+#[deriving(Clone,PartialEq)]
+pub struct CharClassZhCn;
+
+impl CharClass for CharClassZhCn {
+    // 0:      U+FFFD, or four-byte sequences of GB 18030
+    // 1-94:   first byte 0xa1-0xfe, GB 2312
+    // 95-220: first byte 0x81-0xfe, GBK extension
+
+    fn num_classes(&self) -> uint { 221 }
+
+    fn from_char(&self, c: char) -> Option<uint> {
+        if c < '\u0080' {
+            None
+        } else {
+            let ptr = gb18030::backward(c as u32);
+            if ptr == 0xffff {
+                Some(0)
+            } else {
+                let lead = (ptr / 190) as uint;
+                let trail = (ptr % 190) as uint;
+                if lead < 0xa1 - 0x81 || trail < 0xa1 - 0x41 {
+                    Some(1 + (0xff - 0xa1) + lead)
+                } else {
+                    Some(1 + lead - (0xa1 - 0x81))
+                }
+            }
+        }
+    }
+
+    fn default_freq(&self, cc: uint) -> (f64,f64) {
+        if cc == 0 {
+            (0.0, 99.0)
+        } else if cc >= 1 + (0xff - 0xa1) {
+            (0.0, 9.0)
+        } else {
+            (0.0, 0.0)
+        }
+    }
+}
+
+/// Character classes for Traditional Chinese.
+#[deriving(Clone,PartialEq)]
+pub struct CharClassZhTw;
+
+impl CharClass for CharClassZhTw {
+    // 0:       U+FFFD
+    // 1-178:   first byte 0xa1-0xf9, Big5 (even: second byte < 0x7f, odd: >= 0xa1)
+    // 179-215: first byte 0x81-0xa0 and 0xfa-0xfe, HKSCS extension
+
+    fn num_classes(&self) -> uint { 216 }
+
+    fn from_char(&self, c: char) -> Option<uint> {
+        if c < '\u0080' {
+            None
+        } else {
+            let ptr = big5::backward(c as u32);
+            if ptr == 0xffff {
+                Some(0)
+            } else {
+                let lead = (ptr / 157) as uint;
+                let trail = (ptr % 157) as uint;
+                if lead < 0xa1 - 0x81 {
+                    Some(179 + lead)
+                } else if lead > 0xf9 - 0x81 {
+                    Some(179 + (0xa1 - 0x81) + (lead - (0xfa - 0x81)))
+                } else {
+                    Some(1 + (lead - (0xa1 - 0x81)) * 2 + if trail < 0x7f - 0x40 {0} else {1})
+                }
+            }
+        }
+    }
+
+    fn default_freq(&self, cc: uint) -> (f64,f64) {
+        if cc == 0 {
+            (0.0, 99.0)
+        } else if cc >= 179 {
+            (0.0, 9.0)
+        } else {
+            (0.0, 0.0)
+        }
+    }
+}
+
 /// The trainer for encoding detector.
 #[deriving(Clone)]
 pub struct Trainer<CC> {
@@ -257,7 +346,7 @@ impl<CC:CharClass> Trainer<CC> {
 }
 
 /// The encoding detector.
-#[deriving(Clone)]
+#[deriving(Clone,PartialEq)]
 pub struct Classifier<CC> {
     /// Character classes definition.
     cc: CC,
@@ -295,48 +384,117 @@ impl<CC:CharClass> Classifier<CC> {
     pub fn confidence(&self, s: &str) -> f64 {
         convert_raw_confidence(self.raw_confidence(s))
     }
+
+    /// Reads the classifier written by `Classifier::write` from given reader.
+    /// The number of classes should match the character classes definition.
+    pub fn read(cc: CC, f: &mut Reader) -> Result<Classifier<CC>,String> {
+        let s = try!(f.read_to_string().map_err(|err| err.to_string()));
+        let mut words = s[].lines().filter(|line| !line.trim_left().starts_with("#"))
+                                   .flat_map(|line| line.words());
+        if words.next() != Some("chardet") {
+            return Err("not a character encoding model".to_string());
+        }
+        let nclasses = cc.num_classes();
+        match words.next().and_then(from_str::<uint>) {
+            Some(n) if n == nclasses => {}
+            Some(n) => { return Err(format!("expected {} classes, got {}", nclasses, n)); }
+            None => { return Err("invalid number of classes".to_string()); }
+        }
+        let mut logprobs = Vec::with_capacity(nclasses);
+        for word in words {
+            match from_str::<i32>(word) {
+                Some(v) => { logprobs.push(v); }
+                None => { return Err(format!("invalid probability: {}", word)); }
+            }
+        }
+        if logprobs.len() != nclasses {
+            return Err(format!("expected {} probabilities, got {}", nclasses, logprobs.len()));
+        }
+        Ok(Classifier::new(cc, logprobs))
+    }
+
+    /// Writes the classifier to given writer. The format is a line `chardet <number of classes>`
+    /// followed by precalculated probabilities separated by whitespaces. Lines starting with `#`
+    /// are ignored.
+    pub fn write(&self, w: &mut Writer) -> IoResult<()> {
+        let logprobs = self.logprobs.as_slice();
+        try!(write!(w, "chardet {}", logprobs.len()));
+        for (i, &v) in logprobs.iter().enumerate() {
+            if i % 10 == 0 { try!(write!(w, "\n   ")); }
+            try!(write!(w, " {:7}", v));
+        }
+        write!(w, "\n")
+    }
 }
 
 /**
  * An entry point for `chardet-train` subprogram.
- * It receives one argument, rescaling factor, which is used to normalize the frequencies
- * for each encoding. Smaller factor creates a forgiving detector, and vice versa.
+ * It receives two arguments, rescaling factor and the output directory. Rescaling factor is used
+ * to normalize the frequencies for each encoding. Smaller factor creates a forgiving detector,
+ * and vice versa. The trained classifiers are written to `ko.chardet`, `ja.chardet`,
+ * `zh-cn.chardet` and `zh-tw.chardet` in the output directory (languages without any word in
+ * the corpus are skipped), which can be loaded with
+ * `format::bms::encoding::ChardetModels::load` (normally from `res/chardet`).
  *
  * This subprogram does not prefer the particular language during the training.
  * Rather, it assumes that one string is likely to appear in some encoding
  * if it can be encoded in that encoding, even when that string is not in the prefered language.
  * It's because many artists tried to use as many characters available as possible;
- * for example, there are instances of Cyrillic characters in both encodings.
+ * for example, there are instances of Cyrillic characters in every encoding.
+ * Every string encoded in one encoding and decoded in another is used as a negative example
+ * for the latter.
  */
 #[cfg(not(no_subprogram))]
 pub fn chardet_train(args: &[String]) -> int {
-    use std::io::{stdin, stderr, BufferedReader};
-    use encoding::{Encoding, EncoderTrap, DecoderTrap};
-    use encoding::all::{WINDOWS_949, WINDOWS_31J};
+    use std::io::{stdin, stderr, File, BufferedReader, BufferedWriter};
+    use encoding::{Encoding, EncodingRef, EncoderTrap, DecoderTrap};
+    use encoding::all::{WINDOWS_949, WINDOWS_31J, GB18030, BIG5_2003};
 
-    if args.len() != 1 {
+    if args.len() != 2 {
         let _ = write!(&mut stderr(),
-                       "Usage: {prog} --subprogram chardet-train <rescale> \
-                               < per-line-corpus-in-utf-8 > code.rs\n", prog = ::exename());
+                       "Usage: {prog} --subprogram chardet-train <rescale> <output-dir> \
+                               < per-line-corpus-in-utf-8\n", prog = ::exename());
         return 1;
     }
 
     let rescale = match from_str::<f64>(args[0][]) {
         Some(v) if v > 0.0 => v,
         _ => {
-            let _ = write!(&mut stderr(), "Invalid rescale argument: {}\n", args[1]);
+            let _ = write!(&mut stderr(), "Invalid rescale argument: {}\n", args[0]);
+            return 1;
+        }
+    };
+    let outdir = match Path::new_opt(args[1][]) {
+        Some(path) => path,
+        None => {
+            let _ = write!(&mut stderr(), "Invalid output directory: {}\n", args[1]);
             return 1;
         }
     };
 
-    // `<lang1>trainer<lang2>` is a trainer using character classes for <lang1> and
-    // weights scaled as <lang2>. this makes the training a bit faster.
-    let mut kotrainerko = Trainer::new(CharClassKo);
-    let mut kotrainerja = Trainer::new(CharClassKo);
-    let mut jatrainerko = Trainer::new(CharClassJa);
-    let mut jatrainerja = Trainer::new(CharClassJa);
-    let mut nkowords = 0u;
-    let mut njawords = 0u;
+    let langs = ["Korean", "Japanese", "Simplified Chinese", "Traditional Chinese"];
+    let encodings = [WINDOWS_949 as EncodingRef, WINDOWS_31J as EncodingRef,
+                     GB18030 as EncodingRef, BIG5_2003 as EncodingRef];
+
+    // `<lang>trainers[i]` is a trainer using character classes for <lang> and weights scaled as
+    // the i-th language. this makes the training a bit faster.
+    let mut kotrainers = Vec::from_elem(langs.len(), Trainer::new(CharClassKo));
+    let mut jatrainers = Vec::from_elem(langs.len(), Trainer::new(CharClassJa));
+    let mut zhcntrainers = Vec::from_elem(langs.len(), Trainer::new(CharClassZhCn));
+    let mut zhtwtrainers = Vec::from_elem(langs.len(), Trainer::new(CharClassZhTw));
+    let mut nlangwords = Vec::from_elem(langs.len(), 0u);
+
+    // trains `trainers[lang]` with the word encoded in the `lang`-th encoding. the word is
+    // a positive example for the trainer's own language (`own`) and a negative one otherwise.
+    fn train_word<CC:CharClass>(trainers: &mut [Trainer<CC>], own: uint, encoding: EncodingRef,
+                                lang: uint, w: &str, encoded: &[u8]) {
+        if lang == own {
+            trainers[mut][lang].train(w, true);
+        } else {
+            let w_ = encoding.decode(encoded, DecoderTrap::Replace).unwrap();
+            trainers[mut][lang].train(w_[], false);
+        }
+    }
 
     let mut stream = BufferedReader::new(stdin());
     let words: Vec<String> =
@@ -346,56 +504,98 @@ pub fn chardet_train(args: &[String]) -> int {
         if (i + 1) % 10000 == 0 {
             let _ = write!(&mut stderr(), "Processing {} out of {} words...\n", i + 1, nwords);
         }
-        match WINDOWS_949.encode(w[], EncoderTrap::Strict) {
-            Ok(w_) => {
-                let w_ = WINDOWS_31J.decode(w_[], DecoderTrap::Replace).unwrap();
-                nkowords += 1;
-                kotrainerko.train(w[], true);
-                jatrainerko.train(w_[], false);
-            }
-            Err(..) => {}
-        }
-        match WINDOWS_31J.encode(w[], EncoderTrap::Strict) {
-            Ok(w_) => {
-                let w_ = WINDOWS_949.decode(w_[], DecoderTrap::Replace).unwrap();
-                njawords += 1;
-                jatrainerja.train(w[], true);
-                kotrainerja.train(w_[], false);
-            }
-            Err(..) => {}
+
+        // GB 18030 can encode every character, so only its two-byte part (i.e. GBK) counts
+        let ingbk = !CharClassZhCn.classes(w[]).any(|cls| cls == 0);
+        for (lang, &encoding) in encodings.iter().enumerate() {
+            if lang == 2 && !ingbk { continue; }
+            let encoded = match encoding.encode(w[], EncoderTrap::Strict) {
+                Ok(encoded) => encoded,
+                Err(..) => { continue; }
+            };
+            nlangwords[mut][lang] += 1;
+            train_word(kotrainers[mut], 0, encodings[0], lang, w[], encoded[]);
+            train_word(jatrainers[mut], 1, encodings[1], lang, w[], encoded[]);
+            train_word(zhcntrainers[mut], 2, encodings[2], lang, w[], encoded[]);
+            train_word(zhtwtrainers[mut], 3, encodings[3], lang, w[], encoded[]);
         }
     }
 
-    if nkowords == 0 {
-        let _ = write!(&mut stderr(), "There are no Korean words available.\n");
+    if nlangwords.iter().all(|&n| n == 0) {
+        let _ = write!(&mut stderr(), "There are no words available.\n");
         return 1;
     }
-    if njawords == 0 {
-        let _ = write!(&mut stderr(), "There are no Japanese words available.\n");
-        return 1;
+    for (lang, &n) in langs.iter().zip(nlangwords.iter()) {
+        if n == 0 {
+            let _ = write!(&mut stderr(), "There are no {} words available, skipping.\n", *lang);
+        }
     }
 
-    kotrainerko.scale(rescale / nkowords as f64);
-    jatrainerko.scale(rescale / nkowords as f64);
-    kotrainerja.scale(rescale / njawords as f64);
-    jatrainerja.scale(rescale / njawords as f64);
-
-    kotrainerko.merge(kotrainerja);
-    jatrainerja.merge(jatrainerko);
-
-    fn process_trainer<CC:CharClass+Clone>(mut trainer: Trainer<CC>, varname: &str) {
-        trainer.add_default();
-        let classifier = trainer.into_classifier();
-        print!("static {}: &'static [i32] = &[", varname);
-        for (i, &v) in classifier.logprobs.as_slice().iter().enumerate() {
-            if i % 10 == 0 { print!("\n   "); }
-            print!(" {:7},", v);
+    // languages without any words are neither written nor used as negative examples
+    fn process_trainers<CC:CharClass+Clone>(trainers: Vec<Trainer<CC>>, own: uint,
+                                            nlangwords: &[uint], rescale: f64,
+                                            path: &Path) -> IoResult<()> {
+        if nlangwords[own] == 0 { return Ok(()); }
+        let mut trainers = trainers.into_iter().zip(nlangwords.iter())
+                                   .filter(|&(_, &n)| n > 0)
+                                   .map(|(mut trainer, &n)| {
+            trainer.scale(rescale / n as f64);
+            trainer
+        });
+        let mut merged = trainers.next().unwrap();
+        for trainer in trainers {
+            merged.merge(trainer);
         }
-        println!("\n];\n");
+        merged.add_default();
+        let classifier = merged.into_classifier();
+        let mut f = BufferedWriter::new(try!(File::create(path)));
+        try!(write!(&mut f, "# trained with rescale factor {}\n", rescale));
+        try!(classifier.write(&mut f));
+        f.flush()
+    }
+
+    // stops at the first failed write
+    fn write_models(kotrainers: Vec<Trainer<CharClassKo>>, jatrainers: Vec<Trainer<CharClassJa>>,
+                    zhcntrainers: Vec<Trainer<CharClassZhCn>>,
+                    zhtwtrainers: Vec<Trainer<CharClassZhTw>>,
+                    nlangwords: &[uint], rescale: f64, outdir: &Path) -> IoResult<()> {
+        try!(process_trainers(kotrainers, 0, nlangwords, rescale, &outdir.join("ko.chardet")));
+        try!(process_trainers(jatrainers, 1, nlangwords, rescale, &outdir.join("ja.chardet")));
+        try!(process_trainers(zhcntrainers, 2, nlangwords, rescale,
+                              &outdir.join("zh-cn.chardet")));
+        try!(process_trainers(zhtwtrainers, 3, nlangwords, rescale,
+                              &outdir.join("zh-tw.chardet")));
+        Ok(())
     }
 
-    process_trainer(kotrainerko, "LOG_PROBS_KO");
-    process_trainer(jatrainerja, "LOG_PROBS_JA");
-    return 0;
+    let ret = write_models(kotrainers, jatrainers, zhcntrainers, zhtwtrainers,
+                           nlangwords[], rescale, &outdir);
+    match ret {
+        Ok(()) => 0,
+        Err(err) => {
+            let _ = write!(&mut stderr(), "Couldn't write the models: {}\n", err);
+            1
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, MemWriter};
+    use super::{Classifier, CharClassJa};
+
+    #[test]
+    fn test_read_write() {
+        let logprobs = Vec::from_fn(122, |i| i as i32 * 1000 - 50000);
+        let classifier = Classifier::new(CharClassJa, logprobs);
+        let mut w = MemWriter::new();
+        classifier.write(&mut w).unwrap();
+        let written = w.into_inner();
+        let read = Classifier::read(CharClassJa, &mut BufReader::new(written[])).unwrap();
+        assert!(read == classifier);
+
+        assert!(Classifier::read(CharClassJa, &mut BufReader::new(b"chardet 1\n0\n")).is_err());
+        assert!(Classifier::read(CharClassJa, &mut BufReader::new(b"chardet 122\n0\n")).is_err());
+        assert!(Classifier::read(CharClassJa, &mut BufReader::new(b"# foo\nbar 1\n")).is_err());
+    }
+}
@@ -78,3 +78,12 @@ pub mod option {
     }
 }
 
+/// Random number utilities for Rust. Parallels to `std::rand`.
+pub mod rand {
+    use std::rand::{Isaac64Rng, SeedableRng};
+
+    /// Returns a random number generator which always generates the same sequence for given seed.
+    pub fn seeded_rng(seed: u64) -> Isaac64Rng {
+        SeedableRng::from_seed([seed][])
+    }
+}